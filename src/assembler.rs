@@ -1,34 +1,334 @@
 extern crate byteorder;
 
+use crate::att;
+use crate::encoder;
+use crate::preprocessor::{self, split_arguments, Preprocessor};
+use crate::source::{self, FileSystemLoader, SourceLoader};
 use crate::*;
-use byteorder::{LittleEndian, WriteBytesExt};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    UnknownInstruction(String),
+    UnknownRegister(String),
+    InvalidNumber(String),
+    ValueOutOfRange(String),
+    OperandCount { expected: usize, found: usize },
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    UnknownSectionAttribute(String),
+    OriginRedefined,
+    UnknownDirective(String),
+    UnmatchedDirective(String),
+    UnterminatedConditional,
+    UnterminatedBlock(String),
+    OutsideMacro(String),
+    InvalidMacroName(String),
+    InvalidExpression(String),
+    MacroRecursion(String),
+    UserError(String),
+    UserWarning(String),
+    FileNotFound(String),
+    IncludeCycle(String),
+    Io(String),
+    InvalidOperands(String),
+    InvalidInMode { what: String, bits: u32 },
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnknownInstruction(op) => write!(f, "unknown instruction '{}'", op),
+            ErrorKind::UnknownRegister(reg) => write!(f, "unknown register '{}'", reg),
+            ErrorKind::InvalidNumber(number) => write!(f, "invalid number '{}'", number),
+            ErrorKind::ValueOutOfRange(value) => write!(f, "value '{}' out of range", value),
+            ErrorKind::OperandCount { expected, found } => write!(
+                f,
+                "expected {} operand{}, found {}",
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                found
+            ),
+            ErrorKind::UndefinedLabel(label) => write!(f, "undefined label '{}'", label),
+            ErrorKind::DuplicateLabel(label) => write!(f, "label '{}' already defined", label),
+            ErrorKind::UnknownSectionAttribute(attribute) => {
+                write!(f, "unknown section attribute '{}'", attribute)
+            }
+            ErrorKind::OriginRedefined => write!(f, "program origin redefined"),
+            ErrorKind::UnknownDirective(directive) => {
+                write!(f, "unknown preprocessor directive '{}'", directive)
+            }
+            ErrorKind::UnmatchedDirective(directive) => {
+                let opening = match directive.as_str() {
+                    "%endmacro" => "%macro",
+                    "%endrep" | "%exitrep" => "%rep",
+                    _ => "%if",
+                };
+                write!(f, "'{}' without matching '{}'", directive, opening)
+            }
+            ErrorKind::UnterminatedConditional => write!(f, "'%if' without matching '%endif'"),
+            ErrorKind::UnterminatedBlock(directive) => {
+                write!(
+                    f,
+                    "'{}' without matching '%end{}'",
+                    directive,
+                    &directive[1..]
+                )
+            }
+            ErrorKind::OutsideMacro(directive) => write!(f, "'{}' outside of a macro", directive),
+            ErrorKind::InvalidMacroName(name) => write!(f, "invalid macro name '{}'", name),
+            ErrorKind::InvalidExpression(message) => write!(f, "invalid expression {}", message),
+            ErrorKind::MacroRecursion(text) => write!(f, "macro recursion too deep in '{}'", text),
+            ErrorKind::UserError(message) | ErrorKind::UserWarning(message) => {
+                write!(f, "{}", message)
+            }
+            ErrorKind::FileNotFound(name) => write!(f, "file '{}' not found", name),
+            ErrorKind::IncludeCycle(path) => write!(f, "'{}' includes itself", path),
+            ErrorKind::Io(message) => write!(f, "{}", message),
+            ErrorKind::InvalidOperands(message) => write!(f, "{}", message),
+            ErrorKind::InvalidInMode { what, bits } => {
+                write!(f, "{} is not valid in {}-bit mode", what, bits)
+            }
+        }
+    }
+}
+
+// An error in the assembly source, with the (1-based) number and text of the offending line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssemblyError {
+    // The included file containing the line, or the path of the main source if known.
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub source: String,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}: {}", file.display(), self.line, self.kind),
+            None => write!(f, "line {}: {}", self.line, self.kind),
+        }
+    }
+}
+
+impl std::error::Error for AssemblyError {}
 
 enum AssemblyLineResult {
     Bytes(Vec<u8>),
     Label(String),
-    Section(String),
+    Section(SectionDirective),
+    Origin(u64),
+    Bits(u32),
+    Extern(Vec<String>),
+    Constant(String, i64),
+    Syntax(Syntax),
     Relocation(Relocation),
+    // Pads the section to a multiple of `alignment`, unless that takes more than `max` bytes.
+    Align {
+        alignment: u64,
+        fill: Option<u8>,
+        max: Option<u64>,
+    },
+    Global(Vec<String>),
+    Local(Vec<String>),
+    Type(String, SymbolType),
+    Size(String, SizeExpression),
+    // `local` commons are reserved in .bss instead of being left to the linker.
+    Common {
+        name: String,
+        size: u64,
+        align: u64,
+        local: bool,
+    },
+}
+
+// A term of a .size expression, added or subtracted. The location counter "." is a value.
+enum SizeTerm {
+    Value(i64),
+    Label(String),
+}
+
+type SizeExpression = Vec<(i64, SizeTerm)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Syntax {
+    Intel,
+    Att,
+}
+
+struct SectionDirective {
+    name: String,
+    start: Option<u64>,
+    vstart: Option<u64>,
+    align: Option<u64>,
+    flags: Option<String>,
+    nobits: Option<bool>,
+}
+
+// Parses "NAME [start=N] [vstart=N] [align=N]", as accepted by nasm for flat binaries.
+fn section_directive(argument: &str) -> Result<SectionDirective, ErrorKind> {
+    let mut words = argument.split_whitespace();
+    let mut directive = SectionDirective {
+        name: words.next().unwrap_or("").to_string(),
+        start: None,
+        vstart: None,
+        align: None,
+        flags: None,
+        nobits: None,
+    };
+    for word in words {
+        let mut parts = word.splitn(2, '=');
+        let key = parts.next().unwrap();
+        let value = parts.next();
+        let field = match key {
+            "start" => &mut directive.start,
+            "vstart" => &mut directive.vstart,
+            "align" => &mut directive.align,
+            _ => return Err(ErrorKind::UnknownSectionAttribute(word.to_string())),
+        };
+        let value = to_uint::<u64>(value.unwrap_or(""))?;
+        if key == "align" && !value.is_power_of_two() {
+            return Err(ErrorKind::ValueOutOfRange(word.to_string()));
+        }
+        *field = Some(value);
+    }
+    Ok(directive)
+}
+
+// Parses the arguments of GAS's `.section NAME[, "FLAGS"[, @TYPE]]`.
+fn gas_section_directive(arguments: &[&str]) -> Result<SectionDirective, ErrorKind> {
+    let name = arguments[0].trim_matches('"');
+    if name.is_empty() {
+        return Err(ErrorKind::OperandCount {
+            expected: 1,
+            found: 0,
+        });
+    }
+    let flags = match arguments.get(1) {
+        Some(flags) if flags.len() >= 2 && flags.starts_with('"') && flags.ends_with('"') => {
+            let flags = &flags[1..flags.len() - 1];
+            if !flags.chars().all(|c| "awxMSG".contains(c)) {
+                return Err(ErrorKind::UnknownSectionAttribute(flags.to_string()));
+            }
+            Some(flags.to_string())
+        }
+        Some(flags) => return Err(ErrorKind::UnknownSectionAttribute(flags.to_string())),
+        None => None,
+    };
+    // Further arguments, like the entity size of mergeable sections, don't matter here.
+    let nobits = match arguments.get(2).map(|t| t.trim_start_matches(['@', '%'])) {
+        Some("progbits") => Some(false),
+        Some("nobits") => Some(true),
+        Some(typ) => return Err(ErrorKind::UnknownSectionAttribute(typ.to_string())),
+        None => None,
+    };
+    Ok(SectionDirective {
+        name: name.to_string(),
+        start: None,
+        vstart: None,
+        align: None,
+        flags,
+        nobits,
+    })
 }
 
 pub struct Relocation {
     typ: RelocationType,
     label: String,
     location: u64,
+    addend: i64,
+    // Index of the preprocessed line the relocation comes from, for error messages.
+    line: usize,
 }
 
-fn register_offset(reg: &str) -> u8 {
-    let offsets = vec!["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
-    offsets.iter().position(|&r| r == reg).unwrap() as u8
+// Resolves nasm-style local labels (".loop", scoped under the preceding non-local label) and
+// GAS-style numeric labels ("1:", referenced as "1b" or "1f") to unique names. GAS local
+// symbols like ".LC0" are left alone.
+#[derive(Default)]
+struct LabelScope {
+    global: Option<String>,
+    // How often each numeric label has been defined so far.
+    numeric: HashMap<String, usize>,
 }
 
-fn to_uint<T: HexAndDecimalConvertable>(str: &str) -> T {
+impl LabelScope {
+    fn is_local(name: &str) -> bool {
+        name.starts_with('.') && !name.starts_with("..") && !name.starts_with(".L")
+    }
+
+    // '^' can't occur in labels, so these names don't clash with others.
+    fn numeric_name(number: &str, count: usize) -> String {
+        format!(".L{}^{}", number, count)
+    }
+
+    // Returns the full name of a label being defined.
+    fn define(&mut self, name: &str) -> String {
+        if name.chars().all(|c| c.is_ascii_digit()) {
+            let count = self.numeric.entry(name.to_string()).or_insert(0);
+            *count += 1;
+            Self::numeric_name(name, *count)
+        } else if Self::is_local(name) {
+            format!("{}{}", self.global.as_deref().unwrap_or(""), name)
+        } else {
+            // Labels starting with "..", like the ones of macros, and ".L" labels don't start a
+            // new scope.
+            if !name.starts_with('.') {
+                self.global = Some(name.to_string());
+            }
+            name.to_string()
+        }
+    }
+
+    // Returns the full name of a label referenced at the current position.
+    fn reference(&self, name: &str) -> String {
+        let (number, direction) = name.split_at(name.len().saturating_sub(1));
+        if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) {
+            let count = self.numeric.get(number).copied().unwrap_or(0);
+            match direction {
+                "b" if count > 0 => return Self::numeric_name(number, count),
+                "f" => return Self::numeric_name(number, count + 1),
+                _ => {}
+            }
+        }
+        if Self::is_local(name) {
+            format!("{}{}", self.global.as_deref().unwrap_or(""), name)
+        } else {
+            name.to_string()
+        }
+    }
+}
+
+fn to_uint<T: HexAndDecimalConvertable>(str: &str) -> Result<T, ErrorKind> {
     let s = str.trim();
-    if s.len() > 1 && &s[0..2] == "0x" {
-        T::from_hex_str(s.trim_start_matches("0x")).unwrap()
+    let result = if s.len() > 1 && &s[0..2] == "0x" {
+        T::from_hex_str(s.trim_start_matches("0x"))
+    } else {
+        T::parse_decimal(s)
+    };
+    result.map_err(|_| {
+        if !s.is_empty()
+            && s.trim_start_matches("0x")
+                .chars()
+                .all(|c| c.is_ascii_hexdigit())
+        {
+            ErrorKind::ValueOutOfRange(s.to_string())
+        } else {
+            ErrorKind::InvalidNumber(s.to_string())
+        }
+    })
+}
+
+fn expect_operands(arguments: &[&str], expected: usize) -> Result<(), ErrorKind> {
+    let found = arguments.iter().filter(|a| !a.is_empty()).count();
+    if found == expected {
+        Ok(())
     } else {
-        T::parse_decimal(s).unwrap()
+        Err(ErrorKind::OperandCount { expected, found })
     }
 }
 
@@ -55,172 +355,1153 @@ impl HexAndDecimalConvertable for u32 {
     }
 }
 
-//fn call(arguments: Vec<&str>, location: u64) -> Vec<AssemblyLineResult> {
-//    let label = arguments[0];
-//    if panic_on_missing_label {
-//        let label_location = labels.get(label).expect("Label not defined");
-//        let jump_target = (*label_location as i32 - (location as i32 + 5)) as u32;
-//        let mut ret = vec![0xe8];
-//        ret.write_u32::<LittleEndian>(jump_target).unwrap();
-//        vec![AssemblyLineResult::Bytes(ret)]
-//    } else {
-//        vec![AssemblyLineResult::Bytes(vec![0xe8, 0, 0, 0, 0])]
-//    }
-//}
-//
-//fn jmp(opcode: u8, arguments: Vec<&str>, location: u64) -> Vec<AssemblyLineResult> {
-//    let label = arguments[0];
-//    if panic_on_missing_label {
-//        let label_location = labels.get(label).expect("Label not defined");
-//        let jump_target = (*label_location as i8 - (location as i8 + 2)) as u64;
-//        vec![AssemblyLineResult::Bytes(vec![opcode, jump_target as u8])]
-//    } else {
-//        vec![AssemblyLineResult::Bytes(vec![opcode, 0])]
-//    }
-//}
-
-fn assemble_line(line: &str, location: u64) -> Vec<AssemblyLineResult> {
-    if line.trim().is_empty() {
-        return vec![];
-    }
-
-    let mut parts = line.trim().splitn(2, ' ');
-    let op = parts.next().unwrap().trim();
+impl HexAndDecimalConvertable for u64 {
+    fn from_hex_str(s: &str) -> Result<Self, &'static str> {
+        u64::from_str_radix(s, 16).map_err(|_| "from_str_radix failed :(")
+    }
+    fn parse_decimal(s: &str) -> Result<Self, &'static str> {
+        s.parse().map_err(|_| "u64::parse failed")
+    }
+}
+
+// Parses the mode of "bits N", "[bits N]", "use16", "use32" or "use64".
+fn bits_directive(op: &str, arguments: &[&str]) -> Result<Option<u32>, ErrorKind> {
+    let bits = match op {
+        "bits" => {
+            expect_operands(arguments, 1)?;
+            to_uint(arguments[0])?
+        }
+        "use16" | "use32" | "use64" => {
+            expect_operands(arguments, 0)?;
+            to_uint(&op[3..])?
+        }
+        _ => return Ok(None),
+    };
+    match bits {
+        16 | 32 | 64 => Ok(Some(bits)),
+        _ => Err(ErrorKind::ValueOutOfRange(bits.to_string())),
+    }
+}
+
+// Parses ".intel_syntax [noprefix]" and ".att_syntax [prefix]".
+fn syntax_directive(line: &str) -> Option<Syntax> {
+    let mut words = line.split_whitespace();
+    let syntax = match words.next()? {
+        ".intel_syntax" => Syntax::Intel,
+        ".att_syntax" => Syntax::Att,
+        _ => return None,
+    };
+    match words.next() {
+        None | Some("prefix") | Some("noprefix") => Some(syntax),
+        Some(_) => None,
+    }
+}
+
+// Parses the string literals of .ascii and friends, with C-like escapes.
+fn gas_strings(text: &str) -> Result<Vec<Vec<u8>>, ErrorKind> {
+    let error = || ErrorKind::InvalidOperands(format!("invalid string '{}'", text));
+    let mut ret = vec![];
+    let mut chars = text.trim().chars().peekable();
+    loop {
+        match chars.next() {
+            Some('"') => {}
+            None if !ret.is_empty() => return Ok(ret),
+            _ => return Err(error()),
+        }
+        let mut string = vec![];
+        loop {
+            let c = match chars.next().ok_or_else(error)? {
+                '"' => break,
+                '\\' => match chars.next().ok_or_else(error)? {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'x' => {
+                        let mut value = 0u8;
+                        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(16)) {
+                            value = value.wrapping_mul(16).wrapping_add(digit as u8);
+                            chars.next();
+                        }
+                        string.push(value);
+                        continue;
+                    }
+                    digit @ '0'..='7' => {
+                        let mut value = digit as u8 - b'0';
+                        for _ in 0..2 {
+                            match chars.peek().and_then(|c| c.to_digit(8)) {
+                                Some(digit) => value = value.wrapping_mul(8) + digit as u8,
+                                None => break,
+                            }
+                            chars.next();
+                        }
+                        string.push(value);
+                        continue;
+                    }
+                    c => c,
+                },
+                c => c,
+            };
+            let mut buffer = [0; 4];
+            string.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+        }
+        ret.push(string);
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        match chars.next() {
+            Some(',') => {
+                while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                    chars.next();
+                }
+            }
+            None => return Ok(ret),
+            Some(_) => return Err(error()),
+        }
+    }
+}
+
+// The label which $ and $$ are referenced as, the start of the section they appear in. No label
+// can be defined with this name.
+const SECTION_START: &str = "$$";
+
+// Replaces $ and $$, outside of identifiers, with the addresses of the line and of its section.
+fn substitute_locations(text: &str, line: u64, section: u64) -> String {
+    let mut ret = String::new();
+    let mut chars = text.chars().peekable();
+    let mut previous = ' ';
+    while let Some(c) = chars.next() {
+        if c == '$' && !(previous.is_ascii_alphanumeric() || "_.?#@~^".contains(previous)) {
+            if chars.peek() == Some(&'$') {
+                chars.next();
+                ret += &section.to_string();
+            } else {
+                ret += &line.to_string();
+            }
+            previous = ' ';
+        } else {
+            ret.push(c);
+            previous = c;
+        }
+    }
+    ret
+}
+
+// Evaluates a constant expression, in which $ and $$ may appear. The section's address isn't
+// known yet, so expressions like $-$$ are constant but $ on its own is not.
+fn constant_expression(text: &str, location: u64) -> Result<i64, ErrorKind> {
+    let evaluate = |section: u64| {
+        preprocessor::evaluate(&substitute_locations(text, section + location, section))
+    };
+    let value = evaluate(0)?;
+    if evaluate(0x1000_0000)? != value {
+        return Err(ErrorKind::InvalidExpression(format!(
+            "'{}' depends on the address of the section",
+            text
+        )));
+    }
+    Ok(value)
+}
+
+// Parses a number or a label with an optional "+N" or "-N" addend.
+fn gas_value(text: &str) -> Result<(Option<String>, i64), ErrorKind> {
+    if let Ok(value) = encoder::parse_number(text) {
+        return Ok((None, value));
+    }
+    let (label, addend) = match text.rfind(['+', '-']) {
+        Some(i) if i > 0 => (
+            text[..i].trim(),
+            encoder::parse_number(&text[i..].replace('+', ""))?,
+        ),
+        _ => (text, 0),
+    };
+    if !encoder::is_label(label) {
+        return Err(ErrorKind::InvalidNumber(text.to_string()));
+    }
+    Ok((Some(label.to_string()), addend))
+}
+
+// Parses a fill byte, which may be written as a signed value.
+fn fill_byte(text: &str) -> Result<u8, ErrorKind> {
+    let value = encoder::parse_number(text)?;
+    if !(-128..256).contains(&value) {
+        return Err(ErrorKind::ValueOutOfRange(text.to_string()));
+    }
+    Ok(value as u8)
+}
+
+// Parses a value of a data directive. Besides the values gas_value() accepts, this can be $ or
+// $$ with an optional addend, or a constant expression.
+fn data_value(text: &str, location: u64) -> Result<(Option<String>, i64), ErrorKind> {
+    let text = text.trim();
+    let here = match text.strip_prefix("$$") {
+        Some(rest) => Some((0, rest)),
+        None => text.strip_prefix('$').map(|rest| (location as i64, rest)),
+    };
+    if let Some((base, rest)) = here {
+        let rest = rest.trim();
+        let addend = match rest.strip_prefix('+') {
+            _ if rest.is_empty() => Some(0),
+            Some(addend) => encoder::parse_number(addend).ok(),
+            None if rest.starts_with('-') => encoder::parse_number(rest).ok(),
+            None => None,
+        };
+        if let Some(addend) = addend {
+            return Ok((Some(SECTION_START.to_string()), base.wrapping_add(addend)));
+        }
+    }
+    gas_value(text).or_else(|error| match constant_expression(text, location) {
+        Ok(value) => Ok((None, value)),
+        // Values without $ or $$ report why they aren't a number or label.
+        Err(expression_error) if text.contains('$') => Err(expression_error),
+        Err(_) => Err(error),
+    })
+}
+
+// Emits the values of .byte, .word, .long or .quad, and of db, dw, dd and dq. Labels become
+// relocations. Strings are padded with zeros to a multiple of the size, like nasm does.
+fn data_directive(
+    size: usize,
+    arguments: &[&str],
+    location: u64,
+) -> Result<Vec<AssemblyLineResult>, ErrorKind> {
+    let mut bytes = vec![];
+    let mut ret = vec![];
+    for argument in arguments {
+        let quoted = argument.len() > 1
+            && argument.starts_with(['"', '\'', '`'])
+            && argument.ends_with(&argument[..1]);
+        if quoted {
+            bytes.extend_from_slice(&argument.as_bytes()[1..argument.len() - 1]);
+            bytes.resize(align_up(bytes.len() as u64, size as u64) as usize, 0);
+            continue;
+        }
+        let (label, value) = data_value(argument, location)?;
+        let label = match label {
+            Some(label) => label,
+            None => {
+                let bits = size as u32 * 8;
+                if size < 8 && !(-(1 << (bits - 1))..1 << bits).contains(&value) {
+                    return Err(ErrorKind::ValueOutOfRange(argument.to_string()));
+                }
+                bytes.extend_from_slice(&value.to_le_bytes()[..size]);
+                continue;
+            }
+        };
+        let typ = match size {
+            8 => RelocationType::U64,
+            4 => RelocationType::U32,
+            2 => RelocationType::U16,
+            _ => {
+                return Err(ErrorKind::InvalidOperands(format!(
+                    "bytes can't refer to label '{}'",
+                    label
+                )))
+            }
+        };
+        ret.push(AssemblyLineResult::Relocation(Relocation {
+            typ,
+            label,
+            location: location + bytes.len() as u64,
+            addend: value,
+            line: 0,
+        }));
+        bytes.resize(bytes.len() + size, 0);
+    }
+    ret.insert(0, AssemblyLineResult::Bytes(bytes));
+    Ok(ret)
+}
+
+// Parses the expression of .size, like ".-main" or "end-start".
+fn size_expression(text: &str, location: u64) -> Result<SizeExpression, ErrorKind> {
+    let error = || ErrorKind::InvalidExpression(format!("'{}'", text));
+    let mut terms = vec![];
+    let mut sign = 1;
+    let mut start = 0;
+    for (i, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), '+')))
+    {
+        if c != '+' && c != '-' {
+            continue;
+        }
+        let term = text[start..i].trim();
+        if term.is_empty() && (i != 0 || i == text.len()) {
+            return Err(error());
+        } else if term == "." {
+            terms.push((sign, SizeTerm::Value(location as i64)));
+        } else if let Ok(value) = encoder::parse_number(term) {
+            terms.push((sign, SizeTerm::Value(value)));
+        } else if encoder::is_label(term) {
+            terms.push((sign, SizeTerm::Label(term.to_string())));
+        } else if !term.is_empty() {
+            return Err(error());
+        }
+        sign = if c == '-' { -1 } else { 1 };
+        start = i + 1;
+    }
+    Ok(terms)
+}
+
+fn names(arguments: &[&str]) -> Result<Vec<String>, ErrorKind> {
+    let names: Vec<String> = arguments
+        .iter()
+        .filter(|a| !a.is_empty())
+        .map(|a| a.to_string())
+        .collect();
+    if names.is_empty() {
+        return Err(ErrorKind::OperandCount {
+            expected: 1,
+            found: 0,
+        });
+    }
+    Ok(names)
+}
+
+// Handles `global name[:function|:data], ...`.
+fn global_directive(arguments: &[&str]) -> Result<Vec<AssemblyLineResult>, ErrorKind> {
+    let mut ret = vec![];
+    let mut globals = vec![];
+    for argument in names(arguments)? {
+        let (name, typ) = match argument.split_once(':') {
+            Some((name, typ)) => (name.trim(), Some(typ.trim())),
+            None => (argument.as_str(), None),
+        };
+        let typ = match typ {
+            None => None,
+            Some("function") => Some(SymbolType::Function),
+            Some("data") | Some("object") => Some(SymbolType::Object),
+            Some(typ) => {
+                return Err(ErrorKind::InvalidOperands(format!(
+                    "unknown symbol type '{}'",
+                    typ
+                )))
+            }
+        };
+        if let Some(typ) = typ {
+            ret.push(AssemblyLineResult::Type(name.to_string(), typ));
+        }
+        globals.push(name.to_string());
+    }
+    ret.push(AssemblyLineResult::Global(globals));
+    Ok(ret)
+}
+
+// Handles the GNU as directives, so that compiler output can be assembled. Returns None for
+// unknown directives. Unlike in GAS, undefined symbols aren't external unless declared with
+// .globl or .extern, and PLT references like "call puts@PLT" aren't supported.
+fn gas_directive(
+    op: &str,
+    rest: &str,
+    location: u64,
+) -> Result<Option<Vec<AssemblyLineResult>>, ErrorKind> {
+    let arguments = split_arguments(rest);
+    let optional = |i: usize| arguments.get(i).filter(|a| !a.is_empty());
+    let result = match op {
+        ".text" | ".data" | ".bss" => {
+            expect_operands(&arguments, 0)?;
+            AssemblyLineResult::Section(gas_section_directive(&[op])?)
+        }
+        ".section" => AssemblyLineResult::Section(gas_section_directive(&arguments)?),
+        ".globl" | ".global" => AssemblyLineResult::Global(names(&arguments)?),
+        ".local" => AssemblyLineResult::Local(names(&arguments)?),
+        ".extern" => AssemblyLineResult::Extern(names(&arguments)?),
+        ".type" => {
+            expect_operands(&arguments, 2)?;
+            let typ = match arguments[1].trim_start_matches(['@', '%']) {
+                "function" => SymbolType::Function,
+                "object" => SymbolType::Object,
+                "notype" => SymbolType::NoType,
+                typ => {
+                    return Err(ErrorKind::InvalidOperands(format!(
+                        "unknown symbol type '{}'",
+                        typ
+                    )))
+                }
+            };
+            AssemblyLineResult::Type(arguments[0].to_string(), typ)
+        }
+        ".size" => {
+            expect_operands(&arguments, 2)?;
+            AssemblyLineResult::Size(
+                arguments[0].to_string(),
+                size_expression(arguments[1], location)?,
+            )
+        }
+        ".byte" => return data_directive(1, &arguments, location).map(Some),
+        ".word" | ".short" | ".value" => return data_directive(2, &arguments, location).map(Some),
+        ".long" | ".int" => return data_directive(4, &arguments, location).map(Some),
+        ".quad" => return data_directive(8, &arguments, location).map(Some),
+        ".ascii" | ".asciz" | ".string" => {
+            let mut bytes = vec![];
+            for string in gas_strings(rest)? {
+                bytes.extend_from_slice(&string);
+                if op != ".ascii" {
+                    bytes.push(0);
+                }
+            }
+            AssemblyLineResult::Bytes(bytes)
+        }
+        ".zero" | ".skip" | ".space" => {
+            if op == ".zero" {
+                expect_operands(&arguments, 1)?;
+            }
+            let count = to_uint::<u32>(arguments[0])?;
+            let fill = optional(1).map_or(Ok(0), |f| fill_byte(f))?;
+            AssemblyLineResult::Bytes(vec![fill; count as usize])
+        }
+        ".p2align" | ".balign" | ".align" => {
+            let alignment = if op == ".p2align" {
+                let power = to_uint::<u32>(arguments[0])?;
+                if power > 31 {
+                    return Err(ErrorKind::ValueOutOfRange(arguments[0].to_string()));
+                }
+                1 << power
+            } else {
+                // Like GAS on x86, .align takes a number of bytes.
+                let alignment = to_uint::<u64>(arguments[0])?;
+                if !alignment.is_power_of_two() {
+                    return Err(ErrorKind::ValueOutOfRange(arguments[0].to_string()));
+                }
+                alignment
+            };
+            AssemblyLineResult::Align {
+                alignment,
+                fill: optional(1).map(|f| fill_byte(f)).transpose()?,
+                max: optional(2).map(|m| to_uint(m)).transpose()?,
+            }
+        }
+        ".equ" | ".set" => {
+            expect_operands(&arguments, 2)?;
+            if !encoder::is_label(arguments[0]) {
+                return Err(ErrorKind::InvalidOperands(format!(
+                    "invalid constant name '{}'",
+                    arguments[0]
+                )));
+            }
+            AssemblyLineResult::Constant(
+                arguments[0].to_string(),
+                encoder::parse_number(arguments[1])?,
+            )
+        }
+        ".comm" | ".lcomm" => {
+            if arguments.len() < 2 || arguments.len() > 3 {
+                return Err(ErrorKind::OperandCount {
+                    expected: 2,
+                    found: arguments.iter().filter(|a| !a.is_empty()).count(),
+                });
+            }
+            let align = optional(2).map_or(Ok(1), |a| to_uint::<u64>(a))?;
+            if !align.is_power_of_two() {
+                return Err(ErrorKind::ValueOutOfRange(arguments[2].to_string()));
+            }
+            AssemblyLineResult::Common {
+                name: arguments[0].to_string(),
+                size: to_uint(arguments[1])?,
+                align,
+                local: op == ".lcomm",
+            }
+        }
+        // Debugging information and comments compilers add, which don't affect the code.
+        ".file" | ".ident" | ".loc" => return Ok(Some(vec![])),
+        _ if op.starts_with(".cfi_") => return Ok(Some(vec![])),
+        _ => return Ok(None),
+    };
+    Ok(Some(vec![result]))
+}
+
+fn assemble_line(
+    line: &str,
+    location: u64,
+    bits: u32,
+    syntax: Syntax,
+) -> Result<Vec<AssemblyLineResult>, ErrorKind> {
+    let mut line = line.trim();
+    if line.is_empty() {
+        return Ok(vec![]);
+    }
+    if let Some(syntax) = syntax_directive(line) {
+        return Ok(vec![AssemblyLineResult::Syntax(syntax)]);
+    }
+    // AT&T syntax is translated to Intel syntax, which the rest of the assembler works with.
+    let translated;
+    if syntax == Syntax::Att {
+        translated = att::translate(line)?;
+        line = &translated;
+        if line.is_empty() {
+            return Ok(vec![]);
+        }
+    }
+    if line.starts_with('[') && line.ends_with(']') {
+        line = &line[1..line.len() - 1];
+    }
+
+    let line = line.trim();
+    let (op, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+
+    // `name equ value`, the colon after the name is optional.
+    if let Some(value) = rest.strip_prefix("equ") {
+        let name = op.trim_end_matches(':');
+        if value.starts_with(char::is_whitespace) && encoder::is_label(name) {
+            let value = encoder::parse_number(value)?;
+            return Ok(vec![AssemblyLineResult::Constant(name.to_string(), value)]);
+        }
+    }
 
     if op.ends_with(':') {
-        vec![AssemblyLineResult::Label(
+        // An instruction can follow the label on the same line.
+        let mut ret = vec![AssemblyLineResult::Label(
             op.trim_end_matches(':').to_string(),
-        )]
+        )];
+        ret.extend(assemble_line(rest, location, bits, Syntax::Intel)?);
+        Ok(ret)
     } else {
-        let mut arguments: Vec<&str> = parts.next().unwrap_or("").split(',').collect();
-        arguments = arguments.iter().map(|a| a.trim()).collect();
+        if op.starts_with('.') {
+            if let Some(results) = gas_directive(op, rest, location)? {
+                return Ok(results);
+            }
+        }
+        let arguments = split_arguments(rest);
+        if let Some(bits) = bits_directive(op, &arguments)? {
+            return Ok(vec![AssemblyLineResult::Bits(bits)]);
+        }
         match op {
-            "section" => vec![AssemblyLineResult::Section(arguments[0].to_string())],
-            "syscall" => vec![AssemblyLineResult::Bytes(vec![0xf, 0x5])],
-            "ret" => vec![AssemblyLineResult::Bytes(vec![0xc3])],
-            "mov" => {
-                let target = arguments[0];
-                let source = arguments[1];
-                let opcode = 0xb8 + register_offset(target);
-                let mut ret = vec![opcode];
-                if source.chars().next().unwrap().is_digit(10) {
-                    let value: u32 = to_uint(source);
-                    ret.write_u32::<LittleEndian>(value).unwrap();
-                    vec![AssemblyLineResult::Bytes(ret)]
-                } else {
-                    vec![
-                        AssemblyLineResult::Bytes(ret),
-                        AssemblyLineResult::Relocation(Relocation {
-                            typ: RelocationType::U32,
-                            label: source.to_string(),
-                            location: location + 1,
-                        }),
-                    ]
+            "section" => {
+                expect_operands(&arguments, 1)?;
+                Ok(vec![AssemblyLineResult::Section(section_directive(
+                    arguments[0],
+                )?)])
+            }
+            "extern" => Ok(vec![AssemblyLineResult::Extern(names(&arguments)?)]),
+            "global" => global_directive(&arguments),
+            "org" => {
+                expect_operands(&arguments, 1)?;
+                Ok(vec![AssemblyLineResult::Origin(to_uint(arguments[0])?)])
+            }
+            "db" => data_directive(1, &arguments, location),
+            "dw" => data_directive(2, &arguments, location),
+            "dd" => data_directive(4, &arguments, location),
+            "dq" => data_directive(8, &arguments, location),
+            "times" => times(rest, location, bits),
+            _ => {
+                let encoding = encoder::encode(op, &arguments, bits)?;
+                let mut ret = vec![AssemblyLineResult::Bytes(encoding.bytes)];
+                for fixup in encoding.fixups {
+                    // $ is the start of the line, an offset into its section.
+                    let (label, addend) = match fixup.label.as_str() {
+                        "$" => (
+                            SECTION_START.to_string(),
+                            fixup.addend.wrapping_add(location as i64),
+                        ),
+                        _ => (fixup.label, fixup.addend),
+                    };
+                    ret.push(AssemblyLineResult::Relocation(Relocation {
+                        typ: fixup.typ,
+                        label,
+                        location: location + fixup.offset as u64,
+                        addend,
+                        line: 0,
+                    }));
                 }
+                Ok(ret)
             }
-            //"jmp" => jmp(0xeb, arguments, location),
-            //"je" => jmp(0x74, arguments, location),
-            //"jg" => jmp(0x7f, arguments, location),
-            //"jl" => jmp(0x7c, arguments, location),
-            //"jle" => jmp(0x7e, arguments, location),
-            "cmp" => {
-                let target = arguments[0];
-                let value = to_uint::<u8>(arguments[1]);
-                let modrm = 0xf8 + register_offset(target);
-                vec![AssemblyLineResult::Bytes(vec![0x83, modrm, value])]
-            }
-            //"call" => call(arguments, location),
-            "db" => {
-                let mut ret = vec![];
-                for arg in &arguments {
-                    if arg.as_bytes().first() == Some(&b'"') {
-                        ret.extend_from_slice(arg.trim_matches('"').as_bytes());
-                    } else {
-                        ret.push(to_uint(arg));
-                    }
+        }
+    }
+}
+
+// Handles `times count line`, which repeats the line. Like nasm, the count is the longest
+// prefix which is a constant expression.
+fn times(rest: &str, location: u64, bits: u32) -> Result<Vec<AssemblyLineResult>, ErrorKind> {
+    let (count, line) = rest
+        .char_indices()
+        .filter(|&(_, c)| c.is_whitespace())
+        .rev()
+        .find_map(|(i, _)| {
+            let count = constant_expression(&rest[..i], location).ok()?;
+            Some((count, &rest[i..]))
+        })
+        .ok_or_else(|| {
+            let count = rest.split_whitespace().next().unwrap_or("");
+            constant_expression(count, location)
+                .err()
+                .unwrap_or(ErrorKind::OperandCount {
+                    expected: 2,
+                    found: 1,
+                })
+        })?;
+    let count = u32::try_from(count).map_err(|_| ErrorKind::ValueOutOfRange(count.to_string()))?;
+
+    let mut bytes = vec![];
+    let mut ret = vec![];
+    for _ in 0..count {
+        for result in assemble_line(line, location + bytes.len() as u64, bits, Syntax::Intel)? {
+            match result {
+                AssemblyLineResult::Bytes(repeated) => bytes.extend_from_slice(&repeated),
+                relocation @ AssemblyLineResult::Relocation(_) => ret.push(relocation),
+                _ => {
+                    return Err(ErrorKind::InvalidOperands(
+                        "times can only repeat instructions and data".to_string(),
+                    ))
                 }
-                vec![AssemblyLineResult::Bytes(ret)]
             }
-            _ => panic!("Not implemented"),
         }
     }
+    ret.insert(0, AssemblyLineResult::Bytes(bytes));
+    Ok(ret)
+}
+
+// Options for assemble_with().
+pub struct Options {
+    // Macros defined before the source is preprocessed, like with nasm's -D.
+    pub defines: Vec<(String, String)>,
+    // Path of the source. %include and incbin search relative to it, and errors refer to it.
+    pub path: Option<PathBuf>,
+    // Directories searched after the including file's directory, like with nasm's -I.
+    pub include_dirs: Vec<PathBuf>,
+    pub loader: Box<dyn SourceLoader>,
+    // The initial mode, 16, 32 or 64 bits, until the first bits directive.
+    pub bits: u32,
+    // The initial syntax, until the first .intel_syntax or .att_syntax directive.
+    pub syntax: Syntax,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            defines: vec![],
+            path: None,
+            include_dirs: vec![],
+            loader: Box::new(FileSystemLoader),
+            bits: 64,
+            syntax: Syntax::Intel,
+        }
+    }
+}
+
+// Handles `incbin "file"[, offset[, length]]`.
+fn incbin(arguments: &str, from: Option<&Path>, options: &Options) -> Result<Vec<u8>, ErrorKind> {
+    let arguments = split_arguments(arguments);
+    let name = arguments[0].trim_matches(|c| c == '"' || c == '\'' || c == '`');
+    if name.is_empty() || arguments.len() > 3 {
+        return Err(ErrorKind::OperandCount {
+            expected: 1,
+            found: arguments.iter().filter(|a| !a.is_empty()).count(),
+        });
+    }
+    let (_, content) = source::find(&*options.loader, name, from, &options.include_dirs)?;
+    let offset = match arguments.get(1) {
+        Some(offset) => to_uint::<u64>(offset)? as usize,
+        None => 0,
+    };
+    if offset > content.len() {
+        return Err(ErrorKind::ValueOutOfRange(arguments[1].to_string()));
+    }
+    let end = match arguments.get(2) {
+        Some(length) => offset
+            .checked_add(to_uint::<u64>(length)? as usize)
+            .ok_or_else(|| ErrorKind::ValueOutOfRange(length.to_string()))?,
+        None => content.len(),
+    };
+    Ok(content[offset..content.len().min(end)].to_vec())
+}
+
+// The instruction mnemonics the assembler knows.
+pub fn mnemonics() -> Vec<&'static str> {
+    encoder::mnemonics()
 }
 
-pub fn assemble(text: &str) -> AssemblyResult {
-    // A label has a name, a section name, and a location relative to that section.
-    let mut labels: HashMap<String, (String, u64)> = HashMap::new();
+// The operand forms an instruction accepts, like "r/m, imm", or None for unknown mnemonics.
+pub fn operand_forms(mnemonic: &str) -> Option<&'static [&'static str]> {
+    encoder::operand_forms(mnemonic)
+}
+
+// Directives of the assembler and the preprocessor.
+pub const DIRECTIVES: [&str; 68] = [
+    "section",
+    "extern",
+    "global",
+    "org",
+    "db",
+    "dw",
+    "dd",
+    "dq",
+    "times",
+    "equ",
+    "bits",
+    "use16",
+    "use32",
+    "use64",
+    "incbin",
+    ".intel_syntax",
+    ".att_syntax",
+    ".text",
+    ".data",
+    ".bss",
+    ".section",
+    ".globl",
+    ".global",
+    ".local",
+    ".extern",
+    ".type",
+    ".size",
+    ".byte",
+    ".word",
+    ".short",
+    ".value",
+    ".long",
+    ".int",
+    ".quad",
+    ".ascii",
+    ".asciz",
+    ".string",
+    ".zero",
+    ".skip",
+    ".space",
+    ".p2align",
+    ".balign",
+    ".align",
+    ".equ",
+    ".set",
+    ".comm",
+    ".lcomm",
+    "%define",
+    "%xdefine",
+    "%undef",
+    "%assign",
+    "%macro",
+    "%endmacro",
+    "%rotate",
+    "%if",
+    "%ifdef",
+    "%ifndef",
+    "%elif",
+    "%elifdef",
+    "%elifndef",
+    "%else",
+    "%endif",
+    "%rep",
+    "%exitrep",
+    "%endrep",
+    "%include",
+    "%error",
+    "%warning",
+];
+
+pub fn assemble(text: &str) -> Result<AssemblyResult, AssemblyError> {
+    assemble_with(text, &Options::default())
+}
+
+pub fn assemble_with(text: &str, options: &Options) -> Result<AssemblyResult, AssemblyError> {
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_loader(&*options.loader);
+    for directory in &options.include_dirs {
+        preprocessor.add_include_dir(directory);
+    }
+    for (name, value) in &options.defines {
+        preprocessor.define(name, value);
+    }
+    let source_lines = preprocessor.process_file(text, options.path.as_deref())?;
+
+    // A label has a name, a section name, a location relative to that section and the line
+    // defining it.
+    let mut labels: HashMap<String, (String, u64, usize)> = HashMap::new();
+    // Constants defined with equ, with their value and the line defining them.
+    let mut constants: HashMap<String, (i64, usize)> = HashMap::new();
+    // Symbols declared with extern, which are left to the linker.
+    let mut externs: HashSet<String> = HashSet::new();
+    // Symbol attributes from .globl, .local and .type, and the .size expressions with the
+    // section they were evaluated in and the line.
+    let mut globals: HashSet<String> = HashSet::new();
+    let mut locals: HashSet<String> = HashSet::new();
+    let mut types: HashMap<String, SymbolType> = HashMap::new();
+    let mut sizes: Vec<(String, SizeExpression, String, usize)> = vec![];
+    let mut commons: Vec<CommonSymbol> = vec![];
 
     let mut sections: Vec<AssemblySection> = vec![];
-    let mut relocations: Vec<Relocation> = vec![];
-    let mut location: u64 = 0;
-    let mut i: i32 = -1;
-    for line in text.lines() {
-        for result in assemble_line(&line, location) {
+    // Relocations with the name of the section containing them and the full label name.
+    let mut relocations: Vec<(String, String, Relocation)> = vec![];
+    let mut scope = LabelScope::default();
+    let mut origin = None;
+    let mut current: Option<usize> = None;
+    let mut bits = options.bits;
+    let mut syntax = options.syntax;
+    let mut lines = vec![];
+    for (index, source_line) in source_lines.iter().enumerate() {
+        let number = source_line.number;
+        let line = source_line.text.as_str();
+        let error = |kind| AssemblyError {
+            file: source_line.file.clone(),
+            line: number,
+            source: source_line.source.clone(),
+            kind,
+        };
+        let location = current.map_or(0, |i| sections[i].content.len() as u64);
+        // The section and offset of the first byte of this line, and the number of bytes.
+        let mut placement: Option<(usize, u64)> = None;
+        let mut length = 0;
+        let results = match line.trim().strip_prefix("incbin") {
+            Some(arguments) if arguments.starts_with(char::is_whitespace) => {
+                let file = source_line.file.as_deref();
+                vec![AssemblyLineResult::Bytes(
+                    incbin(arguments, file, options).map_err(error)?,
+                )]
+            }
+            _ => assemble_line(line, location, bits, syntax).map_err(error)?,
+        };
+        for result in results {
+            // Like nasm, put everything before the first section directive into .text.
+            if current.is_none()
+                && !matches!(
+                    result,
+                    AssemblyLineResult::Section(_)
+                        | AssemblyLineResult::Origin(_)
+                        | AssemblyLineResult::Bits(_)
+                        | AssemblyLineResult::Extern(_)
+                        | AssemblyLineResult::Constant(..)
+                        | AssemblyLineResult::Syntax(_)
+                        | AssemblyLineResult::Global(_)
+                        | AssemblyLineResult::Local(_)
+                        | AssemblyLineResult::Type(..)
+                        | AssemblyLineResult::Common { .. }
+                )
+            {
+                current = Some(enter_section(&mut sections, ".text"));
+            }
             match result {
                 AssemblyLineResult::Bytes(bytes) => {
-                    sections[i as usize].content.write_all(&bytes).unwrap();
-                    location += bytes.len() as u64;
+                    let i = current.unwrap();
+                    if sections[i].nobits && bytes.iter().any(|&b| b != 0) {
+                        return Err(error(ErrorKind::InvalidOperands(format!(
+                            "data in nobits section '{}'",
+                            sections[i].name
+                        ))));
+                    }
+                    placement.get_or_insert((i, sections[i].content.len() as u64));
+                    length += bytes.len() as u64;
+                    sections[i].content.write_all(&bytes).unwrap();
                 }
                 AssemblyLineResult::Label(name) => {
-                    labels.insert(name, (sections[sections.len() - 1].name.clone(), location));
+                    let section = &sections[current.unwrap()];
+                    let location = section.content.len() as u64;
+                    let name = scope.define(&name);
+                    if labels.contains_key(&name) || constants.contains_key(&name) {
+                        return Err(error(ErrorKind::DuplicateLabel(name)));
+                    }
+                    labels.insert(name, (section.name.clone(), location, number));
+                }
+                AssemblyLineResult::Constant(name, value) => {
+                    let name = scope.define(&name);
+                    if labels.contains_key(&name) || constants.contains_key(&name) {
+                        return Err(error(ErrorKind::DuplicateLabel(name)));
+                    }
+                    constants.insert(name, (value, number));
                 }
-                AssemblyLineResult::Section(name) => {
-                    sections.push(AssemblySection {
-                        name,
-                        content: vec![],
-                    });
-                    i += 1;
-                    location = 0;
+                AssemblyLineResult::Section(directive) => {
+                    let i = enter_section(&mut sections, &directive.name);
+                    let section = &mut sections[i];
+                    section.start = directive.start.or(section.start);
+                    section.vstart = directive.vstart.or(section.vstart);
+                    section.align = directive.align.or(section.align);
+                    section.flags = directive.flags.or_else(|| section.flags.take());
+                    section.nobits = directive.nobits.unwrap_or(section.nobits);
+                    current = Some(i);
                 }
-                AssemblyLineResult::Relocation(relocation) => {
-                    match relocation.typ {
-                        RelocationType::U32 => {
-                            sections[i as usize]
-                                .content
-                                .write_all(&[0 as u8; 4])
-                                .unwrap();
-                            location += 4 as u64;
-                        }
-                        RelocationType::U64 => {
-                            sections[i as usize]
-                                .content
-                                .write_all(&[0 as u8; 8])
-                                .unwrap();
-                            location += 8 as u64;
-                        }
+                AssemblyLineResult::Align {
+                    alignment,
+                    fill,
+                    max,
+                } => {
+                    let i = current.unwrap();
+                    let section = &mut sections[i];
+                    let length = section.content.len() as u64;
+                    let padding = (alignment - length % alignment) % alignment;
+                    if max.is_none_or(|max| padding <= max) {
+                        let padding = match fill {
+                            Some(fill) => vec![fill; padding as usize],
+                            None if section.is_code() => nops(padding as usize, bits),
+                            None => vec![0; padding as usize],
+                        };
+                        placement.get_or_insert((i, length));
+                        section.content.extend_from_slice(&padding);
+                    }
+                    section.align = Some(section.align.unwrap_or(1).max(alignment));
+                }
+                AssemblyLineResult::Global(names) => {
+                    globals.extend(names.iter().map(|n| scope.reference(n)))
+                }
+                AssemblyLineResult::Local(names) => {
+                    locals.extend(names.iter().map(|n| scope.reference(n)))
+                }
+                AssemblyLineResult::Type(name, typ) => {
+                    types.insert(scope.reference(&name), typ);
+                }
+                AssemblyLineResult::Size(name, terms) => {
+                    let terms = terms
+                        .into_iter()
+                        .map(|(sign, term)| match term {
+                            SizeTerm::Label(label) => {
+                                (sign, SizeTerm::Label(scope.reference(&label)))
+                            }
+                            term => (sign, term),
+                        })
+                        .collect();
+                    let section = sections[current.unwrap()].name.clone();
+                    sizes.push((scope.reference(&name), terms, section, index));
+                }
+                AssemblyLineResult::Common {
+                    name,
+                    size,
+                    align,
+                    local,
+                } => {
+                    let name = scope.reference(&name);
+                    if !local && !locals.contains(&name) {
+                        externs.insert(name.clone());
+                        commons.push(CommonSymbol { name, size, align });
+                        continue;
                     }
-                    relocations.push(relocation);
+                    // Local commons are reserved in .bss like GAS does.
+                    let i = enter_section(&mut sections, ".bss");
+                    let section = &mut sections[i];
+                    let offset = align_up(section.content.len() as u64, align);
+                    section.content.resize((offset + size) as usize, 0);
+                    section.align = Some(section.align.unwrap_or(1).max(align));
+                    if labels.contains_key(&name) || constants.contains_key(&name) {
+                        return Err(error(ErrorKind::DuplicateLabel(name)));
+                    }
+                    types.entry(name.clone()).or_insert(SymbolType::Object);
+                    let size = vec![(1, SizeTerm::Value(size as i64))];
+                    sizes.push((name.clone(), size, ".bss".to_string(), index));
+                    labels.insert(name, (".bss".to_string(), offset, number));
+                }
+                AssemblyLineResult::Origin(address) => {
+                    if origin.is_some() && origin != Some(address) {
+                        return Err(error(ErrorKind::OriginRedefined));
+                    }
+                    origin = Some(address);
+                }
+                AssemblyLineResult::Bits(mode) => bits = mode,
+                AssemblyLineResult::Syntax(mode) => syntax = mode,
+                AssemblyLineResult::Extern(names) => externs.extend(names),
+                AssemblyLineResult::Relocation(relocation) => {
+                    // The field itself is part of the preceding bytes.
+                    relocations.push((
+                        sections[current.unwrap()].name.clone(),
+                        scope.reference(&relocation.label),
+                        Relocation {
+                            line: index,
+                            ..relocation
+                        },
+                    ));
                 }
             }
         }
+        lines.push(SourceLine {
+            line: number,
+            source: source_line.source.clone(),
+            section: placement.map(|(i, _)| sections[i].name.clone()),
+            offset: placement.map_or(0, |(_, offset)| offset),
+            length,
+        });
     }
 
+    // Like in GAS, global symbols which aren't defined are external.
+    externs.extend(
+        globals
+            .iter()
+            .filter(|name| !labels.contains_key(*name))
+            .cloned(),
+    );
+
     // Resolve relocations.
     let mut resolved_relocations = vec![];
-    for relocation in relocations {
-        let (section, addend) = labels
-            .get(&relocation.label)
-            .expect("Could not resolve label");
+    let mut references = vec![];
+    for (source, label, relocation) in relocations {
+        let source_line = &source_lines[relocation.line];
+        if label != SECTION_START {
+            references.push((label.clone(), source_line.number));
+        }
+        let error = |kind| AssemblyError {
+            file: source_line.file.clone(),
+            line: source_line.number,
+            source: source_line.source.clone(),
+            kind,
+        };
+        let content = &mut sections
+            .iter_mut()
+            .find(|s| s.name == source)
+            .unwrap()
+            .content;
+        let start = relocation.location as usize;
+        let size = relocation.typ.size();
+
+        // Constants are absolute values, which are patched in right away. Like in nasm,
+        // negative values are accepted for unsigned fields.
+        if let Some((value, _)) = constants.get(&label) {
+            let value = value.wrapping_add(relocation.addend);
+            let bits = size as u32 * 8;
+            let fits = relocation.typ.fits(value)
+                || (relocation.typ == RelocationType::U32 || relocation.typ == RelocationType::U16)
+                    && (-(1 << (bits - 1))..0).contains(&value);
+            if relocation.typ.is_relative() || !fits {
+                return Err(error(ErrorKind::ValueOutOfRange(label)));
+            }
+            content[start..start + size].copy_from_slice(&value.to_le_bytes()[..size]);
+            continue;
+        }
+
+        let (section, offset) = match labels.get(&label) {
+            Some((section, offset, _)) => (section.clone(), *offset),
+            None if label == SECTION_START => (source.clone(), 0),
+            None if externs.contains(&label) => (String::new(), 0),
+            None => return Err(error(ErrorKind::UndefinedLabel(relocation.label.clone()))),
+        };
+        let value = (offset as i64).wrapping_add(relocation.addend);
+
+        // Relative references within a section don't depend on its address.
+        if relocation.typ.is_relative() && section == source {
+            let value = value - relocation.location as i64;
+            if !relocation.typ.fits(value) {
+                return Err(error(ErrorKind::ValueOutOfRange(label)));
+            }
+            content[start..start + size].copy_from_slice(&value.to_le_bytes()[..size]);
+            continue;
+        }
         resolved_relocations.push(ResolvedRelocation {
+            source,
             location: relocation.location,
             typ: relocation.typ,
-            section: section.to_string(),
-            addend: *addend,
+            section,
+            addend: value as u64,
+            label,
+            line: source_line.number,
         });
     }
 
-    AssemblyResult {
+    // Evaluate .size expressions. Labels must be in the section the expression is in.
+    let mut symbol_sizes: HashMap<String, u64> = HashMap::new();
+    for (name, terms, section, line) in sizes {
+        let source_line = &source_lines[line];
+        let error = |kind| AssemblyError {
+            file: source_line.file.clone(),
+            line: source_line.number,
+            source: source_line.source.clone(),
+            kind,
+        };
+        let mut size = 0i64;
+        for (sign, term) in terms {
+            let value = match term {
+                SizeTerm::Value(value) => value,
+                SizeTerm::Label(label) => match labels.get(&label) {
+                    Some((s, offset, _)) if *s == section => *offset as i64,
+                    Some(_) => {
+                        return Err(error(ErrorKind::InvalidExpression(format!(
+                            "'{}' refers to another section",
+                            source_line.source.trim()
+                        ))))
+                    }
+                    None => return Err(error(ErrorKind::UndefinedLabel(label))),
+                },
+            };
+            size = size.wrapping_add(sign * value);
+        }
+        if !labels.contains_key(&name) {
+            return Err(error(ErrorKind::UndefinedLabel(name)));
+        }
+        if size < 0 {
+            return Err(error(ErrorKind::ValueOutOfRange(size.to_string())));
+        }
+        symbol_sizes.insert(name, size as u64);
+    }
+
+    let mut symbols: Vec<Symbol> = labels
+        .into_iter()
+        .map(|(name, (section, offset, line))| Symbol {
+            global: globals.contains(&name),
+            typ: types.get(&name).copied().unwrap_or(SymbolType::NoType),
+            size: symbol_sizes.get(&name).copied(),
+            name,
+            section,
+            offset,
+            line,
+        })
+        .collect();
+    symbols.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut constants: Vec<Constant> = constants
+        .into_iter()
+        .map(|(name, (value, line))| Constant { name, value, line })
+        .collect();
+    constants.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(AssemblyResult {
         sections,
         relocations: resolved_relocations,
+        origin,
+        lines,
+        symbols,
+        constants,
+        commons,
+        references,
+        warnings: preprocessor.into_warnings(),
+    })
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+// Padding made of long nops, like GAS aligns code with. 16-bit code gets plain nops.
+fn nops(length: usize, bits: u32) -> Vec<u8> {
+    const NOPS: [&[u8]; 9] = [
+        &[0x90],
+        &[0x66, 0x90],
+        &[0x0f, 0x1f, 0x00],
+        &[0x0f, 0x1f, 0x40, 0x00],
+        &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+        &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+        &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+        &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+        &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    ];
+    if bits == 16 {
+        return vec![0x90; length];
     }
+    let mut ret = vec![];
+    while ret.len() < length {
+        let n = (length - ret.len()).min(NOPS.len());
+        ret.extend_from_slice(NOPS[n - 1]);
+    }
+    ret
+}
+
+// Switches to the named section, creating it on first use. Returns its index.
+fn enter_section(sections: &mut Vec<AssemblySection>, name: &str) -> usize {
+    if let Some(i) = sections.iter().position(|s| s.name == name) {
+        return i;
+    }
+    sections.push(AssemblySection {
+        name: name.to_string(),
+        content: vec![],
+        start: None,
+        vstart: None,
+        align: None,
+        flags: None,
+        nobits: name == ".bss" || name.starts_with(".bss."),
+    });
+    sections.len() - 1
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::source::MemoryLoader;
 
     fn assert_assembly(line: &str, expected: Vec<u8>) {
-        let result = assemble_line(line, 0).remove(0);
+        let result = assemble_line(line, 0, 64, Syntax::Intel).unwrap().remove(0);
         let assembly = match result {
             AssemblyLineResult::Bytes(bytes) => bytes,
             _ => panic!("Unexpected AssemblyLineResult type"),
@@ -230,10 +1511,18 @@ mod tests {
 
     #[test]
     fn conversion() {
-        assert_eq!(to_uint::<u32>("0x42"), 66);
-        assert_eq!(to_uint::<u32>("42"), 42);
-        assert_eq!(to_uint::<u32>("0x0"), 0);
-        assert_eq!(to_uint::<u8>("0x0"), 0);
+        assert_eq!(to_uint::<u32>("0x42"), Ok(66));
+        assert_eq!(to_uint::<u32>("42"), Ok(42));
+        assert_eq!(to_uint::<u32>("0x0"), Ok(0));
+        assert_eq!(to_uint::<u8>("0x0"), Ok(0));
+        assert_eq!(
+            to_uint::<u8>("256"),
+            Err(ErrorKind::ValueOutOfRange("256".to_string()))
+        );
+        assert_eq!(
+            to_uint::<u8>("12g"),
+            Err(ErrorKind::InvalidNumber("12g".to_string()))
+        );
     }
 
     #[test]
@@ -256,7 +1545,8 @@ mod tests {
     #[test]
     fn mov_with_reference() {
         let result =
-            assemble("section .text\nmov esi, message\nsection .rodata\nmessage:\ndb \"Hello\"");
+            assemble("section .text\nmov esi, message\nsection .rodata\nmessage:\ndb \"Hello\"")
+                .unwrap();
         assert_eq!(result.sections[0].name, ".text");
         assert_eq!(result.sections[1].name, ".rodata");
         assert_eq!(result.sections[0].content, vec![0xb8 + 6, 0, 0, 0, 0]);
@@ -264,29 +1554,391 @@ mod tests {
         assert_eq!(result.relocations[0].location, 1);
     }
 
-    //#[test]
-    //fn jmp() {
-    //    assert_assembly("loop:\njmp loop"), vec![0xeb, 0xfe]);
-    //    assert_assembly("loop:\nje loop"), vec![0x74, 0xfe]);
-    //    assert_eq!(
-    //        assemble("forever:\njmp skip\njmp forever\nskip:"),
-    //        vec![0xeb, 0x02, 0xeb, 0xfc]
-    //    );
-    //}
-
-    //#[test]
-    //fn call() {
-    //    assert_eq!(
-    //        assemble("call loop\nret\nloop:"),
-    //        vec![0xe8, 1, 0, 0, 0, 0xc3]
-    //    );
-    //}
+    #[test]
+    fn jmp() {
+        let content = |text| assemble(text).unwrap().sections[0].content.clone();
+        assert_eq!(content("loop:\njmp short loop"), vec![0xeb, 0xfe]);
+        assert_eq!(content("loop:\nje short loop"), vec![0x74, 0xfe]);
+        assert_eq!(
+            content("forever:\njmp short skip\njmp short forever\nskip:"),
+            vec![0xeb, 0x02, 0xeb, 0xfc]
+        );
+        assert_eq!(content("jne done\ndone:"), vec![0x0f, 0x85, 0, 0, 0, 0]);
+
+        let text = format!("jmp short far\n{}far:", "nop\n".repeat(128));
+        let error = assemble(&text).err().unwrap();
+        assert_eq!(error.line, 1);
+        assert_eq!(error.kind, ErrorKind::ValueOutOfRange("far".to_string()));
+    }
+
+    #[test]
+    fn call() {
+        let result = assemble("call loop\nret\nloop:").unwrap();
+        assert_eq!(result.sections[0].content, vec![0xe8, 1, 0, 0, 0, 0xc3]);
+        assert!(result.relocations.is_empty());
+
+        let result = assemble("call f\nsection .other\nf:").unwrap();
+        assert_eq!(result.relocations[0].typ, RelocationType::Pc32);
+        assert_eq!(result.relocations[0].addend, (-4_i64) as u64);
+    }
+
+    #[test]
+    fn bits() {
+        let content = |text| assemble(text).unwrap().sections[0].content.clone();
+        assert_eq!(
+            content("bits 16\nmov ax, 1\nmov eax, 1\n[bits 32]\nmov eax, 1\nuse64\nmov rax, -1"),
+            vec![
+                0xb8, 1, 0, 0x66, 0xb8, 1, 0, 0, 0, 0xb8, 1, 0, 0, 0, 0x48, 0xc7, 0xc0, 0xff, 0xff,
+                0xff, 0xff
+            ]
+        );
+        let options = Options {
+            bits: 16,
+            ..Default::default()
+        };
+        let result = assemble_with("org 0x7c00\nmov si, message\nmessage:", &options).unwrap();
+        assert_eq!(result.sections[0].content, vec![0xbe, 0, 0]);
+        assert_eq!(result.relocations[0].typ, RelocationType::U16);
+
+        let error = assemble("bits 32\nret\npush rax").err().unwrap();
+        assert_eq!(error.line, 3);
+        assert_eq!(
+            error.kind,
+            ErrorKind::InvalidInMode {
+                what: "64-bit stack operand".to_string(),
+                bits: 32
+            }
+        );
+        let error = assemble("bits 8").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::ValueOutOfRange("8".to_string()));
+    }
 
     #[test]
     fn cmp() {
         assert_assembly("cmp eax, 5", vec![0x83, 0xf8, 5]);
     }
 
+    #[test]
+    fn implicit_text_section() {
+        let result = assemble("ret").unwrap();
+        assert_eq!(result.sections[0].name, ".text");
+        assert_eq!(result.sections[0].content, vec![0xc3]);
+    }
+
+    #[test]
+    fn sections() {
+        let result = assemble(
+            "org 0x7c00\nsection .text\nret\nsection .data start=0x10 align=16\n\
+             db 1\nsection .text\nsyscall",
+        )
+        .unwrap();
+        assert_eq!(result.origin, Some(0x7c00));
+        assert_eq!(result.sections.len(), 2);
+        assert_eq!(result.sections[0].content, vec![0xc3, 0xf, 0x5]);
+        assert_eq!(result.sections[1].start, Some(0x10));
+        assert_eq!(result.sections[1].vstart, None);
+        assert_eq!(result.sections[1].align, Some(16));
+
+        let error = assemble("section .data follows=.text").err().unwrap();
+        assert_eq!(
+            error.kind,
+            ErrorKind::UnknownSectionAttribute("follows=.text".to_string())
+        );
+        let error = assemble("org 0x100\norg 0x200").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::OriginRedefined);
+    }
+
+    #[test]
+    fn preprocessor() {
+        let options = Options {
+            defines: vec![("EXIT_CODE".to_string(), "3".to_string())],
+            ..Default::default()
+        };
+        let text = "%ifdef EXIT_CODE\nmov edi, EXIT_CODE ; exit code\n%else\n\
+                    mov edi, 0\n%endif\ndb \"a, b\"";
+        let result = assemble_with(text, &options).unwrap();
+        assert_eq!(
+            result.sections[0].content,
+            vec![0xbf, 3, 0, 0, 0, b'a', b',', b' ', b'b']
+        );
+        assert_eq!(result.lines[1].line, 2);
+        assert_eq!(result.lines[1].source, "mov edi, EXIT_CODE ; exit code");
+
+        let error = assemble("ret\n%if 1\nfrobnicate\n%endif").err().unwrap();
+        assert_eq!(error.line, 3);
+        assert!(assemble("%warning careful").unwrap().warnings().len() == 1);
+    }
+
+    #[test]
+    fn constant_expressions() {
+        let text = "%define SQ(x) x*x\nmov ebx, SQ(3)\nmov ecx, (2+3)*4\n\
+                    mov eax, [rbx + SQ(2) - (1 << 2)*2]\nmov eax, [rcx*(1+1)]";
+        let result = assemble(text).unwrap();
+        assert_eq!(
+            result.sections[0].content,
+            vec![
+                0xbb, 9, 0, 0, 0, 0xb9, 20, 0, 0, 0, 0x8b, 0x43, 0xfc, 0x8b, 0x04, 0x4d, 0, 0, 0, 0
+            ]
+        );
+        let error = assemble("mov eax, 1/0").err().unwrap();
+        assert!(matches!(error.kind, ErrorKind::InvalidExpression(_)));
+        let error = assemble("mov eax, 12z").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::InvalidNumber("12z".to_string()));
+    }
+
+    #[test]
+    fn labels() {
+        let text = "first:\n.loop:\nret\nsecond:\nret\n.loop:\nmov eax, .loop\n\
+                    mov ebx, first.loop\n1:\nmov ecx, 1b\nmov edx, 1f\n1:";
+        let result = assemble(text).unwrap();
+        let targets: Vec<(&str, u64)> = result
+            .relocations
+            .iter()
+            .map(|r| (r.label.as_str(), r.addend))
+            .collect();
+        assert_eq!(
+            targets,
+            vec![
+                ("second.loop", 2),
+                ("first.loop", 0),
+                (".L1^1", 12),
+                (".L1^2", 22)
+            ]
+        );
+
+        let error = assemble("a:\nret\na:").err().unwrap();
+        assert_eq!(error.line, 3);
+        assert_eq!(error.kind, ErrorKind::DuplicateLabel("a".to_string()));
+        let error = assemble("a:\n.x:\n.x:").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::DuplicateLabel("a.x".to_string()));
+        let error = assemble("mov eax, 1b").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::UndefinedLabel("1b".to_string()));
+
+        let result = assemble("nop\nstart: mov ecx, 1\njmp start").unwrap();
+        assert_eq!(
+            result.section_content(".text").unwrap(),
+            &[0x90, 0xb9, 1, 0, 0, 0, 0xe9, 0xf6, 0xff, 0xff, 0xff][..]
+        );
+    }
+
+    #[test]
+    fn att_syntax() {
+        let intel = "mov eax, 60\nstart: mov qword [rbp-8], 1\nlea rsi, [rel message]\n\
+                     movzx eax, byte [rsi+rcx*1]\njne start\ncall rax\nmessage:";
+        let att = ".att_syntax\nmovl $60, %eax # exit\nstart: movq $1, -8(%rbp)\n\
+                   leaq message(%rip), %rsi\nmovzbl (%rsi,%rcx,1), %eax\njne start\n\
+                   call *%rax\nmessage:";
+        let expected = assemble(intel).unwrap();
+        let result = assemble(att).unwrap();
+        assert_eq!(
+            result.section_content(".text"),
+            expected.section_content(".text")
+        );
+
+        // The syntax can be switched back and set from the options.
+        let result = assemble(".att_syntax\npushq %rbx\n.intel_syntax noprefix\npush rbx").unwrap();
+        assert_eq!(result.section_content(".text").unwrap(), &[0x53, 0x53][..]);
+        let options = Options {
+            syntax: Syntax::Att,
+            ..Options::default()
+        };
+        let result = assemble_with("xorl %eax, %eax", &options).unwrap();
+        assert_eq!(result.section_content(".text").unwrap(), &[0x31, 0xc0][..]);
+        let error = assemble_with("movl $1, %exx", &options).err().unwrap();
+        assert_eq!(error.kind, ErrorKind::UnknownRegister("exx".to_string()));
+    }
+
+    #[test]
+    fn gas_directives() {
+        let text = "\t.file\t\"test.c\"\n\t.text\n\t.globl\tmain\n\t.type\tmain, @function\n\
+                    main:\n\tmov\teax, dword [rel .LC0]\n.loop:\n\tret\n\t.size\tmain, .-main\n\
+                    \t.p2align 4\n\t.section\t.rodata,\"a\"\n.LC0:\n\t.long\t-1, 2\n\
+                    \t.word\t0x102\n\t.byte\t1, 255\n\t.quad\tmain+1\n\t.ascii\t\"a\\tb\", \"\\101\"\n\
+                    \t.string\t\"\\\"\\x41\"\n\t.balign 4, 0xcc\n\t.zero\t2\n\t.skip 1, 7\n\
+                    \t.set\tSIZE, 3\n\t.long\tSIZE\n\t.data\n\t.local\tcounter\n\t.comm\tcounter,8,8\n\
+                    \t.comm\tshared,16,32\n\t.section\t.note.GNU-stack,\"\",@progbits\n";
+        let result = assemble(text).unwrap();
+
+        // The code is padded with a long nop.
+        assert_eq!(
+            result.section_content(".text").unwrap(),
+            &[0x8b, 0x05, 0, 0, 0, 0, 0xc3, 0x66, 0x0f, 0x1f, 0x84, 0, 0, 0, 0, 0][..]
+        );
+        assert_eq!(result.section(".text").unwrap().align(), Some(16));
+        assert_eq!(
+            result.section_content(".rodata").unwrap(),
+            &[
+                0xff, 0xff, 0xff, 0xff, 2, 0, 0, 0, 2, 1, 1, 255, 0, 0, 0, 0, 0, 0, 0, 0, b'a',
+                b'\t', b'b', b'A', b'"', b'A', 0, 0xcc, 0, 0, 7, 3, 0, 0, 0
+            ][..]
+        );
+        let rodata = result.section(".rodata").unwrap();
+        assert_eq!(rodata.flags(), Some("a"));
+        assert!(!rodata.is_code());
+        let relocation = &result.relocations()[1];
+        assert_eq!(
+            (relocation.location(), relocation.typ(), relocation.addend()),
+            (12, RelocationType::U64, 1)
+        );
+
+        let main = result.symbol("main").unwrap();
+        assert!(main.is_global());
+        assert_eq!((main.typ(), main.size()), (SymbolType::Function, Some(7)));
+        // ".L" labels don't start a scope for local labels.
+        assert!(result.symbol("main.loop").is_some());
+        assert!(!result.symbol(".LC0").unwrap().is_global());
+
+        // Local commons are reserved in .bss, others are left to the linker.
+        let bss = result.section(".bss").unwrap();
+        assert!(bss.is_nobits());
+        assert_eq!(bss.content().len(), 8);
+        let counter = result.symbol("counter").unwrap();
+        assert_eq!((counter.section(), counter.size()), (".bss", Some(8)));
+        assert_eq!(result.commons().len(), 1);
+        assert_eq!(
+            (result.commons()[0].name(), result.commons()[0].size()),
+            ("shared", 16)
+        );
+        assert_eq!(result.commons()[0].align(), 32);
+        assert_eq!(result.section(".note.GNU-stack").unwrap().flags(), Some(""));
+
+        // Undefined global symbols are external.
+        let result = assemble(".globl puts\ncall puts").unwrap();
+        assert!(result.relocations()[0].is_external());
+
+        let error = assemble(".byte main\nmain:").err().unwrap();
+        assert_eq!(
+            error.kind,
+            ErrorKind::InvalidOperands("bytes can't refer to label 'main'".to_string())
+        );
+        let error = assemble(".byte 256").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::ValueOutOfRange("256".to_string()));
+        let error = assemble(".bss\n.byte 1").err().unwrap();
+        assert_eq!(
+            error.kind,
+            ErrorKind::InvalidOperands("data in nobits section '.bss'".to_string())
+        );
+        let error = assemble("f:\nret\n.size f, .-g").err().unwrap();
+        assert_eq!(error.line, 3);
+        assert_eq!(error.kind, ErrorKind::UndefinedLabel("g".to_string()));
+        let error = assemble(".section .x, \"q\"").err().unwrap();
+        assert_eq!(
+            error.kind,
+            ErrorKind::UnknownSectionAttribute("q".to_string())
+        );
+        let error = assemble(".ascii \"open").err().unwrap();
+        assert_eq!(
+            error.kind,
+            ErrorKind::InvalidOperands("invalid string '\"open'".to_string())
+        );
+    }
+
+    #[test]
+    fn constants() {
+        let text = "mov eax, SYS_EXIT\nSYS_EXIT equ 60\nSTATUS: equ -1\nmov edi, STATUS\n\
+                    add rsp, STATUS\nret";
+        let result = assemble(text).unwrap();
+        assert_eq!(
+            result.section_content(".text").unwrap(),
+            &[
+                0xb8, 60, 0, 0, 0, 0xbf, 0xff, 0xff, 0xff, 0xff, 0x48, 0x81, 0xc4, 0xff, 0xff,
+                0xff, 0xff, 0xc3
+            ][..]
+        );
+        assert!(result.relocations.is_empty());
+        assert!(result.symbols.is_empty());
+
+        let error = assemble("A equ 70000\nmov ax, A").err().unwrap();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.kind, ErrorKind::ValueOutOfRange("A".to_string()));
+        let error = assemble("a:\na equ 1").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::DuplicateLabel("a".to_string()));
+    }
+
+    #[test]
+    fn include() {
+        let mut loader = MemoryLoader::new();
+        loader.add_file("src/macros.inc", b"%include \"exit.inc\"\n%define VALUE 2");
+        loader.add_file("lib/exit.inc", b"%macro exit 0\nmov eax, 60\n%endmacro");
+        loader.add_file("src/data.bin", b"0123456789");
+        loader.add_file("src/bad.inc", b"ret\nfrobnicate");
+        loader.add_file("src/loop.inc", b"%include \"../src/loop.inc\"");
+        let options = Options {
+            path: Some(PathBuf::from("src/main.asm")),
+            include_dirs: vec![PathBuf::from("lib")],
+            loader: Box::new(loader),
+            ..Default::default()
+        };
+
+        let text = "%include \"macros.inc\"\nexit\nmov edi, VALUE\nincbin \"data.bin\", 2, 3";
+        let result = assemble_with(text, &options).unwrap();
+        assert_eq!(
+            result.sections[0].content,
+            vec![0xb8, 60, 0, 0, 0, 0xbf, 2, 0, 0, 0, b'2', b'3', b'4']
+        );
+
+        let error = assemble_with("%include \"bad.inc\"", &options)
+            .err()
+            .unwrap();
+        assert_eq!(error.file, Some(PathBuf::from("src/bad.inc")));
+        assert_eq!(error.line, 2);
+        let error = assemble_with("\n%include \"loop.inc\"", &options)
+            .err()
+            .unwrap();
+        assert_eq!(error.file, Some(PathBuf::from("src/loop.inc")));
+        assert_eq!(
+            error.kind,
+            ErrorKind::IncludeCycle("src/loop.inc".to_string())
+        );
+        let error = assemble_with("\n%include \"missing.inc\"", &options)
+            .err()
+            .unwrap();
+        assert_eq!(error.file, Some(PathBuf::from("src/main.asm")));
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.kind,
+            ErrorKind::FileNotFound("missing.inc".to_string())
+        );
+        let error = assemble_with(
+            "ret\nincbin \"data.bin\", 1, 18446744073709551615",
+            &options,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.kind,
+            ErrorKind::ValueOutOfRange("18446744073709551615".to_string())
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = assemble("ret\nmov rxx, 60").err().unwrap();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.source, "mov rxx, 60");
+        assert_eq!(error.kind, ErrorKind::UnknownRegister("rxx".to_string()));
+
+        let error = assemble("frobnicate").err().unwrap();
+        assert_eq!(
+            error.kind,
+            ErrorKind::UnknownInstruction("frobnicate".to_string())
+        );
+
+        let error = assemble("mov eax").err().unwrap();
+        assert_eq!(
+            error.kind,
+            ErrorKind::OperandCount {
+                expected: 2,
+                found: 1
+            }
+        );
+
+        let error = assemble("ret\nmov esi, nowhere").err().unwrap();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.kind, ErrorKind::UndefinedLabel("nowhere".to_string()));
+    }
+
     #[test]
     fn db() {
         assert_assembly("db 0x42", vec![0x42]);
@@ -295,4 +1947,42 @@ mod tests {
         assert_assembly("db \"*\", 0x42, 42", vec![42, 0x42, 42]);
         assert_assembly("db \"hello\"", vec![104, 101, 108, 108, 111]);
     }
+
+    #[test]
+    fn data_and_times() {
+        assert_assembly("dw 0x1234, -1", vec![0x34, 0x12, 0xff, 0xff]);
+        assert_assembly("dd 1", vec![1, 0, 0, 0]);
+        assert_assembly("dq 0x100", vec![0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_assembly("dw 'abc'", vec![b'a', b'b', b'c', 0]);
+        assert_assembly("dd (2+3)*4", vec![20, 0, 0, 0]);
+        assert_assembly("times 3 nop", vec![0x90; 3]);
+        assert_assembly("times 2*2 db 1, 2", vec![1, 2, 1, 2, 1, 2, 1, 2]);
+        let assembly = assemble("nop\nnop\ntimes 4-($-$$) db 0xcc").unwrap();
+        assert_eq!(assembly.sections[0].content, vec![0x90, 0x90, 0xcc, 0xcc]);
+
+        // $ refers to the start of the line, also when repeated.
+        let assembly = assemble("nop\njmp $\ntimes 2 jmp short $").unwrap();
+        assert_eq!(
+            assembly.sections[0].content,
+            vec![0x90, 0xe9, 0xfb, 0xff, 0xff, 0xff, 0xeb, 0xfe, 0xeb, 0xfe]
+        );
+        let assembly = assemble("nop\ndq $ + 2").unwrap();
+        assert_eq!(assembly.relocations[0].section, ".text");
+        assert_eq!(assembly.relocations[0].addend, 3);
+
+        let error = |line: &str| assemble(line).err().unwrap().kind;
+        assert_eq!(
+            error("times -1 nop"),
+            ErrorKind::ValueOutOfRange("-1".to_string())
+        );
+        assert!(matches!(
+            error("times $ nop"),
+            ErrorKind::InvalidExpression(_)
+        ));
+        assert!(matches!(
+            error("times 2 section .data"),
+            ErrorKind::InvalidOperands(_)
+        ));
+        assert!(matches!(error("dd 2 *"), ErrorKind::InvalidNumber(_)));
+    }
 }
@@ -0,0 +1,277 @@
+use crate::assembler::ErrorKind;
+use crate::encoder;
+use crate::x86::Register;
+
+// Mnemonics whose AT&T names differ from the Intel ones, besides the size suffix.
+const RENAMED: [(&str, &str); 7] = [
+    ("cbtw", "cbw"),
+    ("cwtl", "cwde"),
+    ("cltq", "cdqe"),
+    ("cwtd", "cwd"),
+    ("cltd", "cdq"),
+    ("cqto", "cqo"),
+    ("movabsq", "movq"),
+];
+
+const SEGMENTS: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+
+fn invalid(message: String) -> ErrorKind {
+    ErrorKind::InvalidOperands(message)
+}
+
+fn size_keyword(suffix: char) -> Option<&'static str> {
+    match suffix {
+        'b' => Some("byte"),
+        'w' => Some("word"),
+        'l' => Some("dword"),
+        'q' => Some("qword"),
+        _ => None,
+    }
+}
+
+// Removes a '#' comment, outside of strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+// Splits operands at commas outside of parentheses.
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut ret = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                ret.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    ret.push(text[start..].trim());
+    ret.retain(|o| !o.is_empty());
+    ret
+}
+
+// Splits an AT&T mnemonic into the Intel mnemonic and the operand size given by its suffix.
+// movzbl and friends also have the size of the source, which is returned separately.
+fn mnemonic(name: &str) -> Result<(String, Option<char>, Option<char>), ErrorKind> {
+    let name = name.to_lowercase();
+    let name = RENAMED
+        .iter()
+        .find(|(att, _)| *att == name)
+        .map_or(name.as_str(), |(_, intel)| intel);
+    for (prefix, intel) in &[("movz", "movzx"), ("movs", "movsx")] {
+        let mut suffixes = name.strip_prefix(prefix).unwrap_or("").chars();
+        if let (Some(source), Some(destination), None) =
+            (suffixes.next(), suffixes.next(), suffixes.next())
+        {
+            if size_keyword(source).is_some() && size_keyword(destination).is_some() {
+                return Ok((intel.to_string(), Some(destination), Some(source)));
+            }
+        }
+    }
+    if encoder::operand_forms(name).is_some() {
+        return Ok((name.to_string(), None, None));
+    }
+    let (stem, suffix) = name.split_at(name.len().saturating_sub(1));
+    let suffix = suffix.chars().next();
+    match suffix {
+        Some(suffix)
+            if size_keyword(suffix).is_some() && encoder::operand_forms(stem).is_some() =>
+        {
+            Ok((stem.to_string(), Some(suffix), None))
+        }
+        _ => Err(ErrorKind::UnknownInstruction(name.to_string())),
+    }
+}
+
+fn register(text: &str) -> Option<&str> {
+    let name = text.strip_prefix('%').unwrap_or(text);
+    Register::from_name(name).map(|_| name)
+}
+
+// Translates a memory operand like "%fs:-8(%rbp,%rax,4)" to "[fs: rbp+rax*4-8]".
+fn memory(text: &str, size: Option<&str>) -> Result<String, ErrorKind> {
+    let error = || invalid(format!("invalid memory operand '{}'", text));
+    let (segment, rest) = match text.find(':') {
+        Some(i) => {
+            let segment = text[..i].trim().trim_start_matches('%');
+            if !SEGMENTS.contains(&segment) {
+                return Err(error());
+            }
+            (Some(segment), text[i + 1..].trim())
+        }
+        None => (None, text),
+    };
+    let (displacement, registers) = match rest.find('(') {
+        Some(i) if rest.ends_with(')') => (rest[..i].trim(), &rest[i + 1..rest.len() - 1]),
+        Some(_) => return Err(error()),
+        None => (rest, ""),
+    };
+
+    let mut terms = vec![];
+    let parts: Vec<&str> = registers.split(',').map(str::trim).collect();
+    if registers.trim().is_empty() {
+        // Only a displacement.
+    } else if parts.len() > 3 {
+        return Err(error());
+    } else {
+        let base = parts[0].strip_prefix('%').unwrap_or(parts[0]);
+        if base == "rip" {
+            // Labels relative to rip are written with "rel" in Intel syntax.
+            if displacement.is_empty() || parts.len() > 1 {
+                return Err(error());
+            }
+            return Ok(format!("{}[rel {}]", size.unwrap_or(""), displacement));
+        }
+        if !base.is_empty() {
+            terms.push(register(base).ok_or_else(error)?.to_string());
+        }
+        if let Some(index) = parts.get(1).filter(|p| !p.is_empty()) {
+            let index = register(index).ok_or_else(error)?;
+            match parts.get(2) {
+                Some(scale) => terms.push(format!("{}*{}", index, scale)),
+                None => terms.push(index.to_string()),
+            }
+        }
+    }
+
+    let mut address = terms.join("+");
+    if !displacement.is_empty() {
+        if !address.is_empty() && !displacement.starts_with('-') {
+            address.push('+');
+        }
+        address += displacement;
+    }
+    if address.is_empty() {
+        return Err(error());
+    }
+    let segment = segment.map_or(String::new(), |s| format!("{}: ", s));
+    Ok(format!("{}[{}{}]", size.unwrap_or(""), segment, address))
+}
+
+// Translates an operand. Branch targets are labels unless they start with '*'.
+fn operand(text: &str, size: Option<&str>, branch: bool) -> Result<String, ErrorKind> {
+    if let Some(immediate) = text.strip_prefix('$') {
+        return Ok(immediate.trim().to_string());
+    }
+    if let Some(name) = register(text) {
+        return Ok(name.to_string());
+    }
+    if text.starts_with('%') && !text.contains(':') {
+        return Err(ErrorKind::UnknownRegister(text[1..].to_string()));
+    }
+    match text.strip_prefix('*') {
+        Some(target) if branch => match register(target.trim()) {
+            Some(name) => Ok(name.to_string()),
+            None => memory(target.trim(), size),
+        },
+        Some(_) => Err(invalid(format!("'*' outside of a branch: '{}'", text))),
+        None if branch => Ok(text.to_string()),
+        None => memory(text, size),
+    }
+}
+
+// Translates an instruction in AT&T syntax to Intel syntax, reversing the operand order.
+// Labels and directives are returned unchanged.
+pub(crate) fn translate(line: &str) -> Result<String, ErrorKind> {
+    let line = strip_comment(line).trim();
+    let (label, rest) = match line.find(':') {
+        Some(i) if encoder::is_label(&line[..i]) => (&line[..=i], line[i + 1..].trim()),
+        _ => ("", line),
+    };
+    if rest.is_empty() || rest.starts_with('.') || rest.starts_with('[') {
+        return Ok(line.to_string());
+    }
+    let (name, operands) = match rest.find(char::is_whitespace) {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, ""),
+    };
+    let (name, suffix, source_suffix) = mnemonic(name)?;
+    let branch = name == "jmp" || name == "call" || name.starts_with('j');
+    let size = suffix.and_then(size_keyword).map(|k| format!("{} ", k));
+    let source_size = source_suffix
+        .and_then(size_keyword)
+        .map(|k| format!("{} ", k));
+
+    let operands = split_operands(operands);
+    let count = operands.len();
+    let mut translated = vec![];
+    for (i, text) in operands.iter().enumerate().rev() {
+        // The operand size doesn't apply to the address lea computes.
+        let size = if name == "lea" {
+            None
+        } else if source_size.is_some() && i == 0 && count == 2 {
+            source_size.as_deref()
+        } else {
+            size.as_deref()
+        };
+        translated.push(operand(text, size, branch)?);
+    }
+    let separator = if label.is_empty() { "" } else { " " };
+    Ok(
+        format!("{}{}{} {}", label, separator, name, translated.join(", "))
+            .trim_end()
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_instructions() {
+        let cases = [
+            ("movl $60, %eax", "mov eax, 60"),
+            ("movq %rsp, %rbp # frame", "mov rbp, rsp"),
+            ("addq $8, -16(%rbp)", "add qword [rbp-16], 8"),
+            ("movb %al, (%rdi,%rcx,1)", "mov byte [rdi+rcx*1], al"),
+            ("leaq 8(,%rax,4), %rdx", "lea rdx, [rax*4+8]"),
+            ("leaq message(%rip), %rsi", "lea rsi, [rel message]"),
+            ("movzbl (%rsi), %eax", "movzx eax, byte [rsi]"),
+            ("movswq %ax, %rbx", "movsx rbx, ax"),
+            ("imul $3, %ebx, %eax", "imul eax, ebx, 3"),
+            ("jmp *%rax", "jmp rax"),
+            ("call *8(%rax)", "call [rax+8]"),
+            ("jne .loop", "jne .loop"),
+            ("loop: decl %ecx", "loop: dec ecx"),
+            ("movq %fs:0x28, %rax", "mov rax, qword [fs: 0x28]"),
+            ("cltq", "cdqe"),
+            ("shlq %cl, %rdx", "shl rdx, cl"),
+            ("pushq $1", "push 1"),
+            ("ret", "ret"),
+            (".globl main", ".globl main"),
+        ];
+        for (att, intel) in &cases {
+            assert_eq!(translate(att).as_deref(), Ok(*intel), "{}", att);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            translate("frobl %eax"),
+            Err(ErrorKind::UnknownInstruction("frobl".to_string()))
+        );
+        assert_eq!(
+            translate("movl %exx, %eax"),
+            Err(ErrorKind::UnknownRegister("exx".to_string()))
+        );
+        assert!(translate("movl 8(%rax, %eax").is_err());
+        assert!(translate("movl *%rax, %eax").is_err());
+    }
+}
@@ -1,22 +1,258 @@
+use minitools::assembler::{AssemblyError, Syntax};
 use std::env;
 use std::fs;
-use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let assembly = fs::read_to_string(&args[1])?;
+// Exit codes.
+const ASSEMBLY_ERROR: i32 = 1;
+const USAGE_ERROR: i32 = 2;
+const IO_ERROR: i32 = 3;
 
-    let result = minitools::assembler::assemble(&assembly);
-    let binary = minitools::elf::create_binary(result)?;
+const USAGE: &str = "Usage: minias [options] <input.asm>
 
-    let filename = format!(
-        "{}.o",
-        Path::new(&args[1]).file_stem().unwrap().to_str().unwrap()
-    );
-    let mut buffer = File::create(&filename)?;
+Assembles <input.asm>, or standard input if it is \"-\".
 
-    buffer.write_all(&binary)?;
-    Ok(())
+Options:
+  -o <file>          Write output to <file>, or standard output if it is \"-\"
+                     (default: input name with the format's extension)
+  -f <format>        Output format: elf64 (default), elf32, bin, ihex, srec
+                     (elf32 starts in 32-bit mode)
+  --syntax <syntax>  Input syntax until a .intel_syntax or .att_syntax directive:
+                     intel (default) or att
+  -I <dir>           Add <dir> to the include search path
+  -D <name>[=<value>]
+                     Define a preprocessor macro
+  -l <file>          Write a listing to <file>, or standard output if it is \"-\"
+  --werror           Treat warnings as errors
+  -h, --help         Show this help
+  --version          Show the version
+
+Exit codes: 0 on success, 1 on assembly errors, including ones the output format can't
+represent, 2 on invalid usage, 3 on I/O errors.";
+
+#[derive(Copy, Clone, PartialEq)]
+enum Format {
+    Elf64,
+    Elf32,
+    Bin,
+    IntelHex,
+    SRecord,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Elf64 | Format::Elf32 => "o",
+            Format::Bin => "bin",
+            Format::IntelHex => "ihex",
+            Format::SRecord => "srec",
+        }
+    }
+}
+
+struct Options {
+    input: String,
+    output: Option<String>,
+    format: Format,
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, String)>,
+    listing: Option<String>,
+    werror: bool,
+    syntax: Syntax,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("minias: {}", message);
+    eprintln!("Try 'minias --help' for more information.");
+    process::exit(USAGE_ERROR);
+}
+
+fn parse_options(args: &[String]) -> Options {
+    let mut options = Options {
+        input: String::new(),
+        output: None,
+        format: Format::Elf64,
+        include_dirs: vec![],
+        defines: vec![],
+        listing: None,
+        werror: false,
+        syntax: Syntax::Intel,
+    };
+    let mut input = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| -> String {
+            args.next()
+                .cloned()
+                .unwrap_or_else(|| usage_error(&format!("option '{}' requires a value", name)))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "--version" => {
+                println!("minias {}", env!("CARGO_PKG_VERSION"));
+                process::exit(0);
+            }
+            "-o" => options.output = Some(value("-o")),
+            "-f" => {
+                options.format = match value("-f").as_str() {
+                    "elf64" | "elf" => Format::Elf64,
+                    "elf32" => Format::Elf32,
+                    "bin" => Format::Bin,
+                    "ihex" => Format::IntelHex,
+                    "srec" => Format::SRecord,
+                    other => usage_error(&format!("unknown output format '{}'", other)),
+                }
+            }
+            "--syntax" => {
+                options.syntax = match value("--syntax").as_str() {
+                    "intel" => Syntax::Intel,
+                    "att" => Syntax::Att,
+                    other => usage_error(&format!("unknown syntax '{}'", other)),
+                }
+            }
+            "-I" => options.include_dirs.push(PathBuf::from(value("-I"))),
+            "-D" => {
+                let definition = value("-D");
+                let mut parts = definition.splitn(2, '=');
+                let name = parts.next().unwrap().to_string();
+                if name.is_empty() {
+                    usage_error("-D requires a macro name");
+                }
+                let value = parts.next().unwrap_or("").to_string();
+                options.defines.push((name, value));
+            }
+            "-l" => options.listing = Some(value("-l")),
+            "--werror" => options.werror = true,
+            "-" => input = Some(arg.clone()),
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option '{}'", arg)),
+            _ => {
+                if input.is_some() {
+                    usage_error("more than one input file given");
+                }
+                input = Some(arg.clone());
+            }
+        }
+    }
+    options.input = input.unwrap_or_else(|| usage_error("no input file given"));
+    options
+}
+
+fn io_error(path: &str, err: std::io::Error) -> ! {
+    eprintln!("minias: {}: {}", path, err);
+    process::exit(IO_ERROR);
+}
+
+// Errors creating the output format, like overlapping sections or unsupported relocations, are
+// errors in the assembly.
+fn output_error(input_name: &str, err: std::io::Error) -> ! {
+    eprintln!("{}: error: {}", input_name, err);
+    process::exit(ASSEMBLY_ERROR);
+}
+
+// Formats "file:line" for diagnostics. Errors in included files have their own file name.
+fn location(input_name: &str, err: &AssemblyError) -> String {
+    match &err.file {
+        Some(file) => format!("{}:{}", file.display(), err.line),
+        None => format!("{}:{}", input_name, err.line),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = parse_options(&args);
+
+    // Without an explicit output file, the output goes next to the input, or to stdout for input
+    // from stdin.
+    let filename = match (&options.output, options.input.as_str()) {
+        (Some(output), _) => PathBuf::from(output),
+        (None, "-") => PathBuf::from("-"),
+        (None, input) => {
+            let input = Path::new(input);
+            if input.file_stem().is_none() {
+                usage_error(&format!(
+                    "can't derive an output file name from '{}', use -o",
+                    input.display()
+                ));
+            }
+            input.with_extension(options.format.extension())
+        }
+    };
+
+    let assembly = if options.input == "-" {
+        let mut assembly = String::new();
+        std::io::stdin()
+            .read_to_string(&mut assembly)
+            .unwrap_or_else(|err| io_error("<stdin>", err));
+        assembly
+    } else {
+        fs::read_to_string(&options.input).unwrap_or_else(|err| io_error(&options.input, err))
+    };
+    let input_name = if options.input == "-" {
+        "<stdin>"
+    } else {
+        &options.input
+    };
+
+    let assembler_options = minitools::assembler::Options {
+        defines: options.defines.clone(),
+        path: Some(PathBuf::from(&options.input)).filter(|_| options.input != "-"),
+        include_dirs: options.include_dirs.clone(),
+        bits: if options.format == Format::Elf32 {
+            32
+        } else {
+            64
+        },
+        syntax: options.syntax,
+        ..Default::default()
+    };
+    let result = match minitools::assembler::assemble_with(&assembly, &assembler_options) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("{}: error: {}", location(input_name, &err), err.kind);
+            process::exit(ASSEMBLY_ERROR);
+        }
+    };
+    let severity = if options.werror { "error" } else { "warning" };
+    for warning in result.warnings() {
+        eprintln!(
+            "{}: {}: {}",
+            location(input_name, warning),
+            severity,
+            warning.kind
+        );
+    }
+    if options.werror && !result.warnings().is_empty() {
+        process::exit(ASSEMBLY_ERROR);
+    }
+
+    if let Some(listing) = &options.listing {
+        let text = minitools::listing::create_listing(&result);
+        if listing == "-" {
+            print!("{}", text);
+        } else {
+            fs::write(listing, text).unwrap_or_else(|err| io_error(listing, err));
+        }
+    }
+
+    let output = match options.format {
+        Format::Elf64 => minitools::elf::create_binary(result),
+        Format::Elf32 => minitools::elf::create_binary32(result),
+        Format::Bin => minitools::flat::create_binary(result),
+        Format::IntelHex => minitools::flat::create_intel_hex(result),
+        Format::SRecord => minitools::flat::create_srecord(result),
+    }
+    .unwrap_or_else(|err| output_error(input_name, err));
+    if filename == Path::new("-") {
+        std::io::stdout()
+            .write_all(&output)
+            .unwrap_or_else(|err| io_error("<stdout>", err));
+    } else {
+        fs::write(&filename, &output)
+            .unwrap_or_else(|err| io_error(&filename.display().to_string(), err));
+    }
 }
@@ -0,0 +1,168 @@
+use minitools::disassembler;
+use minitools::elf::{ElfFile, ElfSection, SHT_PROGBITS, STT_FILE};
+use minitools::x86::Operand;
+use std::env;
+use std::fs;
+use std::process;
+
+// How many bytes to show per line before wrapping, like GNU objdump does.
+const BYTES_PER_LINE: usize = 7;
+
+fn format_offset(name: &str, offset: i64, show_zero: bool) -> String {
+    if offset == 0 && !show_zero {
+        format!("<{}>", name)
+    } else if offset < 0 {
+        format!("<{}-0x{:x}>", name, -offset)
+    } else {
+        format!("<{}+0x{:x}>", name, offset)
+    }
+}
+
+// The symbol closest before `address` in the section, if any.
+fn symbolize(elf: &ElfFile, section_index: usize, address: u64) -> Option<String> {
+    elf.symbols
+        .iter()
+        .filter(|s| s.section as usize == section_index && s.typ != STT_FILE && s.value <= address)
+        .max_by_key(|s| s.value)
+        .map(|s| {
+            let name = if s.name.is_empty() {
+                elf.sections[section_index].name.clone()
+            } else {
+                s.name.clone()
+            };
+            format_offset(&name, (address - s.value) as i64, false)
+        })
+}
+
+fn relocation_annotation(
+    elf: &ElfFile,
+    section_index: usize,
+    section: &ElfSection,
+    start: u64,
+    length: usize,
+) -> Option<String> {
+    let relocation = elf.relocations.iter().find(|r| {
+        r.section == section_index && r.offset >= start && r.offset < start + length as u64
+    })?;
+    let addend = match relocation.addend {
+        Some(addend) => addend,
+        // REL relocations store their addend in the relocated field itself.
+        None => {
+            let o = relocation.offset as usize;
+            let bytes = section.content.get(o..o + 4)?;
+            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64
+        }
+    };
+    // Always show the addend, as it is what the linker will add to the symbol's address.
+    Some(format_offset(
+        &elf.symbol_name(relocation.symbol),
+        addend,
+        true,
+    ))
+}
+
+fn disassemble_section(elf: &ElfFile, section_index: usize) {
+    let section = &elf.sections[section_index];
+    println!();
+    println!("Disassembly of section {}:", section.name);
+
+    let labels = elf.labels(section_index);
+
+    let bits = if elf.bits == 32 { 32 } else { 64 };
+    let mut printed_label = section.content.is_empty();
+    for (address, length, result) in
+        disassembler::decode_all(&section.content, section.address, bits)
+    {
+        if let Some(label) = labels.iter().find(|s| s.value == address) {
+            println!();
+            println!("{:016x} <{}>:", address, label.name);
+            printed_label = true;
+        }
+        if !printed_label {
+            println!();
+            println!("{:016x} <{}>:", address, section.name);
+            printed_label = true;
+        }
+
+        let offset = (address - section.address) as usize;
+        let bytes = &section.content[offset..offset + length];
+        let text = match result {
+            Ok(instruction) => {
+                let mut text = instruction.to_string();
+                if let Some(annotation) =
+                    relocation_annotation(elf, section_index, section, offset as u64, length)
+                {
+                    text += &format!(" {}", annotation);
+                } else if let Some(Operand::Target(target)) = instruction.operands.first() {
+                    if let Some(symbol) = symbolize(elf, section_index, *target) {
+                        text += &format!(" {}", symbol);
+                    }
+                }
+                text
+            }
+            Err(_) => "(bad)".to_string(),
+        };
+
+        for (i, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let hex = hex.join(" ");
+            let chunk_address = address + (i * BYTES_PER_LINE) as u64;
+            if i == 0 {
+                println!(
+                    "{:>4x}:\t{:<width$}\t{}",
+                    chunk_address,
+                    hex,
+                    text,
+                    width = BYTES_PER_LINE * 3
+                );
+            } else {
+                println!("{:>4x}:\t{}", chunk_address, hex);
+            }
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: miniobjdump -d <file>...");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut disassemble = false;
+    let mut files = vec![];
+    for arg in &args {
+        match arg.as_str() {
+            "-d" => disassemble = true,
+            _ if arg.starts_with('-') => usage(),
+            _ => files.push(arg),
+        }
+    }
+    if !disassemble || files.is_empty() {
+        usage();
+    }
+
+    let mut failed = false;
+    for file in files {
+        let elf = match fs::read(file).and_then(|bytes| minitools::elf::parse(&bytes)) {
+            Ok(elf) => elf,
+            Err(err) => {
+                eprintln!("miniobjdump: {}: {}", file, err);
+                failed = true;
+                continue;
+            }
+        };
+
+        println!();
+        println!("{}:     file format {}", file, elf.format_name());
+        println!();
+        for (index, section) in elf.sections.iter().enumerate() {
+            if section.is_executable() && section.typ == SHT_PROGBITS {
+                disassemble_section(&elf, index);
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
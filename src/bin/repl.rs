@@ -2,48 +2,1237 @@ extern crate byteorder;
 extern crate rustyline;
 extern crate tempfile;
 
+use minitools::assembler::{self, AssemblyError, ErrorKind, Options, Syntax};
+use minitools::cpu::{self, AddressSpace, Cpu, Event};
+use minitools::disassembler::{self, DecodeError};
+use minitools::emulator::SparseMemory;
+use minitools::flat::{self, PlacedSection};
+use minitools::x86::{Instruction, Register, Size};
+use minitools::{listing, AssemblyResult};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper};
+use std::borrow::Cow::{self, Owned};
+use std::collections::HashMap;
+use std::env;
 use std::io::Write;
+use std::path::PathBuf;
 use std::process::Command;
 use tempfile::NamedTempFile;
 
-fn main() -> std::io::Result<()> {
-    let mut rl = Editor::<()>::new();
-    if rl.load_history("history.txt").is_err() {
-        println!("No previous history.");
+fn hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    hex.join(" ")
+}
+
+fn binary(bytes: &[u8]) -> String {
+    let binary: Vec<String> = bytes.iter().map(|b| format!("{:08b}", b)).collect();
+    binary.join(" ")
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputStyle {
+    Hex,
+    Binary,
+    C,
+    Rust,
+}
+
+impl OutputStyle {
+    fn from_name(name: &str) -> Option<OutputStyle> {
+        match name {
+            "hex" => Some(OutputStyle::Hex),
+            "bin" => Some(OutputStyle::Binary),
+            "c" => Some(OutputStyle::C),
+            "rust" => Some(OutputStyle::Rust),
+            _ => None,
+        }
+    }
+
+    fn format(self, bytes: &[u8]) -> String {
+        match self {
+            OutputStyle::Hex => hex(bytes),
+            OutputStyle::Binary => binary(bytes),
+            OutputStyle::C => {
+                let bytes: Vec<String> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+                format!("{{ {} }}", bytes.join(", "))
+            }
+            OutputStyle::Rust => {
+                let bytes: String = bytes.iter().map(|b| format!("\\x{:02x}", b)).collect();
+                format!("b\"{}\"", bytes)
+            }
+        }
+    }
+}
+
+fn little_endian(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+fn describe_value(bytes: &[u8]) -> String {
+    let unsigned = little_endian(bytes);
+    let bits = bytes.len() * 8;
+    let signed = if bits < 64 && unsigned >> (bits - 1) == 1 {
+        unsigned as i64 - (1 << bits)
+    } else {
+        unsigned as i64
+    };
+    if signed < 0 {
+        format!("{} (0x{:x}, unsigned {})", signed, unsigned, unsigned)
+    } else {
+        format!("{} (0x{:x})", unsigned, unsigned)
+    }
+}
+
+fn describe_prefix(prefix: u8) -> &'static str {
+    match prefix {
+        0x66 => "operand size override",
+        0x67 => "address size override",
+        0xf0 => "lock",
+        0xf2 => "repne",
+        0xf3 => "rep",
+        0x26 => "es segment override",
+        0x2e => "cs segment override",
+        0x36 => "ss segment override",
+        0x3e => "ds segment override",
+        0x64 => "fs segment override",
+        0x65 => "gs segment override",
+        _ => "unknown prefix",
+    }
+}
+
+// Register numbers in ModRM and SIB are extended by a REX bit, show both parts.
+fn describe_register_field(value: u8, extension: bool) -> String {
+    if extension {
+        format!("{}+8", value)
+    } else {
+        format!("{}", value)
+    }
+}
+
+// The mod field's addressing form. mod=00 rm=101 is RIP-relative in 64-bit mode but an
+// absolute disp32 otherwise, and 16-bit addressing has its own table.
+fn describe_addressing(md: u8, rm: u8, address_bits: u32) -> &'static str {
+    match (md, rm, address_bits) {
+        (3, _, _) => "register",
+        (0, 6, 16) => "disp16 only",
+        (0, 5, 64) => "RIP-relative disp32",
+        (0, 5, 32) => "disp32 only",
+        (0, _, _) => "memory",
+        (1, _, _) => "memory + disp8",
+        (_, _, 16) => "memory + disp16",
+        _ => "memory + disp32",
+    }
+}
+
+fn describe_modrm(modrm: u8, sib: bool, rex: u8, address_bits: u32) -> String {
+    let md = modrm >> 6;
+    let reg = (modrm >> 3) & 7;
+    let rm = modrm & 7;
+    let mut addressing = describe_addressing(md, rm, address_bits).to_string();
+    // With a SIB byte, rm only announces it and REX.B extends the SIB base instead.
+    let rm_description = if sib {
+        addressing += ", SIB follows";
+        format!("{}", rm)
+    } else {
+        describe_register_field(rm, rex & 1 != 0)
+    };
+    format!(
+        "mod={} ({}) reg={} rm={}",
+        md,
+        addressing,
+        describe_register_field(reg, rex & 4 != 0),
+        rm_description
+    )
+}
+
+fn describe_sib(sib: u8, md: u8, rex: u8) -> String {
+    let scale = sib >> 6;
+    let index = (sib >> 3) & 7;
+    let base = sib & 7;
+    let index_description = if index == 4 && rex & 2 == 0 {
+        "none".to_string()
+    } else {
+        describe_register_field(index, rex & 2 != 0)
+    };
+    // Base 101 with mod=00 has no base register, only a disp32.
+    let base_description = if base == 5 && md == 0 {
+        "none (disp32)".to_string()
+    } else {
+        describe_register_field(base, rex & 1 != 0)
+    };
+    format!(
+        "scale={} index={} base={}",
+        1 << scale,
+        index_description,
+        base_description
+    )
+}
+
+// Prints the fields of an encoded instruction, in the order they appear in the encoding.
+fn explain(instruction: &Instruction, bits: u32) {
+    let layout = &instruction.layout;
+    let rex = layout.rex.unwrap_or(0);
+    let address_bits = match (bits, layout.prefixes.contains(&0x67)) {
+        (64, false) => 64,
+        (64, true) | (32, false) | (16, true) => 32,
+        _ => 16,
+    };
+    let mut rows: Vec<(&str, Vec<u8>, String, String)> = vec![];
+    for &prefix in &layout.prefixes {
+        rows.push((
+            "prefix",
+            vec![prefix],
+            binary(&[prefix]),
+            describe_prefix(prefix).to_string(),
+        ));
+    }
+    if let Some(rex) = layout.rex {
+        rows.push((
+            "rex",
+            vec![rex],
+            format!(
+                "0100 {} {} {} {}",
+                (rex >> 3) & 1,
+                (rex >> 2) & 1,
+                (rex >> 1) & 1,
+                rex & 1
+            ),
+            format!(
+                "W={} R={} X={} B={}",
+                (rex >> 3) & 1,
+                (rex >> 2) & 1,
+                (rex >> 1) & 1,
+                rex & 1
+            ),
+        ));
+    }
+    rows.push((
+        "opcode",
+        layout.opcode.clone(),
+        binary(&layout.opcode),
+        instruction.mnemonic.to_string(),
+    ));
+    if let Some(modrm) = layout.modrm {
+        rows.push((
+            "modrm",
+            vec![modrm],
+            format!(
+                "{:02b} {:03b} {:03b}",
+                modrm >> 6,
+                (modrm >> 3) & 7,
+                modrm & 7
+            ),
+            describe_modrm(modrm, layout.sib.is_some(), rex, address_bits),
+        ));
+    }
+    if let Some(sib) = layout.sib {
+        let md = layout.modrm.unwrap_or(0) >> 6;
+        rows.push((
+            "sib",
+            vec![sib],
+            format!("{:02b} {:03b} {:03b}", sib >> 6, (sib >> 3) & 7, sib & 7),
+            describe_sib(sib, md, rex),
+        ));
+    }
+    if !layout.displacement.is_empty() {
+        rows.push((
+            "displacement",
+            layout.displacement.clone(),
+            binary(&layout.displacement),
+            describe_value(&layout.displacement),
+        ));
+    }
+    if !layout.immediate.is_empty() {
+        rows.push((
+            "immediate",
+            layout.immediate.clone(),
+            binary(&layout.immediate),
+            describe_value(&layout.immediate),
+        ));
+    }
+
+    let hex_width = rows.iter().map(|r| hex(&r.1).len()).max().unwrap_or(0);
+    let binary_width = rows.iter().map(|r| r.2.len()).max().unwrap_or(0);
+    println!("{}", instruction);
+    for (label, bytes, binary, description) in rows {
+        println!(
+            "  {:<13}{:<hw$}  {:<bw$}  {}",
+            label,
+            hex(&bytes),
+            binary,
+            description,
+            hw = hex_width,
+            bw = binary_width
+        );
+    }
+}
+
+// Assembles the program with nasm, for comparison. Returns nasm's error output if it fails.
+fn nasm_encode(program: &str, bits: u32) -> std::io::Result<Result<Vec<u8>, String>> {
+    let mut file = NamedTempFile::new()?;
+    let file2 = NamedTempFile::new()?;
+
+    writeln!(file, "BITS {}", bits)?;
+    writeln!(file, "{}", program)?;
+
+    let output = Command::new("nasm")
+        .arg(file.path())
+        .arg("-o")
+        .arg(file2.path())
+        .output()?;
+
+    if output.status.success() && output.stderr.is_empty() {
+        Ok(Ok(std::fs::read(file2.path())?))
+    } else {
+        let mut message = String::from_utf8_lossy(&output.stdout).to_string();
+        message += &String::from_utf8_lossy(&output.stderr);
+        Ok(Err(message.trim().to_string()))
+    }
+}
+
+// The virtual machine the REPL executes instructions on has a stack, and the program's sections
+// are loaded at the addresses of a flat image.
+const STACK_BOTTOM: u64 = 0x10000;
+const STACK_SIZE: usize = 0x10000;
+
+struct Session {
+    compare_with_nasm: bool,
+    explain: bool,
+    bits: u32,
+    syntax: Syntax,
+    output: OutputStyle,
+    cpu: Cpu,
+    memory: SparseMemory,
+    // Where each section was last loaded into memory.
+    loaded: HashMap<String, u64>,
+    // The lines entered so far, which are assembled together so that labels and constants
+    // persist. The machine state before each line is kept for :undo.
+    program: Vec<String>,
+    snapshots: Vec<(Cpu, SparseMemory)>,
+}
+
+impl Session {
+    fn new() -> Session {
+        let (cpu, memory) = new_machine();
+        Session {
+            compare_with_nasm: false,
+            explain: false,
+            bits: 64,
+            syntax: Syntax::Intel,
+            output: OutputStyle::Hex,
+            cpu,
+            memory,
+            loaded: HashMap::new(),
+            program: vec![],
+            snapshots: vec![],
+        }
+    }
+
+    fn reset(&mut self) {
+        let (cpu, memory) = new_machine();
+        self.cpu = cpu;
+        self.memory = memory;
+        self.loaded.clear();
+        self.program.clear();
+        self.snapshots.clear();
+    }
+
+    // Maps the sections into memory. A section is written in full where it wasn't loaded
+    // before, otherwise only the new lines are, so that values the program stored are kept.
+    fn load(&mut self, sections: &[PlacedSection]) {
+        for section in sections {
+            self.memory
+                .map(section.address, section.content.len() as u64);
+            if self.loaded.get(&section.name) != Some(&section.address) {
+                self.memory
+                    .write(section.address, &section.content)
+                    .unwrap();
+                self.loaded.insert(section.name.clone(), section.address);
+            }
+        }
+    }
+
+    // The mode and syntax at the end of the program, which new lines are assembled in.
+    fn mode(&self) -> (u32, Syntax) {
+        let (mut bits, mut syntax) = (self.bits, self.syntax);
+        for line in self.program.iter().flat_map(|entry| entry.lines()) {
+            let line = line.trim().trim_start_matches('[').trim_end_matches(']');
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("bits"), Some(value)) => bits = value.parse().unwrap_or(bits),
+                (Some("use16"), None) => bits = 16,
+                (Some("use32"), None) => bits = 32,
+                (Some("use64"), None) => bits = 64,
+                (Some(".intel_syntax"), _) => syntax = Syntax::Intel,
+                (Some(".att_syntax"), _) => syntax = Syntax::Att,
+                _ => {}
+            }
+        }
+        (bits, syntax)
+    }
+
+    // Options for assembling with the session's initial mode and syntax.
+    fn options(&self) -> Options {
+        Options {
+            bits: self.bits,
+            syntax: self.syntax,
+            ..Options::default()
+        }
+    }
+
+    fn assemble(&self) -> Result<AssemblyResult, AssemblyError> {
+        assembler::assemble_with(&self.program.join("\n"), &self.options())
+    }
+
+    // Changes a setting, from a command or the configuration file.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let on_off = |value| match value {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(format!("expected on or off, found '{}'", value)),
+        };
+        match name {
+            "bits" => match value {
+                "16" | "32" | "64" => self.bits = value.parse().unwrap(),
+                _ => return Err(format!("expected 16, 32 or 64, found '{}'", value)),
+            },
+            "syntax" => match value {
+                "intel" => self.syntax = Syntax::Intel,
+                "att" => self.syntax = Syntax::Att,
+                _ => return Err(format!("expected intel or att, found '{}'", value)),
+            },
+            "output" => {
+                self.output = OutputStyle::from_name(value)
+                    .ok_or_else(|| format!("expected hex, bin, c or rust, found '{}'", value))?
+            }
+            "explain" => self.explain = on_off(value)?,
+            "nasm" => self.compare_with_nasm = on_off(value)?,
+            _ => return Err(format!("unknown setting '{}'", name)),
+        }
+        Ok(())
+    }
+}
+
+// Where the REPL keeps its files, following the XDG base directory specification:
+// $XDG_CONFIG_HOME/minitools/repl.conf and $XDG_STATE_HOME/minitools/history.
+fn xdg_directory(variable: &str, fallback: &str) -> Option<PathBuf> {
+    let base = match env::var_os(variable) {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => PathBuf::from(env::var_os("HOME")?).join(fallback),
+    };
+    Some(base.join("minitools"))
+}
+
+// Reads the defaults from the configuration file, with lines like "bits = 32". Empty lines and
+// lines starting with '#' are ignored.
+fn load_config(session: &mut Session) {
+    let path = match xdg_directory("XDG_CONFIG_HOME", ".config") {
+        Some(directory) => directory.join("repl.conf"),
+        None => return,
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let result = match line.find('=') {
+            Some(i) => session.set(line[..i].trim(), line[i + 1..].trim()),
+            None => Err("expected 'name = value'".to_string()),
+        };
+        if let Err(message) = result {
+            println!("warning: {}:{}: {}", path.display(), number + 1, message);
+        }
+    }
+}
+
+fn new_machine() -> (Cpu, SparseMemory) {
+    let mut cpu = Cpu::new();
+    cpu.registers[cpu::RSP] = STACK_BOTTOM + STACK_SIZE as u64;
+    let mut memory = SparseMemory::new();
+    memory.map(STACK_BOTTOM, STACK_SIZE as u64);
+    (cpu, memory)
+}
+
+fn print_registers(cpu: &Cpu) {
+    let registers: Vec<String> = cpu
+        .registers
+        .iter()
+        .enumerate()
+        .map(|(number, value)| {
+            let name = Register::new(number as u8, Size::Qword).name();
+            format!("{:>3}: 0x{:016x}", name, value)
+        })
+        .collect();
+    for row in registers.chunks(4) {
+        println!("{}", row.join("  "));
+    }
+    println!("rip: 0x{:016x}", cpu.rip);
+    let flags: Vec<&str> = cpu::FLAGS
+        .iter()
+        .filter(|(_, flag)| cpu.flag(*flag))
+        .map(|(name, _)| *name)
+        .collect();
+    println!("flags: {}", flags.join(" "));
+}
+
+// Prints the registers and flags which differ between two CPU states.
+fn print_changes(before: &Cpu, after: &Cpu) {
+    for (number, (old, new)) in before.registers.iter().zip(&after.registers).enumerate() {
+        if old != new {
+            let name = Register::new(number as u8, Size::Qword).name();
+            println!("{}: 0x{:x} -> 0x{:x}", name, old, new);
+        }
+    }
+    if before.rip != after.rip {
+        println!("rip: 0x{:x} -> 0x{:x}", before.rip, after.rip);
+    }
+    let flags: Vec<String> = cpu::FLAGS
+        .iter()
+        .filter(|(_, flag)| before.flag(*flag) != after.flag(*flag))
+        .map(|(name, flag)| format!("{}{}", if after.flag(*flag) { "+" } else { "-" }, name))
+        .collect();
+    if !flags.is_empty() {
+        println!("flags: {}", flags.join(" "));
+    }
+}
+
+// Runs the encoded instructions on the session's CPU, as if they were located at rip.
+fn execute(session: &mut Session, bytes: &[u8]) {
+    let before = session.cpu.clone();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let instruction = match disassembler::decode(&bytes[offset..], session.cpu.rip) {
+            Ok(instruction) => instruction,
+            Err(err) => {
+                println!("cannot execute: {}", err);
+                break;
+            }
+        };
+        offset += instruction.length;
+        match session.cpu.execute(&instruction, &mut session.memory) {
+            Ok(Event::Continue) => {}
+            Ok(Event::Syscall) => println!("syscall {} is not emulated", session.cpu.registers[0]),
+            Ok(Event::Breakpoint) => println!("breakpoint"),
+            Ok(Event::Halt) => println!("halted"),
+            Err(fault) => {
+                println!("fault: {}", fault);
+                break;
+            }
+        }
+    }
+    print_changes(&before, &session.cpu);
+}
+
+// Directives emitting data or padding, which isn't executed.
+const DATA_DIRECTIVES: [&str; 20] = [
+    "db", "dw", "dd", "dq", ".byte", ".word", ".short", ".value", ".long", ".int", ".quad",
+    ".ascii", ".asciz", ".string", ".zero", ".skip", ".space", ".p2align", ".balign", ".align",
+];
+
+fn is_data_directive(line: &str) -> bool {
+    line.split_whitespace()
+        .next()
+        .is_some_and(|word| DATA_DIRECTIVES.contains(&word))
+}
+
+// Parses bytes written like "48 89 e5", "4889e5", "0x48, 0x89" or "\\x48\\x89".
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let mut ret = vec![];
+    for token in text.split(|c: char| c.is_whitespace() || c == ',') {
+        let digits: String = token.split("\\x").collect();
+        let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+        if !digits.len().is_multiple_of(2) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid hex '{}'", token));
+        }
+        for i in (0..digits.len()).step_by(2) {
+            ret.push(u8::from_str_radix(&digits[i..i + 2], 16).unwrap());
+        }
+    }
+    Ok(ret)
+}
+
+// Recognizes a line of hex bytes entered instead of an instruction. A first word which is an
+// instruction, directive or macro, like "db" or "add", is not taken as hex.
+fn raw_hex(line: &str, macros: &[String]) -> Option<Vec<u8>> {
+    let first = line.split_whitespace().next()?.to_lowercase();
+    if assembler::operand_forms(&first).is_some()
+        || assembler::DIRECTIVES.contains(&first.as_str())
+        || macros.contains(&first)
+    {
+        return None;
+    }
+    parse_hex(line).ok().filter(|bytes| !bytes.is_empty())
+}
+
+// Prints the instructions the bytes decode to, with their lengths. Bytes which don't start a
+// valid instruction are shown one at a time, the incomplete instruction at the end as a whole.
+fn disassemble(bytes: &[u8], bits: u32) {
+    for (address, length, result) in disassembler::decode_all(bytes, 0, bits as u8) {
+        let start = address as usize;
+        let (length, text) = match result {
+            Ok(instruction) => (length, instruction.to_string()),
+            Err(DecodeError::Truncated) => (bytes.len() - start, "(truncated)".to_string()),
+            Err(err) => (length, format!("(bad) {}", err)),
+        };
+        println!(
+            "{:4x}: {:<30} {:2}  {}",
+            address,
+            hex(&bytes[start..start + length]),
+            length,
+            text
+        );
+        if start + length == bytes.len() {
+            break;
+        }
+    }
+}
+
+fn explain_bytes(bytes: &[u8], address: u64, bits: u32) {
+    for (location, length, result) in disassembler::decode_all(bytes, address, bits as u8) {
+        match result {
+            Ok(instruction) => explain(&instruction, bits),
+            Err(err) => {
+                let offset = (location - address) as usize;
+                println!("{}: {}", hex(&bytes[offset..offset + length]), err);
+            }
+        }
     }
+}
+
+// Commands, for completion and hints.
+const COMMANDS: [&str; 18] = [
+    ":help", ":quit", ":regs", ":list", ":undo", ":reset", ":save", ":load", ":bits", ":syntax",
+    ":hex", ":bin", ":c", ":rust", ":explain", ":native", ":dis", ":q",
+];
+
+const SIZE_KEYWORDS: [&str; 7] = ["byte", "word", "dword", "qword", "short", "near", "rel"];
+
+// Colours of the highlighted tokens, as ANSI SGR parameters.
+const COLOUR_MNEMONIC: &str = "1;34";
+const COLOUR_REGISTER: &str = "32";
+const COLOUR_NUMBER: &str = "33";
+const COLOUR_LABEL: &str = "35";
+const COLOUR_KEYWORD: &str = "36";
+const COLOUR_ERROR: &str = "1;31";
+const COLOUR_COMMENT: &str = "90";
+
+fn paint(text: &str, colour: &str) -> String {
+    format!("\x1b[{}m{}\x1b[0m", colour, text)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_.$?@#~%".contains(c)
+}
+
+// The labels and constants, and the macros defined in the program. They are found textually so
+// that they are known even while the program doesn't assemble.
+fn defined_names(program: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut labels = vec![];
+    let mut macros = vec![];
+    for line in program.iter().flat_map(|entry| entry.lines()) {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("%macro"), Some(name)) => macros.push(name.to_string()),
+            (Some(name), Some("equ")) => labels.push(name.trim_end_matches(':').to_string()),
+            (Some(name), _) if name.ends_with(':') => {
+                labels.push(name.trim_end_matches(':').to_string())
+            }
+            _ => {}
+        }
+    }
+    (labels, macros)
+}
+
+// Completes, hints, highlights and validates input lines using what the assembler knows.
+struct ReplHelper {
+    mnemonics: Vec<&'static str>,
+    registers: Vec<&'static str>,
+    // Names defined by the session's program.
+    labels: Vec<String>,
+    macros: Vec<String>,
+    filenames: FilenameCompleter,
+}
+
+impl ReplHelper {
+    fn new() -> ReplHelper {
+        ReplHelper {
+            mnemonics: assembler::mnemonics(),
+            registers: Register::all_names(),
+            labels: vec![],
+            macros: vec![],
+            filenames: FilenameCompleter::new(),
+        }
+    }
+
+    fn is_instruction(&self, word: &str) -> bool {
+        let lower = word.to_lowercase();
+        // AT&T mnemonics can have a size suffix.
+        let stem = lower.strip_suffix(&['b', 'w', 'l', 'q'][..]).unwrap_or("");
+        assembler::operand_forms(&lower).is_some()
+            || assembler::operand_forms(stem).is_some()
+            || assembler::DIRECTIVES.contains(&lower.as_str())
+            || self.macros.iter().any(|m| m == word)
+    }
+
+    fn is_prefix(&self, word: &str) -> bool {
+        self.mnemonics
+            .iter()
+            .chain(&assembler::DIRECTIVES)
+            .any(|m| m.starts_with(word))
+            || self.macros.iter().any(|m| m.starts_with(word))
+    }
+
+    fn colour(&self, word: &str, first: bool) -> Option<&'static str> {
+        let lower = word.to_lowercase();
+        if word.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+            Some(COLOUR_NUMBER)
+        } else if first && self.is_instruction(word) {
+            Some(COLOUR_MNEMONIC)
+        } else if first && !word.starts_with('%') && !self.is_prefix(&lower) {
+            // Only flagged once no instruction can start with it, so typing isn't an error.
+            Some(COLOUR_ERROR)
+        } else if Register::from_name(word.trim_start_matches('%')).is_some() {
+            Some(COLOUR_REGISTER)
+        } else if SIZE_KEYWORDS.contains(&lower.as_str()) || lower == "equ" {
+            Some(COLOUR_KEYWORD)
+        } else if self.labels.iter().any(|l| l == word) {
+            Some(COLOUR_LABEL)
+        } else {
+            None
+        }
+    }
+}
+
+// Skips a label definition at the start of a line, returning the rest.
+fn skip_label(line: &str) -> &str {
+    let trimmed = line.trim_start();
+    let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    if trimmed[..end].ends_with(':') {
+        trimmed[end..].trim_start()
+    } else {
+        trimmed
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        if before.trim_start().starts_with(":save ") || before.trim_start().starts_with(":load ") {
+            return self.filenames.complete(line, pos, ctx);
+        }
+        let start = before
+            .rfind(|c: char| !is_word_char(c) && c != ':')
+            .map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let candidates: Vec<&str> = if word.starts_with(':') {
+            COMMANDS.to_vec()
+        } else if skip_label(before).len() == word.len() {
+            let mut names = self.mnemonics.clone();
+            names.extend_from_slice(&assembler::DIRECTIVES);
+            names.extend(self.macros.iter().map(String::as_str));
+            names
+        } else {
+            let mut names = self.registers.clone();
+            names.extend_from_slice(&SIZE_KEYWORDS);
+            names.extend(self.labels.iter().map(String::as_str));
+            names
+        };
+        let mut matches: Vec<Pair> = candidates
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .map(|c| Pair {
+                display: c.to_string(),
+                replacement: c.to_string(),
+            })
+            .collect();
+        matches.sort_by(|a, b| a.display.cmp(&b.display));
+        matches.dedup_by(|a, b| a.display == b.display);
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for ReplHelper {
+    // Shows the operand forms of the instruction being typed, or the rest of a command.
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        if line.starts_with(':') {
+            let mut commands = COMMANDS.iter().filter(|c| c.starts_with(line));
+            return match (commands.next(), commands.next()) {
+                (Some(command), None) => Some(command[line.len()..].to_string()),
+                _ => None,
+            };
+        }
+        let rest = skip_label(line);
+        let mnemonic = rest.trim_end();
+        if mnemonic.is_empty() || mnemonic.contains(char::is_whitespace) {
+            return None;
+        }
+        let forms = assembler::operand_forms(mnemonic)?;
+        if forms == [""] || rest.len() > mnemonic.len() + 1 {
+            return None;
+        }
+        let separator = if rest.len() == mnemonic.len() {
+            " "
+        } else {
+            ""
+        };
+        Some(format!("{}{}", separator, forms.join(" | ")))
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if line.trim_start().starts_with(':') {
+            return Owned(paint(line, COLOUR_KEYWORD));
+        }
+        if raw_hex(line, &self.macros).is_some() {
+            return Owned(paint(line, COLOUR_NUMBER));
+        }
+        let mut ret = String::new();
+        for (number, text) in line.split('\n').enumerate() {
+            if number > 0 {
+                ret.push('\n');
+            }
+            let (code, comment) = match text.find(';') {
+                Some(i) => text.split_at(i),
+                None => (text, ""),
+            };
+            let mut first = true;
+            let mut rest = code;
+            while let Some(c) = rest.chars().next() {
+                if c == '"' || c == '\'' {
+                    let end = rest[1..].find(c).map_or(rest.len(), |i| i + 2);
+                    ret += &paint(&rest[..end], COLOUR_NUMBER);
+                    rest = &rest[end..];
+                } else if is_word_char(c) {
+                    let end = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+                    let word = &rest[..end];
+                    rest = &rest[end..];
+                    let label = rest.starts_with(':') || rest.trim_start().starts_with("equ ");
+                    let colour = if label && first {
+                        Some(COLOUR_LABEL)
+                    } else {
+                        self.colour(word, first)
+                    };
+                    if !label {
+                        first = false;
+                    }
+                    match colour {
+                        Some(colour) => ret += &paint(word, colour),
+                        None => ret += word,
+                    }
+                } else {
+                    ret.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+            if !comment.is_empty() {
+                ret += &paint(comment, COLOUR_COMMENT);
+            }
+        }
+        Owned(ret)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Owned(paint(hint, COLOUR_COMMENT))
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+impl Validator for ReplHelper {
+    // Macro definitions, conditionals and repetitions continue on the next line until they are
+    // closed.
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        if input.trim_start().starts_with(':') {
+            return Ok(ValidationResult::Valid(None));
+        }
+        let mut depth = 0;
+        for line in input.lines() {
+            let directive = line.split_whitespace().next().unwrap_or("").to_lowercase();
+            match directive.as_str() {
+                "%macro" | "%rep" | "%if" | "%ifdef" | "%ifndef" => depth += 1,
+                "%endmacro" | "%endrep" | "%endif" => depth -= 1,
+                _ => {}
+            }
+        }
+        if depth > 0 {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Helper for ReplHelper {}
+
+// How long a snippet may run natively before it is killed.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const NATIVE_TIMEOUT: u32 = 2;
+
+// Reads a snippet up to an empty line and runs it natively in a child process, printing the
+// registers and flags at its end.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn native(session: &Session, rl: &mut Editor<ReplHelper>) {
+    use minitools::jit::{self, NativeOutcome};
+
+    // The snippet runs in this 64-bit process.
+    let (bits, syntax) = session.mode();
+    if bits != 64 {
+        println!("error: native execution needs bits 64");
+        return;
+    }
+
+    println!("Enter the snippet, finish with an empty line.");
+    let mut lines = vec![];
     loop {
-        let readline = rl.readline(">> ");
-        match readline {
+        match rl.readline(".. ") {
+            Ok(line) if line.trim().is_empty() => break,
             Ok(line) => {
                 rl.add_history_entry(line.as_str());
+                lines.push(line);
+            }
+            Err(_) => return,
+        }
+    }
+    let options = Options {
+        bits,
+        syntax,
+        ..Options::default()
+    };
+    let snippet = match jit::assemble_snippet(&lines.join("\n"), &options) {
+        Ok(snippet) => snippet,
+        Err(err) => {
+            println!("error: line {}: {}", err.line, err.kind);
+            return;
+        }
+    };
+    match jit::run_native(&snippet, NATIVE_TIMEOUT) {
+        Ok(NativeOutcome::Finished(cpu)) => print_registers(&cpu),
+        Ok(NativeOutcome::Exited(status)) => println!("exited with status {}", status),
+        Ok(NativeOutcome::Signaled(libc::SIGALRM)) => {
+            println!("killed after {} seconds", NATIVE_TIMEOUT)
+        }
+        Ok(NativeOutcome::Signaled(signal)) => {
+            let name = match signal {
+                libc::SIGSEGV => "segmentation fault",
+                libc::SIGBUS => "bus error",
+                libc::SIGILL => "illegal instruction",
+                libc::SIGFPE => "arithmetic exception",
+                libc::SIGTRAP => "breakpoint",
+                _ => "signal",
+            };
+            println!("killed by {} ({})", name, signal);
+        }
+        Err(err) => println!("error: {}", err),
+    }
+}
 
-                let mut file = NamedTempFile::new()?;
-                let file2 = NamedTempFile::new()?;
+// Shows the program as a listing with section offsets, or just numbered if it doesn't
+// assemble yet.
+fn list(session: &Session) {
+    match session.assemble() {
+        Ok(result) => print!("{}", listing::create_listing(&result)),
+        Err(_) => {
+            for (number, line) in session.program.iter().enumerate() {
+                println!("{:6} {}", number + 1, line);
+            }
+        }
+    }
+}
+
+fn save(session: &Session, path: &str) -> std::io::Result<()> {
+    let mut text = session.program.join("\n");
+    text.push('\n');
+    std::fs::write(path, text)
+}
 
-                writeln!(file, "BITS 64")?;
-                writeln!(file, "{}", line)?;
+// Replaces the session with the lines of a file. They are assembled, but not executed.
+fn load(session: &mut Session, path: &str) -> std::io::Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    session.reset();
+    for line in text.lines() {
+        session.program.push(line.to_string());
+        session
+            .snapshots
+            .push((session.cpu.clone(), session.memory.clone()));
+    }
+    println!("Loaded {} lines.", session.program.len());
+    if let Err(err) = session.assemble() {
+        println!("error: line {}: {}", err.line, err.kind);
+    }
+    Ok(())
+}
+
+// The directive :bits or :syntax appends to the program, so that the mode only changes for the
+// following lines and :undo restores it.
+fn mode_directive(command: &str, value: &str) -> Result<String, String> {
+    match (command, value) {
+        (":bits", "16") | (":bits", "32") | (":bits", "64") => Ok(format!("bits {}", value)),
+        (":bits", _) => Err(format!("expected 16, 32 or 64, found '{}'", value)),
+        (_, "intel") => Ok(".intel_syntax noprefix".to_string()),
+        (_, "att") => Ok(".att_syntax".to_string()),
+        _ => Err(format!("expected intel or att, found '{}'", value)),
+    }
+}
+
+const HELP: &str = "\
+Lines are appended to the program and assembled together, so labels and constants persist.
+Code is executed on an emulated machine with a stack, and the sections loaded at the addresses
+of a flat image. Tab completes mnemonics, registers, labels and commands, and macros or
+conditionals continue until closed.
+
+:help                 show this help
+:quit                 leave the REPL
+:regs                 show the registers and flags
+:list                 show the program with section offsets
+:undo                 remove the last line and restore the machine state before it
+:reset                clear the program and the machine
+:save FILE            write the program to a file
+:load FILE            replace the program with the lines of a file
+:bits 16|32|64        switch the mode for the following lines
+:syntax intel|att     switch the assembly syntax for the following lines
+:hex :bin :c :rust    show the bytes as hex, binary, a C array or a Rust byte string
+:explain              toggle explaining the encoding of each instruction
+:native               run a snippet natively, up to an empty line
+:dis HEX              disassemble bytes like '48 89 e5 c3', a line of hex alone does the same
+
+Defaults for bits, syntax, output (hex, bin, c or rust), explain and nasm (on or off) can be
+set in $XDG_CONFIG_HOME/minitools/repl.conf, with lines like 'bits = 32'.";
+
+// Runs a command, returns false if the REPL should quit.
+fn command(session: &mut Session, rl: &mut Editor<ReplHelper>, line: &str) -> bool {
+    let line = line.trim();
+    let (name, argument) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+    match (name, argument) {
+        (":help", "") => println!("{}", HELP),
+        (":quit", "") | (":q", "") => return false,
+        (":bits", value) | (":syntax", value) if !value.is_empty() => {
+            match mode_directive(name, value) {
+                Ok(directive) => {
+                    let length = session.program.len();
+                    evaluate(session, &directive);
+                    if session.program.len() > length {
+                        println!("Using {} {}.", &name[1..], value);
+                    }
+                }
+                Err(message) => println!("error: {}", message),
+            }
+        }
+        (":hex", "") | (":bin", "") | (":c", "") | (":rust", "") => {
+            session.output = OutputStyle::from_name(&name[1..]).unwrap();
+        }
+        (":explain", "") => {
+            session.explain = !session.explain;
+            println!(
+                "Explain mode {}.",
+                if session.explain { "on" } else { "off" }
+            );
+        }
+        (":regs", "") => print_registers(&session.cpu),
+        (":dis", text) if !text.is_empty() => match parse_hex(text) {
+            Ok(bytes) => disassemble(&bytes, session.mode().0),
+            Err(message) => println!("error: {}", message),
+        },
+        (":list", "") => list(session),
+        (":undo", "") => match (session.program.pop(), session.snapshots.pop()) {
+            (Some(line), Some((cpu, memory))) => {
+                session.cpu = cpu;
+                session.memory = memory;
+                println!("Removed '{}'.", line);
+            }
+            _ => println!("Nothing to undo."),
+        },
+        (":reset", "") => {
+            session.reset();
+            println!("Program and machine reset.");
+        }
+        (":save", path) if !path.is_empty() => match save(session, path) {
+            Ok(()) => println!("Saved {} lines to {}.", session.program.len(), path),
+            Err(err) => println!("error: {}: {}", path, err),
+        },
+        (":load", path) if !path.is_empty() => {
+            if let Err(err) = load(session, path) {
+                println!("error: {}: {}", path, err);
+            }
+        }
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        (":native", "") => native(session, rl),
+        (":save", _) | (":load", _) => println!("error: {} needs a file name", name),
+        (":dis", _) => println!("error: :dis needs the bytes to disassemble"),
+        (":bits", _) | (":syntax", _) => println!("error: {} needs a value", name),
+        _ => println!("error: unknown command '{}', see :help", line),
+    }
+    true
+}
 
-                let output = Command::new("nasm")
-                    .arg(file.path())
-                    .arg("-o")
-                    .arg(file2.path())
-                    .output()?;
-                print!("{}", std::str::from_utf8(&output.stdout).unwrap());
-                print!("{}", std::str::from_utf8(&output.stderr).unwrap());
+// Appends the input, one or more lines, to the program and assembles all of it. The bytes of
+// the new lines are printed and, if they are code, executed with rip at their address. Input
+// referring to a label which isn't defined yet is kept, but not executed.
+fn evaluate(session: &mut Session, input: &str) {
+    let input = input.trim_end();
+    let first: usize = session.program.iter().map(|e| e.split('\n').count()).sum();
+    let numbers = first + 1..first + 1 + input.split('\n').count();
+    session.program.push(input.to_string());
+    session
+        .snapshots
+        .push((session.cpu.clone(), session.memory.clone()));
+    let program = session.program.join("\n");
 
-                if output.stderr.is_empty() {
-                    let bytes = std::fs::read(file2.path())?;
-                    for byte in &bytes {
-                        print!("{:02x} ", byte);
+    let ours = match session.assemble() {
+        Ok(result) => {
+            let lines: Vec<_> = result
+                .lines()
+                .iter()
+                .filter(|l| numbers.contains(&l.line()) && l.length() > 0)
+                .cloned()
+                .collect();
+            match flat::layout(result) {
+                Ok(sections) => {
+                    session.load(&sections);
+                    let (bits, _) = session.mode();
+                    for source_line in lines {
+                        let section = sections
+                            .iter()
+                            .find(|s| Some(s.name.as_str()) == source_line.section())
+                            .unwrap();
+                        let start = source_line.offset() as usize;
+                        let bytes = &section.content[start..start + source_line.length() as usize];
+                        let address = section.address + source_line.offset();
+                        println!("{}", session.output.format(bytes));
+                        session.memory.write(address, bytes).unwrap();
+                        let source = skip_label(source_line.source());
+                        if section.name != ".text" || is_data_directive(source) {
+                            continue;
+                        }
+                        if session.explain {
+                            explain_bytes(bytes, address, bits);
+                        }
+                        // The emulator only implements 64-bit mode.
+                        if bits == 64 {
+                            session.cpu.rip = address;
+                            execute(session, bytes);
+                        }
                     }
-                    //print!("| ");
-                    //for byte in &bytes {
-                    //    print!("{:08b} ", byte);
-                    //}
-                    println!();
+                    Some(sections)
                 }
+                Err(err) => {
+                    println!("error: {}", err);
+                    session.program.pop();
+                    session.snapshots.pop();
+                    None
+                }
+            }
+        }
+        Err(err) => {
+            if let ErrorKind::UndefinedLabel(_) = err.kind {
+                println!("note: line {}: {}, not executed", err.line, err.kind);
+            } else {
+                println!("error: {}", err.kind);
+                session.program.pop();
+                session.snapshots.pop();
+            }
+            None
+        }
+    };
+
+    // nasm doesn't know the GNU as syntax directives.
+    if !session.compare_with_nasm
+        || session.syntax == Syntax::Att
+        || program.contains(".att_syntax")
+        || program.contains(".intel_syntax")
+    {
+        return;
+    }
+    // nasm only sees complete programs, so compare the whole flat binary.
+    let ours = ours.map(|sections| {
+        let mut image = vec![];
+        for section in sections {
+            image.resize(section.address as usize, 0);
+            image.extend_from_slice(&section.content);
+        }
+        image
+    });
+    match nasm_encode(&program, session.bits) {
+        Ok(Ok(bytes)) => {
+            if ours.is_some() && ours.as_ref() != Some(&bytes) {
+                println!("nasm: {}", hex(&bytes));
+                println!("warning: encodings differ");
+            }
+        }
+        Ok(Err(message)) => {
+            println!("nasm: {}", message);
+            if ours.is_some() {
+                println!("warning: nasm rejects this program");
+            }
+        }
+        Err(err) => println!("warning: could not run nasm: {}", err),
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let mut session = Session::new();
+    load_config(&mut session);
+    if env::args().skip(1).any(|arg| arg == "--nasm") {
+        session.compare_with_nasm = true;
+    }
+
+    let history = xdg_directory("XDG_STATE_HOME", ".local/state").map(|d| d.join("history"));
+    let mut rl = Editor::<ReplHelper>::new();
+    rl.set_helper(Some(ReplHelper::new()));
+    if let Some(history) = &history {
+        if rl.load_history(history).is_err() {
+            println!("No previous history.");
+        }
+    }
+    println!("Type :help for a list of commands.");
+    loop {
+        let readline = rl.readline(">> ");
+        match readline {
+            Ok(line) => {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                rl.add_history_entry(line.as_str());
+                if line.trim_start().starts_with(':') {
+                    if !command(&mut session, &mut rl, &line) {
+                        break;
+                    }
+                } else if let Some(bytes) = raw_hex(&line, &defined_names(&session.program).1) {
+                    disassemble(&bytes, session.mode().0);
+                } else {
+                    evaluate(&mut session, &line);
+                }
+                let (labels, macros) = defined_names(&session.program);
+                let helper = rl.helper_mut().unwrap();
+                helper.labels = labels;
+                helper.macros = macros;
             }
             Err(ReadlineError::Interrupted) => {
                 break;
@@ -57,7 +1246,88 @@ fn main() -> std::io::Result<()> {
             }
         }
     }
-    rl.save_history("history.txt").unwrap();
+    if let Some(history) = &history {
+        std::fs::create_dir_all(history.parent().unwrap())?;
+        if let Err(err) = rl.save_history(history) {
+            println!(
+                "warning: cannot save history to {}: {:?}",
+                history.display(),
+                err
+            );
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displacement_only_depends_on_mode() {
+        // mov eax, [disp32]: 8b 05
+        assert_eq!(
+            describe_modrm(0x05, false, 0, 64),
+            "mod=0 (RIP-relative disp32) reg=0 rm=5"
+        );
+        assert_eq!(
+            describe_modrm(0x05, false, 0, 32),
+            "mod=0 (disp32 only) reg=0 rm=5"
+        );
+        assert_eq!(
+            describe_modrm(0x06, false, 0, 16),
+            "mod=0 (disp16 only) reg=0 rm=6"
+        );
+    }
+
+    #[test]
+    fn sections_in_memory() {
+        let mut session = Session::new();
+        evaluate(&mut session, "x: db 1");
+        evaluate(&mut session, "mov al, [rel x]");
+        assert_eq!(session.cpu.registers[0], 1);
+        // Values stored by the program are kept when more lines are loaded.
+        evaluate(&mut session, "mov byte [rel x], 5");
+        evaluate(&mut session, "mov cl, [rel x]");
+        assert_eq!(session.cpu.registers[1], 5);
+
+        evaluate(&mut session, "section .data\ny: dq 0x1234\nsection .text");
+        evaluate(&mut session, "mov rdx, [rel y]");
+        assert_eq!(session.cpu.registers[2], 0x1234);
+    }
+
+    #[test]
+    fn mode_directives() {
+        let mut session = Session::new();
+        evaluate(&mut session, "mov eax, 1");
+        evaluate(&mut session, &mode_directive(":bits", "32").unwrap());
+        evaluate(&mut session, &mode_directive(":syntax", "att").unwrap());
+        assert_eq!(session.mode(), (32, Syntax::Att));
+        evaluate(&mut session, "movl $2, %ebx");
+        assert_eq!(
+            session.program,
+            vec!["mov eax, 1", "bits 32", ".att_syntax", "movl $2, %ebx"]
+        );
+        // Earlier lines keep their mode.
+        let result = session.assemble().unwrap();
+        assert_eq!(
+            result.section_content(".text").unwrap(),
+            [0xb8, 1, 0, 0, 0, 0xbb, 2, 0, 0, 0]
+        );
+        session.program.truncate(1);
+        assert_eq!(session.mode(), (64, Syntax::Intel));
+        assert!(mode_directive(":bits", "8").is_err());
+        assert!(mode_directive(":syntax", "gas").is_err());
+    }
+
+    #[test]
+    fn sib_without_base() {
+        // mov eax, [rcx*4 + disp32]: 8b 04 8d
+        assert_eq!(
+            describe_sib(0x8d, 0, 0),
+            "scale=4 index=1 base=none (disp32)"
+        );
+        assert_eq!(describe_sib(0x8d, 1, 0), "scale=4 index=1 base=5");
+    }
+}
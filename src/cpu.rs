@@ -0,0 +1,761 @@
+use crate::disassembler;
+use crate::x86::*;
+use std::fmt;
+
+pub const CF: u64 = 1;
+pub const PF: u64 = 1 << 2;
+pub const AF: u64 = 1 << 4;
+pub const ZF: u64 = 1 << 6;
+pub const SF: u64 = 1 << 7;
+pub const DF: u64 = 1 << 10;
+pub const OF: u64 = 1 << 11;
+
+// The status flags, in the order they should be shown.
+pub const FLAGS: [(&str, u64); 7] = [
+    ("CF", CF),
+    ("PF", PF),
+    ("AF", AF),
+    ("ZF", ZF),
+    ("SF", SF),
+    ("DF", DF),
+    ("OF", OF),
+];
+
+pub const RSP: usize = 4;
+pub const RBP: usize = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    // Access to an address nothing is mapped at.
+    UnmappedMemory(u64),
+    // The bytes at this address don't decode to an instruction.
+    InvalidInstruction(u64),
+    // A decoded instruction the interpreter can't execute.
+    Unsupported(String),
+    DivideError,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UnmappedMemory(address) => {
+                write!(f, "access to unmapped memory at 0x{:x}", address)
+            }
+            Fault::InvalidInstruction(address) => {
+                write!(f, "invalid instruction at 0x{:x}", address)
+            }
+            Fault::Unsupported(instruction) => {
+                write!(f, "unsupported instruction '{}'", instruction)
+            }
+            Fault::DivideError => write!(f, "divide error"),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
+// Something that needs the attention of whoever runs the CPU.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    Continue,
+    Syscall,
+    Breakpoint,
+    Halt,
+}
+
+// Anything the CPU can read from and write to.
+pub trait AddressSpace {
+    fn read(&self, address: u64, buffer: &mut [u8]) -> Result<(), Fault>;
+    fn write(&mut self, address: u64, bytes: &[u8]) -> Result<(), Fault>;
+}
+
+// A single contiguous block of memory.
+#[derive(Clone)]
+pub struct FlatMemory {
+    pub base: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl FlatMemory {
+    pub fn new(base: u64, size: usize) -> FlatMemory {
+        FlatMemory {
+            base,
+            bytes: vec![0; size],
+        }
+    }
+
+    fn range(&self, address: u64, length: usize) -> Result<std::ops::Range<usize>, Fault> {
+        let start = address
+            .checked_sub(self.base)
+            .ok_or(Fault::UnmappedMemory(address))? as usize;
+        if start + length > self.bytes.len() {
+            return Err(Fault::UnmappedMemory(address));
+        }
+        Ok(start..start + length)
+    }
+}
+
+impl AddressSpace for FlatMemory {
+    fn read(&self, address: u64, buffer: &mut [u8]) -> Result<(), Fault> {
+        let range = self.range(address, buffer.len())?;
+        buffer.copy_from_slice(&self.bytes[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, address: u64, bytes: &[u8]) -> Result<(), Fault> {
+        let range = self.range(address, bytes.len())?;
+        self.bytes[range].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cpu {
+    pub registers: [u64; 16],
+    pub rip: u64,
+    pub rflags: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+}
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
+fn sign_bit(size: Size) -> u64 {
+    1 << (size.bits() - 1)
+}
+
+fn signed(value: u64, size: Size) -> i64 {
+    disassembler::sign_extend(value, size)
+}
+
+impl Cpu {
+    pub fn new() -> Cpu {
+        Cpu {
+            registers: [0; 16],
+            rip: 0,
+            // Bit 1 is reserved and always set, bit 9 enables interrupts.
+            rflags: 0x202,
+            fs_base: 0,
+            gs_base: 0,
+        }
+    }
+
+    pub fn flag(&self, flag: u64) -> bool {
+        self.rflags & flag != 0
+    }
+
+    pub fn set_flag(&mut self, flag: u64, value: bool) {
+        if value {
+            self.rflags |= flag;
+        } else {
+            self.rflags &= !flag;
+        }
+    }
+
+    pub fn read_register(&self, register: Register) -> u64 {
+        let value = self.registers[register.number as usize];
+        if register.high_byte {
+            (value >> 8) & 0xff
+        } else {
+            value & register.size.mask()
+        }
+    }
+
+    pub fn write_register(&mut self, register: Register, value: u64) {
+        let old = &mut self.registers[register.number as usize];
+        *old = match register.size {
+            Size::Byte if register.high_byte => (*old & !0xff00) | ((value & 0xff) << 8),
+            Size::Byte => (*old & !0xff) | (value & 0xff),
+            Size::Word => (*old & !0xffff) | (value & 0xffff),
+            // Writes to 32-bit registers clear the upper half.
+            Size::Dword => value & 0xffff_ffff,
+            Size::Qword => value,
+        };
+    }
+
+    pub fn condition(&self, condition: usize) -> bool {
+        let result = match condition / 2 {
+            0 => self.flag(OF),
+            1 => self.flag(CF),
+            2 => self.flag(ZF),
+            3 => self.flag(CF) || self.flag(ZF),
+            4 => self.flag(SF),
+            5 => self.flag(PF),
+            6 => self.flag(SF) != self.flag(OF),
+            _ => self.flag(ZF) || self.flag(SF) != self.flag(OF),
+        };
+        // Odd conditions are the negations of the even ones.
+        result != (condition % 2 == 1)
+    }
+
+    fn effective_address(&self, memory: &Memory) -> u64 {
+        let mut address = memory.displacement as u64;
+        if memory.rip_relative {
+            address = address.wrapping_add(self.rip);
+        }
+        let mut address_size = Size::Qword;
+        if let Some(base) = memory.base {
+            address = address.wrapping_add(self.read_register(base));
+            address_size = base.size;
+        }
+        if let Some(index) = memory.index {
+            address =
+                address.wrapping_add(self.read_register(index).wrapping_mul(memory.scale as u64));
+            address_size = index.size;
+        }
+        address &= address_size.mask();
+        match memory.segment {
+            Some("fs") => address.wrapping_add(self.fs_base),
+            Some("gs") => address.wrapping_add(self.gs_base),
+            _ => address,
+        }
+    }
+
+    pub fn read_memory(
+        &self,
+        space: &dyn AddressSpace,
+        address: u64,
+        size: Size,
+    ) -> Result<u64, Fault> {
+        let mut buffer = [0; 8];
+        space.read(address, &mut buffer[..size.bytes()])?;
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    pub fn write_memory(
+        &self,
+        space: &mut dyn AddressSpace,
+        address: u64,
+        size: Size,
+        value: u64,
+    ) -> Result<(), Fault> {
+        space.write(address, &value.to_le_bytes()[..size.bytes()])
+    }
+
+    fn read(&self, space: &dyn AddressSpace, operand: &Operand, size: Size) -> Result<u64, Fault> {
+        match operand {
+            Operand::Register(register) => Ok(self.read_register(*register)),
+            Operand::Immediate(value) => Ok(*value as u64 & size.mask()),
+            Operand::Memory(memory) => {
+                self.read_memory(space, self.effective_address(memory), memory.size)
+            }
+            Operand::Target(address) => Ok(*address),
+        }
+    }
+
+    fn write(
+        &mut self,
+        space: &mut dyn AddressSpace,
+        operand: &Operand,
+        value: u64,
+    ) -> Result<(), Fault> {
+        match operand {
+            Operand::Register(register) => {
+                self.write_register(*register, value);
+                Ok(())
+            }
+            Operand::Memory(memory) => {
+                let address = self.effective_address(memory);
+                self.write_memory(space, address, memory.size, value)
+            }
+            _ => Err(Fault::Unsupported("write to immediate".to_string())),
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        space: &mut dyn AddressSpace,
+        size: Size,
+        value: u64,
+    ) -> Result<(), Fault> {
+        let rsp = self.registers[RSP].wrapping_sub(size.bytes() as u64);
+        self.write_memory(space, rsp, size, value)?;
+        self.registers[RSP] = rsp;
+        Ok(())
+    }
+
+    pub fn pop(&mut self, space: &dyn AddressSpace, size: Size) -> Result<u64, Fault> {
+        let value = self.read_memory(space, self.registers[RSP], size)?;
+        self.registers[RSP] = self.registers[RSP].wrapping_add(size.bytes() as u64);
+        Ok(value)
+    }
+
+    // Sets ZF, SF and PF according to a result, and returns it.
+    fn set_result_flags(&mut self, result: u64, size: Size) -> u64 {
+        let result = result & size.mask();
+        self.set_flag(ZF, result == 0);
+        self.set_flag(SF, result & sign_bit(size) != 0);
+        self.set_flag(PF, (result as u8).count_ones().is_multiple_of(2));
+        result
+    }
+
+    fn add(&mut self, a: u64, b: u64, carry: u64, size: Size) -> u64 {
+        let mask = size.mask();
+        let result = a.wrapping_add(b).wrapping_add(carry) & mask;
+        self.set_flag(CF, a as u128 + b as u128 + carry as u128 > mask as u128);
+        self.set_flag(OF, (a ^ result) & (b ^ result) & sign_bit(size) != 0);
+        self.set_flag(AF, (a ^ b ^ result) & 0x10 != 0);
+        self.set_result_flags(result, size)
+    }
+
+    fn sub(&mut self, a: u64, b: u64, borrow: u64, size: Size) -> u64 {
+        let result = a.wrapping_sub(b).wrapping_sub(borrow) & size.mask();
+        self.set_flag(CF, b as u128 + borrow as u128 > a as u128);
+        self.set_flag(OF, (a ^ b) & (a ^ result) & sign_bit(size) != 0);
+        self.set_flag(AF, (a ^ b ^ result) & 0x10 != 0);
+        self.set_result_flags(result, size)
+    }
+
+    fn logic(&mut self, result: u64, size: Size) -> u64 {
+        self.set_flag(CF, false);
+        self.set_flag(OF, false);
+        self.set_flag(AF, false);
+        self.set_result_flags(result, size)
+    }
+
+    fn shift(&mut self, mnemonic: &str, value: u64, count: u64, size: Size) -> Result<u64, Fault> {
+        let bits = size.bits() as u64;
+        let count = count & if size == Size::Qword { 0x3f } else { 0x1f };
+        if count == 0 {
+            return Ok(value);
+        }
+        let msb = |v: u64| v & sign_bit(size) != 0;
+        let result = match mnemonic {
+            "shl" | "sal" => {
+                let result = if count < 64 { value << count } else { 0 } & size.mask();
+                self.set_flag(CF, count <= bits && (value >> (bits - count)) & 1 != 0);
+                self.set_flag(OF, msb(result) != self.flag(CF));
+                self.set_result_flags(result, size)
+            }
+            "shr" => {
+                let result = if count < 64 { value >> count } else { 0 };
+                self.set_flag(CF, (value >> (count - 1)) & 1 != 0);
+                self.set_flag(OF, msb(value));
+                self.set_result_flags(result, size)
+            }
+            "sar" => {
+                let shifted = signed(value, size) >> count.min(63);
+                self.set_flag(CF, (signed(value, size) >> (count - 1).min(63)) & 1 != 0);
+                self.set_flag(OF, false);
+                self.set_result_flags(shifted as u64, size)
+            }
+            "rol" => {
+                let count = count % bits;
+                let result = ((value << count) | (value >> ((bits - count) % bits))) & size.mask();
+                self.set_flag(CF, result & 1 != 0);
+                self.set_flag(OF, msb(result) != self.flag(CF));
+                result
+            }
+            "ror" => {
+                let count = count % bits;
+                let result = ((value >> count) | (value << ((bits - count) % bits))) & size.mask();
+                self.set_flag(CF, msb(result));
+                self.set_flag(OF, msb(result) != (result & (sign_bit(size) >> 1) != 0));
+                result
+            }
+            _ => return Err(Fault::Unsupported(mnemonic.to_string())),
+        };
+        Ok(result)
+    }
+
+    // The accumulator registers used by mul, imul, div and idiv: al and ah for bytes, otherwise
+    // rax and rdx in the operand size.
+    fn wide_operands(size: Size) -> (Register, Register) {
+        if size == Size::Byte {
+            (
+                Register::new(0, Size::Byte),
+                Register {
+                    number: 0,
+                    size: Size::Byte,
+                    high_byte: true,
+                },
+            )
+        } else {
+            (Register::new(0, size), Register::new(2, size))
+        }
+    }
+
+    fn multiply(&mut self, signed_multiply: bool, value: u64, size: Size) {
+        let (low, high) = Cpu::wide_operands(size);
+        let a = self.read_register(Register::new(0, size));
+        let bits = size.bits();
+        let (result, overflow) = if signed_multiply {
+            let result = signed(a, size) as i128 * signed(value, size) as i128;
+            (
+                result as u128,
+                result != signed(result as u64, size) as i128,
+            )
+        } else {
+            let result = a as u128 * value as u128;
+            (result, result >> bits != 0)
+        };
+        self.write_register(low, result as u64 & size.mask());
+        self.write_register(high, (result >> bits) as u64 & size.mask());
+        self.set_flag(CF, overflow);
+        self.set_flag(OF, overflow);
+    }
+
+    fn divide(&mut self, signed_divide: bool, divisor: u64, size: Size) -> Result<(), Fault> {
+        if divisor == 0 {
+            return Err(Fault::DivideError);
+        }
+        let (low, high) = Cpu::wide_operands(size);
+        let bits = size.bits();
+        let dividend = if size == Size::Byte {
+            self.read_register(Register::new(0, Size::Word)) as u128
+        } else {
+            ((self.read_register(high) as u128) << bits) | self.read_register(low) as u128
+        };
+        let (quotient, remainder) = if signed_divide {
+            // Sign-extend the double-width dividend.
+            let shift = 128 - 2 * bits;
+            let dividend = ((dividend << shift) as i128) >> shift;
+            let divisor = signed(divisor, size) as i128;
+            let quotient = dividend / divisor;
+            if quotient != signed(quotient as u64, size) as i128 {
+                return Err(Fault::DivideError);
+            }
+            (quotient as u64, (dividend % divisor) as u64)
+        } else {
+            let quotient = dividend / divisor as u128;
+            if quotient >> bits != 0 {
+                return Err(Fault::DivideError);
+            }
+            (quotient as u64, (dividend % divisor as u128) as u64)
+        };
+        self.write_register(low, quotient & size.mask());
+        self.write_register(high, remainder & size.mask());
+        Ok(())
+    }
+
+    // Fetches, decodes and executes the instruction at rip.
+    pub fn step(&mut self, space: &mut dyn AddressSpace) -> Result<Event, Fault> {
+        let mut bytes = vec![];
+        for i in 0..15 {
+            let mut byte = [0];
+            if space.read(self.rip + i, &mut byte).is_err() {
+                break;
+            }
+            bytes.push(byte[0]);
+        }
+        if bytes.is_empty() {
+            return Err(Fault::UnmappedMemory(self.rip));
+        }
+        let instruction = disassembler::decode(&bytes, self.rip)
+            .map_err(|_| Fault::InvalidInstruction(self.rip))?;
+        self.execute(&instruction, space)
+    }
+
+    // Executes an already decoded instruction, which is assumed to be located at rip. If the
+    // instruction faults, rip is left pointing at it.
+    pub fn execute(
+        &mut self,
+        instruction: &Instruction,
+        space: &mut dyn AddressSpace,
+    ) -> Result<Event, Fault> {
+        let rip = self.rip;
+        // During execution rip is the next instruction, as RIP-relative operands expect.
+        self.rip = instruction.address.wrapping_add(instruction.length as u64);
+        let result = self.operate(instruction, space);
+        if result.is_err() {
+            self.rip = rip;
+        }
+        result
+    }
+
+    fn operate(
+        &mut self,
+        instruction: &Instruction,
+        space: &mut dyn AddressSpace,
+    ) -> Result<Event, Fault> {
+        let unsupported = || Fault::Unsupported(instruction.to_string());
+        if instruction.prefix.is_some() {
+            return Err(unsupported());
+        }
+
+        let operands = &instruction.operands;
+        let size = match operands.first() {
+            Some(Operand::Register(register)) => register.size,
+            Some(Operand::Memory(memory)) => memory.size,
+            _ => Size::Qword,
+        };
+        let mnemonic = instruction.mnemonic;
+        let condition = |prefix: &str| {
+            let suffix = mnemonic.strip_prefix(prefix)?;
+            CONDITIONS.iter().position(|&c| c == suffix)
+        };
+
+        match mnemonic {
+            "nop" => {}
+            "syscall" => return Ok(Event::Syscall),
+            "int3" => return Ok(Event::Breakpoint),
+            "hlt" => return Ok(Event::Halt),
+            "mov" => {
+                let value = self.read(space, &operands[1], size)?;
+                self.write(space, &operands[0], value)?;
+            }
+            "movzx" | "movsx" | "movsxd" => {
+                let source_size = match &operands[1] {
+                    Operand::Register(register) => register.size,
+                    Operand::Memory(memory) => memory.size,
+                    _ => return Err(unsupported()),
+                };
+                let mut value = self.read(space, &operands[1], source_size)?;
+                if mnemonic != "movzx" {
+                    value = signed(value, source_size) as u64 & size.mask();
+                }
+                self.write(space, &operands[0], value)?;
+            }
+            "lea" => match &operands[1] {
+                Operand::Memory(memory) => {
+                    let address = self.effective_address(memory);
+                    self.write(space, &operands[0], address & size.mask())?;
+                }
+                _ => return Err(unsupported()),
+            },
+            "add" | "adc" | "sub" | "sbb" | "cmp" | "and" | "or" | "xor" | "test" => {
+                let a = self.read(space, &operands[0], size)?;
+                let b = self.read(space, &operands[1], size)?;
+                let carry = self.flag(CF) as u64;
+                let result = match mnemonic {
+                    "add" => self.add(a, b, 0, size),
+                    "adc" => self.add(a, b, carry, size),
+                    "sub" | "cmp" => self.sub(a, b, 0, size),
+                    "sbb" => self.sub(a, b, carry, size),
+                    "or" => self.logic(a | b, size),
+                    "xor" => self.logic(a ^ b, size),
+                    _ => self.logic(a & b, size),
+                };
+                if mnemonic != "cmp" && mnemonic != "test" {
+                    self.write(space, &operands[0], result)?;
+                }
+            }
+            "inc" | "dec" => {
+                let a = self.read(space, &operands[0], size)?;
+                // inc and dec leave the carry flag alone.
+                let carry = self.flag(CF);
+                let result = if mnemonic == "inc" {
+                    self.add(a, 1, 0, size)
+                } else {
+                    self.sub(a, 1, 0, size)
+                };
+                self.set_flag(CF, carry);
+                self.write(space, &operands[0], result)?;
+            }
+            "neg" => {
+                let a = self.read(space, &operands[0], size)?;
+                let result = self.sub(0, a, 0, size);
+                self.write(space, &operands[0], result)?;
+            }
+            "not" => {
+                let a = self.read(space, &operands[0], size)?;
+                self.write(space, &operands[0], !a & size.mask())?;
+            }
+            "shl" | "sal" | "shr" | "sar" | "rol" | "ror" => {
+                let a = self.read(space, &operands[0], size)?;
+                let count = self.read(space, &operands[1], Size::Byte)?;
+                let result = self.shift(mnemonic, a, count, size)?;
+                self.write(space, &operands[0], result)?;
+            }
+            "mul" => {
+                let value = self.read(space, &operands[0], size)?;
+                self.multiply(false, value, size);
+            }
+            "imul" if operands.len() == 1 => {
+                let value = self.read(space, &operands[0], size)?;
+                self.multiply(true, value, size);
+            }
+            "imul" => {
+                let (a, b) = if operands.len() == 3 {
+                    (&operands[1], &operands[2])
+                } else {
+                    (&operands[0], &operands[1])
+                };
+                let a = signed(self.read(space, a, size)?, size) as i128;
+                let b = signed(self.read(space, b, size)?, size) as i128;
+                let result = a * b;
+                let overflow = result != signed(result as u64, size) as i128;
+                self.set_flag(CF, overflow);
+                self.set_flag(OF, overflow);
+                self.write(space, &operands[0], result as u64 & size.mask())?;
+            }
+            "div" | "idiv" => {
+                let value = self.read(space, &operands[0], size)?;
+                self.divide(mnemonic == "idiv", value, size)?;
+            }
+            "xchg" => {
+                let a = self.read(space, &operands[0], size)?;
+                let b = self.read(space, &operands[1], size)?;
+                self.write(space, &operands[0], b)?;
+                self.write(space, &operands[1], a)?;
+            }
+            "cbw" | "cwde" | "cdqe" => {
+                let to = Size::from_bytes(match mnemonic {
+                    "cbw" => 2,
+                    "cwde" => 4,
+                    _ => 8,
+                })
+                .unwrap();
+                let from = Size::from_bytes(to.bytes() / 2).unwrap();
+                let value = signed(self.read_register(Register::new(0, from)), from);
+                self.write_register(Register::new(0, to), value as u64 & to.mask());
+            }
+            "cwd" | "cdq" | "cqo" => {
+                let size = match mnemonic {
+                    "cwd" => Size::Word,
+                    "cdq" => Size::Dword,
+                    _ => Size::Qword,
+                };
+                let negative = self.read_register(Register::new(0, size)) & sign_bit(size) != 0;
+                let value = if negative { size.mask() } else { 0 };
+                self.write_register(Register::new(2, size), value);
+            }
+            "push" => {
+                let size = match &operands[0] {
+                    Operand::Register(register) => register.size,
+                    Operand::Memory(memory) => memory.size,
+                    _ => Size::Qword,
+                };
+                let value = match &operands[0] {
+                    Operand::Immediate(value) => *value as u64,
+                    operand => self.read(space, operand, size)?,
+                };
+                self.push(space, size, value)?;
+            }
+            "pop" => {
+                let value = self.pop(space, size)?;
+                self.write(space, &operands[0], value)?;
+            }
+            "leave" => {
+                self.registers[RSP] = self.registers[RBP];
+                self.registers[RBP] = self.pop(space, Size::Qword)?;
+            }
+            "call" => {
+                let target = self.read(space, &operands[0], Size::Qword)?;
+                self.push(space, Size::Qword, self.rip)?;
+                self.rip = target;
+            }
+            "ret" => {
+                self.rip = self.pop(space, Size::Qword)?;
+                if let Some(Operand::Immediate(bytes)) = operands.first() {
+                    self.registers[RSP] = self.registers[RSP].wrapping_add(*bytes as u64);
+                }
+            }
+            "jmp" => self.rip = self.read(space, &operands[0], Size::Qword)?,
+            "clc" => self.set_flag(CF, false),
+            "stc" => self.set_flag(CF, true),
+            "cmc" => self.set_flag(CF, !self.flag(CF)),
+            "cld" => self.set_flag(DF, false),
+            "std" => self.set_flag(DF, true),
+            _ => {
+                if let Some(condition) = condition("j") {
+                    if self.condition(condition) {
+                        self.rip = self.read(space, &operands[0], Size::Qword)?;
+                    }
+                } else if let Some(condition) = condition("set") {
+                    let value = self.condition(condition) as u64;
+                    self.write(space, &operands[0], value)?;
+                } else if let Some(condition) = condition("cmov") {
+                    let value = if self.condition(condition) {
+                        self.read(space, &operands[1], size)?
+                    } else {
+                        self.read(space, &operands[0], size)?
+                    };
+                    self.write(space, &operands[0], value)?;
+                } else {
+                    return Err(unsupported());
+                }
+            }
+        }
+        Ok(Event::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the code until it hits a hlt instruction.
+    fn run(code: &[u8]) -> Cpu {
+        let mut memory = FlatMemory::new(0x1000, 0x1000);
+        memory.write(0x1000, code).unwrap();
+        memory.write(0x1000 + code.len() as u64, &[0xf4]).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.rip = 0x1000;
+        cpu.registers[RSP] = 0x2000;
+        while cpu.step(&mut memory).unwrap() != Event::Halt {}
+        cpu
+    }
+
+    #[test]
+    fn mov_and_arithmetic() {
+        // mov eax, 60; add eax, 10; sub eax, 80
+        let cpu = run(&[0xb8, 60, 0, 0, 0, 0x83, 0xc0, 10, 0x83, 0xe8, 80]);
+        assert_eq!(cpu.registers[0], 0xffff_fff6);
+        assert!(cpu.flag(CF));
+        assert!(cpu.flag(SF));
+        assert!(!cpu.flag(ZF));
+    }
+
+    #[test]
+    fn partial_registers() {
+        // mov rax, -1; mov al, 0; mov ah, 0x12
+        let cpu = run(&[
+            0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff, 0xb0, 0, 0xb4, 0x12,
+        ]);
+        assert_eq!(cpu.registers[0], 0xffff_ffff_ffff_1200);
+        // mov rax, -1; mov eax, 1
+        let cpu = run(&[0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff, 0xb8, 1, 0, 0, 0]);
+        assert_eq!(cpu.registers[0], 1);
+    }
+
+    #[test]
+    fn loops() {
+        // xor eax, eax; mov ecx, 5; loop: add eax, ecx; dec ecx; jne loop
+        let cpu = run(&[
+            0x31, 0xc0, 0xb9, 5, 0, 0, 0, 0x01, 0xc8, 0xff, 0xc9, 0x75, 0xfa,
+        ]);
+        assert_eq!(cpu.registers[0], 15);
+        assert!(cpu.flag(ZF));
+    }
+
+    #[test]
+    fn stack() {
+        // push 42; call f; hlt; f: pop rbx; pop rax; push rbx; ret
+        let cpu = run(&[0x6a, 42, 0xe8, 1, 0, 0, 0, 0xf4, 0x5b, 0x58, 0x53, 0xc3]);
+        assert_eq!(cpu.registers[0], 42);
+        assert_eq!(cpu.registers[RSP], 0x2000);
+    }
+
+    #[test]
+    fn multiply_and_divide() {
+        // mov eax, 7; mov ecx, 6; mul ecx; mov ecx, 4; div ecx
+        let cpu = run(&[
+            0xb8, 7, 0, 0, 0, 0xb9, 6, 0, 0, 0, 0xf7, 0xe1, 0xb9, 4, 0, 0, 0, 0xf7, 0xf1,
+        ]);
+        assert_eq!(cpu.registers[0], 10);
+        assert_eq!(cpu.registers[2], 2);
+    }
+
+    #[test]
+    fn faults() {
+        let mut memory = FlatMemory::new(0x1000, 0x10);
+        let mut cpu = Cpu::new();
+        cpu.rip = 0x1000;
+        // div ecx with ecx = 0
+        memory.write(0x1000, &[0xf7, 0xf1]).unwrap();
+        assert_eq!(cpu.step(&mut memory), Err(Fault::DivideError));
+        // A faulting instruction leaves rip at itself.
+        assert_eq!(cpu.rip, 0x1000);
+        // mov eax, dword [0]
+        memory
+            .write(0x1000, &[0x8b, 0x04, 0x25, 0, 0, 0, 0])
+            .unwrap();
+        assert_eq!(cpu.step(&mut memory), Err(Fault::UnmappedMemory(0)));
+        assert_eq!(cpu.rip, 0x1000);
+    }
+}
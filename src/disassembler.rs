@@ -0,0 +1,744 @@
+use crate::x86::*;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    // The bytes end in the middle of an instruction.
+    Truncated,
+    // The bytes don't form an instruction we know.
+    Invalid,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "truncated instruction"),
+            DecodeError::Invalid => write!(f, "invalid or unsupported instruction"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// The longest instruction the processor will accept.
+const MAX_LENGTH: usize = 15;
+
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFTS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const UNARY: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+const JCC: [&str; 16] = [
+    "jo", "jno", "jb", "jae", "je", "jne", "jbe", "ja", "js", "jns", "jp", "jnp", "jl", "jge",
+    "jle", "jg",
+];
+const SETCC: [&str; 16] = [
+    "seto", "setno", "setb", "setae", "sete", "setne", "setbe", "seta", "sets", "setns", "setp",
+    "setnp", "setl", "setge", "setle", "setg",
+];
+const CMOVCC: [&str; 16] = [
+    "cmovo", "cmovno", "cmovb", "cmovae", "cmove", "cmovne", "cmovbe", "cmova", "cmovs", "cmovns",
+    "cmovp", "cmovnp", "cmovl", "cmovge", "cmovle", "cmovg",
+];
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    bits: u8,
+    layout: Layout,
+    operand_size_prefix: bool,
+    address_size_prefix: bool,
+    segment: Option<&'static str>,
+    rex: u8,
+    modrm: Option<(u8, u8, u8)>,
+}
+
+impl<'a> Decoder<'a> {
+    fn next(&mut self) -> Result<u8, DecodeError> {
+        if self.position >= MAX_LENGTH {
+            return Err(DecodeError::Invalid);
+        }
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or(DecodeError::Truncated)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn next_n(&mut self, n: usize) -> Result<Vec<u8>, DecodeError> {
+        (0..n).map(|_| self.next()).collect()
+    }
+
+    fn rex_w(&self) -> bool {
+        self.rex & 8 != 0
+    }
+
+    fn rex_r(&self) -> u8 {
+        (self.rex & 4) << 1
+    }
+
+    fn rex_x(&self) -> u8 {
+        (self.rex & 2) << 2
+    }
+
+    fn rex_b(&self) -> u8 {
+        (self.rex & 1) << 3
+    }
+
+    // The size of "v" operands, which depends on the mode, REX.W and the 0x66 prefix.
+    fn operand_size(&self) -> Size {
+        if self.rex_w() {
+            Size::Qword
+        } else if self.operand_size_prefix == (self.bits == 16) {
+            Size::Dword
+        } else {
+            Size::Word
+        }
+    }
+
+    // The size of pushed and popped values, which defaults to 64 bit in 64-bit mode.
+    fn stack_size(&self) -> Size {
+        if self.bits == 64 {
+            if self.operand_size_prefix {
+                Size::Word
+            } else {
+                Size::Qword
+            }
+        } else {
+            self.operand_size()
+        }
+    }
+
+    fn address_size(&self) -> Size {
+        match (self.bits, self.address_size_prefix) {
+            (64, false) => Size::Qword,
+            (64, true) | (32, false) | (16, true) => Size::Dword,
+            _ => Size::Word,
+        }
+    }
+
+    fn register(&self, number: u8, size: Size) -> Register {
+        // Without a REX prefix, byte registers 4 to 7 are ah, ch, dh and bh.
+        if size == Size::Byte && self.rex == 0 && (4..8).contains(&number) {
+            Register {
+                number: number - 4,
+                size,
+                high_byte: true,
+            }
+        } else {
+            Register::new(number, size)
+        }
+    }
+
+    fn immediate(&mut self, size: Size) -> Result<i64, DecodeError> {
+        let bytes = self.next_n(size.bytes())?;
+        let mut value: u64 = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            value |= (*byte as u64) << (8 * i);
+        }
+        self.layout.immediate.extend_from_slice(&bytes);
+        Ok(sign_extend(value, size))
+    }
+
+    // An immediate of the operand size, which is at most 32 bits wide and sign-extended to 64
+    // bits.
+    fn immediate_z(&mut self) -> Result<i64, DecodeError> {
+        match self.operand_size() {
+            Size::Word => self.immediate(Size::Word),
+            _ => self.immediate(Size::Dword),
+        }
+    }
+
+    fn relative(&mut self, size: Size) -> Result<Operand, DecodeError> {
+        let offset = self.immediate(size)?;
+        Ok(Operand::Target(offset as u64))
+    }
+
+    fn read_modrm(&mut self) -> Result<(u8, u8, u8), DecodeError> {
+        if let Some(modrm) = self.modrm {
+            return Ok(modrm);
+        }
+        let byte = self.next()?;
+        self.layout.modrm = Some(byte);
+        let modrm = (byte >> 6, (byte >> 3) & 7, byte & 7);
+        self.modrm = Some(modrm);
+        Ok(modrm)
+    }
+
+    // The opcode extension or register stored in the reg field of the ModRM byte.
+    fn reg_field(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_modrm()?.1)
+    }
+
+    fn reg_operand(&mut self, size: Size) -> Result<Operand, DecodeError> {
+        let reg = self.reg_field()? | self.rex_r();
+        Ok(Operand::Register(self.register(reg, size)))
+    }
+
+    fn rm_operand(&mut self, size: Size) -> Result<Operand, DecodeError> {
+        let (md, _, rm) = self.read_modrm()?;
+        if md == 3 {
+            return Ok(Operand::Register(self.register(rm | self.rex_b(), size)));
+        }
+        let mut memory = Memory {
+            size,
+            segment: self.segment,
+            base: None,
+            index: None,
+            scale: 1,
+            displacement: 0,
+            rip_relative: false,
+        };
+        let address_size = self.address_size();
+        if address_size == Size::Word {
+            return self.rm_operand_16(md, rm, memory);
+        }
+        let mut displacement_size = match md {
+            1 => Some(Size::Byte),
+            2 => Some(Size::Dword),
+            _ => None,
+        };
+        if rm == 4 {
+            let sib = self.next()?;
+            self.layout.sib = Some(sib);
+            let scale = sib >> 6;
+            let index = ((sib >> 3) & 7) | self.rex_x();
+            let base = sib & 7;
+            if index != 4 {
+                memory.index = Some(Register::new(index, address_size));
+                memory.scale = 1 << scale;
+            }
+            if base == 5 && md == 0 {
+                displacement_size = Some(Size::Dword);
+            } else {
+                memory.base = Some(Register::new(base | self.rex_b(), address_size));
+            }
+        } else if rm == 5 && md == 0 {
+            displacement_size = Some(Size::Dword);
+            memory.rip_relative = self.bits == 64;
+        } else {
+            memory.base = Some(Register::new(rm | self.rex_b(), address_size));
+        }
+        if let Some(size) = displacement_size {
+            memory.displacement = self.displacement(size)?;
+        }
+        Ok(Operand::Memory(memory))
+    }
+
+    fn rm_operand_16(
+        &mut self,
+        md: u8,
+        rm: u8,
+        mut memory: Memory,
+    ) -> Result<Operand, DecodeError> {
+        // bx, bp, si and di, combined as the eight possible rm values.
+        let combinations: [(Option<u8>, Option<u8>); 8] = [
+            (Some(3), Some(6)),
+            (Some(3), Some(7)),
+            (Some(5), Some(6)),
+            (Some(5), Some(7)),
+            (Some(6), None),
+            (Some(7), None),
+            (Some(5), None),
+            (Some(3), None),
+        ];
+        let (base, index) = combinations[rm as usize];
+        memory.base = base.map(|b| Register::new(b, Size::Word));
+        memory.index = index.map(|i| Register::new(i, Size::Word));
+        match md {
+            0 if rm == 6 => {
+                memory.base = None;
+                memory.displacement = self.displacement(Size::Word)?;
+            }
+            1 => memory.displacement = self.displacement(Size::Byte)?,
+            2 => memory.displacement = self.displacement(Size::Word)?,
+            _ => {}
+        }
+        Ok(Operand::Memory(memory))
+    }
+
+    fn displacement(&mut self, size: Size) -> Result<i64, DecodeError> {
+        let bytes = self.next_n(size.bytes())?;
+        let mut value: u64 = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            value |= (*byte as u64) << (8 * i);
+        }
+        self.layout.displacement.extend_from_slice(&bytes);
+        Ok(sign_extend(value, size))
+    }
+
+    fn memory_only(&mut self, size: Size) -> Result<Operand, DecodeError> {
+        match self.rm_operand(size)? {
+            Operand::Register(_) => Err(DecodeError::Invalid),
+            memory => Ok(memory),
+        }
+    }
+
+    fn decode(&mut self) -> Result<(&'static str, Vec<Operand>), DecodeError> {
+        let opcode = self.next()?;
+        self.layout.opcode.push(opcode);
+        let v = self.operand_size();
+        let b = Size::Byte;
+
+        Ok(match opcode {
+            0x0f => return self.decode_two_byte(),
+            0x00..=0x3f if opcode & 7 < 6 => {
+                let mnemonic = ALU[(opcode >> 3) as usize];
+                match opcode & 7 {
+                    0 => (mnemonic, vec![self.rm_operand(b)?, self.reg_operand(b)?]),
+                    1 => (mnemonic, vec![self.rm_operand(v)?, self.reg_operand(v)?]),
+                    2 => (mnemonic, vec![self.reg_operand(b)?, self.rm_operand(b)?]),
+                    3 => (mnemonic, vec![self.reg_operand(v)?, self.rm_operand(v)?]),
+                    4 => (
+                        mnemonic,
+                        vec![reg(0, b), Operand::Immediate(self.immediate(b)?)],
+                    ),
+                    _ => (
+                        mnemonic,
+                        vec![reg(0, v), Operand::Immediate(self.immediate_z()?)],
+                    ),
+                }
+            }
+            0x40..=0x47 if self.bits != 64 => ("inc", vec![reg(opcode & 7, v)]),
+            0x48..=0x4f if self.bits != 64 => ("dec", vec![reg(opcode & 7, v)]),
+            0x50..=0x57 => (
+                "push",
+                vec![reg((opcode & 7) | self.rex_b(), self.stack_size())],
+            ),
+            0x58..=0x5f => (
+                "pop",
+                vec![reg((opcode & 7) | self.rex_b(), self.stack_size())],
+            ),
+            0x63 if self.bits == 64 => (
+                "movsxd",
+                vec![self.reg_operand(v)?, self.rm_operand(Size::Dword)?],
+            ),
+            0x68 => ("push", vec![Operand::Immediate(self.immediate_z()?)]),
+            0x69 => {
+                let operands = vec![self.reg_operand(v)?, self.rm_operand(v)?];
+                let immediate = self.immediate_z()?;
+                (
+                    "imul",
+                    [operands, vec![Operand::Immediate(immediate)]].concat(),
+                )
+            }
+            0x6a => ("push", vec![Operand::Immediate(self.immediate(b)?)]),
+            0x6b => {
+                let operands = vec![self.reg_operand(v)?, self.rm_operand(v)?];
+                let immediate = self.immediate(b)?;
+                (
+                    "imul",
+                    [operands, vec![Operand::Immediate(immediate)]].concat(),
+                )
+            }
+            0x70..=0x7f => (JCC[(opcode & 0xf) as usize], vec![self.relative(b)?]),
+            0x80..=0x83 if opcode != 0x82 => {
+                let size = if opcode == 0x80 { b } else { v };
+                let mnemonic = ALU[self.reg_field()? as usize];
+                let target = self.rm_operand(size)?;
+                let immediate = if opcode == 0x81 {
+                    self.immediate_z()?
+                } else {
+                    self.immediate(b)?
+                };
+                (mnemonic, vec![target, Operand::Immediate(immediate)])
+            }
+            0x84 => ("test", vec![self.rm_operand(b)?, self.reg_operand(b)?]),
+            0x85 => ("test", vec![self.rm_operand(v)?, self.reg_operand(v)?]),
+            0x86 => ("xchg", vec![self.rm_operand(b)?, self.reg_operand(b)?]),
+            0x87 => ("xchg", vec![self.rm_operand(v)?, self.reg_operand(v)?]),
+            0x88 => ("mov", vec![self.rm_operand(b)?, self.reg_operand(b)?]),
+            0x89 => ("mov", vec![self.rm_operand(v)?, self.reg_operand(v)?]),
+            0x8a => ("mov", vec![self.reg_operand(b)?, self.rm_operand(b)?]),
+            0x8b => ("mov", vec![self.reg_operand(v)?, self.rm_operand(v)?]),
+            0x8d => ("lea", vec![self.reg_operand(v)?, self.memory_only(v)?]),
+            0x8f if self.reg_field()? == 0 => ("pop", vec![self.rm_operand(self.stack_size())?]),
+            0x90 if self.rex_b() == 0 => ("nop", vec![]),
+            0x90..=0x97 => ("xchg", vec![reg((opcode & 7) | self.rex_b(), v), reg(0, v)]),
+            0x98 => match v {
+                Size::Word => ("cbw", vec![]),
+                Size::Dword => ("cwde", vec![]),
+                _ => ("cdqe", vec![]),
+            },
+            0x99 => match v {
+                Size::Word => ("cwd", vec![]),
+                Size::Dword => ("cdq", vec![]),
+                _ => ("cqo", vec![]),
+            },
+            0xa4 => ("movsb", vec![]),
+            0xa5 => (string_mnemonic(["movsw", "movsd", "movsq"], v), vec![]),
+            0xa8 => (
+                "test",
+                vec![reg(0, b), Operand::Immediate(self.immediate(b)?)],
+            ),
+            0xa9 => (
+                "test",
+                vec![reg(0, v), Operand::Immediate(self.immediate_z()?)],
+            ),
+            0xaa => ("stosb", vec![]),
+            0xab => (string_mnemonic(["stosw", "stosd", "stosq"], v), vec![]),
+            0xb0..=0xb7 => {
+                let register = self.register((opcode & 7) | self.rex_b(), b);
+                let immediate = self.immediate(b)?;
+                (
+                    "mov",
+                    vec![Operand::Register(register), Operand::Immediate(immediate)],
+                )
+            }
+            0xb8..=0xbf => {
+                let register = reg((opcode & 7) | self.rex_b(), v);
+                let immediate = self.immediate(v)?;
+                ("mov", vec![register, Operand::Immediate(immediate)])
+            }
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let size = if opcode & 1 == 0 { b } else { v };
+                let mnemonic = SHIFTS[self.reg_field()? as usize];
+                let target = self.rm_operand(size)?;
+                let count = match opcode {
+                    0xc0 | 0xc1 => Operand::Immediate(self.immediate(b)?),
+                    0xd0 | 0xd1 => Operand::Immediate(1),
+                    _ => reg(1, b),
+                };
+                (mnemonic, vec![target, count])
+            }
+            0xc2 => {
+                let immediate = self.immediate(Size::Word)? as u16;
+                ("ret", vec![Operand::Immediate(immediate as i64)])
+            }
+            0xc3 => ("ret", vec![]),
+            0xc6 if self.reg_field()? == 0 => {
+                let target = self.rm_operand(b)?;
+                ("mov", vec![target, Operand::Immediate(self.immediate(b)?)])
+            }
+            0xc7 if self.reg_field()? == 0 => {
+                let target = self.rm_operand(v)?;
+                ("mov", vec![target, Operand::Immediate(self.immediate_z()?)])
+            }
+            0xc9 => ("leave", vec![]),
+            0xcc => ("int3", vec![]),
+            0xcd => {
+                let immediate = self.immediate(b)? as u8;
+                ("int", vec![Operand::Immediate(immediate as i64)])
+            }
+            0xe8 => ("call", vec![self.relative(self.branch_size())?]),
+            0xe9 => ("jmp", vec![self.relative(self.branch_size())?]),
+            0xeb => ("jmp", vec![self.relative(b)?]),
+            0xf4 => ("hlt", vec![]),
+            0xf5 => ("cmc", vec![]),
+            0xf6 | 0xf7 => {
+                let size = if opcode == 0xf6 { b } else { v };
+                let extension = self.reg_field()?;
+                let target = self.rm_operand(size)?;
+                if extension < 2 {
+                    let immediate = if size == b {
+                        self.immediate(b)?
+                    } else {
+                        self.immediate_z()?
+                    };
+                    ("test", vec![target, Operand::Immediate(immediate)])
+                } else {
+                    (UNARY[extension as usize], vec![target])
+                }
+            }
+            0xf8 => ("clc", vec![]),
+            0xf9 => ("stc", vec![]),
+            0xfc => ("cld", vec![]),
+            0xfd => ("std", vec![]),
+            0xfe => match self.reg_field()? {
+                0 => ("inc", vec![self.rm_operand(b)?]),
+                1 => ("dec", vec![self.rm_operand(b)?]),
+                _ => return Err(DecodeError::Invalid),
+            },
+            0xff => {
+                // Indirect jumps and calls always use 64-bit targets in 64-bit mode.
+                let branch = if self.bits == 64 { Size::Qword } else { v };
+                match self.reg_field()? {
+                    0 => ("inc", vec![self.rm_operand(v)?]),
+                    1 => ("dec", vec![self.rm_operand(v)?]),
+                    2 => ("call", vec![self.rm_operand(branch)?]),
+                    4 => ("jmp", vec![self.rm_operand(branch)?]),
+                    6 => ("push", vec![self.rm_operand(self.stack_size())?]),
+                    _ => return Err(DecodeError::Invalid),
+                }
+            }
+            _ => return Err(DecodeError::Invalid),
+        })
+    }
+
+    fn decode_two_byte(&mut self) -> Result<(&'static str, Vec<Operand>), DecodeError> {
+        let opcode = self.next()?;
+        self.layout.opcode.push(opcode);
+        let v = self.operand_size();
+
+        Ok(match opcode {
+            0x05 if self.bits == 64 => ("syscall", vec![]),
+            0x0b => ("ud2", vec![]),
+            0x1f if self.reg_field()? == 0 => ("nop", vec![self.rm_operand(v)?]),
+            0x31 => ("rdtsc", vec![]),
+            0x40..=0x4f => (
+                CMOVCC[(opcode & 0xf) as usize],
+                vec![self.reg_operand(v)?, self.rm_operand(v)?],
+            ),
+            0x80..=0x8f => (
+                JCC[(opcode & 0xf) as usize],
+                vec![self.relative(self.branch_size())?],
+            ),
+            0x90..=0x9f => (
+                SETCC[(opcode & 0xf) as usize],
+                vec![self.rm_operand(Size::Byte)?],
+            ),
+            0xa2 => ("cpuid", vec![]),
+            0xaf => ("imul", vec![self.reg_operand(v)?, self.rm_operand(v)?]),
+            0xb6 | 0xbe => {
+                let mnemonic = if opcode == 0xb6 { "movzx" } else { "movsx" };
+                (
+                    mnemonic,
+                    vec![self.reg_operand(v)?, self.rm_operand(Size::Byte)?],
+                )
+            }
+            0xb7 | 0xbf => {
+                let mnemonic = if opcode == 0xb7 { "movzx" } else { "movsx" };
+                (
+                    mnemonic,
+                    vec![self.reg_operand(v)?, self.rm_operand(Size::Word)?],
+                )
+            }
+            _ => return Err(DecodeError::Invalid),
+        })
+    }
+
+    // The size of the offset of relative near jumps and calls.
+    fn branch_size(&self) -> Size {
+        if self.bits == 16 {
+            Size::Word
+        } else {
+            Size::Dword
+        }
+    }
+}
+
+fn reg(number: u8, size: Size) -> Operand {
+    Operand::Register(Register::new(number, size))
+}
+
+fn string_mnemonic(mnemonics: [&'static str; 3], size: Size) -> &'static str {
+    match size {
+        Size::Word => mnemonics[0],
+        Size::Dword => mnemonics[1],
+        _ => mnemonics[2],
+    }
+}
+
+pub fn sign_extend(value: u64, size: Size) -> i64 {
+    match size {
+        Size::Byte => value as u8 as i8 as i64,
+        Size::Word => value as u16 as i16 as i64,
+        Size::Dword => value as u32 as i32 as i64,
+        Size::Qword => value as i64,
+    }
+}
+
+// Decodes the 64-bit mode instruction at the start of `bytes`, which is located at `address`.
+pub fn decode(bytes: &[u8], address: u64) -> Result<Instruction, DecodeError> {
+    decode_with_bits(bytes, address, 64)
+}
+
+pub fn decode_with_bits(bytes: &[u8], address: u64, bits: u8) -> Result<Instruction, DecodeError> {
+    let mut decoder = Decoder {
+        bytes,
+        position: 0,
+        bits,
+        layout: Layout::default(),
+        operand_size_prefix: false,
+        address_size_prefix: false,
+        segment: None,
+        rex: 0,
+        modrm: None,
+    };
+
+    let mut prefix = None;
+    loop {
+        let byte = *bytes.get(decoder.position).ok_or(DecodeError::Truncated)?;
+        match byte {
+            0x66 => decoder.operand_size_prefix = true,
+            0x67 => decoder.address_size_prefix = true,
+            0xf0 => prefix = Some("lock"),
+            0xf2 => prefix = Some("repne"),
+            0xf3 => prefix = Some("rep"),
+            0x26 => decoder.segment = Some("es"),
+            0x2e => decoder.segment = Some("cs"),
+            0x36 => decoder.segment = Some("ss"),
+            0x3e => decoder.segment = Some("ds"),
+            0x64 => decoder.segment = Some("fs"),
+            0x65 => decoder.segment = Some("gs"),
+            _ => break,
+        }
+        let byte = decoder.next()?;
+        decoder.layout.prefixes.push(byte);
+    }
+    if bits == 64 {
+        let byte = *bytes.get(decoder.position).ok_or(DecodeError::Truncated)?;
+        if byte & 0xf0 == 0x40 {
+            decoder.rex = decoder.next()?;
+            decoder.layout.rex = Some(byte);
+        }
+    }
+
+    let (mnemonic, mut operands) = decoder.decode()?;
+    let length = decoder.position;
+
+    // Relative targets were stored as offsets, turn them into absolute addresses.
+    for operand in &mut operands {
+        if let Operand::Target(offset) = operand {
+            *offset = address.wrapping_add(length as u64).wrapping_add(*offset);
+        }
+    }
+
+    // The repeat prefixes only have a meaning for string instructions.
+    let string_instruction = mnemonic.starts_with("stos")
+        || (mnemonic.starts_with("movs") && !mnemonic.starts_with("movsx"));
+    if (prefix == Some("repne") || prefix == Some("rep")) && !string_instruction {
+        prefix = None;
+    }
+
+    Ok(Instruction {
+        address,
+        length,
+        prefix,
+        mnemonic,
+        operands,
+        layout: decoder.layout,
+    })
+}
+
+// Decodes as many instructions as possible. Bytes which can't be decoded are returned as errors
+// of length 1, so that decoding can resynchronize afterwards.
+pub fn decode_all(
+    bytes: &[u8],
+    address: u64,
+    bits: u8,
+) -> Vec<(u64, usize, Result<Instruction, DecodeError>)> {
+    let mut ret = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let location = address + offset as u64;
+        match decode_with_bits(&bytes[offset..], location, bits) {
+            Ok(instruction) => {
+                let length = instruction.length;
+                ret.push((location, length, Ok(instruction)));
+                offset += length;
+            }
+            Err(error) => {
+                ret.push((location, 1, Err(error)));
+                offset += 1;
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_disassembly(bytes: &[u8], expected: &str) {
+        let instruction = decode(bytes, 0).unwrap();
+        assert_eq!(instruction.to_string(), expected);
+        assert_eq!(instruction.length, bytes.len());
+    }
+
+    #[test]
+    fn simple() {
+        assert_disassembly(&[0xf, 0x5], "syscall");
+        assert_disassembly(&[0xc3], "ret");
+        assert_disassembly(&[0x90], "nop");
+    }
+
+    #[test]
+    fn mov() {
+        assert_disassembly(&[0xb8, 0x3c, 0, 0, 0], "mov eax, 0x3c");
+        assert_disassembly(&[0xbe, 0, 0, 0, 0], "mov esi, 0");
+        assert_disassembly(&[0x48, 0x89, 0xe5], "mov rbp, rsp");
+        assert_disassembly(&[0x41, 0xb0, 0x01], "mov r8b, 1");
+        assert_disassembly(&[0xb4, 0x01], "mov ah, 1");
+        assert_disassembly(
+            &[0x48, 0xb8, 1, 0, 0, 0, 0, 0, 0, 0x10],
+            "mov rax, 0x1000000000000001",
+        );
+    }
+
+    #[test]
+    fn memory() {
+        assert_disassembly(&[0x8b, 0x45, 0xf8], "mov eax, dword [rbp - 8]");
+        assert_disassembly(&[0x48, 0x8d, 0x04, 0x98], "lea rax, [rax + rbx*4]");
+        assert_disassembly(
+            &[0x48, 0x8b, 0x05, 0x10, 0, 0, 0],
+            "mov rax, qword [rip + 0x10]",
+        );
+        assert_disassembly(
+            &[0xc7, 0x04, 0x25, 0, 0x10, 0, 0, 0x2a, 0, 0, 0],
+            "mov dword [0x1000], 0x2a",
+        );
+        assert_disassembly(
+            &[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0, 0, 0],
+            "mov rax, qword [fs:0x28]",
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_disassembly(&[0x83, 0xf8, 0x05], "cmp eax, 5");
+        assert_disassembly(&[0x48, 0x83, 0xec, 0x10], "sub rsp, 0x10");
+        assert_disassembly(&[0x31, 0xc0], "xor eax, eax");
+        assert_disassembly(&[0x48, 0xf7, 0xd8], "neg rax");
+        assert_disassembly(&[0xd1, 0xe0], "shl eax, 1");
+        assert_disassembly(&[0x83, 0xe0, 0xf0], "and eax, -0x10");
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(decode(&[0xeb, 0xfe], 0x10).unwrap().to_string(), "jmp 0x10");
+        assert_eq!(
+            decode(&[0x0f, 0x84, 0x10, 0, 0, 0], 0).unwrap().to_string(),
+            "je 0x16"
+        );
+        assert_eq!(
+            decode(&[0xe8, 0, 0, 0, 0], 0x20).unwrap().to_string(),
+            "call 0x25"
+        );
+        assert_disassembly(&[0xff, 0xd0], "call rax");
+    }
+
+    #[test]
+    fn layout() {
+        let instruction = decode(&[0x66, 0x41, 0xc7, 0x44, 0x24, 0x08, 0x34, 0x12], 0).unwrap();
+        assert_eq!(instruction.to_string(), "mov word [r12 + 8], 0x1234");
+        assert_eq!(instruction.layout.prefixes, vec![0x66]);
+        assert_eq!(instruction.layout.rex, Some(0x41));
+        assert_eq!(instruction.layout.opcode, vec![0xc7]);
+        assert_eq!(instruction.layout.modrm, Some(0x44));
+        assert_eq!(instruction.layout.sib, Some(0x24));
+        assert_eq!(instruction.layout.displacement, vec![0x08]);
+        assert_eq!(instruction.layout.immediate, vec![0x34, 0x12]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(decode(&[0xb8, 0x3c], 0), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0x0f, 0xff], 0), Err(DecodeError::Invalid));
+        assert_eq!(decode(&[0x06], 0), Err(DecodeError::Invalid));
+        let all = decode_all(&[0x06, 0xc3], 0, 64);
+        assert_eq!(all.len(), 2);
+        assert!(all[0].2.is_err());
+        assert_eq!(all[1].0, 1);
+    }
+
+    #[test]
+    fn other_modes() {
+        let instruction = decode_with_bits(&[0x40], 0, 32).unwrap();
+        assert_eq!(instruction.to_string(), "inc eax");
+        let instruction = decode_with_bits(&[0xb8, 0x34, 0x12], 0, 16).unwrap();
+        assert_eq!(instruction.to_string(), "mov ax, 0x1234");
+        let instruction = decode_with_bits(&[0x8b, 0x46, 0x02], 0, 16).unwrap();
+        assert_eq!(instruction.to_string(), "mov ax, word [bp + 2]");
+    }
+}
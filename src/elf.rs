@@ -1,9 +1,38 @@
 extern crate byteorder;
 use crate::*;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 
 use std::io::prelude::*;
 
+// Section types.
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_REL: u32 = 9;
+
+// Section flags.
+pub const SHF_WRITE: u64 = 1;
+pub const SHF_ALLOC: u64 = 2;
+pub const SHF_EXECINSTR: u64 = 4;
+
+// The section index of undefined symbols, and of common symbols, which the linker allocates.
+pub const SHN_UNDEF: u16 = 0;
+pub const SHN_COMMON: u16 = 0xfff2;
+
+// Symbol bindings, types and visibilities.
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+pub const STV_DEFAULT: u8 = 0;
+pub const STV_HIDDEN: u8 = 2;
+
 struct Segment {
     typ: u32,
     flags: u32,
@@ -12,6 +41,7 @@ struct Segment {
     size: u64,
 }
 
+#[derive(Clone)]
 struct Section {
     name: String,
     typ: u32,
@@ -19,9 +49,21 @@ struct Section {
     content: Vec<u8>,
     link: u32,
     info: u32,
+    alignment: u64,
     entry_size: u64,
 }
 
+impl Section {
+    // The number of bytes the section occupies in the file.
+    fn file_size(&self) -> u64 {
+        if self.typ == SHT_NOBITS {
+            0
+        } else {
+            self.content.len() as u64
+        }
+    }
+}
+
 fn section_names_bytes(sections: &[Section]) -> Vec<u8> {
     let section_names: Vec<String> = sections.iter().map(|s| format!("{}\0", s.name)).collect();
     let mut ret = section_names.join("").into_bytes();
@@ -29,24 +71,41 @@ fn section_names_bytes(sections: &[Section]) -> Vec<u8> {
     ret
 }
 
-fn string_bytes(symbols: &[Symbol]) -> Vec<u8> {
+fn string_bytes(symbols: &[&ElfSymbol]) -> Vec<u8> {
     let section_names: Vec<String> = symbols.iter().map(|s| format!("{}\0", s.name)).collect();
     let mut ret = section_names.join("").into_bytes();
     ret.insert(0, 0);
     ret
 }
 
-fn symbol_bytes(symbols: &[Symbol]) -> Vec<u8> {
+// Writes a "word" of 4 or 8 bytes, depending on the ELF class.
+fn write_word(buffer: &mut Vec<u8>, bits: u8, value: u64) -> std::io::Result<()> {
+    if bits == 64 {
+        buffer.write_u64::<LittleEndian>(value)
+    } else {
+        buffer.write_u32::<LittleEndian>(value as u32)
+    }
+}
+
+fn symbol_bytes(symbols: &[&ElfSymbol], bits: u8) -> Vec<u8> {
     let mut ret = vec![];
     let mut offset = 1;
-    ret.write_all(&[0 as u8; 24]).unwrap();
+    ret.write_all(&vec![0_u8; symbol_entry_size(bits) as usize])
+        .unwrap();
     for symbol in symbols {
         // Offset of this symbol's name in the string table this section links to.
         ret.write_u32::<LittleEndian>(offset).unwrap();
         offset += symbol.name.len() as u32 + 1;
 
+        // In ELF32, value and size come before the other fields.
+        if bits == 32 {
+            ret.write_u32::<LittleEndian>(symbol.value as u32).unwrap();
+            ret.write_u32::<LittleEndian>(symbol.size as u32).unwrap();
+        }
+
         // Symbol type and binding attributes.
-        ret.write_all(&[symbol.typ_and_binding]).unwrap();
+        ret.write_all(&[symbol.binding << 4 | symbol.typ & 0xf])
+            .unwrap();
 
         // Symbol visibility.
         ret.write_all(&[symbol.visibility]).unwrap();
@@ -54,303 +113,1203 @@ fn symbol_bytes(symbols: &[Symbol]) -> Vec<u8> {
         // Index of the section this symbol is defined in relation to.
         ret.write_u16::<LittleEndian>(symbol.section).unwrap();
 
-        // Value of the symbol.
-        ret.write_u64::<LittleEndian>(symbol.value).unwrap();
+        if bits == 64 {
+            // Value of the symbol.
+            ret.write_u64::<LittleEndian>(symbol.value).unwrap();
 
-        // Size of the symbol.
-        ret.write_u64::<LittleEndian>(symbol.size).unwrap();
+            // Size of the symbol.
+            ret.write_u64::<LittleEndian>(symbol.size).unwrap();
+        }
     }
     ret
 }
 
-fn relocation_bytes(relocations: &[ResolvedRelocation], sections: &[Section]) -> Vec<u8> {
+fn symbol_entry_size(bits: u8) -> u64 {
+    if bits == 64 {
+        24
+    } else {
+        16
+    }
+}
+
+// The i386 relocation type corresponding to an x86-64 one.
+fn i386_relocation_type(typ: RelocationType) -> std::io::Result<u32> {
+    match typ {
+        RelocationType::U32 | RelocationType::S32 => Ok(1), // R_386_32
+        RelocationType::Pc32 => Ok(2),                      // R_386_PC32
+        RelocationType::U16 => Ok(20),                      // R_386_16
+        RelocationType::Pc16 => Ok(21),                     // R_386_PC16
+        RelocationType::Pc8 => Ok(23),                      // R_386_PC8
+        RelocationType::U64 => Err(invalid(
+            "64-bit relocations are not supported in 32-bit objects",
+        )),
+    }
+}
+
+// The size of the field relocated by an i386 relocation type, where the addend of REL
+// relocations is stored.
+fn i386_relocation_size(typ: u32) -> Option<usize> {
+    match typ {
+        1 | 2 => Some(4),
+        20 | 21 => Some(2),
+        22 | 23 => Some(1),
+        _ => None,
+    }
+}
+
+// RELA relocations for ELF64, REL relocations for ELF32. `symbols` maps the symbol indices of
+// ObjectBuilder to those in the symbol table.
+fn relocation_bytes(relocations: &[&ElfRelocation], symbols: &[usize], bits: u8) -> Vec<u8> {
     let mut ret = vec![];
     for relocation in relocations {
         // The location at which to apply the relocation action, relative to the beginning of the
         // section referenced by the relocation section.
-        ret.write_u64::<LittleEndian>(relocation.location).unwrap();
+        write_word(&mut ret, bits, relocation.offset).unwrap();
 
         // Info field, which contains both the index of the symbol we're referring to, as well as
         // the type of the relocation.
-        let symbol = sections
-            .iter()
-            .position(|s| s.name == relocation.section)
-            .unwrap()
-            + 1;
-
-        ret.write_u32::<LittleEndian>(relocation.typ as u32)
-            .unwrap();
-        ret.write_u32::<LittleEndian>(symbol as u32).unwrap();
+        let symbol = symbols[relocation.symbol] as u32;
+        if bits == 32 {
+            ret.write_u32::<LittleEndian>(symbol << 8 | relocation.typ)
+                .unwrap();
+            continue;
+        }
+        ret.write_u32::<LittleEndian>(relocation.typ).unwrap();
+        ret.write_u32::<LittleEndian>(symbol).unwrap();
 
         // Addend, relative to the referenced symbol.
-        ret.write_u64::<LittleEndian>(relocation.addend).unwrap();
+        ret.write_i64::<LittleEndian>(relocation.addend.unwrap_or(0))
+            .unwrap();
     }
     ret
 }
 
-fn alignment_from_section_name(_name: &str) -> u64 {
-    // I don't know whether these are actually required, so let's just set this to 0.
-    0
+// Assembles a relocatable ELF object from sections, symbols and relocations.
+pub struct ObjectBuilder {
+    bits: u8,
+    sections: Vec<Section>,
+    symbols: Vec<ElfSymbol>,
+    relocations: Vec<ElfRelocation>,
 }
 
-struct Symbol {
-    name: String,
-    typ_and_binding: u8,
-    visibility: u8,
-    section: u16,
-    value: u64,
-    size: u64,
-}
-
-pub fn create_binary(assembly: AssemblyResult) -> std::io::Result<Vec<u8>> {
-    let header_size = 4 + 4 + 8 + 8 * 2 + 2 * 4 + 3 * 8;
-    let pht_entry_size = 2 * 4 + 6 * 8;
-    let sht_entry_size = 4 * 4 + 6 * 8;
-
-    let mut sections = vec![];
+impl ObjectBuilder {
+    // Starts an x86-64 object for 64 bits, or an i386 object for 32 bits.
+    pub fn new(bits: u8) -> std::io::Result<ObjectBuilder> {
+        if bits != 32 && bits != 64 {
+            return Err(invalid("ELF objects have 32 or 64 bits"));
+        }
+        Ok(ObjectBuilder {
+            bits,
+            sections: vec![],
+            symbols: vec![],
+            relocations: vec![],
+        })
+    }
 
-    for s in &assembly.sections {
-        let flags = if s.name == ".rodata" { 2 } else { 6 };
-        let section = Section {
-            name: s.name.clone(),
-            typ: 1,
+    // Adds a section and returns its index in the section header table, to be used by symbols
+    // and relocations. For SHT_NOBITS sections, only the length of the content matters.
+    pub fn add_section(
+        &mut self,
+        name: &str,
+        typ: u32,
+        flags: u64,
+        alignment: u64,
+        content: Vec<u8>,
+    ) -> usize {
+        self.sections.push(Section {
+            name: name.to_string(),
+            typ,
             flags,
-            content: s.content.clone(),
+            content,
             link: 0,
             info: 0,
+            alignment,
             entry_size: 0,
-        };
-        sections.push(section);
+        });
+        self.sections.len()
     }
 
-    let mut symbols = vec![];
+    // Adds a symbol and returns its index for relocations. Its section is an index returned by
+    // add_section(), or SHN_UNDEF for external symbols. The symbol table lists local symbols
+    // first, so the final index may differ.
+    pub fn add_symbol(&mut self, symbol: ElfSymbol) -> usize {
+        self.symbols.push(symbol);
+        self.symbols.len()
+    }
 
-    let mut index = 1;
-    for section in &assembly.sections {
-        let text_section_symbol = Symbol {
-            name: section.name.clone(),
-            typ_and_binding: (0 << 4) | 3, // LOCAL, SECTION
-            visibility: 0,
-            section: index,
-            value: 0,
-            size: 0,
+    // Adds a relocation with an R_X86_64_* or R_386_* type. Its symbol is an index returned by
+    // add_symbol(). ELF32 objects use REL relocations, so the addend is stored in the relocated
+    // field.
+    pub fn add_relocation(&mut self, relocation: ElfRelocation) {
+        self.relocations.push(relocation);
+    }
+
+    pub fn build(&self) -> std::io::Result<Vec<u8>> {
+        let bits = self.bits;
+        let w = (bits / 8) as u64;
+        let header_size = 4 + 4 + 8 + 2 * 2 + 4 + 3 * w + 4 + 6 * 2;
+        let pht_entry_size = 2 * 4 + 6 * w;
+        let sht_entry_size = 4 * 4 + 6 * w;
+
+        let mut sections = self.sections.clone();
+        for relocation in &self.relocations {
+            if relocation.section == 0 || relocation.section > sections.len() {
+                return Err(invalid("relocation in unknown section"));
+            }
+            if relocation.symbol == 0 || relocation.symbol > self.symbols.len() {
+                return Err(invalid("relocation against unknown symbol"));
+            }
+            if bits == 32 {
+                let size = i386_relocation_size(relocation.typ)
+                    .ok_or_else(|| invalid("unsupported i386 relocation type"))?;
+                let content = &mut sections[relocation.section - 1].content;
+                let start = relocation.offset as usize;
+                let field = content
+                    .get_mut(start..start + size)
+                    .ok_or_else(|| invalid("relocation out of bounds"))?;
+                // Without an explicit addend, the field already holds it.
+                if let Some(addend) = relocation.addend {
+                    field.copy_from_slice(&addend.to_le_bytes()[..size]);
+                }
+            }
+        }
+
+        // Local symbols must come before all others. symbol_indices maps the indices returned
+        // by add_symbol() to the final ones.
+        let mut order: Vec<usize> = (0..self.symbols.len()).collect();
+        order.sort_by_key(|&i| self.symbols[i].binding != STB_LOCAL);
+        let mut symbol_indices = vec![0; self.symbols.len() + 1];
+        for (index, &i) in (1..).zip(order.iter()) {
+            symbol_indices[i + 1] = index;
+        }
+        let symbols: Vec<&ElfSymbol> = order.iter().map(|&i| &self.symbols[i]).collect();
+        let locals = symbols.iter().filter(|s| s.binding == STB_LOCAL).count();
+
+        let string_table = Section {
+            name: ".strtab".to_string(),
+            typ: SHT_STRTAB,
+            flags: 0,
+            content: string_bytes(&symbols),
+            link: 0,
+            info: 0,
+            alignment: 0,
+            entry_size: 0,
         };
-        index += 1;
+        sections.push(string_table);
+
+        let symbol_table = Section {
+            name: ".symtab".to_string(),
+            typ: SHT_SYMTAB,
+            flags: 0,
+            content: symbol_bytes(&symbols, bits),
+            link: sections.len() as u32,
+            // One greater than the symbol table index of the last local symbol.
+            info: (locals + 1) as u32,
+            alignment: 0,
+            entry_size: symbol_entry_size(bits),
+        };
+        sections.push(symbol_table);
+        let symbol_table_index = sections.len() as u32;
+
+        // One relocation section for each section with relocations.
+        for (index, section) in (1..).zip(self.sections.iter()) {
+            let relocations: Vec<&ElfRelocation> = self
+                .relocations
+                .iter()
+                .filter(|r| r.section == index)
+                .collect();
+            if relocations.is_empty() {
+                continue;
+            }
+            let (prefix, typ, entry_size) = if bits == 64 {
+                (".rela", SHT_RELA, 3 * w)
+            } else {
+                (".rel", SHT_REL, 2 * w)
+            };
+            let relocation_table = Section {
+                name: format!("{}{}", prefix, section.name),
+                typ,
+                flags: 0,
+                content: relocation_bytes(&relocations, &symbol_indices, bits),
+                link: symbol_table_index,
+                info: index as u32,
+                alignment: 0,
+                entry_size,
+            };
+            sections.push(relocation_table);
+        }
+
+        let section_names_section = Section {
+            name: ".shstrtab".to_string(),
+            typ: SHT_STRTAB,
+            flags: 0,
+            content: vec![],
+            link: 0,
+            info: 0,
+            alignment: 0,
+            entry_size: 0,
+        };
+        sections.push(section_names_section);
 
-        symbols.push(text_section_symbol);
-    }
+        let i = sections.len() - 1;
+        sections[i].content = section_names_bytes(&sections);
 
-    let start_symbol = Symbol {
-        name: "_start".to_string(),
-        typ_and_binding: (1 << 4) | 0, // GLOBAL, NO_TYPE
-        visibility: 0,
-        section: (sections.iter().position(|s| s.name == ".text").unwrap() + 1) as u16,
-        value: 0,
-        size: 0,
-    };
-    symbols.push(start_symbol);
-
-    let start_symbol2 = Symbol {
-        name: "foobar".to_string(),
-        typ_and_binding: (1 << 4) | 0, // GLOBAL, NO_TYPE
-        visibility: 0,
-        section: (sections.iter().position(|s| s.name == ".rodata").unwrap() + 1) as u16,
-        value: 0,
-        size: 0,
-    };
-    symbols.push(start_symbol2);
-
-    let string_table = Section {
-        name: ".strtab".to_string(),
-        typ: 3, // SHT_STRTAB
-        flags: 0,
-        content: string_bytes(&symbols),
-        link: 0,
-        info: 0,
-        entry_size: 0,
-    };
-    sections.push(string_table);
-
-    let symbol_table = Section {
-        name: ".symtab".to_string(),
-        typ: 2, // SHT_SYMTAB
-        flags: 0,
-        content: symbol_bytes(&symbols),
-        link: (sections.iter().position(|s| s.name == ".strtab").unwrap() + 1) as u32,
-        // FIXME, actually "one greater than the symbol table index of the last local symbol"
-        // http://refspecs.linuxbase.org/elf/gabi4+/ch4.sheader.html#sh_link
-        info: (assembly.sections.len() + 1) as u32,
-        entry_size: 24,
-    };
-    sections.push(symbol_table);
-
-    let relocation_table = Section {
-        name: ".rela.text".to_string(),
-        typ: 4, // SHT_RELA
-        flags: 0,
-        content: relocation_bytes(&assembly.relocations, &sections),
-        link: (sections.iter().position(|s| s.name == ".symtab").unwrap() + 1) as u32,
-        info: (sections.iter().position(|s| s.name == ".text").unwrap() + 1) as u32,
-        entry_size: 24,
-    };
-    sections.push(relocation_table);
-
-    let section_names_section = Section {
-        name: ".shstrtab".to_string(),
-        typ: 3,
-        flags: 0,
-        content: vec![],
-        link: 0,
-        info: 0,
-        entry_size: 0,
-    };
-    sections.push(section_names_section);
+        let content_size: u64 = sections.iter().map(Section::file_size).sum();
+
+        let segments: Vec<Segment> = vec![];
+
+        let mut buffer = vec![];
+
+        // Magic number: 0x7F plus "ELF".
+        buffer.write_all(b"\x7fELF")?;
+
+        // 32-bit format (1) or 64-bit format (2).
+        buffer.write_all(&[bits / 32])?;
+
+        // Little endian (1) or big endian (2).
+        buffer.write_all(&[1])?;
+
+        // ELF version. Original and curent version is 1.
+        buffer.write_all(&[1])?;
+
+        // Target OS ABI. Linux is 3, but it's often set to 0, regardless of platform.
+        buffer.write_all(&[0])?;
+
+        // Padding.
+        buffer.write_all(&[0; 8])?;
+
+        // Starting here, endianess goes into effect!
+
+        // Object type: Relocatable is 1, executable is 2.
+        buffer.write_u16::<LittleEndian>(1)?;
 
-    let i = sections.len() - 1;
-    sections[i].content = section_names_bytes(&sections);
+        // Instruction set architecture. x86 is 3, AMD64 is 62.
+        buffer.write_u16::<LittleEndian>(if bits == 64 { 62 } else { 3 })?;
 
-    let content_sizes: Vec<u64> = sections.iter().map(|s| s.content.len() as u64).collect();
-    let content_size: u64 = content_sizes.iter().sum();
+        // Always set to 1?
+        buffer.write_u32::<LittleEndian>(1)?;
 
-    let segments: Vec<Segment> = vec![];
+        // Address of the entry point. For object files, this is 0.
+        write_word(&mut buffer, bits, 0)?;
 
-    let mut buffer = vec![];
+        // Start of the program header table. We don't need it in an object file.
+        write_word(&mut buffer, bits, 0)?;
 
-    // Magic number: 0x7F plus "ELF".
+        // Start of the section header table.
+        write_word(
+            &mut buffer,
+            bits,
+            header_size + pht_entry_size * (segments.len() as u64) + content_size,
+        )?;
 
-    // 32-bit format (1) or 64-bit format (2).
-    buffer.write_all(&[2])?;
+        // "flags"
+        buffer.write_u32::<LittleEndian>(0)?;
 
-    // Little endian (1) or big endian (2).
-    buffer.write_all(&[1])?;
+        // Size of the header.
+        buffer.write_u16::<LittleEndian>(header_size as u16)?;
 
-    // ELF version. Original and curent version is 1.
-    buffer.write_all(&[1])?;
+        // Size of a program header table entry.
+        buffer.write_u16::<LittleEndian>(0)?;
 
-    // Target OS ABI. Linux is 3, but it's often set to 0, regardless of platform.
-    buffer.write_all(&[0])?;
+        // Number of entries in the program header table.
+        buffer.write_u16::<LittleEndian>(segments.len() as u16)?;
 
-    // Padding.
-    buffer.write_all(&[0; 8])?;
+        // Size of a section header table entry.
+        buffer.write_u16::<LittleEndian>(sht_entry_size as u16)?;
 
-    // Starting here, endianess goes into effect!
+        // Number of entries in the section header table.
+        buffer.write_u16::<LittleEndian>((sections.len() + 1) as u16)?;
 
-    // Object type: Executable is 2.
-    buffer.write_u16::<LittleEndian>(2)?;
+        // Index of section header table entry that contains section names.
+        buffer.write_u16::<LittleEndian>(sections.len() as u16)?;
 
-    // Instruction set architecture. x86 is 3, AMD64 is 62.
-    buffer.write_u16::<LittleEndian>(62)?;
+        // Beginning of program header table.
 
-    // Always set to 1?
-    buffer.write_u32::<LittleEndian>(1)?;
+        for segment in &segments {
+            // Type of the segment. Loadable segment is 1.
+            buffer.write_u32::<LittleEndian>(segment.typ)?;
 
-    // Address of the entry point. For object files, this is 0.
-    buffer.write_u64::<LittleEndian>(0)?;
+            // Segment-dependent flags. In ELF32, they come after the sizes.
+            if bits == 64 {
+                buffer.write_u32::<LittleEndian>(segment.flags)?;
+            }
 
-    // Start of the program header table. We don't need it in an object file.
-    buffer.write_u64::<LittleEndian>(0)?;
+            // Offset.
+            write_word(&mut buffer, bits, segment.offset)?;
 
-    // Start of the section header table.
-    buffer.write_u64::<LittleEndian>(
-        header_size + pht_entry_size * (segments.len() as u64) + content_size,
-    )?;
+            // Virtual address of the segment in memory.
+            write_word(&mut buffer, bits, segment.address)?;
 
-    // "flags"
-    buffer.write_u32::<LittleEndian>(0)?;
+            // Physical address of the segment in memory.
+            write_word(&mut buffer, bits, segment.address)?;
 
-    // Size of the header.
-    buffer.write_u16::<LittleEndian>(header_size as u16)?;
+            // Size of the segment in the file image.
+            write_word(&mut buffer, bits, segment.size)?;
 
-    // Size of a program header table entry.
-    buffer.write_u16::<LittleEndian>(0)?;
+            // Size of the segment in memory.
+            write_word(&mut buffer, bits, segment.size)?;
 
-    // Number of entries in the program header table.
-    buffer.write_u16::<LittleEndian>(segments.len() as u16)?;
+            if bits == 32 {
+                buffer.write_u32::<LittleEndian>(segment.flags)?;
+            }
 
-    // Size of a section header table entry.
-    buffer.write_u16::<LittleEndian>(sht_entry_size as u16)?;
+            // Alignment.
+            write_word(&mut buffer, bits, 0)?;
+        }
 
-    // Number of entries in the section header table.
-    buffer.write_u16::<LittleEndian>((sections.len() + 1) as u16)?;
+        // content.
+        for section in &sections {
+            buffer.write_all(&section.content[..section.file_size() as usize])?;
+        }
 
-    // Index of section header table entry that contains section names.
-    buffer.write_u16::<LittleEndian>(sections.len() as u16)?;
+        // Beginning of section header table.
 
-    // Beginning of program header table.
+        let mut name_offset = 1;
+        let mut offset = header_size + pht_entry_size * (segments.len() as u64);
 
-    for segment in &segments {
-        // Type of the segment. Loadable segment is 1.
-        buffer.write_u32::<LittleEndian>(segment.typ)?;
+        // First entry is filled with zeroes by convention.
+        buffer.write_all(&vec![0_u8; sht_entry_size as usize])?;
 
-        // Segment-dependent flags.
-        buffer.write_u32::<LittleEndian>(segment.flags)?;
+        for section in &sections {
+            // Offset of this section's name in the .shrtrtab section.
+            buffer.write_u32::<LittleEndian>(name_offset)?;
+            name_offset += (section.name.len() + 1) as u32;
 
-        // Offset.
-        buffer.write_u64::<LittleEndian>(segment.offset)?;
+            // Type. PROGBITS is 1, SYMTAB is 2, STRTAB is 3.
+            buffer.write_u32::<LittleEndian>(section.typ)?;
 
-        // Virtual address of the segment in memory.
-        buffer.write_u64::<LittleEndian>(segment.address)?;
+            // Flags, to mark if this section is writable or executable.
+            write_word(&mut buffer, bits, section.flags)?;
 
-        // Physical address of the segment in memory.
-        buffer.write_u64::<LittleEndian>(segment.address)?;
+            // Address at which the first byte of this entry will be loaded.
+            // For object files, this is 0?
+            write_word(&mut buffer, bits, 0)?;
 
-        // Size of the segment in the file image.
-        buffer.write_u64::<LittleEndian>(segment.size)?;
+            // Offset from the beginning of the file of this section.
+            write_word(&mut buffer, bits, offset)?;
 
-        // Size of the segment in memory.
-        buffer.write_u64::<LittleEndian>(segment.size)?;
+            // Size of this section in bytes.
+            offset += section.file_size();
+            write_word(&mut buffer, bits, section.content.len() as u64)?;
 
-        // Alignment.
-        buffer.write_u64::<LittleEndian>(0)?;
+            // Linked section. Interpretation depends on this section's type.
+            buffer.write_u32::<LittleEndian>(section.link)?;
+
+            // Extra information. Interpretation depends on this section's type.
+            buffer.write_u32::<LittleEndian>(section.info)?;
+
+            // Alignment constraint.
+            write_word(&mut buffer, bits, section.alignment)?;
+
+            // Size of one entry, if this section contains fixed-size entries.
+            write_word(&mut buffer, bits, section.entry_size)?;
+        }
+
+        Ok(buffer)
     }
+}
+
+// Creates an ELF64 x86-64 object file.
+pub fn create_binary(assembly: AssemblyResult) -> std::io::Result<Vec<u8>> {
+    create_object(assembly, 64)
+}
+
+// Creates an ELF32 i386 object file, which can be linked with `ld -m elf_i386`.
+pub fn create_binary32(assembly: AssemblyResult) -> std::io::Result<Vec<u8>> {
+    create_object(assembly, 32)
+}
+
+fn create_object(assembly: AssemblyResult, bits: u8) -> std::io::Result<Vec<u8>> {
+    let mut builder = ObjectBuilder::new(bits)?;
 
-    // content.
-    for section in &sections {
-        buffer.write_all(&section.content)?;
+    // Each section gets a section symbol for relocations.
+    let mut section_symbols = vec![];
+    for s in &assembly.sections {
+        let flags = match &s.flags {
+            Some(flags) => flags.chars().fold(0, |f, c| match c {
+                'a' => f | SHF_ALLOC,
+                'w' => f | SHF_WRITE,
+                'x' => f | SHF_EXECINSTR,
+                _ => f,
+            }),
+            None if s.name == ".rodata" => SHF_ALLOC,
+            None if s.is_writable() => SHF_ALLOC | SHF_WRITE,
+            None => SHF_ALLOC | SHF_EXECINSTR,
+        };
+        let typ = if s.nobits { SHT_NOBITS } else { SHT_PROGBITS };
+        let alignment = s.align.unwrap_or(0);
+        let index = builder.add_section(&s.name, typ, flags, alignment, s.content.clone());
+        section_symbols.push(builder.add_symbol(ElfSymbol {
+            name: s.name.clone(),
+            typ: STT_SECTION,
+            binding: STB_LOCAL,
+            visibility: STV_DEFAULT,
+            section: index as u16,
+            value: 0,
+            size: 0,
+        }));
     }
 
-    // Beginning of section header table.
+    let position = |name: &str| assembly.sections.iter().position(|s| s.name == name);
+
+    // Labels, except GAS's local ".L" symbols, with the attributes from .globl, .type and .size.
+    // Relocations reference global symbols directly, so that they can be preempted.
+    let mut globals = vec![];
+    for symbol in &assembly.symbols {
+        if symbol.name.starts_with(".L") {
+            continue;
+        }
+        let index = builder.add_symbol(ElfSymbol {
+            name: symbol.name.clone(),
+            typ: match symbol.typ {
+                SymbolType::NoType => STT_NOTYPE,
+                SymbolType::Function => STT_FUNC,
+                SymbolType::Object => STT_OBJECT,
+            },
+            binding: if symbol.global { STB_GLOBAL } else { STB_LOCAL },
+            visibility: STV_DEFAULT,
+            section: (position(&symbol.section).unwrap() + 1) as u16,
+            value: symbol.offset,
+            size: symbol.size.unwrap_or(0),
+        });
+        if symbol.global {
+            globals.push((&symbol.name, index, symbol.offset));
+        }
+    }
 
-    let mut name_offset = 1;
-    let mut offset = header_size + pht_entry_size * (segments.len() as u64);
+    // External symbols are undefined global symbols, created on first use. Common symbols are
+    // referenced like them.
+    let mut externals: Vec<(String, usize)> = vec![];
+    for common in &assembly.commons {
+        let symbol = builder.add_symbol(ElfSymbol {
+            name: common.name.clone(),
+            typ: STT_OBJECT,
+            binding: STB_GLOBAL,
+            visibility: STV_DEFAULT,
+            section: SHN_COMMON,
+            // The value of a common symbol is its alignment.
+            value: common.align,
+            size: common.size,
+        });
+        externals.push((common.name.clone(), symbol));
+    }
+    for relocation in &assembly.relocations {
+        let (symbol, addend) = if relocation.is_external() {
+            let symbol = match externals.iter().find(|(name, _)| *name == relocation.label) {
+                Some((_, symbol)) => *symbol,
+                None => {
+                    let symbol = builder.add_symbol(ElfSymbol {
+                        name: relocation.label.clone(),
+                        typ: STT_NOTYPE,
+                        binding: STB_GLOBAL,
+                        visibility: STV_DEFAULT,
+                        section: SHN_UNDEF,
+                        value: 0,
+                        size: 0,
+                    });
+                    externals.push((relocation.label.clone(), symbol));
+                    symbol
+                }
+            };
+            (symbol, relocation.addend as i64)
+        } else if let Some((_, symbol, offset)) = globals
+            .iter()
+            .find(|(name, _, _)| **name == relocation.label)
+        {
+            // The addend of a relocation includes the offset of its label in the section.
+            (*symbol, relocation.addend as i64 - *offset as i64)
+        } else {
+            (
+                section_symbols[position(&relocation.section).unwrap()],
+                relocation.addend as i64,
+            )
+        };
+        let typ = if bits == 64 {
+            relocation.typ as u32
+        } else {
+            i386_relocation_type(relocation.typ)?
+        };
+        builder.add_relocation(ElfRelocation {
+            section: position(&relocation.source).unwrap() + 1,
+            offset: relocation.location,
+            typ,
+            symbol,
+            addend: Some(addend),
+        });
+    }
+
+    builder.build()
+}
+
+// A section of a parsed ELF file.
+#[derive(Clone, Debug)]
+pub struct ElfSection {
+    pub name: String,
+    pub typ: u32,
+    pub flags: u64,
+    pub address: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub alignment: u64,
+    pub entry_size: u64,
+    pub content: Vec<u8>,
+}
+
+impl ElfSection {
+    pub fn is_executable(&self) -> bool {
+        self.flags & 4 != 0
+    }
+}
+
+// A segment of a parsed ELF file, as described by the program header table.
+#[derive(Clone, Debug)]
+pub struct ElfSegment {
+    pub typ: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub content: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ElfSymbol {
+    pub name: String,
+    pub typ: u8,
+    pub binding: u8,
+    pub visibility: u8,
+    pub section: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ElfRelocation {
+    // Index of the section the relocation is applied to.
+    pub section: usize,
+    pub offset: u64,
+    pub typ: u32,
+    // Index into the symbol table of the file.
+    pub symbol: usize,
+    // Explicit addend for RELA relocations, or None for REL relocations, which store their addend
+    // at the relocated location.
+    pub addend: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ElfFile {
+    // 32 or 64.
+    pub bits: u8,
+    pub typ: u16,
+    pub machine: u16,
+    pub entry: u64,
+    pub sections: Vec<ElfSection>,
+    pub segments: Vec<ElfSegment>,
+    pub symbols: Vec<ElfSymbol>,
+    pub relocations: Vec<ElfRelocation>,
+}
+
+impl ElfFile {
+    // The name to show for a symbol. Section symbols are usually unnamed, so use the name of their
+    // section instead.
+    pub fn symbol_name(&self, index: usize) -> String {
+        match self.symbols.get(index) {
+            Some(symbol) if symbol.name.is_empty() && symbol.typ == STT_SECTION => self
+                .sections
+                .get(symbol.section as usize)
+                .map(|s| s.name.clone())
+                .unwrap_or_default(),
+            Some(symbol) => symbol.name.clone(),
+            None => String::new(),
+        }
+    }
+
+    // The named symbols labelling addresses in a section, one per address, sorted by address.
+    // Like GNU objdump, global symbols are preferred over local ones. Section and file symbols
+    // are left out.
+    pub fn labels(&self, section: usize) -> Vec<&ElfSymbol> {
+        let mut labels: Vec<&ElfSymbol> = self
+            .symbols
+            .iter()
+            .filter(|s| {
+                s.section as usize == section
+                    && !s.name.is_empty()
+                    && s.typ != STT_SECTION
+                    && s.typ != STT_FILE
+            })
+            .collect();
+        labels.sort_by_key(|s| (s.value, s.binding == STB_LOCAL));
+        labels.dedup_by_key(|s| s.value);
+        labels
+    }
+
+    pub fn format_name(&self) -> &'static str {
+        match (self.bits, self.machine) {
+            (64, 62) => "elf64-x86-64",
+            (32, 3) => "elf32-i386",
+            (64, _) => "elf64-unknown",
+            _ => "elf32-unknown",
+        }
+    }
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Reads little-endian values from a byte slice, with bounds checks. "Words" are 4 or 8 bytes,
+// depending on the ELF class.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    bits: u8,
+}
+
+impl<'a> Reader<'a> {
+    fn slice(&self, offset: u64, size: u64) -> std::io::Result<&'a [u8]> {
+        let start = offset as usize;
+        let end = start
+            .checked_add(size as usize)
+            .ok_or_else(|| invalid("offset out of bounds"))?;
+        self.bytes
+            .get(start..end)
+            .ok_or_else(|| invalid("offset out of bounds"))
+    }
+
+    fn u8(&self, offset: u64) -> std::io::Result<u8> {
+        Ok(self.slice(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: u64) -> std::io::Result<u16> {
+        Ok(LittleEndian::read_u16(self.slice(offset, 2)?))
+    }
+
+    fn u32(&self, offset: u64) -> std::io::Result<u32> {
+        Ok(LittleEndian::read_u32(self.slice(offset, 4)?))
+    }
+
+    fn u64(&self, offset: u64) -> std::io::Result<u64> {
+        Ok(LittleEndian::read_u64(self.slice(offset, 8)?))
+    }
+
+    // The offset of entry `index` in a table of `size` byte entries at `table`, which must be
+    // within the file.
+    fn entry(&self, table: u64, index: u64, size: u64) -> std::io::Result<u64> {
+        let offset = index
+            .checked_mul(size)
+            .and_then(|o| o.checked_add(table))
+            .ok_or_else(|| invalid("offset out of bounds"))?;
+        self.slice(offset, size)?;
+        Ok(offset)
+    }
+
+    fn word(&self, offset: u64) -> std::io::Result<u64> {
+        if self.bits == 64 {
+            self.u64(offset)
+        } else {
+            Ok(self.u32(offset)? as u64)
+        }
+    }
+
+    fn string(&self, offset: u64) -> std::io::Result<String> {
+        let rest = self
+            .bytes
+            .get(offset as usize..)
+            .ok_or_else(|| invalid("string out of bounds"))?;
+        let end = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| invalid("unterminated string"))?;
+        Ok(String::from_utf8_lossy(&rest[..end]).to_string())
+    }
+}
 
-    // First entry is filled with zeroes by convention.
-    buffer.write_all(&[0 as u8; 64]).unwrap();
+pub fn parse(bytes: &[u8]) -> std::io::Result<ElfFile> {
+    if bytes.len() < 16 || &bytes[0..4] != b"\x7fELF" {
+        return Err(invalid("not an ELF file"));
+    }
+    let bits = match bytes[4] {
+        1 => 32,
+        2 => 64,
+        _ => return Err(invalid("unknown ELF class")),
+    };
+    if bytes[5] != 1 {
+        return Err(invalid("only little endian ELF files are supported"));
+    }
+    let r = Reader { bytes, bits };
+    // Size of a "word" in the header, and the offset at which the header fields diverge.
+    let w = (bits / 8) as u64;
+
+    let typ = r.u16(16)?;
+    let machine = r.u16(18)?;
+    let entry = r.word(24)?;
+    let ph_offset = r.word(24 + w)?;
+    let sh_offset = r.word(24 + 2 * w)?;
+    let rest = 24 + 3 * w + 4;
+    let ph_entry_size = r.u16(rest + 2)? as u64;
+    let ph_count = r.u16(rest + 4)? as u64;
+    let sh_entry_size = r.u16(rest + 6)? as u64;
+    let sh_count = r.u16(rest + 8)? as u64;
+    let sh_names_index = r.u16(rest + 10)? as usize;
+
+    let mut segments = vec![];
+    for i in 0..ph_count {
+        let o = r.entry(ph_offset, i, ph_entry_size)?;
+        let segment = if bits == 64 {
+            ElfSegment {
+                typ: r.u32(o)?,
+                flags: r.u32(o + 4)?,
+                offset: r.u64(o + 8)?,
+                address: r.u64(o + 16)?,
+                file_size: r.u64(o + 32)?,
+                memory_size: r.u64(o + 40)?,
+                content: vec![],
+            }
+        } else {
+            ElfSegment {
+                typ: r.u32(o)?,
+                offset: r.u32(o + 4)? as u64,
+                address: r.u32(o + 8)? as u64,
+                file_size: r.u32(o + 16)? as u64,
+                memory_size: r.u32(o + 20)? as u64,
+                flags: r.u32(o + 24)?,
+                content: vec![],
+            }
+        };
+        let content = r.slice(segment.offset, segment.file_size)?.to_vec();
+        segments.push(ElfSegment { content, ..segment });
+    }
 
-    for section in &sections {
-        // Offset of this section's name in the .shrtrtab section.
-        buffer.write_u32::<LittleEndian>(name_offset)?;
-        name_offset += (section.name.len() + 1) as u32;
+    let mut sections = vec![];
+    let mut name_offsets = vec![];
+    for i in 0..sh_count {
+        let o = r.entry(sh_offset, i, sh_entry_size)?;
+        let typ = r.u32(o + 4)?;
+        name_offsets.push(r.u32(o)? as u64);
+        let section = ElfSection {
+            name: String::new(),
+            typ,
+            flags: r.word(o + 8)?,
+            address: r.word(o + 8 + w)?,
+            offset: r.word(o + 8 + 2 * w)?,
+            size: r.word(o + 8 + 3 * w)?,
+            link: r.u32(o + 8 + 4 * w)?,
+            info: r.u32(o + 12 + 4 * w)?,
+            alignment: r.word(o + 16 + 4 * w)?,
+            entry_size: r.word(o + 16 + 5 * w)?,
+            content: vec![],
+        };
+        // NOBITS sections occupy no space in the file.
+        let content = if typ == SHT_NOBITS {
+            vec![]
+        } else {
+            r.slice(section.offset, section.size)?.to_vec()
+        };
+        sections.push(ElfSection { content, ..section });
+    }
 
-        // Type. PROGBITS is 1, SYMTAB is 2, STRTAB is 3.
-        buffer.write_u32::<LittleEndian>(section.typ)?;
+    // Now that all sections are read, resolve their names.
+    if let Some(names) = sections.get(sh_names_index).map(|s| s.offset) {
+        for (section, offset) in sections.iter_mut().zip(name_offsets) {
+            let offset = names
+                .checked_add(offset)
+                .ok_or_else(|| invalid("string out of bounds"))?;
+            section.name = r.string(offset)?;
+        }
+    }
 
-        // Flags, to mark if this section is writable or executable.
-        buffer.write_u64::<LittleEndian>(section.flags)?;
+    let mut symbols = vec![];
+    if let Some(symtab) = sections.iter().find(|s| s.typ == SHT_SYMTAB) {
+        let strtab = sections
+            .get(symtab.link as usize)
+            .ok_or_else(|| invalid("symbol table without string table"))?;
+        let entry_size = if bits == 64 { 24 } else { 16 };
+        for i in 0..symtab.size / entry_size {
+            let o = r.entry(symtab.offset, i, entry_size)?;
+            let (name, value, size, info, other, section) = if bits == 64 {
+                (
+                    r.u32(o)?,
+                    r.u64(o + 8)?,
+                    r.u64(o + 16)?,
+                    r.u8(o + 4)?,
+                    r.u8(o + 5)?,
+                    r.u16(o + 6)?,
+                )
+            } else {
+                (
+                    r.u32(o)?,
+                    r.u32(o + 4)? as u64,
+                    r.u32(o + 8)? as u64,
+                    r.u8(o + 12)?,
+                    r.u8(o + 13)?,
+                    r.u16(o + 14)?,
+                )
+            };
+            let name = strtab
+                .offset
+                .checked_add(name as u64)
+                .ok_or_else(|| invalid("string out of bounds"))?;
+            symbols.push(ElfSymbol {
+                name: r.string(name)?,
+                typ: info & 0xf,
+                binding: info >> 4,
+                visibility: other & 3,
+                section,
+                value,
+                size,
+            });
+        }
+    }
 
-        // Address at which the first byte of this entry will be loaded.
-        // For object files, this is 0?
-        buffer.write_u64::<LittleEndian>(0)?;
+    let mut relocations = vec![];
+    for section in sections
+        .iter()
+        .filter(|s| s.typ == SHT_RELA || s.typ == SHT_REL)
+    {
+        let rela = section.typ == SHT_RELA;
+        let entry_size = match (bits, rela) {
+            (64, true) => 24,
+            (64, false) => 16,
+            (_, true) => 12,
+            (_, false) => 8,
+        };
+        for i in 0..section.size / entry_size {
+            let o = r.entry(section.offset, i, entry_size)?;
+            let offset = r.word(o)?;
+            let info = r.word(o + w)?;
+            let (symbol, typ) = if bits == 64 {
+                (info >> 32, info & 0xffff_ffff)
+            } else {
+                (info >> 8, info & 0xff)
+            };
+            let addend = if !rela {
+                None
+            } else if bits == 64 {
+                Some(r.u64(o + 2 * w)? as i64)
+            } else {
+                Some(r.u32(o + 2 * w)? as i32 as i64)
+            };
+            relocations.push(ElfRelocation {
+                section: section.info as usize,
+                offset,
+                typ: typ as u32,
+                symbol: symbol as usize,
+                addend,
+            });
+        }
+    }
 
-        // Offset from the beginning of the file of this section.
-        buffer.write_u64::<LittleEndian>(offset)?;
+    Ok(ElfFile {
+        bits,
+        typ,
+        machine,
+        entry,
+        sections,
+        segments,
+        symbols,
+        relocations,
+    })
+}
 
-        // Size of this section in bytes.
-        offset += section.content.len() as u64;
-        buffer.write_u64::<LittleEndian>(section.content.len() as u64)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let assembly = crate::assembler::assemble(
+            "section .text\nmov esi, message\nsection .rodata\nmessage:\ndb \"Hello\"",
+        )
+        .unwrap();
+        let elf = parse(&create_binary(assembly).unwrap()).unwrap();
+        assert_eq!(elf.bits, 64);
+        assert_eq!(elf.machine, 62);
+
+        let text = elf.sections.iter().find(|s| s.name == ".text").unwrap();
+        assert!(text.is_executable());
+        assert_eq!(text.content, vec![0xbe, 0, 0, 0, 0]);
+        let rodata = elf.sections.iter().find(|s| s.name == ".rodata").unwrap();
+        assert_eq!(rodata.content, b"Hello".to_vec());
+
+        let message = elf.symbols.iter().find(|s| s.name == "message").unwrap();
+        assert_eq!((message.binding, message.value), (STB_LOCAL, 0));
+        assert!(!elf.symbols.iter().any(|s| s.name == "_start"));
+
+        assert_eq!(elf.relocations.len(), 1);
+        let relocation = &elf.relocations[0];
+        assert_eq!(elf.sections[relocation.section].name, ".text");
+        assert_eq!(relocation.offset, 1);
+        assert_eq!(relocation.typ, RelocationType::U32 as u32);
+        assert_eq!(elf.symbol_name(relocation.symbol), ".rodata");
+        assert_eq!(relocation.addend, Some(0));
+
+        let assembly = crate::assembler::assemble("extern puts\ncall puts").unwrap();
+        let elf = parse(&create_binary(assembly).unwrap()).unwrap();
+        let puts = elf.symbols.iter().position(|s| s.name == "puts").unwrap();
+        assert_eq!(elf.symbols[puts].section, SHN_UNDEF);
+        assert_eq!(elf.relocations[0].symbol, puts);
+        assert_eq!(elf.relocations[0].addend, Some(-4));
+    }
 
-        // Linked section. Interpretation depends on this section's type.
-        buffer.write_u32::<LittleEndian>(section.link)?;
+    #[test]
+    fn labels() {
+        let symbol = |name: &str, typ, binding, value| ElfSymbol {
+            name: name.to_string(),
+            typ,
+            binding,
+            visibility: 0,
+            section: 1,
+            value,
+            size: 0,
+        };
+        let elf = ElfFile {
+            bits: 64,
+            typ: 1,
+            machine: 62,
+            entry: 0,
+            sections: vec![],
+            segments: vec![],
+            symbols: vec![
+                symbol("", 3, 0, 0),
+                symbol("local", 0, 0, 0),
+                symbol("_start", 0, 1, 0),
+                symbol("helper", 2, 0, 4),
+            ],
+            relocations: vec![],
+        };
+        let labels: Vec<(&str, u64)> = elf
+            .labels(1)
+            .iter()
+            .map(|s| (s.name.as_str(), s.value))
+            .collect();
+        assert_eq!(labels, vec![("_start", 0), ("helper", 4)]);
+    }
 
-        // Extra information. Interpretation depends on this section's type.
-        buffer.write_u32::<LittleEndian>(section.info)?;
+    #[test]
+    fn roundtrip32() {
+        let options = crate::assembler::Options {
+            bits: 32,
+            ..Default::default()
+        };
+        let assembly = crate::assembler::assemble_with(
+            "section .text\nmov esi, message\ncall print\nsection .rodata\ndb 0\nmessage:\n\
+             print:",
+            &options,
+        )
+        .unwrap();
+        let elf = parse(&create_binary32(assembly).unwrap()).unwrap();
+        assert_eq!(elf.bits, 32);
+        assert_eq!(elf.format_name(), "elf32-i386");
+
+        // The addends are stored in the relocated fields.
+        let text = elf.sections.iter().find(|s| s.name == ".text").unwrap();
+        assert_eq!(
+            text.content,
+            vec![0xbe, 1, 0, 0, 0, 0xe8, 0xfd, 0xff, 0xff, 0xff]
+        );
+        let types: Vec<(u64, u32, Option<i64>)> = elf
+            .relocations
+            .iter()
+            .map(|r| (r.offset, r.typ, r.addend))
+            .collect();
+        assert_eq!(types, vec![(1, 1, None), (6, 2, None)]);
+        assert!(elf
+            .relocations
+            .iter()
+            .all(|r| elf.symbol_name(r.symbol) == ".rodata"));
 
-        // Alignment constraint.
-        buffer.write_u64::<LittleEndian>(alignment_from_section_name(&section.name))?;
+        let assembly = crate::assembler::assemble("mov rax, message\nmessage:").unwrap();
+        assert!(create_binary32(assembly).is_err());
+    }
 
-        // Size of one entry, if this section contains fixed-size entries.
-        buffer.write_u64::<LittleEndian>(section.entry_size)?;
+    #[test]
+    fn gas_symbols() {
+        let assembly = crate::assembler::assemble(
+            ".text\n.globl main\n.type main, @function\nmain:\nret\n.size main, .-main\n\
+             .data\n.align 8\nvalue:\n.quad 1\n.section .state, \"aw\"\n\
+             .section .bss\n.zero 16\n.comm shared, 4, 4\n.text\nmov eax, [rel shared]",
+        )
+        .unwrap();
+        let elf = parse(&create_binary(assembly).unwrap()).unwrap();
+        let section = |name: &str| elf.sections.iter().find(|s| s.name == name).unwrap();
+        assert_eq!(section(".text").flags, SHF_ALLOC | SHF_EXECINSTR);
+        assert_eq!(section(".data").flags, SHF_ALLOC | SHF_WRITE);
+        assert_eq!(section(".data").alignment, 8);
+        assert_eq!(section(".state").flags, SHF_ALLOC | SHF_WRITE);
+        let bss = section(".bss");
+        assert_eq!((bss.typ, bss.size), (SHT_NOBITS, 16));
+
+        let symbol = |name: &str| elf.symbols.iter().find(|s| s.name == name).unwrap();
+        let main = symbol("main");
+        assert_eq!(
+            (main.binding, main.typ, main.size),
+            (STB_GLOBAL, STT_FUNC, 1)
+        );
+        let value = symbol("value");
+        assert_eq!((value.binding, value.value), (STB_LOCAL, 0));
+
+        let shared = elf.symbols.iter().position(|s| s.name == "shared").unwrap();
+        let common = &elf.symbols[shared];
+        assert_eq!(
+            (common.section, common.value, common.size),
+            (SHN_COMMON, 4, 4)
+        );
+        assert_eq!(elf.relocations[0].symbol, shared);
+    }
+
+    #[test]
+    fn start_symbol() {
+        let assembly = crate::assembler::assemble(
+            "helper: ret
+global _start
+_start:
+call helper
+global main:function
+main:
+mov eax, [main + 2]
+mov rbx, helper",
+        )
+        .unwrap();
+        let elf = parse(&create_binary(assembly).unwrap()).unwrap();
+        let starts: Vec<&ElfSymbol> = elf.symbols.iter().filter(|s| s.name == "_start").collect();
+        assert_eq!(starts.len(), 1);
+        assert_eq!((starts[0].binding, starts[0].value), (STB_GLOBAL, 1));
+        let main = elf.symbols.iter().find(|s| s.name == "main").unwrap();
+        assert_eq!(
+            (main.binding, main.typ, main.value),
+            (STB_GLOBAL, STT_FUNC, 6)
+        );
+        let helper = elf.symbols.iter().find(|s| s.name == "helper").unwrap();
+        assert_eq!(helper.binding, STB_LOCAL);
+        assert!(!elf.symbols.iter().any(|s| s.name == "foobar"));
+        // The global symbol is referenced directly, the local one through its section.
+        let references: Vec<(String, Option<i64>)> = elf
+            .relocations
+            .iter()
+            .map(|r| (elf.symbol_name(r.symbol), r.addend))
+            .collect();
+        assert_eq!(
+            references,
+            vec![
+                ("main".to_string(), Some(2)),
+                (".text".to_string(), Some(0))
+            ]
+        );
+
+        let assembly = crate::assembler::assemble("_start: ret").unwrap();
+        let elf = parse(&create_binary(assembly).unwrap()).unwrap();
+        let start = elf.symbols.iter().find(|s| s.name == "_start").unwrap();
+        assert_eq!(start.binding, STB_LOCAL);
     }
 
-    Ok(buffer)
+    #[test]
+    fn builder() {
+        let mut builder = ObjectBuilder::new(64).unwrap();
+        let text = builder.add_section(
+            ".text",
+            SHT_PROGBITS,
+            SHF_ALLOC | SHF_EXECINSTR,
+            16,
+            vec![0xe8, 0, 0, 0, 0, 0xc3],
+        );
+        let bss = builder.add_section(".bss", SHT_NOBITS, SHF_ALLOC | SHF_WRITE, 8, vec![0; 64]);
+        let symbol = |name: &str, typ, binding, section: usize, size| ElfSymbol {
+            name: name.to_string(),
+            typ,
+            binding,
+            visibility: STV_DEFAULT,
+            section: section as u16,
+            value: 0,
+            size,
+        };
+        builder.add_symbol(symbol("main", STT_FUNC, STB_GLOBAL, text, 6));
+        let puts = builder.add_symbol(symbol("puts", STT_NOTYPE, STB_GLOBAL, 0, 0));
+        builder.add_symbol(symbol("buffer", STT_OBJECT, STB_LOCAL, bss, 64));
+        builder.add_relocation(ElfRelocation {
+            section: text,
+            offset: 1,
+            typ: RelocationType::Pc32 as u32,
+            symbol: puts,
+            addend: Some(-4),
+        });
+        let elf = parse(&builder.build().unwrap()).unwrap();
+
+        // Local symbols come first.
+        let names: Vec<&str> = elf.symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["", "buffer", "main", "puts"]);
+        let symtab = elf.sections.iter().find(|s| s.name == ".symtab").unwrap();
+        assert_eq!(symtab.info, 2);
+        assert_eq!(elf.symbols[2].typ, STT_FUNC);
+        assert_eq!(elf.symbols[2].size, 6);
+
+        let bss = &elf.sections[bss];
+        assert_eq!((bss.typ, bss.size, bss.alignment), (SHT_NOBITS, 64, 8));
+        assert!(bss.content.is_empty());
+
+        let relocation = &elf.relocations[0];
+        assert_eq!(elf.sections[relocation.section].name, ".text");
+        assert_eq!(elf.symbol_name(relocation.symbol), "puts");
+        assert_eq!(relocation.addend, Some(-4));
+        assert!(elf.sections.iter().any(|s| s.name == ".rela.text"));
+
+        builder.add_relocation(ElfRelocation {
+            section: text,
+            offset: 0,
+            typ: 2,
+            symbol: 7,
+            addend: None,
+        });
+        assert!(builder.build().is_err());
+        assert!(ObjectBuilder::new(16).is_err());
+    }
+
+    #[test]
+    fn builder32() {
+        // REL relocations without an addend keep the one in the relocated field.
+        let mut builder = ObjectBuilder::new(32).unwrap();
+        let text = builder.add_section(
+            ".text",
+            SHT_PROGBITS,
+            SHF_ALLOC | SHF_EXECINSTR,
+            16,
+            vec![0xb8, 8, 0, 0, 0, 0xb8, 8, 0, 0, 0],
+        );
+        let symbol = builder.add_symbol(ElfSymbol {
+            name: "data".to_string(),
+            typ: STT_NOTYPE,
+            binding: STB_GLOBAL,
+            visibility: STV_DEFAULT,
+            section: SHN_UNDEF,
+            value: 0,
+            size: 0,
+        });
+        for (offset, addend) in [(1, None), (6, Some(4))] {
+            builder.add_relocation(ElfRelocation {
+                section: text,
+                offset,
+                typ: 1,
+                symbol,
+                addend,
+            });
+        }
+        let elf = parse(&builder.build().unwrap()).unwrap();
+        assert_eq!(
+            elf.sections[text].content,
+            vec![0xb8, 8, 0, 0, 0, 0xb8, 4, 0, 0, 0]
+        );
+        assert!(elf.relocations.iter().all(|r| r.addend.is_none()));
+    }
+
+    #[test]
+    fn not_elf() {
+        assert!(parse(b"hello").is_err());
+
+        // Offsets past the end of the file are errors, not overflows.
+        let assembly = crate::assembler::assemble("ret").unwrap();
+        let mut bytes = create_binary(assembly).unwrap();
+        bytes[40..48].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(parse(&bytes).is_err());
+    }
 }
@@ -0,0 +1,695 @@
+use crate::cpu::{self, AddressSpace, Cpu, Event, Fault};
+use crate::elf::{self, ElfFile};
+use std::collections::HashMap;
+use std::fmt;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// Where sections of relocatable objects are placed.
+const LOAD_ADDRESS: u64 = 0x40_0000;
+const STACK_TOP: u64 = 0x7fff_ffff_f000;
+const STACK_SIZE: u64 = 0x10_0000;
+const MMAP_START: u64 = 0x7000_0000_0000;
+// Limits on what a single syscall or segment may ask for, as pages are allocated eagerly.
+const MAX_MAPPING: u64 = 0x1000_0000;
+const MAX_TRANSFER: u64 = 0x10_0000;
+
+// Linux error numbers returned by the emulated syscalls.
+const ENOENT: u64 = 2;
+const EBADF: u64 = 9;
+const ENOMEM: u64 = 12;
+const EFAULT: u64 = 14;
+const ENODEV: u64 = 19;
+const EINVAL: u64 = 22;
+const ENOSYS: u64 = 38;
+
+#[derive(Debug)]
+pub enum Error {
+    // The ELF file could not be loaded.
+    Load(String),
+    Fault(Fault),
+    // The program ran for more instructions than allowed.
+    InstructionLimit,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Load(message) => write!(f, "could not load program: {}", message),
+            Error::Fault(fault) => write!(f, "{}", fault),
+            Error::InstructionLimit => write!(f, "instruction limit exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Fault> for Error {
+    fn from(fault: Fault) -> Error {
+        Error::Fault(fault)
+    }
+}
+
+fn page_start(address: u64) -> u64 {
+    address & !(PAGE_SIZE - 1)
+}
+
+fn page_end(address: u64) -> Option<u64> {
+    address.checked_add(PAGE_SIZE - 1).map(page_start)
+}
+
+// Memory which consists of individually mapped pages.
+#[derive(Clone, Default)]
+pub struct SparseMemory {
+    pages: HashMap<u64, Vec<u8>>,
+}
+
+impl SparseMemory {
+    pub fn new() -> SparseMemory {
+        SparseMemory::default()
+    }
+
+    // Maps zeroed pages covering the given range. Pages which are already mapped keep their
+    // content.
+    pub fn map(&mut self, address: u64, size: u64) {
+        for page in SparseMemory::pages(address, size) {
+            self.pages
+                .entry(page)
+                .or_insert_with(|| vec![0; PAGE_SIZE as usize]);
+        }
+    }
+
+    pub fn unmap(&mut self, address: u64, size: u64) {
+        for page in SparseMemory::pages(address, size) {
+            self.pages.remove(&page);
+        }
+    }
+
+    // The pages covering the range, which is cut off at the end of the address space.
+    fn pages(address: u64, size: u64) -> impl Iterator<Item = u64> {
+        (page_start(address)..address.saturating_add(size)).step_by(PAGE_SIZE as usize)
+    }
+
+    pub fn is_mapped(&self, address: u64) -> bool {
+        self.pages.contains_key(&page_start(address))
+    }
+}
+
+impl AddressSpace for SparseMemory {
+    fn read(&self, address: u64, buffer: &mut [u8]) -> Result<(), Fault> {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            let a = address.wrapping_add(i as u64);
+            let page = self
+                .pages
+                .get(&page_start(a))
+                .ok_or(Fault::UnmappedMemory(a))?;
+            *byte = page[(a - page_start(a)) as usize];
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: u64, bytes: &[u8]) -> Result<(), Fault> {
+        // Check first, so that a failing write doesn't change anything.
+        for i in 0..bytes.len() as u64 {
+            let a = address.wrapping_add(i);
+            if !self.is_mapped(a) {
+                return Err(Fault::UnmappedMemory(a));
+            }
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            let a = address.wrapping_add(i as u64);
+            let page = self.pages.get_mut(&page_start(a)).unwrap();
+            page[(a - page_start(a)) as usize] = *byte;
+        }
+        Ok(())
+    }
+}
+
+struct OpenFile {
+    path: String,
+    position: usize,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+// The files an emulated program can access. Standard input and output are the files
+// /dev/stdin, /dev/stdout and /dev/stderr, which are opened as descriptors 0, 1 and 2.
+pub struct VirtualFileSystem {
+    files: HashMap<String, Vec<u8>>,
+    descriptors: HashMap<u64, OpenFile>,
+}
+
+impl Default for VirtualFileSystem {
+    fn default() -> VirtualFileSystem {
+        VirtualFileSystem::new()
+    }
+}
+
+impl VirtualFileSystem {
+    pub fn new() -> VirtualFileSystem {
+        let mut fs = VirtualFileSystem {
+            files: HashMap::new(),
+            descriptors: HashMap::new(),
+        };
+        for (fd, name) in ["/dev/stdin", "/dev/stdout", "/dev/stderr"]
+            .iter()
+            .enumerate()
+        {
+            fs.files.insert(name.to_string(), vec![]);
+            fs.descriptors.insert(
+                fd as u64,
+                OpenFile {
+                    path: name.to_string(),
+                    position: 0,
+                    readable: fd == 0,
+                    writable: fd != 0,
+                    append: true,
+                },
+            );
+        }
+        fs
+    }
+
+    pub fn add_file(&mut self, path: &str, content: &[u8]) {
+        self.files.insert(path.to_string(), content.to_vec());
+    }
+
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        self.files.get(path).map(|f| f.as_slice())
+    }
+
+    // Opens a file with Linux open(2) flags, returning a descriptor or an error number.
+    pub fn open(&mut self, path: &str, flags: u64) -> Result<u64, u64> {
+        let access = flags & 3;
+        if !self.files.contains_key(path) {
+            if flags & 0x40 == 0 {
+                return Err(ENOENT);
+            }
+            self.files.insert(path.to_string(), vec![]);
+        }
+        if flags & 0x200 != 0 && access != 0 {
+            self.files.insert(path.to_string(), vec![]);
+        }
+        let fd = (0..).find(|fd| !self.descriptors.contains_key(fd)).unwrap();
+        self.descriptors.insert(
+            fd,
+            OpenFile {
+                path: path.to_string(),
+                position: 0,
+                readable: access != 1,
+                writable: access != 0,
+                append: flags & 0x400 != 0,
+            },
+        );
+        Ok(fd)
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<(), u64> {
+        self.descriptors.remove(&fd).map(|_| ()).ok_or(EBADF)
+    }
+
+    pub fn read(&mut self, fd: u64, count: usize) -> Result<Vec<u8>, u64> {
+        let file = self
+            .descriptors
+            .get_mut(&fd)
+            .filter(|f| f.readable)
+            .ok_or(EBADF)?;
+        let content = &self.files[&file.path];
+        let start = file.position.min(content.len());
+        let end = start.saturating_add(count).min(content.len());
+        file.position = end;
+        Ok(content[start..end].to_vec())
+    }
+
+    pub fn write(&mut self, fd: u64, bytes: &[u8]) -> Result<usize, u64> {
+        let file = self
+            .descriptors
+            .get_mut(&fd)
+            .filter(|f| f.writable)
+            .ok_or(EBADF)?;
+        let content = self.files.get_mut(&file.path).unwrap();
+        if file.append {
+            file.position = content.len();
+        }
+        let end = file.position + bytes.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[file.position..end].copy_from_slice(bytes);
+        file.position = end;
+        Ok(bytes.len())
+    }
+}
+
+pub struct Emulator {
+    pub cpu: Cpu,
+    pub memory: SparseMemory,
+    pub fs: VirtualFileSystem,
+    // Stop with an error after this many instructions, to catch runaway programs.
+    pub instruction_limit: Option<u64>,
+    brk_start: u64,
+    brk: u64,
+    mmap_next: u64,
+    exit_code: Option<i32>,
+}
+
+impl Emulator {
+    // Loads an ELF executable, or a relocatable object file which is then linked in place.
+    pub fn load(bytes: &[u8]) -> Result<Emulator, Error> {
+        let elf = elf::parse(bytes).map_err(|e| Error::Load(e.to_string()))?;
+        if elf.bits != 64 || elf.machine != 62 {
+            return Err(Error::Load(
+                "only x86-64 programs are supported".to_string(),
+            ));
+        }
+        let mut emulator = Emulator {
+            cpu: Cpu::new(),
+            memory: SparseMemory::new(),
+            fs: VirtualFileSystem::new(),
+            instruction_limit: None,
+            brk_start: 0,
+            brk: 0,
+            mmap_next: MMAP_START,
+            exit_code: None,
+        };
+        let end = match elf.typ {
+            1 => emulator.load_relocatable(&elf)?,
+            2 => emulator.load_executable(&elf)?,
+            _ => return Err(Error::Load("unsupported ELF type".to_string())),
+        };
+        emulator.brk_start =
+            page_end(end).ok_or_else(|| Error::Load("program too large".to_string()))?;
+        emulator.brk = emulator.brk_start;
+        emulator.setup_stack()?;
+        Ok(emulator)
+    }
+
+    fn load_executable(&mut self, elf: &ElfFile) -> Result<u64, Error> {
+        let mut end = 0;
+        // Only PT_LOAD segments occupy memory.
+        for segment in elf.segments.iter().filter(|s| s.typ == 1) {
+            let segment_end = segment
+                .address
+                .checked_add(segment.memory_size)
+                .filter(|_| segment.memory_size <= MAX_MAPPING)
+                .ok_or_else(|| {
+                    Error::Load(format!("segment at 0x{:x} is too large", segment.address))
+                })?;
+            self.memory.map(segment.address, segment.memory_size);
+            self.memory.write(segment.address, &segment.content)?;
+            end = end.max(segment_end);
+        }
+        self.cpu.rip = elf.entry;
+        Ok(end)
+    }
+
+    fn load_relocatable(&mut self, elf: &ElfFile) -> Result<u64, Error> {
+        // Place each allocated section on its own pages.
+        let mut addresses = HashMap::new();
+        let mut address = LOAD_ADDRESS;
+        for (index, section) in elf.sections.iter().enumerate() {
+            if section.flags & 2 == 0 {
+                continue;
+            }
+            if section.size > MAX_MAPPING {
+                return Err(Error::Load(format!(
+                    "section '{}' is too large",
+                    section.name
+                )));
+            }
+            self.memory.map(address, section.size.max(1));
+            self.memory.write(address, &section.content)?;
+            addresses.insert(index, address);
+            address = page_end(address + section.size.max(1))
+                .ok_or_else(|| Error::Load("program too large".to_string()))?;
+        }
+
+        let symbol_address = |index: usize| -> Result<u64, Error> {
+            let symbol = elf
+                .symbols
+                .get(index)
+                .ok_or_else(|| Error::Load(format!("invalid symbol index {}", index)))?;
+            match symbol.section {
+                // Absolute symbols.
+                0xfff1 => Ok(symbol.value),
+                section => addresses
+                    .get(&(section as usize))
+                    .map(|a| a + symbol.value)
+                    .ok_or_else(|| {
+                        Error::Load(format!("undefined symbol '{}'", elf.symbol_name(index)))
+                    }),
+            }
+        };
+
+        for relocation in &elf.relocations {
+            let base = match addresses.get(&relocation.section) {
+                Some(base) => *base,
+                None => continue,
+            };
+            let location = base + relocation.offset;
+            let s = symbol_address(relocation.symbol)?;
+            let a = match relocation.addend {
+                Some(addend) => addend,
+                None => {
+                    let mut bytes = [0; 4];
+                    self.memory.read(location, &mut bytes)?;
+                    i32::from_le_bytes(bytes) as i64
+                }
+            };
+            let value = s.wrapping_add(a as u64);
+            match relocation.typ {
+                // R_X86_64_64
+                1 => self.memory.write(location, &value.to_le_bytes())?,
+                // R_X86_64_PC32 and R_X86_64_PLT32
+                2 | 4 => {
+                    let relative = value.wrapping_sub(location) as u32;
+                    self.memory.write(location, &relative.to_le_bytes())?
+                }
+                // R_X86_64_32 and R_X86_64_32S
+                10 | 11 => self.memory.write(location, &(value as u32).to_le_bytes())?,
+                typ => return Err(Error::Load(format!("unsupported relocation type {}", typ))),
+            }
+        }
+
+        let start = elf.symbols.iter().position(|s| s.name == "_start");
+        self.cpu.rip = match start {
+            Some(index) => symbol_address(index)?,
+            None => elf
+                .sections
+                .iter()
+                .position(|s| s.name == ".text")
+                .and_then(|index| addresses.get(&index).copied())
+                .ok_or_else(|| Error::Load("no entry point".to_string()))?,
+        };
+        Ok(address)
+    }
+
+    // Sets up the initial process stack: argc, argv, an empty environment and an empty auxiliary
+    // vector.
+    fn setup_stack(&mut self) -> Result<(), Error> {
+        self.memory.map(STACK_TOP - STACK_SIZE, STACK_SIZE);
+        let name = b"program\0";
+        let name_address = STACK_TOP - 16;
+        self.memory.write(name_address, name)?;
+        let words: [u64; 6] = [1, name_address, 0, 0, 0, 0];
+        let mut rsp = name_address - 8 * words.len() as u64;
+        rsp &= !0xf;
+        for (i, word) in words.iter().enumerate() {
+            self.memory.write(rsp + 8 * i as u64, &word.to_le_bytes())?;
+        }
+        self.cpu.registers[cpu::RSP] = rsp;
+        Ok(())
+    }
+
+    pub fn set_stdin(&mut self, input: &[u8]) {
+        self.fs.add_file("/dev/stdin", input);
+    }
+
+    pub fn stdout(&self) -> &[u8] {
+        self.fs.file("/dev/stdout").unwrap_or(&[])
+    }
+
+    pub fn stderr(&self) -> &[u8] {
+        self.fs.file("/dev/stderr").unwrap_or(&[])
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // Runs the program until it exits, and returns its exit code.
+    pub fn run(&mut self) -> Result<i32, Error> {
+        let mut executed = 0;
+        while self.exit_code.is_none() {
+            if Some(executed) == self.instruction_limit {
+                return Err(Error::InstructionLimit);
+            }
+            self.step()?;
+            executed += 1;
+        }
+        Ok(self.exit_code.unwrap())
+    }
+
+    // Executes a single instruction, handling syscalls.
+    pub fn step(&mut self) -> Result<(), Error> {
+        match self.cpu.step(&mut self.memory)? {
+            Event::Syscall => {
+                let result = self.syscall();
+                // The kernel clobbers rcx and r11.
+                self.cpu.registers[1] = self.cpu.rip;
+                self.cpu.registers[11] = self.cpu.rflags;
+                self.cpu.registers[0] = match result {
+                    Ok(value) => value,
+                    Err(errno) => errno.wrapping_neg(),
+                };
+                Ok(())
+            }
+            Event::Continue => Ok(()),
+            Event::Breakpoint => Err(Error::Fault(Fault::Unsupported("int3".to_string()))),
+            Event::Halt => Err(Error::Fault(Fault::Unsupported("hlt".to_string()))),
+        }
+    }
+
+    fn read_string(&self, address: u64) -> Result<String, u64> {
+        let mut bytes = vec![];
+        loop {
+            let mut byte = [0];
+            self.memory
+                .read(address.wrapping_add(bytes.len() as u64), &mut byte)
+                .map_err(|_| EFAULT)?;
+            if byte[0] == 0 {
+                return Ok(String::from_utf8_lossy(&bytes).to_string());
+            }
+            bytes.push(byte[0]);
+        }
+    }
+
+    fn syscall(&mut self) -> Result<u64, u64> {
+        let r = self.cpu.registers;
+        let (number, a1, a2, a3) = (r[0], r[7], r[6], r[2]);
+        let a4 = r[10];
+        match number {
+            // read and write transfer at most MAX_TRANSFER bytes, like Linux caps them too.
+            0 => {
+                let bytes = self.fs.read(a1, a3.min(MAX_TRANSFER) as usize)?;
+                self.memory.write(a2, &bytes).map_err(|_| EFAULT)?;
+                Ok(bytes.len() as u64)
+            }
+            1 => {
+                let mut bytes = vec![0; a3.min(MAX_TRANSFER) as usize];
+                self.memory.read(a2, &mut bytes).map_err(|_| EFAULT)?;
+                Ok(self.fs.write(a1, &bytes)? as u64)
+            }
+            // open
+            2 => {
+                let path = self.read_string(a1)?;
+                self.fs.open(&path, a2)
+            }
+            // close
+            3 => self.fs.close(a1).map(|_| 0),
+            // mmap, only anonymous mappings are supported.
+            9 => {
+                if a4 & 0x20 == 0 {
+                    return Err(ENODEV);
+                }
+                if a2 == 0 {
+                    return Err(EINVAL);
+                }
+                let size = page_end(a2)
+                    .filter(|&size| size <= MAX_MAPPING)
+                    .ok_or(ENOMEM)?;
+                // Honour the address for MAP_FIXED.
+                let address = if a4 & 0x10 != 0 {
+                    if a1 % PAGE_SIZE != 0 || a1.checked_add(size).is_none() {
+                        return Err(EINVAL);
+                    }
+                    self.memory.unmap(a1, size);
+                    a1
+                } else {
+                    let address = self.mmap_next;
+                    self.mmap_next = address.checked_add(size).ok_or(ENOMEM)?;
+                    address
+                };
+                self.memory.map(address, size);
+                Ok(address)
+            }
+            // munmap
+            11 => {
+                if a1 % PAGE_SIZE != 0 || a2 == 0 {
+                    return Err(EINVAL);
+                }
+                self.memory.unmap(a1, page_end(a2).ok_or(EINVAL)?);
+                Ok(0)
+            }
+            // brk. Like Linux, a break which can't be set leaves the current one in place, which
+            // is returned instead of an error.
+            12 => {
+                if a1 > self.brk {
+                    if a1 - self.brk > MAX_MAPPING {
+                        return Ok(self.brk);
+                    }
+                    self.memory.map(self.brk, a1 - self.brk);
+                    self.brk = a1;
+                } else if a1 >= self.brk_start {
+                    self.brk = a1;
+                }
+                Ok(self.brk)
+            }
+            // exit and exit_group
+            60 | 231 => {
+                self.exit_code = Some(a1 as i32);
+                Ok(0)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str, stdin: &[u8]) -> Emulator {
+        let assembly = crate::assembler::assemble(source).unwrap();
+        let binary = crate::elf::create_binary(assembly).unwrap();
+        let mut emulator = Emulator::load(&binary).unwrap();
+        emulator.set_stdin(stdin);
+        emulator.instruction_limit = Some(1000);
+        emulator.run().unwrap();
+        emulator
+    }
+
+    #[test]
+    fn hello_world() {
+        let emulator = run(
+            "section .text
+            mov eax, 1
+            mov edi, 1
+            mov esi, message
+            mov edx, 6
+            syscall
+            mov eax, 60
+            mov edi, 42
+            syscall
+            section .rodata
+            message:
+            db \"Hello\", 10",
+            b"",
+        );
+        assert_eq!(emulator.stdout(), b"Hello\n");
+        assert_eq!(emulator.exit_code(), Some(42));
+    }
+
+    #[test]
+    fn echo() {
+        // Reads from stdin into a buffer, and writes it back to stdout.
+        let emulator = run(
+            "section .text
+            mov eax, 0
+            mov edi, 0
+            mov esi, buffer
+            mov edx, 5
+            syscall
+            mov eax, 1
+            mov edi, 1
+            mov esi, buffer
+            mov edx, 5
+            syscall
+            mov eax, 60
+            mov edi, 0
+            syscall
+            section .rodata
+            section .data
+            buffer:
+            db 0, 0, 0, 0, 0",
+            b"echo!",
+        );
+        assert_eq!(emulator.stdout(), b"echo!");
+        assert_eq!(emulator.exit_code(), Some(0));
+    }
+
+    #[test]
+    fn brk() {
+        let assembly =
+            crate::assembler::assemble("mov eax, 12\nmov edi, 0\nsyscall\nsection .rodata")
+                .unwrap();
+        let binary = crate::elf::create_binary(assembly).unwrap();
+        let mut emulator = Emulator::load(&binary).unwrap();
+        for _ in 0..3 {
+            emulator.step().unwrap();
+        }
+        let start = emulator.cpu.registers[0];
+        assert!(start > LOAD_ADDRESS && start.is_multiple_of(PAGE_SIZE));
+        assert!(!emulator.memory.is_mapped(start));
+
+        emulator.cpu.registers[0] = 12;
+        emulator.cpu.registers[7] = start + 0x10;
+        assert_eq!(emulator.syscall(), Ok(start + 0x10));
+        assert!(emulator.memory.is_mapped(start));
+    }
+
+    #[test]
+    fn syscall_limits() {
+        let assembly = crate::assembler::assemble("hlt\nsection .rodata").unwrap();
+        let binary = crate::elf::create_binary(assembly).unwrap();
+        let mut emulator = Emulator::load(&binary).unwrap();
+        let mut syscall = |number: u64, a1: u64, a2: u64, a3: u64, a4: u64| {
+            let registers = &mut emulator.cpu.registers;
+            registers[0] = number;
+            registers[7] = a1;
+            registers[6] = a2;
+            registers[2] = a3;
+            registers[10] = a4;
+            emulator.syscall()
+        };
+        // write and read with huge counts from or to unmapped memory.
+        assert_eq!(syscall(1, 1, 0, u64::MAX, 0), Err(EFAULT));
+        assert_eq!(syscall(0, 0, 0, u64::MAX, 0), Ok(0));
+        // Anonymous mmap of a huge size, and a fixed one at an unaligned address.
+        assert_eq!(syscall(9, 0, u64::MAX, 3, 0x22), Err(ENOMEM));
+        assert_eq!(syscall(9, 0, MAX_MAPPING + 1, 3, 0x22), Err(ENOMEM));
+        assert_eq!(syscall(9, 0x1001, 0x1000, 3, 0x32), Err(EINVAL));
+        assert_eq!(syscall(9, u64::MAX - 0xfff, 0x2000, 3, 0x32), Err(EINVAL));
+        assert_eq!(syscall(11, 0x1000, u64::MAX, 0, 0), Err(EINVAL));
+        // A break which is too far away is refused.
+        let start = syscall(12, 0, 0, 0, 0).unwrap();
+        assert_eq!(syscall(12, u64::MAX, 0, 0, 0), Ok(start));
+    }
+
+    #[test]
+    fn sparse_memory() {
+        let mut memory = SparseMemory::new();
+        memory.map(0x1ffe, 4);
+        memory.write(0x1ffe, &[1, 2, 3, 4]).unwrap();
+        let mut buffer = [0; 4];
+        memory.read(0x1ffe, &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        assert_eq!(
+            memory.write(0x2ffe, &[0; 4]),
+            Err(Fault::UnmappedMemory(0x3000))
+        );
+        // Ranges running past the end of the address space are cut off.
+        memory.map(u64::MAX - 1, 4);
+        assert!(memory.is_mapped(u64::MAX));
+        memory.unmap(u64::MAX - 1, 4);
+        assert!(!memory.is_mapped(u64::MAX));
+    }
+
+    #[test]
+    fn file_system() {
+        let mut fs = VirtualFileSystem::new();
+        fs.add_file("/input", b"abc");
+        let fd = fs.open("/input", 0).unwrap();
+        assert_eq!(fd, 3);
+        assert_eq!(fs.read(fd, 2).unwrap(), b"ab");
+        assert_eq!(fs.read(fd, 2).unwrap(), b"c");
+        assert_eq!(fs.read(fd, usize::MAX).unwrap(), b"");
+        assert_eq!(fs.write(fd, b"x"), Err(EBADF));
+        assert_eq!(fs.open("/missing", 0), Err(ENOENT));
+        let fd = fs.open("/output", 0x41).unwrap();
+        fs.write(fd, b"hi").unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(fs.file("/output"), Some(&b"hi"[..]));
+    }
+}
@@ -0,0 +1,1545 @@
+use crate::assembler::ErrorKind;
+use crate::preprocessor;
+use crate::x86::{Register, Size, CONDITIONS};
+use crate::RelocationType;
+use std::convert::TryFrom;
+
+// A field of an encoded instruction that refers to a label. For relative fields, the addend
+// already accounts for the distance between the field and the end of the instruction.
+pub(crate) struct Fixup {
+    pub offset: usize,
+    pub typ: RelocationType,
+    pub label: String,
+    pub addend: i64,
+}
+
+pub(crate) struct Encoding {
+    pub bytes: Vec<u8>,
+    pub fixups: Vec<Fixup>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct MemoryOperand {
+    size: Option<Size>,
+    segment: Option<u8>,
+    base: Option<Register>,
+    index: Option<Register>,
+    scale: u8,
+    displacement: i64,
+    label: Option<String>,
+    // [rel label], addressing relative to the next instruction in 64-bit mode.
+    relative: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Register(Register),
+    Immediate(i64),
+    Label(String),
+    Memory(MemoryOperand),
+}
+
+// An operand with the size keyword or "short" written before it, if any.
+struct Parsed {
+    operand: Operand,
+    size: Option<Size>,
+    short: bool,
+}
+
+fn invalid(message: &str) -> ErrorKind {
+    ErrorKind::InvalidOperands(message.to_string())
+}
+
+fn not_in_mode(what: &str, bits: u32) -> ErrorKind {
+    ErrorKind::InvalidInMode {
+        what: what.to_string(),
+        bits,
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '?')
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.?$#@~^".contains(c))
+}
+
+// Parses a decimal or 0x-prefixed hexadecimal number, optionally negative. Values up to
+// u64::MAX are accepted and wrap around.
+pub(crate) fn parse_number(text: &str) -> Result<i64, ErrorKind> {
+    let text = text.trim();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits.trim()),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| {
+        if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit() || c == 'x') {
+            ErrorKind::ValueOutOfRange(text.to_string())
+        } else {
+            ErrorKind::InvalidNumber(text.to_string())
+        }
+    })? as i64;
+    Ok(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+// Parses a number, or evaluates a constant expression like "(2+3)*4". Text which doesn't
+// start like an expression, like "%rax", reports why it isn't a number.
+fn parse_value(text: &str) -> Result<i64, ErrorKind> {
+    parse_number(text).or_else(|error| {
+        let text = text.trim();
+        if text.starts_with(|c: char| c.is_ascii_digit() || "(-+~!".contains(c))
+            && text.contains(|c| "+-*/%()<>=!&|^~".contains(c))
+        {
+            preprocessor::evaluate(text)
+        } else {
+            Err(error)
+        }
+    })
+}
+
+// Whether an operand is a reference to a label rather than a number. Numeric label references
+// like "1b" start with a digit.
+pub(crate) fn is_label(operand: &str) -> bool {
+    let digits = operand.trim_end_matches(['b', 'f']);
+    is_identifier(operand)
+        || (operand.len() == digits.len() + 1
+            && !digits.is_empty()
+            && digits.chars().all(|c| c.is_ascii_digit()))
+}
+
+// $ and $$, the addresses of the line and of its section, are referenced like labels.
+fn is_location(text: &str) -> bool {
+    text == "$" || text == "$$"
+}
+
+fn segment_prefix(name: &str) -> Option<u8> {
+    match name {
+        "es" => Some(0x26),
+        "cs" => Some(0x2e),
+        "ss" => Some(0x36),
+        "ds" => Some(0x3e),
+        "fs" => Some(0x64),
+        "gs" => Some(0x65),
+        _ => None,
+    }
+}
+
+fn size_keyword(word: &str) -> Option<Size> {
+    match word.to_lowercase().as_str() {
+        "byte" => Some(Size::Byte),
+        "word" => Some(Size::Word),
+        "dword" => Some(Size::Dword),
+        "qword" => Some(Size::Qword),
+        _ => None,
+    }
+}
+
+fn parse_memory(text: &str, size: Option<Size>) -> Result<MemoryOperand, ErrorKind> {
+    let mut memory = MemoryOperand {
+        size,
+        segment: None,
+        base: None,
+        index: None,
+        scale: 1,
+        displacement: 0,
+        label: None,
+        relative: false,
+    };
+    let mut inner = text.trim();
+    // The segment may be written inside or outside of the brackets.
+    if let Some(colon) = inner.find(':') {
+        let segment = inner[..colon].trim().trim_start_matches('[').trim();
+        memory.segment =
+            Some(segment_prefix(segment).ok_or_else(|| invalid("invalid segment register"))?);
+        inner = &inner[colon + 1..];
+        if text.trim().starts_with('[') {
+            inner = inner.trim_end_matches(']');
+        }
+    }
+    // GAS writes a displacement or label in front of the brackets, like "-8[rbp]" or
+    // "counter[rip]", and allows leaving the brackets out, like "fs:40".
+    let inner = inner.trim();
+    let combined;
+    let inner = match inner.find('[') {
+        Some(open) if open > 0 && inner.ends_with(']') => {
+            combined = format!("{}+{}", &inner[..open], &inner[open + 1..inner.len() - 1]);
+            combined.as_str()
+        }
+        _ => inner.trim_start_matches('[').trim_end_matches(']').trim(),
+    };
+    let inner = match inner.strip_prefix("rel ") {
+        Some(rest) => {
+            memory.relative = true;
+            rest
+        }
+        None => inner.strip_prefix("abs ").unwrap_or(inner),
+    };
+
+    // Split into terms, keeping their signs. Parenthesized expressions are single terms.
+    let mut terms = vec![];
+    let mut start = 0;
+    let mut negative = false;
+    let mut depth = 0;
+    for (i, c) in inner
+        .char_indices()
+        .chain(std::iter::once((inner.len(), '+')))
+    {
+        if c == '(' {
+            depth += 1;
+        } else if c == ')' {
+            depth -= 1;
+        } else if depth != 0 {
+            continue;
+        }
+        if (c == '+' || c == '-') && i > 0 {
+            terms.push((negative, inner[start..i].trim()));
+            negative = c == '-';
+            start = i + 1;
+        } else if c == '-' && i == 0 {
+            negative = true;
+            start = 1;
+        }
+    }
+    for (negative, term) in terms {
+        if term.is_empty() {
+            return Err(invalid("invalid memory operand"));
+        }
+        let (register, scale) = match term.find('*') {
+            Some(star) => {
+                let (left, right) = (term[..star].trim(), term[star + 1..].trim());
+                match (Register::from_name(left), Register::from_name(right)) {
+                    (Some(register), None) => (Some(register), Some(right)),
+                    (None, Some(register)) => (Some(register), Some(left)),
+                    (Some(_), Some(_)) => return Err(invalid("invalid memory operand")),
+                    // A product of constants.
+                    (None, None) => (None, None),
+                }
+            }
+            None => (Register::from_name(term), None),
+        };
+        if term.eq_ignore_ascii_case("rip") {
+            // GAS's "label[rip]" is "[rel label]".
+            if negative || memory.relative {
+                return Err(invalid("invalid memory operand"));
+            }
+            memory.relative = true;
+        } else if let Some(register) = register {
+            if negative || register.size == Size::Byte {
+                return Err(invalid("invalid memory operand"));
+            }
+            let scale = match scale {
+                Some(scale) => parse_value(scale)?,
+                None => 1,
+            };
+            if !matches!(scale, 1 | 2 | 4 | 8) {
+                return Err(invalid("invalid scale"));
+            }
+            if scale == 1 && memory.base.is_none() {
+                memory.base = Some(register);
+            } else if memory.index.is_none() {
+                memory.index = Some(register);
+                memory.scale = scale as u8;
+            } else {
+                return Err(invalid("too many registers in memory operand"));
+            }
+        } else if (is_label(term) && !term.starts_with(|c: char| c.is_ascii_digit()))
+            || is_location(term)
+        {
+            if negative || memory.label.is_some() {
+                return Err(invalid("invalid memory operand"));
+            }
+            memory.label = Some(term.to_string());
+        } else {
+            let value = parse_value(term)?;
+            memory.displacement += if negative { -value } else { value };
+        }
+    }
+    // [esp] can only be a base.
+    if let (Some(base), Some(index)) = (memory.base, memory.index) {
+        if index.number == 4 && memory.scale == 1 {
+            memory.base = Some(index);
+            memory.index = Some(base);
+        }
+    }
+    if memory.relative && (memory.base.is_some() || memory.index.is_some()) {
+        return Err(invalid("rel cannot be combined with registers"));
+    }
+    Ok(memory)
+}
+
+fn parse_operand(text: &str) -> Result<Parsed, ErrorKind> {
+    let mut text = text.trim();
+    let mut size = None;
+    let mut short = false;
+    // GAS's "DWORD PTR x" is a memory operand even without brackets.
+    let mut ptr = false;
+    loop {
+        let word = text.split_whitespace().next().unwrap_or("");
+        if let Some(keyword) = size_keyword(word) {
+            size = Some(keyword);
+        } else if word == "short" {
+            short = true;
+        } else if word.eq_ignore_ascii_case("ptr") {
+            ptr = true;
+        } else if word.eq_ignore_ascii_case("offset") {
+            // GAS's "OFFSET FLAT:label" is the address of the label.
+            text = text[word.len()..].trim_start();
+            text = text.strip_prefix("FLAT:").unwrap_or(text);
+            continue;
+        } else if word != "near" {
+            break;
+        }
+        text = text[word.len()..].trim_start();
+    }
+    let operand = if text.contains('[') || ptr {
+        Operand::Memory(parse_memory(text, size)?)
+    } else if let Some(register) = Register::from_name(text) {
+        Operand::Register(register)
+    } else if is_label(text) || is_location(text) {
+        Operand::Label(text.to_string())
+    } else {
+        Operand::Immediate(parse_value(text)?)
+    };
+    Ok(Parsed {
+        operand,
+        size,
+        short,
+    })
+}
+
+fn fits_i8(value: i64) -> bool {
+    i8::try_from(value).is_ok()
+}
+
+fn fits_i32(value: i64) -> bool {
+    i32::try_from(value).is_ok()
+}
+
+// Whether an immediate fits into `size`, either signed or unsigned.
+fn fits(value: i64, size: Size) -> bool {
+    match size {
+        Size::Qword => true,
+        _ => (-(1 << (size.bits() - 1))..1 << size.bits()).contains(&value),
+    }
+}
+
+// The number of a register in instruction encodings, where ah, ch, dh and bh take the place of
+// spl, bpl, sil and dil.
+fn code(register: Register) -> u8 {
+    if register.high_byte {
+        register.number + 4
+    } else {
+        register.number
+    }
+}
+
+// Either a register or a memory operand, encoded in the r/m field of the ModRM byte.
+enum Rm<'a> {
+    Register(Register),
+    Memory(&'a MemoryOperand),
+}
+
+// Collects the parts of an instruction in the order they are emitted.
+struct Encoder {
+    bits: u32,
+    prefixes: Vec<u8>,
+    rex: u8,
+    // Whether a REX prefix is required or forbidden regardless of its bits, because of
+    // registers like sil or ah.
+    rex_required: bool,
+    rex_forbidden: bool,
+    opcode: Vec<u8>,
+    modrm: Option<u8>,
+    sib: Option<u8>,
+    displacement: Vec<u8>,
+    displacement_fixup: Option<(RelocationType, String, i64)>,
+    immediate: Vec<u8>,
+    immediate_fixup: Option<(RelocationType, String, i64)>,
+}
+
+impl Encoder {
+    fn new(bits: u32) -> Encoder {
+        Encoder {
+            bits,
+            prefixes: vec![],
+            rex: 0,
+            rex_required: false,
+            rex_forbidden: false,
+            opcode: vec![],
+            modrm: None,
+            sib: None,
+            displacement: vec![],
+            displacement_fixup: None,
+            immediate: vec![],
+            immediate_fixup: None,
+        }
+    }
+
+    fn check_register(&mut self, register: Register) -> Result<(), ErrorKind> {
+        if self.bits != 64 && (register.size == Size::Qword || register.needs_rex()) {
+            return Err(not_in_mode(&format!("register {}", register), self.bits));
+        }
+        self.rex_required |= register.needs_rex();
+        self.rex_forbidden |= register.high_byte;
+        Ok(())
+    }
+
+    // Adds the operand size prefix or REX.W for instructions whose default operand size is
+    // 32 bits (16 bits in 16-bit mode).
+    fn operand_size(&mut self, size: Size) -> Result<(), ErrorKind> {
+        match size {
+            Size::Byte => {}
+            Size::Word if self.bits != 16 => self.prefixes.push(0x66),
+            Size::Dword if self.bits == 16 => self.prefixes.push(0x66),
+            Size::Qword if self.bits != 64 => {
+                return Err(not_in_mode("64-bit operand size", self.bits))
+            }
+            Size::Qword => self.rex |= 0x48,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Like operand_size() for push, pop and near branches, which default to 64 bits in 64-bit
+    // mode and can't use 32 bits there.
+    fn stack_operand_size(&mut self, size: Size) -> Result<(), ErrorKind> {
+        match (self.bits, size) {
+            (64, Size::Qword) | (32, Size::Dword) | (16, Size::Word) => Ok(()),
+            (64, Size::Word) | (32, Size::Word) => {
+                self.prefixes.push(0x66);
+                Ok(())
+            }
+            (16, Size::Dword) => {
+                self.prefixes.push(0x66);
+                Ok(())
+            }
+            _ => Err(not_in_mode(
+                &format!("{}-bit stack operand", size.bits()),
+                self.bits,
+            )),
+        }
+    }
+
+    fn register_in_opcode(&mut self, opcode: u8, register: Register) -> Result<(), ErrorKind> {
+        self.check_register(register)?;
+        if register.number >= 8 {
+            self.rex |= 0x41;
+        }
+        self.opcode.push(opcode + (code(register) & 7));
+        Ok(())
+    }
+
+    fn modrm(&mut self, reg: u8, rm: Rm) -> Result<(), ErrorKind> {
+        match rm {
+            Rm::Register(register) => {
+                self.check_register(register)?;
+                if register.number >= 8 {
+                    self.rex |= 0x41;
+                }
+                self.modrm = Some(0xc0 | (reg & 7) << 3 | (code(register) & 7));
+                Ok(())
+            }
+            Rm::Memory(memory) => self.memory(reg, memory),
+        }
+    }
+
+    // Sets the ModRM byte's reg field to a register, with REX.R if needed.
+    fn modrm_register(&mut self, register: Register, rm: Rm) -> Result<(), ErrorKind> {
+        self.check_register(register)?;
+        if register.number >= 8 {
+            self.rex |= 0x44;
+        }
+        self.modrm(code(register), rm)
+    }
+
+    fn displacement(&mut self, memory: &MemoryOperand, size: Size, typ: RelocationType) {
+        let bytes = memory.displacement.to_le_bytes();
+        self.displacement.extend_from_slice(&bytes[..size.bytes()]);
+        if let Some(label) = &memory.label {
+            self.displacement_fixup = Some((typ, label.clone(), memory.displacement));
+        }
+    }
+
+    fn memory(&mut self, reg: u8, memory: &MemoryOperand) -> Result<(), ErrorKind> {
+        if let Some(segment) = memory.segment {
+            self.prefixes.push(segment);
+        }
+        let address_size = memory.base.or(memory.index).map(|r| r.size);
+        let default_size = match self.bits {
+            16 => Size::Word,
+            32 => Size::Dword,
+            _ => Size::Qword,
+        };
+        let address_size = address_size.unwrap_or(default_size);
+        match (self.bits, address_size) {
+            (64, Size::Qword) | (32, Size::Dword) | (16, Size::Word) => {}
+            (64, Size::Dword) | (32, Size::Word) | (16, Size::Dword) => self.prefixes.push(0x67),
+            _ => {
+                return Err(not_in_mode(
+                    &format!("{}-bit addressing", address_size.bits()),
+                    self.bits,
+                ))
+            }
+        }
+        if memory.relative {
+            if self.bits != 64 {
+                return Err(not_in_mode("rel addressing", self.bits));
+            }
+            self.modrm = Some((reg & 7) << 3 | 0b101);
+            self.displacement(memory, Size::Dword, RelocationType::Pc32);
+            return Ok(());
+        }
+        if address_size == Size::Word {
+            return self.memory16(reg, memory);
+        }
+
+        for register in memory.base.iter().chain(memory.index.iter()) {
+            if register.size != address_size {
+                return Err(invalid("mismatched address registers"));
+            }
+            self.check_register(*register)?;
+        }
+        let absolute = if self.bits == 64 {
+            RelocationType::S32
+        } else {
+            RelocationType::U32
+        };
+        let reg = (reg & 7) << 3;
+        let displacement_size = if memory.label.is_some() {
+            Size::Dword
+        } else if fits_i8(memory.displacement) {
+            Size::Byte
+        } else if fits_i32(memory.displacement) || self.bits != 64 {
+            Size::Dword
+        } else {
+            return Err(ErrorKind::ValueOutOfRange(memory.displacement.to_string()));
+        };
+        let mode = match (memory.displacement, displacement_size) {
+            (0, Size::Byte) => 0b00,
+            (_, Size::Byte) => 0b01,
+            _ => 0b10,
+        };
+
+        match (memory.base, memory.index) {
+            (None, None) => {
+                // In 64-bit mode, mod 00 r/m 101 is RIP-relative, so absolute addresses need a
+                // SIB byte.
+                if self.bits == 64 {
+                    self.modrm = Some(reg | 0b100);
+                    self.sib = Some(0b00_100_101);
+                } else {
+                    self.modrm = Some(reg | 0b101);
+                }
+                self.displacement(memory, Size::Dword, absolute);
+            }
+            (Some(base), None) if base.number & 7 != 4 => {
+                // [ebp] and [r13] can't be encoded without a displacement.
+                let mode = if base.number & 7 == 5 && mode == 0 {
+                    0b01
+                } else {
+                    mode
+                };
+                if base.number >= 8 {
+                    self.rex |= 0x41;
+                }
+                self.modrm = Some(mode << 6 | reg | (base.number & 7));
+                if mode != 0 {
+                    let size = if mode == 1 { Size::Byte } else { Size::Dword };
+                    self.displacement(memory, size, absolute);
+                }
+            }
+            (base, index) => {
+                if let Some(index) = index {
+                    if index.number == 4 {
+                        return Err(invalid("esp can't be an index register"));
+                    }
+                    if index.number >= 8 {
+                        self.rex |= 0x42;
+                    }
+                }
+                let index_bits = index.map_or(0b100, |i| i.number & 7);
+                let scale_bits = match memory.scale {
+                    1 => 0,
+                    2 => 1,
+                    4 => 2,
+                    _ => 3,
+                };
+                match base {
+                    None => {
+                        self.modrm = Some(reg | 0b100);
+                        self.sib = Some(scale_bits << 6 | index_bits << 3 | 0b101);
+                        self.displacement(memory, Size::Dword, absolute);
+                    }
+                    Some(base) => {
+                        let mode = if base.number & 7 == 5 && mode == 0 {
+                            0b01
+                        } else {
+                            mode
+                        };
+                        if base.number >= 8 {
+                            self.rex |= 0x41;
+                        }
+                        self.modrm = Some(mode << 6 | reg | 0b100);
+                        self.sib = Some(scale_bits << 6 | index_bits << 3 | (base.number & 7));
+                        if mode != 0 {
+                            let size = if mode == 1 { Size::Byte } else { Size::Dword };
+                            self.displacement(memory, size, absolute);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 16-bit addressing only allows bx or bp, optionally with si or di.
+    fn memory16(&mut self, reg: u8, memory: &MemoryOperand) -> Result<(), ErrorKind> {
+        let name = |r: Option<Register>| r.map(|r| r.name());
+        let (first, second) = match (name(memory.base), name(memory.index)) {
+            (Some(base), Some(index)) if memory.scale == 1 => (Some(base), Some(index)),
+            (base, None) => (base, None),
+            _ => return Err(invalid("invalid 16-bit addressing")),
+        };
+        let rm = match (first, second) {
+            (Some("bx"), Some("si")) | (Some("si"), Some("bx")) => 0,
+            (Some("bx"), Some("di")) | (Some("di"), Some("bx")) => 1,
+            (Some("bp"), Some("si")) | (Some("si"), Some("bp")) => 2,
+            (Some("bp"), Some("di")) | (Some("di"), Some("bp")) => 3,
+            (Some("si"), None) => 4,
+            (Some("di"), None) => 5,
+            (Some("bp"), None) => 6,
+            (Some("bx"), None) => 7,
+            (None, None) => {
+                self.modrm = Some((reg & 7) << 3 | 0b110);
+                self.displacement(memory, Size::Word, RelocationType::U16);
+                return Ok(());
+            }
+            _ => return Err(invalid("invalid 16-bit addressing")),
+        };
+        let mode = if memory.label.is_some() || !fits_i8(memory.displacement) {
+            0b10
+        } else if memory.displacement != 0 || rm == 6 {
+            0b01
+        } else {
+            0b00
+        };
+        self.modrm = Some(mode << 6 | (reg & 7) << 3 | rm);
+        match mode {
+            0b01 => self.displacement(memory, Size::Byte, RelocationType::U16),
+            0b10 => self.displacement(memory, Size::Word, RelocationType::U16),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn immediate(&mut self, operand: &Operand, size: Size) -> Result<(), ErrorKind> {
+        match operand {
+            Operand::Immediate(value) => {
+                if !fits(*value, size) {
+                    return Err(ErrorKind::ValueOutOfRange(value.to_string()));
+                }
+                self.immediate
+                    .extend_from_slice(&value.to_le_bytes()[..size.bytes()]);
+            }
+            Operand::Label(label) => {
+                let typ = match size {
+                    Size::Word => RelocationType::U16,
+                    Size::Dword if self.rex & 0x08 != 0 => RelocationType::S32,
+                    Size::Dword => RelocationType::U32,
+                    Size::Qword => RelocationType::U64,
+                    Size::Byte => return Err(invalid("label in 8-bit immediate")),
+                };
+                self.immediate.extend_from_slice(&vec![0; size.bytes()]);
+                self.immediate_fixup = Some((typ, label.clone(), 0));
+            }
+            _ => return Err(invalid("expected an immediate")),
+        }
+        Ok(())
+    }
+
+    // A relative branch target, with a field of `size` bytes.
+    fn relative(&mut self, label: &str, size: Size) {
+        let typ = match size {
+            Size::Byte => RelocationType::Pc8,
+            Size::Word => RelocationType::Pc16,
+            _ => RelocationType::Pc32,
+        };
+        self.immediate.extend_from_slice(&vec![0; size.bytes()]);
+        self.immediate_fixup = Some((typ, label.to_string(), 0));
+    }
+
+    fn finish(self) -> Result<Encoding, ErrorKind> {
+        let mut bytes = self.prefixes;
+        if self.rex != 0 || self.rex_required {
+            if self.rex_forbidden {
+                return Err(invalid("ah, bh, ch and dh can't be used with a REX prefix"));
+            }
+            bytes.push(self.rex | 0x40);
+        }
+        bytes.extend_from_slice(&self.opcode);
+        bytes.extend(self.modrm);
+        bytes.extend(self.sib);
+        let mut fixups = vec![];
+        let displacement_offset = bytes.len();
+        bytes.extend_from_slice(&self.displacement);
+        let immediate_offset = bytes.len();
+        bytes.extend_from_slice(&self.immediate);
+        for (offset, fixup) in &[
+            (displacement_offset, self.displacement_fixup),
+            (immediate_offset, self.immediate_fixup),
+        ] {
+            if let Some((typ, label, mut addend)) = fixup.clone() {
+                if typ.is_relative() {
+                    addend -= (bytes.len() - offset) as i64;
+                }
+                fixups.push(Fixup {
+                    offset: *offset,
+                    typ,
+                    label,
+                    addend,
+                });
+            }
+        }
+        Ok(Encoding { bytes, fixups })
+    }
+}
+
+// Returns the operand size of an instruction from its register operands or size keywords.
+// A label as the first operand is most likely a misspelled register.
+fn operand_size(operands: &[&Parsed]) -> Result<Size, ErrorKind> {
+    if let Operand::Label(name) = &operands[0].operand {
+        return Err(ErrorKind::UnknownRegister(name.clone()));
+    }
+    let mut size = None;
+    for parsed in operands {
+        let operand_size = match &parsed.operand {
+            Operand::Register(register) => Some(register.size),
+            Operand::Memory(memory) => memory.size,
+            _ => parsed.size,
+        };
+        match (size, operand_size) {
+            (Some(a), Some(b)) if a != b => return Err(invalid("mismatched operand sizes")),
+            (None, Some(_)) => size = operand_size,
+            _ => {}
+        }
+    }
+    size.ok_or_else(|| invalid("operation size not specified"))
+}
+
+fn rm(operand: &Operand) -> Option<Rm<'_>> {
+    match operand {
+        Operand::Register(register) => Some(Rm::Register(*register)),
+        Operand::Memory(memory) => Some(Rm::Memory(memory)),
+        _ => None,
+    }
+}
+
+fn is_immediate(operand: &Operand) -> bool {
+    matches!(operand, Operand::Immediate(_) | Operand::Label(_))
+}
+
+// Size of immediates for operations on `size`; 64-bit operations use sign-extended 32-bit
+// immediates.
+fn immediate_size(size: Size) -> Size {
+    match size {
+        Size::Qword => Size::Dword,
+        size => size,
+    }
+}
+
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFTS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const UNARY: [&str; 6] = ["not", "neg", "mul", "imul", "div", "idiv"];
+
+// Returns the condition code of a jcc mnemonic, including aliases like jz.
+fn condition(mnemonic: &str) -> Option<u8> {
+    let suffix = mnemonic.strip_prefix('j')?;
+    let suffix = match suffix {
+        "z" => "e",
+        "nz" => "ne",
+        "c" | "nae" => "b",
+        "nc" | "nb" => "ae",
+        "na" => "be",
+        "nbe" => "a",
+        "pe" => "p",
+        "po" => "np",
+        "nge" => "l",
+        "nl" => "ge",
+        "ng" => "le",
+        "nle" => "g",
+        suffix => suffix,
+    };
+    CONDITIONS
+        .iter()
+        .position(|&c| c == suffix)
+        .map(|c| c as u8)
+}
+
+// Instructions without operands, with the modes they are invalid in.
+const SIMPLE: [(&str, &[u8], &[u32]); 23] = [
+    ("ret", &[0xc3], &[]),
+    ("syscall", &[0x0f, 0x05], &[]),
+    ("nop", &[0x90], &[]),
+    ("hlt", &[0xf4], &[]),
+    ("leave", &[0xc9], &[]),
+    ("int3", &[0xcc], &[]),
+    ("cli", &[0xfa], &[]),
+    ("sti", &[0xfb], &[]),
+    ("clc", &[0xf8], &[]),
+    ("stc", &[0xf9], &[]),
+    ("cld", &[0xfc], &[]),
+    ("std", &[0xfd], &[]),
+    ("ud2", &[0x0f, 0x0b], &[]),
+    ("cpuid", &[0x0f, 0xa2], &[]),
+    ("rdtsc", &[0x0f, 0x31], &[]),
+    ("pushf", &[0x9c], &[]),
+    ("popf", &[0x9d], &[]),
+    ("pushfq", &[0x9c], &[16, 32]),
+    ("popfq", &[0x9d], &[16, 32]),
+    ("pusha", &[0x60], &[64]),
+    ("popa", &[0x61], &[64]),
+    ("cqo", &[0x48, 0x99], &[16, 32]),
+    ("cdqe", &[0x48, 0x98], &[16, 32]),
+];
+
+fn simple(mnemonic: &str, bits: u32) -> Option<Result<Vec<u8>, ErrorKind>> {
+    let (_, bytes, invalid_in) = SIMPLE.iter().find(|(m, _, _)| *m == mnemonic)?;
+    if invalid_in.contains(&bits) {
+        return Some(Err(not_in_mode(mnemonic, bits)));
+    }
+    Some(Ok(bytes.to_vec()))
+}
+
+// Instructions without operands whose encoding depends on the operand size, like cwd and cdq.
+const SIZED: [(&str, u8, Size); 4] = [
+    ("cbw", 0x98, Size::Word),
+    ("cwde", 0x98, Size::Dword),
+    ("cwd", 0x99, Size::Word),
+    ("cdq", 0x99, Size::Dword),
+];
+
+fn sized(mnemonic: &str) -> Option<(u8, Size)> {
+    SIZED
+        .iter()
+        .find(|(m, _, _)| *m == mnemonic)
+        .map(|&(_, opcode, size)| (opcode, size))
+}
+
+// Mnemonics handled by encode() itself rather than a table.
+const OTHERS: [&str; 13] = [
+    "mov", "test", "xchg", "inc", "dec", "push", "pop", "lea", "movzx", "movsx", "jmp", "call",
+    "int",
+];
+
+// The jcc mnemonics, without aliases like jz.
+const JCC: [&str; 16] = [
+    "jo", "jno", "jb", "jae", "je", "jne", "jbe", "ja", "js", "jns", "jp", "jnp", "jl", "jge",
+    "jle", "jg",
+];
+
+// All mnemonics the encoder knows, with the condition codes of jcc spelled out.
+pub(crate) fn mnemonics() -> Vec<&'static str> {
+    let mut ret: Vec<&str> = SIMPLE.iter().map(|(m, _, _)| *m).collect();
+    ret.extend(SIZED.iter().map(|(m, _, _)| *m));
+    ret.extend_from_slice(&ALU);
+    ret.extend_from_slice(&SHIFTS);
+    ret.extend_from_slice(&UNARY);
+    ret.extend_from_slice(&OTHERS);
+    ret.extend_from_slice(&JCC);
+    ret
+}
+
+// The operand forms a mnemonic accepts, for help texts. An empty form means no operands.
+pub(crate) fn operand_forms(mnemonic: &str) -> Option<&'static [&'static str]> {
+    let mnemonic = mnemonic.to_lowercase();
+    let mnemonic = mnemonic.as_str();
+    let forms: &[&str] = if simple(mnemonic, 64).is_some() || sized(mnemonic).is_some() {
+        &[""]
+    } else if ALU.contains(&mnemonic) {
+        &["r/m, reg", "reg, r/m", "r/m, imm"]
+    } else if SHIFTS.contains(&mnemonic) {
+        &["r/m, 1", "r/m, cl", "r/m, imm8"]
+    } else if mnemonic == "imul" {
+        &["r/m", "reg, r/m", "reg, r/m, imm"]
+    } else if UNARY.contains(&mnemonic) {
+        &["r/m"]
+    } else if condition(mnemonic).is_some() {
+        &["label"]
+    } else {
+        match mnemonic {
+            "mov" => &["r/m, reg", "reg, r/m", "reg, imm", "r/m, imm"],
+            "test" => &["r/m, reg", "r/m, imm"],
+            "xchg" => &["r/m, reg", "reg, r/m"],
+            "inc" | "dec" => &["r/m"],
+            "push" => &["reg", "mem", "imm"],
+            "pop" => &["reg", "mem"],
+            "lea" => &["reg, mem"],
+            "movzx" | "movsx" => &["reg, r/m8", "reg, r/m16"],
+            "jmp" | "call" => &["label", "r/m"],
+            "int" => &["imm8"],
+            _ => return None,
+        }
+    };
+    Some(forms)
+}
+
+fn expect(operands: &[Parsed], expected: usize) -> Result<(), ErrorKind> {
+    if operands.len() == expected {
+        Ok(())
+    } else {
+        Err(ErrorKind::OperandCount {
+            expected,
+            found: operands.len(),
+        })
+    }
+}
+
+pub(crate) fn encode(mnemonic: &str, operands: &[&str], bits: u32) -> Result<Encoding, ErrorKind> {
+    let mnemonic = mnemonic.to_lowercase();
+    let mnemonic = mnemonic.as_str();
+    let operands = operands
+        .iter()
+        .filter(|o| !o.is_empty())
+        .map(|o| parse_operand(o))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut e = Encoder::new(bits);
+
+    if let Some(bytes) = simple(mnemonic, bits) {
+        expect(&operands, 0)?;
+        return Ok(Encoding {
+            bytes: bytes?,
+            fixups: vec![],
+        });
+    }
+    if let Some((opcode, size)) = sized(mnemonic) {
+        expect(&operands, 0)?;
+        e.operand_size(size)?;
+        e.opcode.push(opcode);
+        return e.finish();
+    }
+
+    // Reports an unknown register, for identifiers that can't be a label in this position.
+    let unknown_register = |operand: &Operand| match operand {
+        Operand::Label(name) => ErrorKind::UnknownRegister(name.clone()),
+        _ => invalid("invalid combination of opcode and operands"),
+    };
+
+    if let Some(k) = ALU.iter().position(|&m| m == mnemonic) {
+        expect(&operands, 2)?;
+        let k = k as u8;
+        let (destination, source) = (&operands[0], &operands[1]);
+        let size = operand_size(&[destination, source])?;
+        let byte = (size != Size::Byte) as u8;
+        e.operand_size(size)?;
+        match (&destination.operand, &source.operand) {
+            (Operand::Register(_), Operand::Register(register))
+            | (Operand::Memory(_), Operand::Register(register)) => {
+                e.opcode.push(k * 8 + byte);
+                e.modrm_register(*register, rm(&destination.operand).unwrap())?;
+            }
+            (Operand::Register(register), Operand::Memory(memory)) => {
+                e.opcode.push(k * 8 + 2 + byte);
+                e.modrm_register(*register, Rm::Memory(memory))?;
+            }
+            (Operand::Register(_), source) | (Operand::Memory(_), source)
+                if is_immediate(source) =>
+            {
+                let accumulator = matches!(destination.operand,
+                    Operand::Register(r) if r.number == 0 && !r.high_byte);
+                let small = matches!(source, Operand::Immediate(v) if fits_i8(*v));
+                if size == Size::Byte && accumulator {
+                    e.opcode.push(k * 8 + 4);
+                    e.immediate(source, Size::Byte)?;
+                } else if size == Size::Byte {
+                    e.opcode.push(0x80);
+                    e.modrm(k, rm(&destination.operand).unwrap())?;
+                    e.immediate(source, Size::Byte)?;
+                } else if small {
+                    e.opcode.push(0x83);
+                    e.modrm(k, rm(&destination.operand).unwrap())?;
+                    e.immediate(source, Size::Byte)?;
+                } else if accumulator {
+                    e.opcode.push(k * 8 + 5);
+                    if let Operand::Register(register) = destination.operand {
+                        e.check_register(register)?;
+                    }
+                    e.immediate(source, immediate_size(size))?;
+                } else {
+                    e.opcode.push(0x81);
+                    e.modrm(k, rm(&destination.operand).unwrap())?;
+                    e.immediate(source, immediate_size(size))?;
+                }
+            }
+            (destination, _) => return Err(unknown_register(destination)),
+        }
+        return e.finish();
+    }
+
+    if let Some(k) = SHIFTS.iter().position(|&m| m == mnemonic) {
+        expect(&operands, 2)?;
+        let k = match k {
+            6 => 4,
+            k => k as u8,
+        };
+        let size = operand_size(&[&operands[0]])?;
+        let byte = (size != Size::Byte) as u8;
+        e.operand_size(size)?;
+        let destination =
+            rm(&operands[0].operand).ok_or_else(|| unknown_register(&operands[0].operand))?;
+        match &operands[1].operand {
+            Operand::Immediate(1) => e.opcode.push(0xd0 + byte),
+            Operand::Register(r) if r.name() == "cl" => e.opcode.push(0xd2 + byte),
+            Operand::Immediate(_) => {
+                e.opcode.push(0xc0 + byte);
+                e.immediate(&operands[1].operand, Size::Byte)?;
+            }
+            _ => return Err(invalid("shift count must be an immediate or cl")),
+        }
+        e.modrm(k, destination)?;
+        return e.finish();
+    }
+
+    if let Some(k) = UNARY.iter().position(|&m| m == mnemonic) {
+        if mnemonic == "imul" && operands.len() > 1 {
+            return imul(e, &operands);
+        }
+        expect(&operands, 1)?;
+        let size = operand_size(&[&operands[0]])?;
+        e.operand_size(size)?;
+        e.opcode.push(0xf6 + (size != Size::Byte) as u8);
+        let operand =
+            rm(&operands[0].operand).ok_or_else(|| unknown_register(&operands[0].operand))?;
+        e.modrm(k as u8 + 2, operand)?;
+        return e.finish();
+    }
+
+    if let Some(cc) = condition(mnemonic) {
+        expect(&operands, 1)?;
+        let label = match &operands[0].operand {
+            Operand::Label(label) => label,
+            _ => return Err(invalid("conditional jumps need a label")),
+        };
+        if operands[0].short {
+            e.opcode.push(0x70 + cc);
+            e.relative(label, Size::Byte);
+        } else {
+            e.opcode.extend_from_slice(&[0x0f, 0x80 + cc]);
+            e.relative(label, if bits == 16 { Size::Word } else { Size::Dword });
+        }
+        return e.finish();
+    }
+
+    match mnemonic {
+        "mov" => {
+            expect(&operands, 2)?;
+            let (destination, source) = (&operands[0], &operands[1]);
+            let size = operand_size(&[destination, source])?;
+            let byte = (size != Size::Byte) as u8;
+            match (&destination.operand, &source.operand) {
+                (Operand::Register(_), Operand::Register(register))
+                | (Operand::Memory(_), Operand::Register(register)) => {
+                    e.operand_size(size)?;
+                    e.opcode.push(0x88 + byte);
+                    e.modrm_register(*register, rm(&destination.operand).unwrap())?;
+                }
+                (Operand::Register(register), Operand::Memory(memory)) => {
+                    e.operand_size(size)?;
+                    e.opcode.push(0x8a + byte);
+                    e.modrm_register(*register, Rm::Memory(memory))?;
+                }
+                (Operand::Register(register), source) if is_immediate(source) => {
+                    match (size, source) {
+                        // Like nasm, use the shortest encoding for 64-bit immediates: writing
+                        // a 32-bit register zero-extends, C7 sign-extends a 32-bit immediate.
+                        (Size::Qword, Operand::Immediate(value))
+                            if bits == 64 && u32::try_from(*value).is_ok() =>
+                        {
+                            e.register_in_opcode(
+                                0xb8,
+                                Register::new(register.number, Size::Dword),
+                            )?;
+                            e.immediate(source, Size::Dword)?;
+                        }
+                        (Size::Qword, Operand::Immediate(value))
+                            if bits == 64 && fits_i32(*value) =>
+                        {
+                            e.operand_size(size)?;
+                            e.opcode.push(0xc7);
+                            e.modrm(0, Rm::Register(*register))?;
+                            e.immediate(source, Size::Dword)?;
+                        }
+                        _ => {
+                            e.operand_size(size)?;
+                            let opcode = if size == Size::Byte { 0xb0 } else { 0xb8 };
+                            e.register_in_opcode(opcode, *register)?;
+                            e.immediate(source, size)?;
+                        }
+                    }
+                }
+                (Operand::Memory(memory), source) if is_immediate(source) => {
+                    e.operand_size(size)?;
+                    e.opcode.push(0xc6 + byte);
+                    e.memory(0, memory)?;
+                    e.immediate(source, immediate_size(size))?;
+                }
+                (destination, _) => return Err(unknown_register(destination)),
+            }
+            e.finish()
+        }
+        "test" => {
+            expect(&operands, 2)?;
+            let (destination, source) = (&operands[0], &operands[1]);
+            let size = operand_size(&[destination, source])?;
+            let byte = (size != Size::Byte) as u8;
+            e.operand_size(size)?;
+            match (&destination.operand, &source.operand) {
+                (Operand::Register(_), Operand::Register(register))
+                | (Operand::Memory(_), Operand::Register(register)) => {
+                    e.opcode.push(0x84 + byte);
+                    e.modrm_register(*register, rm(&destination.operand).unwrap())?;
+                }
+                (Operand::Register(register), source)
+                    if is_immediate(source) && register.number == 0 && !register.high_byte =>
+                {
+                    e.check_register(*register)?;
+                    e.opcode.push(0xa8 + byte);
+                    e.immediate(source, immediate_size(size))?;
+                }
+                (destination, source) if is_immediate(source) => {
+                    e.opcode.push(0xf6 + byte);
+                    let operand = rm(destination).ok_or_else(|| unknown_register(destination))?;
+                    e.modrm(0, operand)?;
+                    e.immediate(source, immediate_size(size))?;
+                }
+                (destination, _) => return Err(unknown_register(destination)),
+            }
+            e.finish()
+        }
+        "xchg" => {
+            expect(&operands, 2)?;
+            let (first, second) = (&operands[0], &operands[1]);
+            let size = operand_size(&[first, second])?;
+            e.operand_size(size)?;
+            match (&first.operand, &second.operand) {
+                // The short form with eax, except for xchg eax, eax, which is nop in 64-bit
+                // mode instead of clearing the upper half of rax.
+                (Operand::Register(a), Operand::Register(b))
+                    if size != Size::Byte
+                        && (a.number == 0 || b.number == 0)
+                        && !(bits == 64 && size == Size::Dword && a.number == b.number) =>
+                {
+                    let other = if a.number == 0 { *b } else { *a };
+                    e.register_in_opcode(0x90, other)?;
+                }
+                (operand, Operand::Register(register)) | (Operand::Register(register), operand) => {
+                    e.opcode.push(0x86 + (size != Size::Byte) as u8);
+                    let operand = rm(operand).ok_or_else(|| unknown_register(operand))?;
+                    e.modrm_register(*register, operand)?;
+                }
+                _ => return Err(invalid("invalid combination of opcode and operands")),
+            }
+            e.finish()
+        }
+        "inc" | "dec" => {
+            expect(&operands, 1)?;
+            let k = (mnemonic == "dec") as u8;
+            let size = operand_size(&[&operands[0]])?;
+            e.operand_size(size)?;
+            match &operands[0].operand {
+                // 40+r and 48+r are REX prefixes in 64-bit mode.
+                Operand::Register(register) if bits != 64 && size != Size::Byte => {
+                    e.register_in_opcode(0x40 + 8 * k, *register)?;
+                }
+                operand => {
+                    e.opcode.push(0xfe + (size != Size::Byte) as u8);
+                    let operand = rm(operand).ok_or_else(|| unknown_register(operand))?;
+                    e.modrm(k, operand)?;
+                }
+            }
+            e.finish()
+        }
+        "push" | "pop" => {
+            expect(&operands, 1)?;
+            let push = mnemonic == "push";
+            let default_size = match bits {
+                16 => Size::Word,
+                32 => Size::Dword,
+                _ => Size::Qword,
+            };
+            match &operands[0].operand {
+                Operand::Register(register) => {
+                    e.stack_operand_size(register.size)?;
+                    let opcode = if push { 0x50 } else { 0x58 };
+                    e.register_in_opcode(opcode, Register::new(register.number, Size::Dword))?;
+                }
+                Operand::Memory(memory) => {
+                    let size = memory
+                        .size
+                        .ok_or_else(|| invalid("operation size not specified"))?;
+                    e.stack_operand_size(size)?;
+                    e.opcode.push(if push { 0xff } else { 0x8f });
+                    e.memory(if push { 6 } else { 0 }, memory)?;
+                }
+                operand if push => {
+                    let size = operands[0].size.unwrap_or(default_size);
+                    e.stack_operand_size(size)?;
+                    match operand {
+                        Operand::Immediate(value)
+                            if fits_i8(*value) && operands[0].size.is_none() =>
+                        {
+                            e.opcode.push(0x6a);
+                            e.immediate(operand, Size::Byte)?;
+                        }
+                        _ => {
+                            e.opcode.push(0x68);
+                            e.immediate(operand, immediate_size(size))?;
+                        }
+                    }
+                }
+                _ => return Err(invalid("invalid combination of opcode and operands")),
+            }
+            e.finish()
+        }
+        "lea" => {
+            expect(&operands, 2)?;
+            match (&operands[0].operand, &operands[1].operand) {
+                (Operand::Register(register), Operand::Memory(memory))
+                    if register.size != Size::Byte =>
+                {
+                    e.operand_size(register.size)?;
+                    e.opcode.push(0x8d);
+                    e.modrm_register(*register, Rm::Memory(memory))?;
+                }
+                _ => return Err(invalid("lea needs a register and a memory operand")),
+            }
+            e.finish()
+        }
+        "movzx" | "movsx" => {
+            expect(&operands, 2)?;
+            let register = match &operands[0].operand {
+                Operand::Register(register) => *register,
+                operand => return Err(unknown_register(operand)),
+            };
+            let size = operand_size(&[&operands[1]])?;
+            if size.bytes() >= register.size.bytes() || size.bytes() > 2 {
+                return Err(invalid("mismatched operand sizes"));
+            }
+            e.operand_size(register.size)?;
+            let base = if mnemonic == "movzx" { 0xb6 } else { 0xbe };
+            e.opcode
+                .extend_from_slice(&[0x0f, base + (size == Size::Word) as u8]);
+            let operand = rm(&operands[1].operand).ok_or_else(|| invalid("invalid source"))?;
+            e.modrm_register(register, operand)?;
+            e.finish()
+        }
+        "jmp" | "call" => {
+            expect(&operands, 1)?;
+            let call = mnemonic == "call";
+            match &operands[0].operand {
+                Operand::Label(label) if operands[0].short && !call => {
+                    e.opcode.push(0xeb);
+                    e.relative(label, Size::Byte);
+                }
+                Operand::Label(label) => {
+                    e.opcode.push(if call { 0xe8 } else { 0xe9 });
+                    e.relative(label, if bits == 16 { Size::Word } else { Size::Dword });
+                }
+                operand => {
+                    let size = match operand {
+                        Operand::Register(register) => register.size,
+                        Operand::Memory(memory) => memory.size.unwrap_or(match bits {
+                            16 => Size::Word,
+                            32 => Size::Dword,
+                            _ => Size::Qword,
+                        }),
+                        _ => return Err(invalid("invalid branch target")),
+                    };
+                    e.stack_operand_size(size)?;
+                    e.opcode.push(0xff);
+                    let target = match operand {
+                        Operand::Register(register) => {
+                            Rm::Register(Register::new(register.number, Size::Dword))
+                        }
+                        Operand::Memory(memory) => Rm::Memory(memory),
+                        _ => unreachable!(),
+                    };
+                    e.modrm(if call { 2 } else { 4 }, target)?;
+                }
+            }
+            e.finish()
+        }
+        "int" => {
+            expect(&operands, 1)?;
+            e.opcode.push(0xcd);
+            e.immediate(&operands[0].operand, Size::Byte)?;
+            e.finish()
+        }
+        _ => Err(ErrorKind::UnknownInstruction(mnemonic.to_string())),
+    }
+}
+
+// The two and three operand forms of imul.
+fn imul(mut e: Encoder, operands: &[Parsed]) -> Result<Encoding, ErrorKind> {
+    let register = match &operands[0].operand {
+        Operand::Register(register) if register.size != Size::Byte => *register,
+        _ => return Err(invalid("imul needs a 16, 32 or 64-bit register")),
+    };
+    e.operand_size(register.size)?;
+    let (source, immediate) = match operands.len() {
+        2 if is_immediate(&operands[1].operand) => (&operands[0], Some(&operands[1])),
+        2 => (&operands[1], None),
+        3 => (&operands[1], Some(&operands[2])),
+        found => return Err(ErrorKind::OperandCount { expected: 3, found }),
+    };
+    operand_size(&[&operands[0], source])?;
+    let source = rm(&source.operand).ok_or_else(|| invalid("invalid source"))?;
+    match immediate {
+        None => {
+            e.opcode.extend_from_slice(&[0x0f, 0xaf]);
+            e.modrm_register(register, source)?;
+        }
+        Some(immediate) => {
+            let small = matches!(immediate.operand, Operand::Immediate(v) if fits_i8(v));
+            e.opcode.push(if small { 0x6b } else { 0x69 });
+            e.modrm_register(register, source)?;
+            let size = if small {
+                Size::Byte
+            } else {
+                immediate_size(register.size)
+            };
+            e.immediate(&immediate.operand, size)?;
+        }
+    }
+    e.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_encoding(bits: u32, line: &str, expected: &[u8]) {
+        let mut parts = line.splitn(2, ' ');
+        let mnemonic = parts.next().unwrap();
+        let operands: Vec<&str> = parts
+            .next()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .collect();
+        let encoding =
+            encode(mnemonic, &operands, bits).unwrap_or_else(|e| panic!("{}: {}", line, e));
+        assert_eq!(encoding.bytes, expected, "{}", line);
+    }
+
+    fn encode_error(bits: u32, line: &str) -> ErrorKind {
+        let mut parts = line.splitn(2, ' ');
+        let mnemonic = parts.next().unwrap();
+        let operands: Vec<&str> = parts
+            .next()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .collect();
+        encode(mnemonic, &operands, bits).err().unwrap()
+    }
+
+    #[test]
+    fn mov() {
+        assert_encoding(64, "mov eax, 60", &[0xb8, 0x3c, 0, 0, 0]);
+        assert_encoding(64, "mov rax, 60", &[0xb8, 0x3c, 0, 0, 0]);
+        assert_encoding(
+            64,
+            "mov rax, -1",
+            &[0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff],
+        );
+        assert_encoding(
+            64,
+            "mov rax, 0x123456789",
+            &[0x48, 0xb8, 0x89, 0x67, 0x45, 0x23, 0x01, 0, 0, 0],
+        );
+        assert_encoding(64, "mov r9, rax", &[0x49, 0x89, 0xc1]);
+        assert_encoding(64, "mov sil, 1", &[0x40, 0xb6, 1]);
+        assert_encoding(64, "mov ah, bl", &[0x88, 0xdc]);
+        assert_encoding(64, "mov ax, 1", &[0x66, 0xb8, 1, 0]);
+        assert_encoding(64, "mov eax, [rbp - 8]", &[0x8b, 0x45, 0xf8]);
+        assert_encoding(64, "mov [rsp + 4], ecx", &[0x89, 0x4c, 0x24, 4]);
+        assert_encoding(64, "mov rax, [r13]", &[0x49, 0x8b, 0x45, 0]);
+        assert_encoding(
+            64,
+            "mov dword [rax + rcx*4 + 0x100], 7",
+            &[0xc7, 0x84, 0x88, 0, 1, 0, 0, 7, 0, 0, 0],
+        );
+        assert_encoding(64, "mov eax, [ebx]", &[0x67, 0x8b, 0x03]);
+        assert_encoding(64, "mov al, [fs:rax]", &[0x64, 0x8a, 0x00]);
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_encoding(64, "cmp eax, 5", &[0x83, 0xf8, 5]);
+        assert_encoding(64, "add eax, 0x1000", &[0x05, 0, 0x10, 0, 0]);
+        assert_encoding(64, "add al, 5", &[0x04, 5]);
+        assert_encoding(64, "sub rsp, 0x100", &[0x48, 0x81, 0xec, 0, 1, 0, 0]);
+        assert_encoding(64, "xor r8d, r8d", &[0x45, 0x31, 0xc0]);
+        assert_encoding(64, "and byte [rdi], 0x7f", &[0x80, 0x27, 0x7f]);
+        assert_encoding(64, "test eax, eax", &[0x85, 0xc0]);
+        assert_encoding(64, "test al, 1", &[0xa8, 1]);
+        assert_encoding(64, "shl eax, 4", &[0xc1, 0xe0, 4]);
+        assert_encoding(64, "sar rdx, 1", &[0x48, 0xd1, 0xfa]);
+        assert_encoding(64, "shr ebx, cl", &[0xd3, 0xeb]);
+        assert_encoding(64, "neg rax", &[0x48, 0xf7, 0xd8]);
+        assert_encoding(64, "div ecx", &[0xf7, 0xf1]);
+        assert_encoding(64, "imul eax, ebx", &[0x0f, 0xaf, 0xc3]);
+        assert_encoding(64, "imul eax, ebx, 10", &[0x6b, 0xc3, 10]);
+        assert_encoding(64, "inc ecx", &[0xff, 0xc1]);
+        assert_encoding(32, "inc ecx", &[0x41]);
+        assert_encoding(64, "lea rsi, [rdi + 8]", &[0x48, 0x8d, 0x77, 8]);
+        assert_encoding(64, "movzx eax, byte [rsi]", &[0x0f, 0xb6, 0x06]);
+        assert_encoding(64, "xchg eax, ebx", &[0x93]);
+        assert_encoding(64, "cqo", &[0x48, 0x99]);
+        assert_encoding(64, "cdq", &[0x99]);
+    }
+
+    #[test]
+    fn stack_and_branches() {
+        assert_encoding(64, "push rbp", &[0x55]);
+        assert_encoding(64, "push r12", &[0x41, 0x54]);
+        assert_encoding(64, "pop rbp", &[0x5d]);
+        assert_encoding(64, "push 1", &[0x6a, 1]);
+        assert_encoding(64, "push 0x1000", &[0x68, 0, 0x10, 0, 0]);
+        assert_encoding(64, "jmp rax", &[0xff, 0xe0]);
+        assert_encoding(64, "call qword [rbx + 8]", &[0xff, 0x53, 8]);
+        assert_encoding(64, "int 0x80", &[0xcd, 0x80]);
+
+        let encoding = encode("jne", &["done"], 64).unwrap();
+        assert_eq!(encoding.bytes, vec![0x0f, 0x85, 0, 0, 0, 0]);
+        assert_eq!(encoding.fixups[0].offset, 2);
+        assert_eq!(encoding.fixups[0].typ, RelocationType::Pc32);
+        assert_eq!(encoding.fixups[0].addend, -4);
+        let encoding = encode("jmp", &["short done"], 64).unwrap();
+        assert_eq!(encoding.bytes, vec![0xeb, 0]);
+        assert_eq!(encoding.fixups[0].addend, -1);
+        let encoding = encode("call", &["f"], 16).unwrap();
+        assert_eq!(encoding.bytes, vec![0xe8, 0, 0]);
+        assert_eq!(encoding.fixups[0].typ, RelocationType::Pc16);
+        let encoding = encode("mov", &["eax", "[rel value]"], 64).unwrap();
+        assert_eq!(encoding.bytes, vec![0x8b, 0x05, 0, 0, 0, 0]);
+        assert_eq!(encoding.fixups[0].addend, -4);
+        let encoding = encode("mov", &["dword [rel value]", "1"], 64).unwrap();
+        assert_eq!(encoding.fixups[0].addend, -8);
+    }
+
+    #[test]
+    fn gas_intel_operands() {
+        assert_encoding(
+            64,
+            "mov QWORD PTR -40[rsp+rax*8], rdi",
+            &[0x48, 0x89, 0x7c, 0xc4, 0xd8],
+        );
+        assert_encoding(64, "mov eax, DWORD PTR [rdx]", &[0x8b, 0x02]);
+        assert_encoding(
+            64,
+            "mov rax, QWORD PTR fs:40",
+            &[0x64, 0x48, 0x8b, 0x04, 0x25, 40, 0, 0, 0],
+        );
+        let encoding = encode("add", &["DWORD PTR counter[rip]", "edi"], 64).unwrap();
+        assert_eq!(encoding.bytes, vec![0x01, 0x3d, 0, 0, 0, 0]);
+        assert_eq!(encoding.fixups[0].label, "counter");
+        assert_eq!(encoding.fixups[0].typ, RelocationType::Pc32);
+        let encoding = encode("lea", &["rdi", ".LC0[rip+2]"], 64).unwrap();
+        assert_eq!(encoding.fixups[0].addend, -2);
+        let encoding = encode("mov", &["edi", "OFFSET FLAT:.LC0"], 64).unwrap();
+        assert_eq!(encoding.bytes, vec![0xbf, 0, 0, 0, 0]);
+        assert_eq!(encoding.fixups[0].label, ".LC0");
+        assert!(encode("mov", &["eax", "x[rip+rbx]"], 64).is_err());
+    }
+
+    #[test]
+    fn modes() {
+        assert_encoding(16, "mov ax, 1", &[0xb8, 1, 0]);
+        assert_encoding(16, "mov eax, 1", &[0x66, 0xb8, 1, 0, 0, 0]);
+        assert_encoding(32, "mov ax, 1", &[0x66, 0xb8, 1, 0]);
+        assert_encoding(16, "mov al, [bx + si + 2]", &[0x8a, 0x40, 2]);
+        assert_encoding(16, "mov ax, [bp]", &[0x8b, 0x46, 0]);
+        assert_encoding(16, "mov ax, [0x7c00]", &[0x8b, 0x06, 0, 0x7c]);
+        assert_encoding(16, "mov eax, [ebx]", &[0x66, 0x67, 0x8b, 0x03]);
+        assert_encoding(32, "mov eax, [0x1000]", &[0x8b, 0x05, 0, 0x10, 0, 0]);
+        assert_encoding(32, "push eax", &[0x50]);
+        assert_encoding(16, "push ax", &[0x50]);
+        assert_encoding(32, "pusha", &[0x60]);
+
+        assert_eq!(
+            encode_error(32, "mov rax, 1"),
+            ErrorKind::InvalidInMode {
+                what: "64-bit operand size".to_string(),
+                bits: 32
+            }
+        );
+        assert_eq!(
+            encode_error(32, "mov r8d, 1"),
+            ErrorKind::InvalidInMode {
+                what: "register r8d".to_string(),
+                bits: 32
+            }
+        );
+        assert_eq!(
+            encode_error(64, "push eax"),
+            ErrorKind::InvalidInMode {
+                what: "32-bit stack operand".to_string(),
+                bits: 64
+            }
+        );
+        assert_eq!(
+            encode_error(64, "pusha"),
+            ErrorKind::InvalidInMode {
+                what: "pusha".to_string(),
+                bits: 64
+            }
+        );
+        assert_eq!(
+            encode_error(64, "mov ax, [bx + si]"),
+            ErrorKind::InvalidInMode {
+                what: "16-bit addressing".to_string(),
+                bits: 64
+            }
+        );
+        assert!(matches!(
+            encode_error(64, "mov ah, sil"),
+            ErrorKind::InvalidOperands(_)
+        ));
+        assert!(matches!(
+            encode_error(64, "mov eax, bx"),
+            ErrorKind::InvalidOperands(_)
+        ));
+        assert!(matches!(
+            encode_error(64, "mov [rax], 1"),
+            ErrorKind::InvalidOperands(_)
+        ));
+    }
+
+    #[test]
+    fn mnemonics() {
+        let mnemonics = super::mnemonics();
+        assert!(mnemonics.contains(&"jge") && mnemonics.contains(&"cdq"));
+        for mnemonic in mnemonics {
+            assert!(operand_forms(mnemonic).is_some(), "{}", mnemonic);
+            // Every mnemonic is known to the encoder, so errors are never about the mnemonic.
+            let error = encode(mnemonic, &["foo", "bar", "baz", "qux"], 64).err();
+            assert!(
+                !matches!(error, Some(ErrorKind::UnknownInstruction(_))),
+                "{}",
+                mnemonic
+            );
+        }
+        assert_eq!(operand_forms("RET"), Some(&[""][..]));
+        assert_eq!(operand_forms("jz"), Some(&["label"][..]));
+        assert_eq!(operand_forms("foo"), None);
+    }
+}
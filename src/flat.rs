@@ -0,0 +1,298 @@
+use crate::*;
+
+// Like nasm, sections without an explicit alignment are aligned to 4 bytes.
+const DEFAULT_ALIGNMENT: u64 = 4;
+
+// A section placed in a flat image. `address` is where its bytes are loaded; labels in it are
+// relative to `virtual_address`, which only differs from `address` with vstart=.
+pub struct PlacedSection {
+    pub name: String,
+    pub address: u64,
+    pub virtual_address: u64,
+    pub content: Vec<u8>,
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+// Assigns addresses to all sections and applies all relocations. The sections are returned
+// sorted by address. .text always comes first, like in nasm; the others follow in the order in
+// which they were declared, unless they have an explicit start=.
+pub fn layout(assembly: AssemblyResult) -> std::io::Result<Vec<PlacedSection>> {
+    let origin = assembly.origin.unwrap_or(0);
+    let mut sections = assembly.sections;
+    if let Some(i) = sections.iter().position(|s| s.name == ".text") {
+        let text = sections.remove(i);
+        sections.insert(0, text);
+    }
+
+    let mut placed: Vec<PlacedSection> = vec![];
+    let mut position = origin;
+    for section in sections {
+        let alignment = section.align.unwrap_or(DEFAULT_ALIGNMENT);
+        let address = match section.start {
+            Some(start) => start,
+            None if placed.is_empty() => origin,
+            None => align_up(position, alignment),
+        };
+        if address < origin {
+            return Err(invalid(format!(
+                "section {} starts at 0x{:x}, before the origin 0x{:x}",
+                section.name, address, origin
+            )));
+        }
+        position = address + section.content.len() as u64;
+        placed.push(PlacedSection {
+            name: section.name,
+            address,
+            virtual_address: section.vstart.unwrap_or(address),
+            content: section.content,
+        });
+    }
+
+    for relocation in &assembly.relocations {
+        if relocation.is_external() {
+            return Err(invalid(format!(
+                "undefined external symbol {}",
+                relocation.label
+            )));
+        }
+        let target = placed
+            .iter()
+            .find(|s| s.name == relocation.section)
+            .ok_or_else(|| invalid(format!("unknown section {}", relocation.section)))?;
+        let source = placed.iter().find(|s| s.name == relocation.source).unwrap();
+        let mut value = target.virtual_address.wrapping_add(relocation.addend) as i64;
+        if relocation.typ.is_relative() {
+            value = value.wrapping_sub((source.virtual_address + relocation.location) as i64);
+        }
+        if !relocation.typ.fits(value) {
+            return Err(invalid(format!(
+                "value 0x{:x} of reference to {} in section {} does not fit in {} bits",
+                value,
+                relocation.label,
+                source.name,
+                relocation.typ.size() * 8
+            )));
+        }
+        let source = placed
+            .iter_mut()
+            .find(|s| s.name == relocation.source)
+            .unwrap();
+        let location = relocation.location as usize;
+        let size = relocation.typ.size();
+        source.content[location..location + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+
+    placed.sort_by_key(|s| s.address);
+    for pair in placed.windows(2) {
+        if pair[0].address + pair[0].content.len() as u64 > pair[1].address {
+            return Err(invalid(format!(
+                "sections {} and {} overlap",
+                pair[0].name, pair[1].name
+            )));
+        }
+    }
+    Ok(placed)
+}
+
+// Creates a raw binary image starting at the origin, with gaps between sections filled with
+// zeros.
+pub fn create_binary(assembly: AssemblyResult) -> std::io::Result<Vec<u8>> {
+    let origin = assembly.origin.unwrap_or(0);
+    let mut image = vec![];
+    for section in layout(assembly)? {
+        let offset = (section.address - origin) as usize;
+        image.resize(offset, 0);
+        image.extend_from_slice(&section.content);
+    }
+    Ok(image)
+}
+
+// Splits the sections into chunks of at most `size` bytes which don't cross a multiple of
+// `boundary`, as (address, bytes) pairs.
+fn chunks(sections: &[PlacedSection], size: u64, boundary: u64) -> Vec<(u64, &[u8])> {
+    let mut ret = vec![];
+    for section in sections {
+        let mut address = section.address;
+        let mut content = &section.content[..];
+        while !content.is_empty() {
+            let length = size
+                .min(boundary - address % boundary)
+                .min(content.len() as u64) as usize;
+            ret.push((address, &content[..length]));
+            address += length as u64;
+            content = &content[length..];
+        }
+    }
+    ret
+}
+
+// Formats a record as hex digits, followed by the one-byte checksum computed by `checksum`.
+fn record(bytes: &[u8], checksum: fn(u8) -> u8) -> String {
+    let sum = bytes.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b));
+    let mut ret: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    ret += &format!("{:02X}\n", checksum(sum));
+    ret
+}
+
+// Creates an Intel HEX file, using extended linear address records for addresses above 64 KiB.
+pub fn create_intel_hex(assembly: AssemblyResult) -> std::io::Result<Vec<u8>> {
+    let sections = layout(assembly)?;
+    let checksum = |sum: u8| sum.wrapping_neg();
+    let mut ret = String::new();
+    let mut upper = 0;
+    for (address, data) in chunks(&sections, 16, 0x10000) {
+        if address + data.len() as u64 > 1 << 32 {
+            return Err(invalid(format!(
+                "address 0x{:x} is too large for Intel HEX",
+                address
+            )));
+        }
+        if address >> 16 != upper {
+            upper = address >> 16;
+            ret += ":";
+            ret += &record(&[2, 0, 0, 4, (upper >> 8) as u8, upper as u8], checksum);
+        }
+        let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, 0];
+        bytes.extend_from_slice(data);
+        ret += ":";
+        ret += &record(&bytes, checksum);
+    }
+    ret += ":";
+    ret += &record(&[0, 0, 0, 1], checksum);
+    Ok(ret.into_bytes())
+}
+
+// Creates a Motorola S-record file. The address size (S1, S2 or S3 records) is the smallest one
+// which fits all addresses.
+pub fn create_srecord(assembly: AssemblyResult) -> std::io::Result<Vec<u8>> {
+    let origin = assembly.origin.unwrap_or(0);
+    let sections = layout(assembly)?;
+    let end = sections
+        .iter()
+        .map(|s| s.address + s.content.len() as u64)
+        .max()
+        .unwrap_or(0);
+    let address_size: usize = match end {
+        0..=0x1_0000 => 2,
+        0x1_0001..=0x100_0000 => 3,
+        0x100_0001..=0x1_0000_0000 => 4,
+        _ => {
+            return Err(invalid(format!(
+                "address 0x{:x} is too large for S-records",
+                end - 1
+            )))
+        }
+    };
+    let checksum = |sum: u8| !sum;
+    let line = |typ: usize, address: u64, data: &[u8]| {
+        let size = if typ == 0 || typ == 5 {
+            2
+        } else {
+            address_size
+        };
+        let mut bytes = vec![(size + data.len() + 1) as u8];
+        bytes.extend_from_slice(&address.to_be_bytes()[8 - size..]);
+        bytes.extend_from_slice(data);
+        format!("S{}{}", typ, record(&bytes, checksum))
+    };
+
+    let mut ret = line(0, 0, b"minias");
+    let records = chunks(&sections, 32, 1 << 32);
+    for (address, data) in &records {
+        ret += &line(address_size - 1, *address, data);
+    }
+    if records.len() < 0x10000 {
+        ret += &line(5, records.len() as u64, &[]);
+    }
+    // The termination record holds the start address, which is the origin.
+    ret += &line(11 - address_size, origin, &[]);
+    Ok(ret.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn origin_and_relocations() {
+        let assembly = "org 0x7c00\nmov esi, message\nret\nsection .data\nmessage:\ndb 1, 2";
+        let image = create_binary(assemble(assembly).unwrap()).unwrap();
+        // .data is aligned to 4 bytes after the 6 bytes of .text.
+        assert_eq!(image, vec![0xbe, 0x08, 0x7c, 0, 0, 0xc3, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn start_and_vstart() {
+        let assembly = "section .data start=0x10 vstart=0x1000\nmessage:\ndb 7\n\
+                        section .text\nmov eax, message";
+        let sections = layout(assemble(assembly).unwrap()).unwrap();
+        assert_eq!(sections[0].name, ".text");
+        assert_eq!(sections[0].address, 0);
+        assert_eq!(sections[0].content, vec![0xb8, 0, 0x10, 0, 0]);
+        assert_eq!(sections[1].name, ".data");
+        assert_eq!(sections[1].address, 0x10);
+        assert_eq!(sections[1].virtual_address, 0x1000);
+
+        let image = create_binary(assemble(assembly).unwrap()).unwrap();
+        assert_eq!(image.len(), 0x11);
+        assert_eq!(image[0x10], 7);
+    }
+
+    #[test]
+    fn boot_sector() {
+        let assembly = "bits 16\norg 0x7c00\nstart:\njmp $\nmov ax, $\n\
+                        times 510-($-$$) db 0\ndw 0xaa55";
+        let image = create_binary(assemble(assembly).unwrap()).unwrap();
+        assert_eq!(image.len(), 512);
+        // jmp $ jumps to itself, and mov ax, $ loads the address of its own line.
+        assert_eq!(image[..6], [0xe9, 0xfd, 0xff, 0xb8, 0x03, 0x7c]);
+        assert!(image[6..510].iter().all(|&b| b == 0));
+        assert_eq!(image[510..], [0x55, 0xaa]);
+    }
+
+    #[test]
+    fn intel_hex() {
+        let assembly =
+            "org 0xfff8\ndb 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17";
+        let hex = create_intel_hex(assemble(assembly).unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(hex).unwrap(),
+            ":08FFF8000001020304050607E5\n\
+             :020000040001F9\n\
+             :0A00000008090A0B0C0D0E0F101179\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn srecord() {
+        let srec = create_srecord(assemble("org 0x1000\nret").unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(srec).unwrap(),
+            "S00900006D696E69617375\n\
+             S1041000C328\n\
+             S5030001FB\n\
+             S9031000EC\n"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let overlap = "ret\nret\nsection .data start=1\ndb 1";
+        assert!(create_binary(assemble(overlap).unwrap()).is_err());
+
+        let before_origin = "org 0x100\nsection .data start=0x10\ndb 1";
+        assert!(create_binary(assemble(before_origin).unwrap()).is_err());
+
+        let too_far = "mov eax, message\nsection .data start=0x100000000\nmessage:";
+        assert!(create_binary(assemble(too_far).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,473 @@
+use crate::assembler::{self, AssemblyError, Options};
+use crate::cpu::Cpu;
+use crate::*;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Assembled code mapped into executable memory. All sections are placed in one mapping, each on
+// its own pages, and unmapped when this is dropped. Once the relocations are applied, code is
+// only executable, .data and .bss are writable and other sections are read-only.
+pub struct JitCode {
+    memory: *mut libc::c_void,
+    size: usize,
+    // Addresses of the mapped sections and of all symbols.
+    sections: HashMap<String, u64>,
+    symbols: HashMap<String, u64>,
+}
+
+// A function in a JitCode, which can't outlive the mapping.
+#[derive(Copy, Clone)]
+pub struct JitFunction<'a> {
+    address: u64,
+    code: PhantomData<&'a JitCode>,
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+impl JitCode {
+    // Maps the sections of `assembly` and resolves its relocations. External symbols are looked
+    // up in `externals`, for example to call host functions.
+    pub fn load(
+        assembly: &AssemblyResult,
+        externals: &HashMap<String, u64>,
+    ) -> io::Result<JitCode> {
+        // Sections start on their own pages, so that they can be protected separately.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut offsets = vec![];
+        let mut size = 0;
+        for section in &assembly.sections {
+            offsets.push(size);
+            size += align_up(section.content.len(), page_size);
+        }
+        if size == 0 {
+            return Err(invalid("no code to load".to_string()));
+        }
+
+        let memory = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if memory == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let mut code = JitCode {
+            memory,
+            size,
+            sections: HashMap::new(),
+            symbols: HashMap::new(),
+        };
+        let base = memory as u64;
+        let bytes = unsafe { std::slice::from_raw_parts_mut(memory as *mut u8, size) };
+        for (section, &offset) in assembly.sections.iter().zip(&offsets) {
+            bytes[offset..offset + section.content.len()].copy_from_slice(&section.content);
+            code.sections
+                .insert(section.name.clone(), base + offset as u64);
+        }
+
+        for relocation in &assembly.relocations {
+            let target = if relocation.is_external() {
+                *externals.get(&relocation.label).ok_or_else(|| {
+                    invalid(format!("undefined external symbol {}", relocation.label))
+                })?
+            } else {
+                code.sections[&relocation.section]
+            };
+            let location = code.sections[&relocation.source] + relocation.location;
+            let mut value = target.wrapping_add(relocation.addend) as i64;
+            if relocation.typ.is_relative() {
+                value = value.wrapping_sub(location as i64);
+            }
+            if !relocation.typ.fits(value) {
+                return Err(invalid(format!(
+                    "reference to {} does not fit in {} bits",
+                    relocation.label,
+                    relocation.typ.size() * 8
+                )));
+            }
+            let start = (location - base) as usize;
+            let size = relocation.typ.size();
+            bytes[start..start + size].copy_from_slice(&value.to_le_bytes()[..size]);
+        }
+
+        for symbol in &assembly.symbols {
+            let address = code.sections[&symbol.section] + symbol.offset;
+            code.symbols.insert(symbol.name.clone(), address);
+        }
+
+        for (section, &offset) in assembly.sections.iter().zip(&offsets) {
+            let protection = if section.is_code() {
+                libc::PROT_READ | libc::PROT_EXEC
+            } else if section.is_writable() {
+                libc::PROT_READ | libc::PROT_WRITE
+            } else {
+                libc::PROT_READ
+            };
+            let length = align_up(section.content.len(), page_size);
+            let start = unsafe { memory.add(offset) };
+            if unsafe { libc::mprotect(start, length, protection) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(code)
+    }
+
+    // The address of a symbol or section.
+    pub fn address(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .get(name)
+            .or_else(|| self.sections.get(name))
+            .copied()
+    }
+
+    pub fn function(&self, name: &str) -> Option<JitFunction<'_>> {
+        self.address(name).map(|address| JitFunction {
+            address,
+            code: PhantomData,
+        })
+    }
+}
+
+impl Drop for JitCode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.memory, self.size);
+        }
+    }
+}
+
+impl JitFunction<'_> {
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// Calls the function with up to six integer arguments, following the System V calling
+    /// convention, and returns rax. More arguments are an error, as they would be passed on the
+    /// stack.
+    ///
+    /// # Safety
+    ///
+    /// The code must be a function following the calling convention, which takes at most the
+    /// given arguments and only accesses memory it is allowed to.
+    pub unsafe fn call(&self, arguments: &[u64]) -> io::Result<u64> {
+        type F0 = extern "sysv64" fn() -> u64;
+        type F1 = extern "sysv64" fn(u64) -> u64;
+        type F2 = extern "sysv64" fn(u64, u64) -> u64;
+        type F3 = extern "sysv64" fn(u64, u64, u64) -> u64;
+        type F4 = extern "sysv64" fn(u64, u64, u64, u64) -> u64;
+        type F5 = extern "sysv64" fn(u64, u64, u64, u64, u64) -> u64;
+        type F6 = extern "sysv64" fn(u64, u64, u64, u64, u64, u64) -> u64;
+        let a = arguments;
+        let address = self.address as usize;
+        Ok(match a.len() {
+            0 => std::mem::transmute::<usize, F0>(address)(),
+            1 => std::mem::transmute::<usize, F1>(address)(a[0]),
+            2 => std::mem::transmute::<usize, F2>(address)(a[0], a[1]),
+            3 => std::mem::transmute::<usize, F3>(address)(a[0], a[1], a[2]),
+            4 => std::mem::transmute::<usize, F4>(address)(a[0], a[1], a[2], a[3]),
+            5 => std::mem::transmute::<usize, F5>(address)(a[0], a[1], a[2], a[3], a[4]),
+            6 => std::mem::transmute::<usize, F6>(address)(a[0], a[1], a[2], a[3], a[4], a[5]),
+            count => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} arguments given, at most six are supported", count),
+                ))
+            }
+        })
+    }
+}
+
+// Offsets in the state page shared with the child process running a snippet: the registers in
+// encoding order, then rflags, the harness' stack pointer and whether the snippet finished.
+const RFLAGS_OFFSET: usize = 16 * 8;
+const STACK_OFFSET: usize = RFLAGS_OFFSET + 8;
+const FINISHED_OFFSET: usize = STACK_OFFSET + 8;
+
+// Saves the callee-saved registers, then enters the snippet with all registers but rsp cleared.
+const PROLOGUE: &str = "extern __state, __snippet
+push rbx
+push rbp
+push r12
+push r13
+push r14
+push r15
+mov rax, __state
+mov [rax + 136], rsp
+mov rax, __snippet
+push rax
+xor eax, eax
+xor ecx, ecx
+xor edx, edx
+xor ebx, ebx
+xor ebp, ebp
+xor esi, esi
+xor edi, edi
+xor r8d, r8d
+xor r9d, r9d
+xor r10d, r10d
+xor r11d, r11d
+xor r12d, r12d
+xor r13d, r13d
+xor r14d, r14d
+xor r15d, r15d
+push 0x202
+popf
+ret";
+
+// Appended to the end of the snippet's code: stores all registers and flags, then returns to
+// the caller of the prologue.
+const EPILOGUE: &str = ".intel_syntax noprefix
+bits 64
+section .text
+extern __state
+__snippet_end:
+push rax
+mov rax, __state
+pop qword [rax]
+mov [rax + 8], rcx
+mov [rax + 16], rdx
+mov [rax + 24], rbx
+mov [rax + 32], rsp
+mov [rax + 40], rbp
+mov [rax + 48], rsi
+mov [rax + 56], rdi
+mov [rax + 64], r8
+mov [rax + 72], r9
+mov [rax + 80], r10
+mov [rax + 88], r11
+mov [rax + 96], r12
+mov [rax + 104], r13
+mov [rax + 112], r14
+mov [rax + 120], r15
+pushf
+pop qword [rax + 128]
+mov qword [rax + 144], 1
+mov rsp, [rax + 136]
+pop r15
+pop r14
+pop r13
+pop r12
+pop rbp
+pop rbx
+ret";
+
+// How a snippet run by run_native() ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NativeOutcome {
+    // The snippet ran to its end. rip is the address of the end.
+    Finished(Cpu),
+    // The snippet called the exit system call.
+    Exited(i32),
+    // The snippet was killed by a signal, like SIGSEGV, or SIGALRM after the timeout.
+    Signaled(i32),
+}
+
+// Assembles a snippet for run_native() with the given options, like the initial syntax. Errors
+// have the line numbers of the snippet.
+pub fn assemble_snippet(source: &str, options: &Options) -> Result<AssemblyResult, AssemblyError> {
+    assembler::assemble_with(&format!("{}\n{}", source, EPILOGUE), options)
+}
+
+// Runs a snippet from assemble_snippet() natively in a forked child process, so that crashes
+// don't affect the caller. The child is killed after `timeout` seconds, which must not be zero
+// as that would disable the alarm. Sections are mapped like by JitCode::load().
+pub fn run_native(snippet: &AssemblyResult, timeout: u32) -> io::Result<NativeOutcome> {
+    if timeout == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the timeout must be at least one second",
+        ));
+    }
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let state = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            page_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if state == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let result = run_with_state(snippet, state as *mut u8, timeout);
+    unsafe {
+        libc::munmap(state, page_size);
+    }
+    result
+}
+
+fn run_with_state(
+    snippet: &AssemblyResult,
+    state: *mut u8,
+    timeout: u32,
+) -> io::Result<NativeOutcome> {
+    let mut externals = HashMap::new();
+    externals.insert("__state".to_string(), state as u64);
+    let code = JitCode::load(snippet, &externals)?;
+    externals.insert("__snippet".to_string(), code.address(".text").unwrap());
+    let prologue = assembler::assemble(PROLOGUE).map_err(|err| invalid(err.to_string()))?;
+    let prologue = JitCode::load(&prologue, &externals)?;
+    let enter = prologue.function(".text").unwrap();
+
+    // Output buffered in the parent would be written twice otherwise.
+    io::stdout().flush()?;
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => unsafe {
+            libc::alarm(timeout);
+            let _ = enter.call(&[]);
+            libc::_exit(0);
+        },
+        child => {
+            let mut status = 0;
+            if unsafe { libc::waitpid(child, &mut status, 0) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let read = |offset: usize| unsafe { (state.add(offset) as *const u64).read() };
+            if unsafe { libc::WIFSIGNALED(status) } {
+                return Ok(NativeOutcome::Signaled(unsafe { libc::WTERMSIG(status) }));
+            }
+            if read(FINISHED_OFFSET) == 0 {
+                return Ok(NativeOutcome::Exited(unsafe { libc::WEXITSTATUS(status) }));
+            }
+            let mut cpu = Cpu::new();
+            for (i, register) in cpu.registers.iter_mut().enumerate() {
+                *register = read(i * 8);
+            }
+            cpu.rflags = read(RFLAGS_OFFSET);
+            cpu.rip = code.address("__snippet_end").unwrap();
+            Ok(NativeOutcome::Finished(cpu))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    extern "sysv64" fn double(x: u64) -> u64 {
+        x * 2
+    }
+
+    #[test]
+    fn call() {
+        let assembly = assemble(
+            "add:\nmov rax, rdi\nadd rax, rsi\nret\n\
+             answer:\nlea rax, [rel value]\nmovzx eax, byte [rax]\nret\n\
+             extern double\ncall_double:\nmov rax, double\njmp rax\n\
+             section .rodata\nvalue:\ndb 42",
+        )
+        .unwrap();
+        let mut externals = HashMap::new();
+        externals.insert("double".to_string(), double as *const () as u64);
+        let code = JitCode::load(&assembly, &externals).unwrap();
+
+        let add = code.function("add").unwrap();
+        assert_eq!(add.address(), code.address(".text").unwrap());
+        unsafe {
+            assert_eq!(add.call(&[2, 3]).unwrap(), 5);
+            assert_eq!(code.function("answer").unwrap().call(&[]).unwrap(), 42);
+            assert_eq!(
+                code.function("call_double").unwrap().call(&[21]).unwrap(),
+                42
+            );
+            assert!(add.call(&[0; 7]).is_err());
+        }
+        assert!(code.function("missing").is_none());
+
+        let error = JitCode::load(&assembly, &HashMap::new()).err().unwrap();
+        assert_eq!(error.to_string(), "undefined external symbol double");
+    }
+
+    #[test]
+    fn writable_data() {
+        let assembly = assemble(
+            "store:
+mov [rel counter], rdi
+add qword [rel buffer], 1
+ret
+             load:
+mov rax, [rel counter]
+add rax, [rel buffer]
+ret
+             section .data
+counter:
+db 0, 0, 0, 0, 0, 0, 0, 0
+             section .bss
+buffer:
+db 0, 0, 0, 0, 0, 0, 0, 0",
+        )
+        .unwrap();
+        let code = JitCode::load(&assembly, &HashMap::new()).unwrap();
+        unsafe {
+            code.function("store").unwrap().call(&[41]).unwrap();
+            assert_eq!(code.function("load").unwrap().call(&[]).unwrap(), 42);
+            assert_eq!((code.address("counter").unwrap() as *const u64).read(), 41);
+        }
+    }
+
+    #[test]
+    fn native() {
+        let options = Options::default();
+        let snippet = assemble_snippet(
+            "mov rax, 5\nmov r15, -1\nadd eax, 0xffffffff\npush 1",
+            &options,
+        )
+        .unwrap();
+        let cpu = match run_native(&snippet, 5).unwrap() {
+            NativeOutcome::Finished(cpu) => cpu,
+            other => panic!("unexpected outcome {:?}", other),
+        };
+        assert_eq!(cpu.registers[0], 4);
+        assert_eq!(cpu.registers[15], u64::MAX);
+        assert_eq!(cpu.registers[1], 0);
+        assert!(cpu.flag(crate::cpu::CF));
+
+        let snippet = assemble_snippet("mov eax, 60\nmov edi, 3\nsyscall", &options).unwrap();
+        assert_eq!(run_native(&snippet, 5).unwrap(), NativeOutcome::Exited(3));
+        let snippet = assemble_snippet("mov rax, [0]", &options).unwrap();
+        assert_eq!(
+            run_native(&snippet, 5).unwrap(),
+            NativeOutcome::Signaled(libc::SIGSEGV)
+        );
+        let snippet = assemble_snippet("loop:\njmp loop", &options).unwrap();
+        assert_eq!(
+            run_native(&snippet, 1).unwrap(),
+            NativeOutcome::Signaled(libc::SIGALRM)
+        );
+        assert!(run_native(&snippet, 0).is_err());
+        assert_eq!(
+            assemble_snippet("nop\nfrobnicate", &options)
+                .err()
+                .unwrap()
+                .line,
+            2
+        );
+
+        let att = Options {
+            syntax: assembler::Syntax::Att,
+            ..Options::default()
+        };
+        let snippet = assemble_snippet("movl $7, %eax", &att).unwrap();
+        match run_native(&snippet, 5).unwrap() {
+            NativeOutcome::Finished(cpu) => assert_eq!(cpu.registers[0], 7),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+    }
+}
@@ -1,26 +1,469 @@
 pub mod assembler;
+mod att;
+pub mod cpu;
+pub mod disassembler;
 pub mod elf;
+pub mod emulator;
+mod encoder;
+pub mod flat;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub mod jit;
+pub mod listing;
+pub mod preprocessor;
+pub mod source;
+pub mod x86;
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct AssemblySection {
     name: String,
     content: Vec<u8>,
+    // Placement attributes from the section directive, only used for flat binaries.
+    start: Option<u64>,
+    vstart: Option<u64>,
+    align: Option<u64>,
+    // Flags of a GAS section directive like "ax" or "aw", and whether the section is only
+    // reserved memory, like .bss. Only used for ELF objects.
+    flags: Option<String>,
+    nobits: bool,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum RelocationType {
     // see http://refspecs.linuxbase.org/elf/x86_64-abi-0.98.pdf
     U32 = 10,
     U64 = 1,
+    S32 = 11,
+    U16 = 12,
+    // Relative to the address of the relocated field.
+    Pc32 = 2,
+    Pc16 = 13,
+    Pc8 = 15,
 }
 
+impl RelocationType {
+    // Size of the relocated field in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            RelocationType::U64 => 8,
+            RelocationType::U32 | RelocationType::S32 | RelocationType::Pc32 => 4,
+            RelocationType::U16 | RelocationType::Pc16 => 2,
+            RelocationType::Pc8 => 1,
+        }
+    }
+
+    pub fn is_relative(self) -> bool {
+        matches!(
+            self,
+            RelocationType::Pc32 | RelocationType::Pc16 | RelocationType::Pc8
+        )
+    }
+
+    // Whether a value fits into the field. Relative and S32 fields are sign-extended.
+    pub fn fits(self, value: i64) -> bool {
+        let bits = self.size() as u32 * 8;
+        match self {
+            RelocationType::U64 => true,
+            RelocationType::U32 | RelocationType::U16 => (0..1 << bits).contains(&value),
+            _ => (-(1 << (bits - 1))..1 << (bits - 1)).contains(&value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ResolvedRelocation {
+    // The section containing the relocated field.
+    source: String,
     location: u64,
     typ: RelocationType,
+    // The target section, or an empty string for external symbols.
     section: String,
     addend: u64,
+    // The referenced label and the source line referencing it, for listings.
+    label: String,
+    line: usize,
+}
+
+// Where the bytes of a source line ended up. `section` is None if the line produced no bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLine {
+    line: usize,
+    source: String,
+    section: Option<String>,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SymbolType {
+    NoType,
+    Function,
+    Object,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    name: String,
+    section: String,
+    offset: u64,
+    line: usize,
+    // Attributes from .globl, .type and .size.
+    global: bool,
+    typ: SymbolType,
+    size: Option<u64>,
+}
+
+// A constant defined with equ, .equ or .set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Constant {
+    name: String,
+    value: i64,
+    line: usize,
 }
 
+// A symbol declared with .comm, which the linker allocates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommonSymbol {
+    name: String,
+    size: u64,
+    align: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct AssemblyResult {
     sections: Vec<AssemblySection>,
     relocations: Vec<ResolvedRelocation>,
+    origin: Option<u64>,
+    lines: Vec<SourceLine>,
+    // Sorted by name.
+    symbols: Vec<Symbol>,
+    // Sorted by name.
+    constants: Vec<Constant>,
+    commons: Vec<CommonSymbol>,
+    // Each use of a label or constant, with the line using it, in source order.
+    references: Vec<(String, usize)>,
+    warnings: Vec<assembler::AssemblyError>,
+}
+
+impl AssemblySection {
+    pub fn new(name: &str, content: Vec<u8>) -> AssemblySection {
+        AssemblySection {
+            name: name.to_string(),
+            content,
+            start: None,
+            vstart: None,
+            align: None,
+            flags: None,
+            nobits: false,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    pub fn start(&self) -> Option<u64> {
+        self.start
+    }
+
+    pub fn vstart(&self) -> Option<u64> {
+        self.vstart
+    }
+
+    pub fn align(&self) -> Option<u64> {
+        self.align
+    }
+
+    pub fn flags(&self) -> Option<&str> {
+        self.flags.as_deref()
+    }
+
+    pub fn is_nobits(&self) -> bool {
+        self.nobits
+    }
+
+    // Whether the section holds writable data: "w" in its flags, or .data and .bss by default.
+    pub fn is_writable(&self) -> bool {
+        match &self.flags {
+            Some(flags) => flags.contains('w'),
+            None => self.nobits || self.name == ".data" || self.name.starts_with(".data."),
+        }
+    }
+
+    // Whether the section holds code: "x" in its flags, or .text by default.
+    pub fn is_code(&self) -> bool {
+        match &self.flags {
+            Some(flags) => flags.contains('x'),
+            None => self.name == ".text" || self.name.starts_with(".text."),
+        }
+    }
+}
+
+impl ResolvedRelocation {
+    // A relocation of the field at `location` in `source` to the address of `section` plus
+    // `addend`.
+    pub fn new(
+        source: &str,
+        location: u64,
+        typ: RelocationType,
+        section: &str,
+        addend: u64,
+    ) -> ResolvedRelocation {
+        ResolvedRelocation {
+            source: source.to_string(),
+            location,
+            typ,
+            section: section.to_string(),
+            addend,
+            label: section.to_string(),
+            line: 0,
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn location(&self) -> u64 {
+        self.location
+    }
+
+    pub fn typ(&self) -> RelocationType {
+        self.typ
+    }
+
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+
+    pub fn addend(&self) -> u64 {
+        self.addend
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    // Whether the relocation refers to a symbol declared with extern, named by label().
+    pub fn is_external(&self) -> bool {
+        self.section.is_empty()
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl SourceLine {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn section(&self) -> Option<&str> {
+        self.section.as_deref()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+impl Symbol {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+
+    pub fn typ(&self) -> SymbolType {
+        self.typ
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+impl Constant {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl CommonSymbol {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn align(&self) -> u64 {
+        self.align
+    }
+}
+
+impl AssemblyResult {
+    // A result without source lines, symbols or warnings, for output formats only.
+    pub fn new(
+        sections: Vec<AssemblySection>,
+        relocations: Vec<ResolvedRelocation>,
+        origin: Option<u64>,
+    ) -> AssemblyResult {
+        AssemblyResult {
+            sections,
+            relocations,
+            origin,
+            lines: vec![],
+            symbols: vec![],
+            constants: vec![],
+            commons: vec![],
+            references: vec![],
+            warnings: vec![],
+        }
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = &AssemblySection> {
+        self.sections.iter()
+    }
+
+    pub fn section(&self, name: &str) -> Option<&AssemblySection> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    pub fn relocations(&self) -> &[ResolvedRelocation] {
+        &self.relocations
+    }
+
+    pub fn origin(&self) -> Option<u64> {
+        self.origin
+    }
+
+    pub fn lines(&self) -> &[SourceLine] {
+        &self.lines
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    // The symbol defined under `name`, which is the full name for local labels.
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols
+            .binary_search_by(|s| s.name.as_str().cmp(name))
+            .ok()
+            .map(|i| &self.symbols[i])
+    }
+
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    // The lines using the label or constant `name`, in source order.
+    pub fn references<'a>(&'a self, name: &'a str) -> impl Iterator<Item = usize> + 'a {
+        self.references
+            .iter()
+            .filter(move |(label, _)| label == name)
+            .map(|(_, line)| *line)
+    }
+
+    pub fn commons(&self) -> &[CommonSymbol] {
+        &self.commons
+    }
+
+    pub fn warnings(&self) -> &[assembler::AssemblyError] {
+        &self.warnings
+    }
+
+    pub fn section_content(&self, name: &str) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.content.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accessors() {
+        let result =
+            assembler::assemble("start:\nmov esi, message\nsection .data\nmessage:").unwrap();
+        let text = result.section(".text").unwrap();
+        assert_eq!(text.content(), &[0xbe, 0, 0, 0, 0]);
+        assert_eq!(text.align(), None);
+        let relocation = &result.relocations()[0];
+        assert_eq!(
+            (
+                relocation.source(),
+                relocation.location(),
+                relocation.section()
+            ),
+            (".text", 1, ".data")
+        );
+        assert_eq!(relocation.typ(), RelocationType::U32);
+        assert_eq!(relocation.line(), 2);
+        assert_eq!(result.lines()[1].section(), Some(".text"));
+        assert_eq!(result.lines()[1].length(), 5);
+        assert_eq!(result.symbol("message").unwrap().section(), ".data");
+        assert!(result.symbol("missing").is_none());
+
+        let built = AssemblyResult::new(
+            vec![
+                AssemblySection::new(".text", vec![0xbe, 0, 0, 0, 0]),
+                AssemblySection::new(".data", vec![]),
+            ],
+            vec![ResolvedRelocation::new(
+                ".text",
+                1,
+                RelocationType::U32,
+                ".data",
+                0,
+            )],
+            None,
+        );
+        assert_eq!(built.sections().count(), 2);
+        assert_eq!(built.relocations()[0].label(), ".data");
+        assert_eq!(built.clone(), built);
+        assert_eq!(
+            crate::flat::create_binary(built).unwrap(),
+            crate::flat::create_binary(result).unwrap()
+        );
+    }
 }
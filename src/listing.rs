@@ -0,0 +1,175 @@
+use crate::*;
+
+// Width of the hex bytes column. Longer output is continued on the next listing line, with a
+// trailing '-' like in nasm.
+const BYTES_WIDTH: usize = 18;
+
+// Splits the bytes of a line into printable tokens: single bytes, and relocated fields in
+// brackets.
+fn tokens(assembly: &AssemblyResult, line: &SourceLine) -> Vec<(u64, String)> {
+    let name = match &line.section {
+        Some(name) => name,
+        None => return vec![],
+    };
+    let content = assembly.section_content(name).unwrap();
+    let mut ret = vec![];
+    let mut offset = line.offset;
+    while offset < line.offset + line.length {
+        let relocation = assembly
+            .relocations
+            .iter()
+            .find(|r| &r.source == name && r.location == offset);
+        let size = relocation.map_or(1, |r| r.typ.size() as u64);
+        let field: String = content[offset as usize..(offset + size) as usize]
+            .iter()
+            .rev()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        if relocation.is_some() {
+            ret.push((offset, format!("[{}]", field)));
+        } else {
+            ret.push((offset, field));
+        }
+        offset += size;
+    }
+    ret
+}
+
+// Creates a nasm-style listing: line number, section offset, bytes and source for each line,
+// followed by a cross-reference table of all symbols.
+pub fn create_listing(assembly: &AssemblyResult) -> String {
+    let mut ret = String::new();
+    for line in &assembly.lines {
+        let tokens = tokens(assembly, line);
+        if tokens.is_empty() {
+            let text = format!(
+                "{:6} {:8} {:w$} {}",
+                line.line,
+                "",
+                "",
+                line.source,
+                w = BYTES_WIDTH + 1
+            );
+            ret += text.trim_end();
+            ret += "\n";
+            continue;
+        }
+
+        // Group the tokens into rows of at most BYTES_WIDTH characters.
+        let mut rows: Vec<(u64, String)> = vec![];
+        for (offset, token) in tokens {
+            match rows.last_mut() {
+                Some((_, row)) if row.len() + token.len() <= BYTES_WIDTH => *row += &token,
+                _ => rows.push((offset, token)),
+            }
+        }
+        let count = rows.len();
+        for (i, (offset, row)) in rows.into_iter().enumerate() {
+            let (continued, source) = if i == 0 {
+                (if count > 1 { "-" } else { "" }, line.source.as_str())
+            } else {
+                (if i + 1 < count { "-" } else { "" }, "")
+            };
+            let bytes = row + continued;
+            let text = format!(
+                "{:6} {:08X} {:w$} {}",
+                line.line,
+                offset,
+                bytes,
+                source,
+                w = BYTES_WIDTH + 1
+            );
+            ret += text.trim_end();
+            ret += "\n";
+        }
+    }
+
+    ret += "\nSymbols:\n";
+    ret += &format!(
+        "{:20} {:12} {:8} {:>7}  References\n",
+        "Name", "Section", "Offset", "Defined"
+    );
+    // Constants are listed with their value, in an "*ABS*" section like in objdump.
+    let mut rows: Vec<(&str, &str, u64, usize)> = assembly
+        .symbols
+        .iter()
+        .map(|s| (s.name.as_str(), s.section.as_str(), s.offset, s.line))
+        .chain(
+            assembly
+                .constants
+                .iter()
+                .map(|c| (c.name.as_str(), "*ABS*", c.value as u64, c.line)),
+        )
+        .collect();
+    rows.sort();
+    for (name, section, offset, line) in rows {
+        let mut references: Vec<usize> = assembly.references(name).collect();
+        references.dedup();
+        let references: Vec<String> = references.iter().map(|l| l.to_string()).collect();
+        let text = format!(
+            "{:20} {:12} {:08X} {:7}  {}",
+            name,
+            section,
+            offset,
+            line,
+            references.join(", ")
+        );
+        ret += text.trim_end();
+        ret += "\n";
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn listing() {
+        let assembly = assemble(
+            "section .text\nmov esi, message\nret\nsection .rodata\nmessage:\n\
+             db \"Hello world!\", 10",
+        )
+        .unwrap();
+        let expected = "     1                              section .text
+     2 00000000 BE[00000000]        mov esi, message
+     3 00000005 C3                  ret
+     4                              section .rodata
+     5                              message:
+     6 00000000 48656C6C6F20776F72- db \"Hello world!\", 10
+     6 00000009 6C64210A
+
+Symbols:
+Name                 Section      Offset   Defined  References
+message              .rodata      00000000       5  2
+";
+        assert_eq!(create_listing(&assembly), expected);
+    }
+
+    #[test]
+    fn cross_reference() {
+        // References resolved while assembling are listed too.
+        let assembly = assemble(
+            "COUNT equ 3
+start:
+mov ecx, COUNT
+.loop:
+dec ecx
+jnz .loop
+call start
+jmp .loop",
+        )
+        .unwrap();
+        let listing = create_listing(&assembly);
+        let table: Vec<&str> = listing.lines().skip_while(|l| *l != "Symbols:").collect();
+        assert_eq!(
+            table[2..],
+            [
+                "COUNT                *ABS*        00000003       1  3",
+                "start                .text        00000000       2  7",
+                "start.loop           .text        00000005       4  6, 8",
+            ]
+        );
+    }
+}
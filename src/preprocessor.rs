@@ -0,0 +1,1049 @@
+use crate::assembler::{AssemblyError, ErrorKind};
+use crate::source::{self, FileSystemLoader, SourceLoader};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// Expansions nested deeper than this are assumed to be infinite recursion.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// A single-line macro from %define, %xdefine or %assign.
+struct Define {
+    parameters: Option<Vec<String>>,
+    body: String,
+}
+
+// A preprocessed line. `source` is the original text, `text` the text to assemble, which is
+// empty for directives.
+pub struct Line {
+    // The included file the line comes from, or the path of the main source if known.
+    pub file: Option<PathBuf>,
+    pub number: usize,
+    pub source: String,
+    pub text: String,
+}
+
+// State of an %if ... %endif block.
+struct Conditional {
+    // The error to report if the block is not closed.
+    unterminated: AssemblyError,
+    // Whether lines in the current branch are assembled.
+    active: bool,
+    // Whether any branch so far was taken, so later %elif and %else branches are not.
+    taken: bool,
+    // Whether the whole block is inside an active branch of the enclosing blocks.
+    enclosing_active: bool,
+    seen_else: bool,
+}
+
+// A multi-line macro from %macro. Without a maximum, any number of parameters is allowed.
+struct Macro {
+    minimum: usize,
+    maximum: Option<usize>,
+    // Whether the last parameter takes the rest of the line, commas included.
+    greedy: bool,
+    defaults: Vec<String>,
+    body: Vec<String>,
+}
+
+// An expansion of a multi-line macro. The id makes its %%labels unique.
+struct Expansion {
+    arguments: Vec<String>,
+    id: usize,
+}
+
+pub struct Preprocessor<'a> {
+    loader: &'a dyn SourceLoader,
+    include_dirs: Vec<PathBuf>,
+    // Files currently being included, to detect cycles.
+    including: Vec<PathBuf>,
+    defines: HashMap<String, Define>,
+    macros: HashMap<String, Macro>,
+    conditionals: Vec<Conditional>,
+    warnings: Vec<AssemblyError>,
+    // Number of macro expansions so far, and how deeply they are currently nested.
+    expansions: usize,
+    depth: usize,
+    // Number of nested %rep blocks being processed. %exitrep sets exit_rep to stop the
+    // innermost one.
+    reps: usize,
+    exit_rep: bool,
+}
+
+// Returns the index of the line ending the block opened by `directive`, taking nested blocks
+// into account.
+fn block_end(lines: &[(usize, String)], start: usize, directive: &str) -> Option<usize> {
+    let end = match directive {
+        "%macro" => "%endmacro",
+        _ => "%endrep",
+    };
+    let mut depth = 0;
+    for (i, (_, line)) in lines.iter().enumerate().skip(start) {
+        let (word, _) = first_word(strip_comment(line));
+        if word == directive {
+            depth += 1;
+        } else if word == end && depth == 0 {
+            return Some(i);
+        } else if word == end {
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// Parses "NAME COUNT [DEFAULTS...]", where COUNT is "N", "N-M" or "N-*", optionally followed
+// by "+" for greedy parameters.
+fn parse_macro(header: &str, body: &[(usize, String)]) -> Result<(String, Macro), ErrorKind> {
+    let (name, rest) = first_word(header);
+    if !name.starts_with(is_identifier_start) {
+        return Err(ErrorKind::InvalidMacroName(name.to_string()));
+    }
+    let (count, defaults) = first_word(rest);
+    let greedy = count.ends_with('+');
+    let count = count.trim_end_matches('+');
+    let number = |text: &str| {
+        text.parse::<usize>()
+            .map_err(|_| ErrorKind::InvalidNumber(text.to_string()))
+    };
+    let (minimum, maximum) = match count.find('-') {
+        Some(i) if &count[i + 1..] == "*" => (number(&count[..i])?, None),
+        Some(i) => (number(&count[..i])?, Some(number(&count[i + 1..])?)),
+        None if count.is_empty() => (0, Some(0)),
+        None => (number(count)?, Some(number(count)?)),
+    };
+    let defaults = if defaults.is_empty() {
+        vec![]
+    } else {
+        split_arguments(defaults)
+            .into_iter()
+            .map(|d| unbrace(d).to_string())
+            .collect()
+    };
+    let definition = Macro {
+        minimum,
+        maximum,
+        greedy,
+        defaults,
+        body: body.iter().map(|(_, line)| line.clone()).collect(),
+    };
+    Ok((name.to_string(), definition))
+}
+
+// Removes the braces nasm uses to pass arguments containing commas.
+fn unbrace(argument: &str) -> &str {
+    if argument.starts_with('{') && argument.ends_with('}') {
+        &argument[1..argument.len() - 1]
+    } else {
+        argument
+    }
+}
+
+impl Macro {
+    // Splits the arguments of an invocation, adding defaults for missing ones.
+    fn arguments(&self, text: &str) -> Result<Vec<String>, ErrorKind> {
+        let mut arguments: Vec<String> = if text.is_empty() {
+            vec![]
+        } else {
+            split_arguments(text)
+                .into_iter()
+                .map(|a| unbrace(a).to_string())
+                .collect()
+        };
+        if let Some(maximum) = self.maximum {
+            if arguments.len() > maximum && self.greedy && maximum > 0 {
+                let rest = arguments.split_off(maximum - 1).join(", ");
+                arguments.push(rest);
+            }
+            if arguments.len() > maximum {
+                return Err(ErrorKind::OperandCount {
+                    expected: maximum,
+                    found: arguments.len(),
+                });
+            }
+        }
+        if arguments.len() < self.minimum {
+            return Err(ErrorKind::OperandCount {
+                expected: self.minimum,
+                found: arguments.len(),
+            });
+        }
+        let given = arguments.len();
+        for i in given..self.maximum.unwrap_or(given) {
+            arguments.push(
+                self.defaults
+                    .get(i - self.minimum)
+                    .cloned()
+                    .unwrap_or_default(),
+            );
+        }
+        Ok(arguments)
+    }
+}
+
+impl Expansion {
+    // Replaces %0, %N, %{N} and %%label outside of strings.
+    fn substitute(&self, line: &str) -> String {
+        let mut ret = String::new();
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            if c == '"' || c == '\'' || c == '`' {
+                let end = rest[1..].find(c).map_or(rest.len(), |i| i + 2);
+                ret += &rest[..end];
+                rest = &rest[end..];
+                continue;
+            }
+            if c != '%' {
+                ret.push(c);
+                rest = &rest[c.len_utf8()..];
+                continue;
+            }
+            let after = &rest[1..];
+            if let Some(label) = after.strip_prefix('%') {
+                let end = label
+                    .find(|c| !is_identifier_char(c))
+                    .unwrap_or(label.len());
+                ret += &format!("..@{}.{}", self.id, &label[..end]);
+                rest = &label[end..];
+                continue;
+            }
+            let (digits, skip) = match after.strip_prefix('{') {
+                Some(braced) => {
+                    let end = braced.find('}').unwrap_or(0);
+                    (&braced[..end], end + 2)
+                }
+                None => {
+                    let end = after
+                        .find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(after.len());
+                    (&after[..end], end)
+                }
+            };
+            match digits.parse::<usize>() {
+                Ok(0) => ret += &self.arguments.len().to_string(),
+                Ok(n) => ret += self.arguments.get(n - 1).map_or("", |a| a.as_str()),
+                Err(_) => {
+                    ret.push('%');
+                    rest = after;
+                    continue;
+                }
+            }
+            rest = &after[skip..];
+        }
+        ret
+    }
+
+    // Rotates the arguments left by `count`, or right if it is negative.
+    fn rotate(&mut self, count: i64) {
+        let length = self.arguments.len() as i64;
+        if length > 0 {
+            self.arguments
+                .rotate_left(count.rem_euclid(length) as usize);
+        }
+    }
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '?' || c == '@'
+}
+
+fn is_identifier_char(c: char) -> bool {
+    is_identifier_start(c) || c.is_ascii_digit() || c == '$' || c == '#' || c == '~'
+}
+
+// Returns the line without its ';' comment, ignoring semicolons in strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            ('"', None) | ('\'', None) | ('`', None) => quote = Some(c),
+            (_, Some(q)) if c == q => quote = None,
+            (';', None) => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// Splits text at commas which are not nested in parentheses, braces or strings.
+pub(crate) fn split_arguments(text: &str) -> Vec<&str> {
+    let mut ret = vec![];
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (c, quote) {
+            ('"', None) | ('\'', None) | ('`', None) => quote = Some(c),
+            (_, Some(q)) if c == q => quote = None,
+            ('(', None) | ('{', None) => depth += 1,
+            (')', None) | ('}', None) => depth -= 1,
+            (',', None) if depth == 0 => {
+                ret.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    ret.push(text[start..].trim());
+    ret
+}
+
+// Splits off the first word of `text`, returning it and the trimmed rest.
+fn first_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], text[end..].trim())
+}
+
+// Removes the quotes around a message of %error or %warning, if there are any.
+fn unquote(text: &str) -> &str {
+    for quote in &['"', '\'', '`'] {
+        if text.len() >= 2 && text.starts_with(*quote) && text.ends_with(*quote) {
+            return &text[1..text.len() - 1];
+        }
+    }
+    text
+}
+
+impl<'a> Preprocessor<'a> {
+    pub fn new() -> Preprocessor<'a> {
+        Preprocessor {
+            loader: &FileSystemLoader,
+            include_dirs: vec![],
+            including: vec![],
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            conditionals: vec![],
+            warnings: vec![],
+            expansions: 0,
+            depth: 0,
+            reps: 0,
+            exit_rep: false,
+        }
+    }
+
+    pub fn set_loader(&mut self, loader: &'a dyn SourceLoader) {
+        self.loader = loader;
+    }
+
+    // Adds a directory to search for %include files, like nasm's -I.
+    pub fn add_include_dir(&mut self, directory: &Path) {
+        self.include_dirs.push(directory.to_path_buf());
+    }
+
+    // Defines a macro as if with %define, e.g. for -D on the command line.
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.defines.insert(
+            name.to_string(),
+            Define {
+                parameters: None,
+                body: value.to_string(),
+            },
+        );
+    }
+
+    pub fn warnings(&self) -> &[AssemblyError] {
+        &self.warnings
+    }
+
+    pub fn into_warnings(self) -> Vec<AssemblyError> {
+        self.warnings
+    }
+
+    fn active(&self) -> bool {
+        self.conditionals.last().is_none_or(|c| c.active)
+    }
+
+    pub fn process(&mut self, text: &str) -> Result<Vec<Line>, AssemblyError> {
+        self.process_file(text, None)
+    }
+
+    // Like process(), for a source read from `path`. Includes are searched relative to it.
+    pub fn process_file(
+        &mut self,
+        text: &str,
+        path: Option<&Path>,
+    ) -> Result<Vec<Line>, AssemblyError> {
+        let lines: Vec<(usize, String)> = (1..).zip(text.lines().map(String::from)).collect();
+        let mut ret = vec![];
+        self.process_lines(&lines, path, None, &mut ret)?;
+        if let Some(conditional) = self.conditionals.last() {
+            return Err(conditional.unterminated.clone());
+        }
+        Ok(ret)
+    }
+
+    // Processes source lines of `file`, or the body of a macro or %rep block. `expansion` is the
+    // innermost macro being expanded, if any.
+    fn process_lines(
+        &mut self,
+        lines: &[(usize, String)],
+        file: Option<&Path>,
+        mut expansion: Option<&mut Expansion>,
+        ret: &mut Vec<Line>,
+    ) -> Result<(), AssemblyError> {
+        let mut i = 0;
+        while i < lines.len() && !self.exit_rep {
+            let (number, raw) = &lines[i];
+            let number = *number;
+            i += 1;
+            let source = match &expansion {
+                Some(expansion) => expansion.substitute(raw),
+                None => raw.clone(),
+            };
+            let error = |kind| AssemblyError {
+                file: file.map(Path::to_path_buf),
+                line: number,
+                source: source.clone(),
+                kind,
+            };
+            let line = strip_comment(&source).trim();
+            let (directive, rest) = first_word(line);
+
+            // Blocks are collected unsubstituted, since %rotate changes the parameters while
+            // a %rep block inside a macro is processed.
+            if self.active() && (directive == "%macro" || directive == "%rep") {
+                let end = block_end(lines, i, directive)
+                    .ok_or_else(|| error(ErrorKind::UnterminatedBlock(directive.to_string())))?;
+                let body = &lines[i..end];
+                i = end + 1;
+                ret.push(Line {
+                    file: file.map(Path::to_path_buf),
+                    number,
+                    source: source.clone(),
+                    text: String::new(),
+                });
+                if directive == "%macro" {
+                    let (name, definition) = parse_macro(rest, body).map_err(error)?;
+                    self.macros.insert(name, definition);
+                } else {
+                    let count = self.evaluate(rest).map_err(error)?;
+                    self.reps += 1;
+                    for _ in 0..count {
+                        let depth = self.conditionals.len();
+                        self.process_lines(body, file, expansion.as_deref_mut(), ret)?;
+                        // %exitrep may leave conditionals of the block open.
+                        self.conditionals.truncate(depth);
+                        if self.exit_rep {
+                            self.exit_rep = false;
+                            break;
+                        }
+                    }
+                    self.reps -= 1;
+                }
+                continue;
+            }
+
+            let text = if line.starts_with('%') {
+                let active = match directive {
+                    "%rotate" if self.active() => {
+                        let expansion = expansion
+                            .as_deref_mut()
+                            .ok_or_else(|| error(ErrorKind::OutsideMacro(directive.to_string())))?;
+                        let count = self.evaluate(rest).map_err(error)?;
+                        expansion.rotate(count);
+                        true
+                    }
+                    "%exitrep" if self.active() => {
+                        if self.reps == 0 {
+                            return Err(error(ErrorKind::UnmatchedDirective(
+                                directive.to_string(),
+                            )));
+                        }
+                        self.exit_rep = true;
+                        true
+                    }
+                    "%include" if self.active() => {
+                        ret.push(Line {
+                            file: file.map(Path::to_path_buf),
+                            number,
+                            source: source.clone(),
+                            text: String::new(),
+                        });
+                        self.include(rest, file, &error, ret)?;
+                        continue;
+                    }
+                    _ => self.directive(directive, rest, &error).map_err(error)?,
+                };
+                if !active {
+                    continue;
+                }
+                String::new()
+            } else if self.active() {
+                self.expand(line, 0).map_err(error)?
+            } else {
+                continue;
+            };
+
+            let (name, arguments) = first_word(&text);
+            if let Some(definition) = self.macros.get(name) {
+                if self.depth > MAX_EXPANSION_DEPTH {
+                    return Err(error(ErrorKind::MacroRecursion(name.to_string())));
+                }
+                let arguments = definition.arguments(arguments).map_err(error)?;
+                let body = definition.body.clone();
+                self.expansions += 1;
+                let mut inner = Expansion {
+                    arguments,
+                    id: self.expansions,
+                };
+                ret.push(Line {
+                    file: file.map(Path::to_path_buf),
+                    number,
+                    source,
+                    text: String::new(),
+                });
+                // Lines of the macro body are reported at the line invoking the macro.
+                let body: Vec<(usize, String)> =
+                    body.into_iter().map(|line| (number, line)).collect();
+                self.depth += 1;
+                let result = self.process_lines(&body, file, Some(&mut inner), ret);
+                self.depth -= 1;
+                result?;
+                continue;
+            }
+            ret.push(Line {
+                file: file.map(Path::to_path_buf),
+                number,
+                source,
+                text,
+            });
+        }
+        Ok(())
+    }
+
+    // Handles %include. `error` reports errors at the %include line.
+    fn include(
+        &mut self,
+        argument: &str,
+        from: Option<&Path>,
+        error: &dyn Fn(ErrorKind) -> AssemblyError,
+        ret: &mut Vec<Line>,
+    ) -> Result<(), AssemblyError> {
+        let name = self.expand(argument, 0).map_err(error)?;
+        let name = unquote(name.trim());
+        let (path, content) =
+            source::find(self.loader, name, from, &self.include_dirs).map_err(error)?;
+        if self.including.contains(&path) || from == Some(path.as_path()) {
+            return Err(error(ErrorKind::IncludeCycle(path.display().to_string())));
+        }
+        let text = String::from_utf8(content)
+            .map_err(|_| error(ErrorKind::Io(format!("{}: invalid UTF-8", path.display()))))?;
+        let lines: Vec<(usize, String)> = (1..).zip(text.lines().map(String::from)).collect();
+        self.including.push(path.clone());
+        let result = self.process_lines(&lines, Some(&path), None, ret);
+        self.including.pop();
+        result
+    }
+
+    // Handles a directive line. Returns whether it was in an active part of the source.
+    fn directive(
+        &mut self,
+        directive: &str,
+        rest: &str,
+        error: &dyn Fn(ErrorKind) -> AssemblyError,
+    ) -> Result<bool, ErrorKind> {
+        match directive {
+            "%if" | "%ifdef" | "%ifndef" => {
+                let enclosing_active = self.active();
+                let condition = enclosing_active && self.condition(directive, rest)?;
+                self.conditionals.push(Conditional {
+                    unterminated: error(ErrorKind::UnterminatedConditional),
+                    active: condition,
+                    taken: condition,
+                    enclosing_active,
+                    seen_else: false,
+                });
+                return Ok(enclosing_active);
+            }
+            "%elif" | "%elifdef" | "%elifndef" | "%else" => {
+                let conditional = match self.conditionals.last() {
+                    Some(conditional) if !conditional.seen_else => conditional,
+                    _ => return Err(ErrorKind::UnmatchedDirective(directive.to_string())),
+                };
+                let enclosing_active = conditional.enclosing_active;
+                let condition = if !enclosing_active || conditional.taken {
+                    false
+                } else if directive == "%else" {
+                    true
+                } else {
+                    self.condition(&directive.replace("%elif", "%if"), rest)?
+                };
+                let conditional = self.conditionals.last_mut().unwrap();
+                conditional.active = condition;
+                conditional.taken |= condition;
+                conditional.seen_else = directive == "%else";
+                return Ok(enclosing_active);
+            }
+            "%endmacro" | "%endrep" if self.active() => {
+                return Err(ErrorKind::UnmatchedDirective(directive.to_string()));
+            }
+            "%endif" => {
+                let conditional = self
+                    .conditionals
+                    .pop()
+                    .ok_or_else(|| ErrorKind::UnmatchedDirective(directive.to_string()))?;
+                return Ok(conditional.enclosing_active);
+            }
+            _ => {}
+        }
+
+        if !self.active() {
+            return Ok(false);
+        }
+        match directive {
+            "%define" | "%xdefine" => {
+                let (name, parameters, body) = self.parse_define(rest)?;
+                let body = if directive == "%xdefine" {
+                    self.expand(body, 0)?
+                } else {
+                    body.to_string()
+                };
+                self.defines
+                    .insert(name.to_string(), Define { parameters, body });
+            }
+            "%undef" => {
+                let (name, _) = first_word(rest);
+                if !name.starts_with(is_identifier_start) {
+                    return Err(ErrorKind::InvalidMacroName(name.to_string()));
+                }
+                self.defines.remove(name);
+            }
+            "%assign" => {
+                let (name, expression) = first_word(rest);
+                if !name.starts_with(is_identifier_start) {
+                    return Err(ErrorKind::InvalidMacroName(name.to_string()));
+                }
+                let value = self.evaluate(expression)?;
+                self.define(name, &value.to_string());
+            }
+            "%error" => {
+                let message = self.expand(rest, 0)?;
+                return Err(ErrorKind::UserError(unquote(&message).to_string()));
+            }
+            "%warning" => {
+                let message = self.expand(rest, 0)?;
+                self.warnings
+                    .push(error(ErrorKind::UserWarning(unquote(&message).to_string())));
+            }
+            _ => return Err(ErrorKind::UnknownDirective(directive.to_string())),
+        }
+        Ok(true)
+    }
+
+    // Parses "NAME body" or "NAME(a, b) body".
+    fn parse_define<'t>(
+        &self,
+        text: &'t str,
+    ) -> Result<(&'t str, Option<Vec<String>>, &'t str), ErrorKind> {
+        let end = text.find(|c| !is_identifier_char(c)).unwrap_or(text.len());
+        let name = &text[..end];
+        if !name.starts_with(is_identifier_start) {
+            return Err(ErrorKind::InvalidMacroName(first_word(text).0.to_string()));
+        }
+        let rest = &text[end..];
+        if rest.starts_with('(') {
+            let close = rest
+                .find(')')
+                .ok_or_else(|| ErrorKind::InvalidMacroName(text.to_string()))?;
+            let parameters = rest[1..close]
+                .split(',')
+                .map(|p| p.trim().to_string())
+                .filter(|p| !p.is_empty())
+                .collect();
+            Ok((name, Some(parameters), rest[close + 1..].trim()))
+        } else {
+            Ok((name, None, rest.trim()))
+        }
+    }
+
+    fn condition(&self, directive: &str, rest: &str) -> Result<bool, ErrorKind> {
+        match directive {
+            "%ifdef" => Ok(self.defines.contains_key(first_word(rest).0)),
+            "%ifndef" => Ok(!self.defines.contains_key(first_word(rest).0)),
+            _ => Ok(self.evaluate(rest)? != 0),
+        }
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<i64, ErrorKind> {
+        evaluate(&self.expand(expression, 0)?)
+    }
+
+    // Replaces all macros in `text`, outside of strings.
+    fn expand(&self, text: &str, depth: usize) -> Result<String, ErrorKind> {
+        if depth > MAX_EXPANSION_DEPTH {
+            return Err(ErrorKind::MacroRecursion(text.to_string()));
+        }
+        let mut ret = String::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if c == '"' || c == '\'' || c == '`' {
+                let end = rest[1..].find(c).map_or(rest.len(), |i| i + 2);
+                ret += &rest[..end];
+                rest = &rest[end..];
+            } else if is_identifier_start(c) || c.is_ascii_digit() {
+                let end = rest.find(|c| !is_identifier_char(c)).unwrap_or(rest.len());
+                let word = &rest[..end];
+                rest = &rest[end..];
+                let define = match self.defines.get(word) {
+                    Some(define) if !c.is_ascii_digit() => define,
+                    _ => {
+                        ret += word;
+                        continue;
+                    }
+                };
+                let body = match &define.parameters {
+                    None => define.body.clone(),
+                    Some(parameters) => {
+                        // A macro with parameters is only expanded when called.
+                        let arguments = match rest.trim_start().strip_prefix('(') {
+                            Some(arguments) => arguments,
+                            None => {
+                                ret += word;
+                                continue;
+                            }
+                        };
+                        let close = matching_parenthesis(arguments)
+                            .ok_or_else(|| ErrorKind::InvalidExpression(text.to_string()))?;
+                        rest = &arguments[close + 1..];
+                        let arguments = split_arguments(&arguments[..close]);
+                        if arguments.len() != parameters.len() {
+                            return Err(ErrorKind::OperandCount {
+                                expected: parameters.len(),
+                                found: arguments.len(),
+                            });
+                        }
+                        substitute(&define.body, parameters, &arguments)
+                    }
+                };
+                ret += &self.expand(&body, depth + 1)?;
+            } else {
+                ret.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl<'a> Default for Preprocessor<'a> {
+    fn default() -> Preprocessor<'a> {
+        Preprocessor::new()
+    }
+}
+
+// Returns the index of the ')' closing an already opened parenthesis.
+// Evaluates a constant expression, with the operators of %if.
+pub(crate) fn evaluate(expression: &str) -> Result<i64, ErrorKind> {
+    Expression::new(expression)
+        .parse()
+        .map_err(|message| ErrorKind::InvalidExpression(format!("{}: {}", expression, message)))
+}
+
+fn matching_parenthesis(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(i),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+// Replaces the parameter names in `body` by the arguments.
+fn substitute(body: &str, parameters: &[String], arguments: &[&str]) -> String {
+    let mut ret = String::new();
+    let mut rest = body;
+    while let Some(c) = rest.chars().next() {
+        if is_identifier_start(c) {
+            let end = rest.find(|c| !is_identifier_char(c)).unwrap_or(rest.len());
+            let word = &rest[..end];
+            match parameters.iter().position(|p| p == word) {
+                Some(i) => ret += arguments[i],
+                None => ret += word,
+            }
+            rest = &rest[end..];
+        } else {
+            ret.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    ret
+}
+
+// A recursive descent parser for the integer expressions of %if and %assign, with nasm's
+// operators and precedences.
+struct Expression<'a> {
+    text: &'a str,
+}
+
+// Binary operators from lowest to highest precedence.
+const BINARY_OPERATORS: &[&[&str]] = &[
+    &["||"],
+    &["^^"],
+    &["&&"],
+    &["==", "!=", "<>", "<=", ">=", "=", "<", ">"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl<'a> Expression<'a> {
+    fn new(text: &'a str) -> Expression<'a> {
+        Expression { text }
+    }
+
+    fn parse(&mut self) -> Result<i64, String> {
+        let value = self.binary(0)?;
+        if !self.text.trim().is_empty() {
+            return Err(format!("unexpected '{}'", self.text.trim()));
+        }
+        Ok(value)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        match self.text.trim_start().strip_prefix(token) {
+            Some(rest) => {
+                self.text = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == BINARY_OPERATORS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'outer: loop {
+            for operator in BINARY_OPERATORS[level] {
+                // Don't mistake "<<" for "<", or "&&" for "&".
+                let text = self.text.trim_start();
+                if BINARY_OPERATORS[..level]
+                    .iter()
+                    .chain(BINARY_OPERATORS[level + 1..].iter())
+                    .flat_map(|o| o.iter())
+                    .any(|o| o.len() > operator.len() && text.starts_with(o))
+                {
+                    continue;
+                }
+                if !self.eat(operator) {
+                    continue;
+                }
+                let right = self.binary(level + 1)?;
+                left = match *operator {
+                    "||" => (left != 0 || right != 0) as i64,
+                    "^^" => ((left != 0) ^ (right != 0)) as i64,
+                    "&&" => (left != 0 && right != 0) as i64,
+                    "==" | "=" => (left == right) as i64,
+                    "!=" | "<>" => (left != right) as i64,
+                    "<=" => (left <= right) as i64,
+                    ">=" => (left >= right) as i64,
+                    "<" => (left < right) as i64,
+                    ">" => (left > right) as i64,
+                    "|" => left | right,
+                    "^" => left ^ right,
+                    "&" => left & right,
+                    "<<" => left.wrapping_shl(right as u32),
+                    ">>" => left.wrapping_shr(right as u32),
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "*" => left.wrapping_mul(right),
+                    "/" | "%" if right == 0 => return Err("division by zero".to_string()),
+                    "/" => left.wrapping_div(right),
+                    _ => left.wrapping_rem(right),
+                };
+                continue 'outer;
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        if self.eat("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.eat("+") {
+            self.unary()
+        } else if self.eat("~") {
+            Ok(!self.unary()?)
+        } else if self.eat("!") {
+            Ok((self.unary()? == 0) as i64)
+        } else if self.eat("(") {
+            let value = self.binary(0)?;
+            if !self.eat(")") {
+                return Err("missing ')'".to_string());
+            }
+            Ok(value)
+        } else {
+            self.number()
+        }
+    }
+
+    fn number(&mut self) -> Result<i64, String> {
+        let text = self.text.trim_start();
+        let end = text.find(|c| !is_identifier_char(c)).unwrap_or(text.len());
+        let word = &text[..end];
+        self.text = &text[end..];
+        let value = if let Some(hex) = word.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16)
+        } else if let Some(binary) = word.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2)
+        } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+            word.parse()
+        } else if word.is_empty() {
+            return Err("expected a number".to_string());
+        } else {
+            return Err(format!("undefined symbol '{}'", word));
+        };
+        value.map_err(|_| format!("invalid number '{}'", word))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(text: &str) -> Result<Vec<String>, ErrorKind> {
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.define("CONFIG", "2");
+        let lines = preprocessor.process(text).map_err(|e| e.kind)?;
+        Ok(lines
+            .into_iter()
+            .map(|l| l.text)
+            .filter(|t| !t.is_empty())
+            .collect())
+    }
+
+    #[test]
+    fn defines() {
+        assert_eq!(
+            process("%define N 5 ; five\nmov eax, N\n%undef N\nmov eax, N").unwrap(),
+            vec!["mov eax, 5", "mov eax, N"]
+        );
+        assert_eq!(
+            process("%define A B\n%define B 1\n%xdefine C A\n%define B 2\ndb A, C").unwrap(),
+            vec!["db 2, 1"]
+        );
+        assert_eq!(
+            process("%define add(a, b) a + b\ndb add(1, add(2, 3)), \"add(1, 2)\"").unwrap(),
+            vec!["db 1 + 2 + 3, \"add(1, 2)\""]
+        );
+        assert_eq!(
+            process("%define X X\nret X"),
+            Err(ErrorKind::MacroRecursion("X".to_string()))
+        );
+    }
+
+    #[test]
+    fn assign() {
+        assert_eq!(
+            process("%assign i 1\n%assign i i * (2 + 3) << 1\ndb i").unwrap(),
+            vec!["db 10"]
+        );
+        assert_eq!(
+            process("%assign i 1 / 0"),
+            Err(ErrorKind::InvalidExpression(
+                "1 / 0: division by zero".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn conditionals() {
+        let text = "%if CONFIG == 1\ndb 1\n%elif CONFIG == 2 && 1\ndb 2\n\
+                    %ifdef MISSING\ndb 3\n%else\ndb 4\n%endif\n%else\ndb 5\n%endif";
+        assert_eq!(process(text).unwrap(), vec!["db 2", "db 4"]);
+        assert_eq!(
+            process("%ifndef CONFIG\n%if UNDEFINED\n%error no\n%endif\n%endif").unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            process("%else"),
+            Err(ErrorKind::UnmatchedDirective("%else".to_string()))
+        );
+        assert_eq!(process("%if 1"), Err(ErrorKind::UnterminatedConditional));
+    }
+
+    #[test]
+    fn macros() {
+        let text = "%macro exit 0-1 0\nmov eax, 60\nmov edi, %1\nsyscall\n%endmacro\n\
+                    exit\nexit 3";
+        assert_eq!(
+            process(text).unwrap(),
+            vec![
+                "mov eax, 60",
+                "mov edi, 0",
+                "syscall",
+                "mov eax, 60",
+                "mov edi, 3",
+                "syscall"
+            ]
+        );
+        assert_eq!(
+            process("%macro data 1+\ndb %0, %1\n%endmacro\ndata 1, 2, {3}").unwrap(),
+            vec!["db 1, 1, 2, 3"]
+        );
+        assert_eq!(
+            process("%macro spin 0\n%%loop:\ndb \"%%loop\"\n%endmacro\nspin\nspin").unwrap(),
+            vec!["..@1.loop:", "db \"%%loop\"", "..@2.loop:", "db \"%%loop\""]
+        );
+        assert_eq!(
+            process("%macro two 2\n%endmacro\ntwo 1"),
+            Err(ErrorKind::OperandCount {
+                expected: 2,
+                found: 1
+            })
+        );
+        assert_eq!(
+            process("%macro forever 0\nforever\n%endmacro\nforever"),
+            Err(ErrorKind::MacroRecursion("forever".to_string()))
+        );
+        assert_eq!(
+            process("%macro m 0\nret"),
+            Err(ErrorKind::UnterminatedBlock("%macro".to_string()))
+        );
+    }
+
+    #[test]
+    fn rep() {
+        let text = "%assign i 0\n%rep 10\n%if i == 3\n%exitrep\n%endif\ndb i\n\
+                    %assign i i + 1\n%endrep";
+        assert_eq!(process(text).unwrap(), vec!["db 0", "db 1", "db 2"]);
+
+        let text = "%macro pushall 1-*\n%rep %0\ndb %1\n%rotate 1\n%endrep\n%endmacro\n\
+                    pushall 1, 2, 3";
+        assert_eq!(process(text).unwrap(), vec!["db 1", "db 2", "db 3"]);
+        assert_eq!(
+            process("%rotate 1"),
+            Err(ErrorKind::OutsideMacro("%rotate".to_string()))
+        );
+        assert_eq!(
+            process("%exitrep"),
+            Err(ErrorKind::UnmatchedDirective("%exitrep".to_string()))
+        );
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(
+            process("%if CONFIG > 1\n%error \"CONFIG too large\"\n%endif"),
+            Err(ErrorKind::UserError("CONFIG too large".to_string()))
+        );
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.process("ret\n%warning check this").unwrap();
+        assert_eq!(preprocessor.warnings()[0].line, 2);
+        assert_eq!(
+            preprocessor.warnings()[0].kind,
+            ErrorKind::UserWarning("check this".to_string())
+        );
+        assert_eq!(
+            process("%frobnicate"),
+            Err(ErrorKind::UnknownDirective("%frobnicate".to_string()))
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use crate::assembler::ErrorKind;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// Loads files for %include and incbin. Tests can use MemoryLoader instead of the file system.
+pub trait SourceLoader {
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>>;
+}
+
+pub struct FileSystemLoader;
+
+impl SourceLoader for FileSystemLoader {
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryLoader {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> MemoryLoader {
+        MemoryLoader::default()
+    }
+
+    pub fn add_file(&mut self, path: &str, content: &[u8]) {
+        self.files
+            .insert(normalize(Path::new(path)), content.to_vec());
+    }
+}
+
+impl SourceLoader for MemoryLoader {
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))
+    }
+}
+
+// Removes "." components and resolves ".." lexically, so that the same file included through
+// different paths is recognized in include cycles.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if ret.file_name().is_some() => {
+                ret.pop();
+            }
+            _ => ret.push(component),
+        }
+    }
+    ret
+}
+
+// Finds `name` relative to the directory of the file including it (or the current directory),
+// then in the include directories. Returns the path found and the content.
+pub fn find(
+    loader: &dyn SourceLoader,
+    name: &str,
+    from: Option<&Path>,
+    include_dirs: &[PathBuf],
+) -> Result<(PathBuf, Vec<u8>), ErrorKind> {
+    let directory = from.and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+    let candidates = std::iter::once(directory).chain(include_dirs.iter().map(PathBuf::as_path));
+    for candidate in candidates {
+        let path = normalize(&candidate.join(name));
+        match loader.load(&path) {
+            Ok(content) => return Ok((path, content)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(ErrorKind::Io(format!("{}: {}", path.display(), err))),
+        }
+    }
+    Err(ErrorKind::FileNotFound(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search() {
+        let mut loader = MemoryLoader::new();
+        loader.add_file("src/a.inc", b"a");
+        loader.add_file("include/b.inc", b"b");
+        let from = Path::new("src/main.asm");
+        let include_dirs = vec![PathBuf::from("include")];
+
+        let (path, content) = find(&loader, "a.inc", Some(from), &include_dirs).unwrap();
+        assert_eq!(path, PathBuf::from("src/a.inc"));
+        assert_eq!(content, b"a");
+        let (path, _) = find(&loader, "b.inc", Some(from), &include_dirs).unwrap();
+        assert_eq!(path, PathBuf::from("include/b.inc"));
+        let (path, _) = find(&loader, "../src/./a.inc", Some(from), &[]).unwrap();
+        assert_eq!(path, PathBuf::from("src/a.inc"));
+        assert_eq!(
+            find(&loader, "c.inc", Some(from), &include_dirs).err(),
+            Some(ErrorKind::FileNotFound("c.inc".to_string()))
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Size {
+    pub fn bytes(self) -> usize {
+        match self {
+            Size::Byte => 1,
+            Size::Word => 2,
+            Size::Dword => 4,
+            Size::Qword => 8,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        self.bytes() as u32 * 8
+    }
+
+    pub fn from_bytes(bytes: usize) -> Option<Size> {
+        match bytes {
+            1 => Some(Size::Byte),
+            2 => Some(Size::Word),
+            4 => Some(Size::Dword),
+            8 => Some(Size::Qword),
+            _ => None,
+        }
+    }
+
+    pub fn keyword(self) -> &'static str {
+        match self {
+            Size::Byte => "byte",
+            Size::Word => "word",
+            Size::Dword => "dword",
+            Size::Qword => "qword",
+        }
+    }
+
+    // Mask selecting the bits of a value that fit into this size.
+    pub fn mask(self) -> u64 {
+        match self {
+            Size::Qword => u64::MAX,
+            _ => (1 << self.bits()) - 1,
+        }
+    }
+}
+
+const NAMES_64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const NAMES_32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const NAMES_16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w",
+    "r14w", "r15w",
+];
+const NAMES_8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+const NAMES_8_HIGH: [&str; 4] = ["ah", "ch", "dh", "bh"];
+
+// A general purpose register. `number` is the index of the full 64-bit register it is a part of,
+// `high_byte` is only set for ah, ch, dh and bh, which address bits 8 to 15 of registers 0 to 3.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Register {
+    pub number: u8,
+    pub size: Size,
+    pub high_byte: bool,
+}
+
+impl Register {
+    pub fn new(number: u8, size: Size) -> Register {
+        Register {
+            number,
+            size,
+            high_byte: false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        let n = self.number as usize;
+        match self.size {
+            Size::Byte if self.high_byte => NAMES_8_HIGH[n],
+            Size::Byte => NAMES_8[n],
+            Size::Word => NAMES_16[n],
+            Size::Dword => NAMES_32[n],
+            Size::Qword => NAMES_64[n],
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Register> {
+        let name = name.to_lowercase();
+        let name = name.as_str();
+        if let Some(n) = NAMES_8_HIGH.iter().position(|&r| r == name) {
+            return Some(Register {
+                number: n as u8,
+                size: Size::Byte,
+                high_byte: true,
+            });
+        }
+        for (names, size) in &[
+            (NAMES_64, Size::Qword),
+            (NAMES_32, Size::Dword),
+            (NAMES_16, Size::Word),
+            (NAMES_8, Size::Byte),
+        ] {
+            if let Some(n) = names.iter().position(|&r| r == name) {
+                return Some(Register::new(n as u8, *size));
+            }
+        }
+        None
+    }
+
+    // Registers r8 to r15 and the low bytes of rsp, rbp, rsi and rdi can only be encoded using a
+    // REX prefix.
+    pub fn needs_rex(self) -> bool {
+        self.number >= 8 || (self.size == Size::Byte && !self.high_byte && self.number >= 4)
+    }
+
+    pub fn all_names() -> Vec<&'static str> {
+        let mut ret = vec![];
+        ret.extend_from_slice(&NAMES_64);
+        ret.extend_from_slice(&NAMES_32);
+        ret.extend_from_slice(&NAMES_16);
+        ret.extend_from_slice(&NAMES_8);
+        ret.extend_from_slice(&NAMES_8_HIGH);
+        ret
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Condition code suffixes, in the order of their encoding in Jcc, SETcc and CMOVcc.
+pub const CONDITIONS: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Memory {
+    pub size: Size,
+    pub segment: Option<&'static str>,
+    pub base: Option<Register>,
+    pub index: Option<Register>,
+    pub scale: u8,
+    pub displacement: i64,
+    pub rip_relative: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    Immediate(i64),
+    Memory(Memory),
+    // Absolute target address of a relative jump or call.
+    Target(u64),
+}
+
+pub fn format_number(value: i64) -> String {
+    if (0..10).contains(&value) {
+        format!("{}", value)
+    } else if value < 0 {
+        format!("-0x{:x}", (value as i128).abs())
+    } else {
+        format!("0x{:x}", value)
+    }
+}
+
+impl Memory {
+    pub fn address_string(&self) -> String {
+        let mut parts = vec![];
+        if self.rip_relative {
+            parts.push("rip".to_string());
+        }
+        if let Some(base) = self.base {
+            parts.push(base.name().to_string());
+        }
+        if let Some(index) = self.index {
+            if self.scale > 1 {
+                parts.push(format!("{}*{}", index.name(), self.scale));
+            } else {
+                parts.push(index.name().to_string());
+            }
+        }
+        let mut ret = parts.join(" + ");
+        if parts.is_empty() {
+            ret = format_number(self.displacement);
+        } else if self.displacement > 0 {
+            ret += &format!(" + {}", format_number(self.displacement));
+        } else if self.displacement < 0 {
+            ret += &format!(" - {}", format_number(-self.displacement));
+        }
+        match self.segment {
+            Some(segment) => format!("[{}:{}]", segment, ret),
+            None => format!("[{}]", ret),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "{}", register),
+            Operand::Immediate(value) => write!(f, "{}", format_number(*value)),
+            Operand::Memory(memory) => {
+                write!(f, "{} {}", memory.size.keyword(), memory.address_string())
+            }
+            Operand::Target(address) => write!(f, "0x{:x}", address),
+        }
+    }
+}
+
+// The raw bytes of an encoded instruction, split into their functional parts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Layout {
+    pub prefixes: Vec<u8>,
+    pub rex: Option<u8>,
+    pub opcode: Vec<u8>,
+    pub modrm: Option<u8>,
+    pub sib: Option<u8>,
+    pub displacement: Vec<u8>,
+    pub immediate: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub address: u64,
+    pub length: usize,
+    pub prefix: Option<&'static str>,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    pub layout: Layout,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(prefix) = self.prefix {
+            write!(f, "{} ", prefix)?;
+        }
+        write!(f, "{}", self.mnemonic)?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            match operand {
+                // The size of the loaded address is irrelevant.
+                Operand::Memory(memory) if self.mnemonic == "lea" => {
+                    write!(f, "{}{}", separator, memory.address_string())?
+                }
+                _ => write!(f, "{}{}", separator, operand)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_names() {
+        assert_eq!(
+            Register::from_name("eax"),
+            Some(Register::new(0, Size::Dword))
+        );
+        assert_eq!(
+            Register::from_name("R12"),
+            Some(Register::new(12, Size::Qword))
+        );
+        assert!(Register::from_name("sil").unwrap().needs_rex());
+        assert_eq!(Register::from_name("ah").unwrap().name(), "ah");
+        assert_eq!(Register::from_name("foo"), None);
+    }
+
+    #[test]
+    fn memory_formatting() {
+        let memory = Memory {
+            size: Size::Dword,
+            segment: None,
+            base: Some(Register::new(5, Size::Qword)),
+            index: Some(Register::new(0, Size::Qword)),
+            scale: 4,
+            displacement: -8,
+            rip_relative: false,
+        };
+        assert_eq!(
+            Operand::Memory(memory).to_string(),
+            "dword [rbp + rax*4 - 8]"
+        );
+    }
+}